@@ -0,0 +1,259 @@
+//! Append-only audit log of state-changing calls, kept in stable memory.
+//!
+//! Each entry stores who called which method, when, what it targeted and the fields it
+//! changed. Entries are only ever inserted (by `record_audit`), never updated or removed.
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+
+use crate::memory::{from_cbor_bytes, to_cbor_bytes, try_from_cbor_bytes, MapValue};
+use crate::roles::is_admin;
+use crate::{BookingId, CanisterState, STATE};
+
+/// Most entries returned by one `get_audit_log` call
+pub const MAX_AUDIT_PAGE_SIZE: u64 = 100;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FieldChange {
+    /// dotted path into the target, e.g. "payment_details.payment_status"; "" for the whole value
+    pub field: String,
+    /// compact JSON, `None` when the field didn't exist
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub method: String,
+    pub booking_id: Option<BookingId>,
+    pub principal: Option<Principal>,
+    /// for calls keyed by email only, e.g. wishlists
+    pub email: Option<String>,
+    pub changes: Vec<FieldChange>,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        to_cbor_bytes(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        from_cbor_bytes(bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl MapValue for AuditEntry {
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        try_from_cbor_bytes(bytes)
+    }
+}
+
+/// What an audited call acted on
+#[derive(Clone, Debug, Default)]
+pub struct AuditTarget {
+    pub booking_id: Option<BookingId>,
+    pub principal: Option<Principal>,
+    pub email: Option<String>,
+}
+
+impl AuditTarget {
+    pub fn booking(booking_id: &BookingId) -> Self {
+        Self {
+            booking_id: Some(booking_id.clone()),
+            ..Default::default()
+        }
+    }
+
+    pub fn principal(principal: Principal) -> Self {
+        Self {
+            principal: Some(principal),
+            ..Default::default()
+        }
+    }
+
+    pub fn email(email: &str) -> Self {
+        Self {
+            email: Some(email.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Every field is optional; `from` and `to` are inclusive timestamps in nanoseconds
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct AuditFilter {
+    pub method: Option<String>,
+    pub booking_id: Option<BookingId>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.method.as_ref().is_none_or(|m| *m == entry.method)
+            && self
+                .booking_id
+                .as_ref()
+                .is_none_or(|id| entry.booking_id.as_ref() == Some(id))
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp <= to)
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AuditLogPage {
+    pub entries: Vec<AuditEntry>,
+    /// pass as `start_after` to get the next page; `None` on the last page
+    pub next_start_after: Option<u64>,
+}
+
+/// Field-level differences between two values, compared through their JSON form
+pub fn diff<T: Serialize>(before: &T, after: &T) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    diff_values("", &to_json(before), &to_json(after), &mut changes);
+    changes
+}
+
+fn to_json<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap_or_else(|e| Value::String(format!("<unserializable: {}>", e)))
+}
+
+fn diff_values(path: &str, before: &Value, after: &Value, changes: &mut Vec<FieldChange>) {
+    match (before, after) {
+        (Value::Object(before_fields), Value::Object(after_fields)) => {
+            let mut keys: Vec<&String> = before_fields.keys().chain(after_fields.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let field = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_values(
+                    &field,
+                    before_fields.get(key).unwrap_or(&Value::Null),
+                    after_fields.get(key).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        _ if before == after => {}
+        _ => changes.push(FieldChange {
+            field: path.to_string(),
+            before: render(before),
+            after: render(after),
+        }),
+    }
+}
+
+fn render(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        value => Some(value.to_string()),
+    }
+}
+
+impl CanisterState {
+    /// Appends an entry and returns its id
+    pub fn record_audit(
+        &mut self,
+        caller: Principal,
+        method: &str,
+        timestamp: u64,
+        target: AuditTarget,
+        changes: Vec<FieldChange>,
+    ) -> u64 {
+        let id = self.audit_log.last_key_value().map_or(0, |(id, _)| id + 1);
+        self.audit_log.insert(
+            id,
+            AuditEntry {
+                id,
+                timestamp,
+                caller,
+                method: method.to_string(),
+                booking_id: target.booking_id,
+                principal: target.principal,
+                email: target.email,
+                changes,
+            },
+        );
+        id
+    }
+
+    /// Runs `change` and, if it succeeds, records what `read` returned before and after it.
+    /// Failed calls leave no entry.
+    pub fn audited<T, E, S: Serialize>(
+        &mut self,
+        caller: Principal,
+        now: u64,
+        method: &str,
+        target: AuditTarget,
+        read: impl Fn(&CanisterState) -> S,
+        change: impl FnOnce(&mut CanisterState) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let before = read(self);
+        let result = change(self)?;
+        let changes = diff(&before, &read(self));
+        self.record_audit(caller, method, now, target, changes);
+        Ok(result)
+    }
+
+    /// Entries with an id above `start_after` that match `filter`, oldest first
+    pub fn get_audit_log(&self, filter: &AuditFilter, start_after: Option<u64>, limit: u64) -> AuditLogPage {
+        let limit = limit.clamp(1, MAX_AUDIT_PAGE_SIZE) as usize;
+        let start = start_after.map_or(0, |id| id.saturating_add(1));
+
+        let mut matching = self
+            .audit_log
+            .range(start..)
+            .map(|(_, entry)| entry)
+            .filter(|entry| filter.matches(entry));
+        let entries: Vec<AuditEntry> = matching.by_ref().take(limit).collect();
+        let next_start_after = if entries.len() == limit && matching.next().is_some() {
+            entries.last().map(|entry| entry.id)
+        } else {
+            None
+        };
+
+        AuditLogPage {
+            entries,
+            next_start_after,
+        }
+    }
+}
+
+/// Caller and time of the current call; fixed values in unit tests, which can't call ic0
+pub(crate) fn call_context() -> (Principal, u64) {
+    if cfg!(test) {
+        (Principal::anonymous(), 0)
+    } else {
+        (ic_cdk::caller(), ic_cdk::api::time())
+    }
+}
+
+/// `CanisterState::audited` for the current call, on the canister state
+pub fn audited<T, E, S: Serialize>(
+    method: &str,
+    target: AuditTarget,
+    read: impl Fn(&CanisterState) -> S,
+    change: impl FnOnce(&mut CanisterState) -> Result<T, E>,
+) -> Result<T, E> {
+    let (caller, now) = call_context();
+    STATE.with(|state| state.borrow_mut().audited(caller, now, method, target, read, change))
+}
+
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn get_audit_log(filter: AuditFilter, start_after: Option<u64>, limit: u64) -> AuditLogPage {
+    STATE.with(|state| state.borrow().get_audit_log(&filter, start_after, limit))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,191 @@
+#[cfg(test)]
+mod audit_tests {
+    use crate::audit::{diff, AuditFilter, AuditTarget, FieldChange, MAX_AUDIT_PAGE_SIZE};
+    use crate::errors::{BackendError, BackendResult};
+    use crate::models::*;
+    use crate::test_utils::create_valid_booking;
+    use candid::Principal;
+
+    fn caller() -> Principal {
+        Principal::from_text("be2us-64aaa-aaaaa-qaabq-cai").unwrap()
+    }
+
+    fn add_booking(state: &mut CanisterState, booking: Booking, now: u64) -> BackendResult<String> {
+        let booking_id = booking.booking_id.clone();
+        let email = booking_id.get_user_email().to_string();
+        state.audited(
+            caller(),
+            now,
+            "add_booking",
+            AuditTarget::booking(&booking_id),
+            |state| state.get_booking_by_id(&booking_id),
+            |state| state.add_booking_and_user(&email, booking),
+        )
+    }
+
+    #[test]
+    fn test_diff_reports_changed_fields_only() {
+        let before = serde_json::json!({"a": 1, "nested": {"b": "x", "c": true}});
+        let after = serde_json::json!({"a": 1, "nested": {"b": "y", "c": true}, "d": 2});
+
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                FieldChange {
+                    field: "d".to_string(),
+                    before: None,
+                    after: Some("2".to_string()),
+                },
+                FieldChange {
+                    field: "nested.b".to_string(),
+                    before: Some("\"x\"".to_string()),
+                    after: Some("\"y\"".to_string()),
+                },
+            ]
+        );
+        assert!(diff(&before, &before).is_empty());
+    }
+
+    #[test]
+    fn test_diff_of_scalars_uses_empty_field() {
+        let changes = diff(&None::<bool>, &Some(true));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].field, "");
+        assert_eq!(changes[0].before, None);
+        assert_eq!(changes[0].after, Some("true".to_string()));
+    }
+
+    #[test]
+    fn test_audited_records_successful_change() {
+        let mut state = CanisterState::new();
+        let booking = create_valid_booking("HB001", "a@b.com");
+        let booking_id = booking.booking_id.clone();
+
+        add_booking(&mut state, booking, 42).unwrap();
+
+        assert_eq!(state.audit_log.len(), 1);
+        let entry = state.audit_log.get(&0).unwrap();
+        assert_eq!(entry.id, 0);
+        assert_eq!(entry.timestamp, 42);
+        assert_eq!(entry.caller, caller());
+        assert_eq!(entry.method, "add_booking");
+        assert_eq!(entry.booking_id, Some(booking_id));
+        assert!(!entry.changes.is_empty());
+    }
+
+    #[test]
+    fn test_audited_skips_failed_change() {
+        let mut state = CanisterState::new();
+        add_booking(&mut state, create_valid_booking("HB001", "a@b.com"), 1).unwrap();
+
+        let result = add_booking(&mut state, create_valid_booking("HB001", "a@b.com"), 2);
+        assert!(matches!(result, Err(BackendError::Conflict(_))));
+        assert_eq!(state.audit_log.len(), 1);
+    }
+
+    #[test]
+    fn test_audit_entry_diff_contains_previous_value() {
+        let mut state = CanisterState::new();
+        let principal = caller();
+        state.user_principal_email_index.insert(principal, "old@b.com".to_string());
+
+        state
+            .audited(
+                caller(),
+                5,
+                "update_user_principal_email_index",
+                AuditTarget::principal(principal),
+                |state| state.user_principal_email_index.get(&principal),
+                |state| -> BackendResult<()> {
+                    state.user_principal_email_index.insert(principal, "new@b.com".to_string());
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        let entry = state.audit_log.get(&0).unwrap();
+        assert_eq!(entry.principal, Some(principal));
+        assert_eq!(
+            entry.changes,
+            vec![FieldChange {
+                field: String::new(),
+                before: Some("\"old@b.com\"".to_string()),
+                after: Some("\"new@b.com\"".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_audit_log_survives_snapshot_restore() {
+        let mut state = CanisterState::new();
+        let backup = state.to_snapshot();
+        add_booking(&mut state, create_valid_booking("HB001", "a@b.com"), 1).unwrap();
+
+        state.restore_snapshot(backup);
+        assert!(state.bookings.is_empty());
+        assert_eq!(state.audit_log.len(), 1);
+    }
+
+    #[test]
+    fn test_get_audit_log_filters() {
+        let mut state = CanisterState::new();
+        let booking = create_valid_booking("HB001", "a@b.com");
+        let booking_id = booking.booking_id.clone();
+        add_booking(&mut state, booking, 10).unwrap();
+        add_booking(&mut state, create_valid_booking("HB002", "a@b.com"), 20).unwrap();
+        state.record_audit(caller(), "run_migrations", 30, AuditTarget::default(), vec![]);
+
+        let by_method = AuditFilter {
+            method: Some("run_migrations".to_string()),
+            ..Default::default()
+        };
+        let page = state.get_audit_log(&by_method, None, 10);
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.entries[0].id, 2);
+
+        let by_booking = AuditFilter {
+            booking_id: Some(booking_id),
+            ..Default::default()
+        };
+        let page = state.get_audit_log(&by_booking, None, 10);
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.entries[0].id, 0);
+
+        let by_time = AuditFilter {
+            from: Some(15),
+            to: Some(30),
+            ..Default::default()
+        };
+        let ids: Vec<u64> = state
+            .get_audit_log(&by_time, None, 10)
+            .entries
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_get_audit_log_pagination() {
+        let mut state = CanisterState::new();
+        for i in 0..5 {
+            state.record_audit(caller(), "update_email_sent", i, AuditTarget::default(), vec![]);
+        }
+
+        let first = state.get_audit_log(&AuditFilter::default(), None, 2);
+        assert_eq!(first.entries.iter().map(|e| e.id).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(first.next_start_after, Some(1));
+
+        let second = state.get_audit_log(&AuditFilter::default(), first.next_start_after, 2);
+        assert_eq!(second.entries.iter().map(|e| e.id).collect::<Vec<_>>(), vec![2, 3]);
+
+        let last = state.get_audit_log(&AuditFilter::default(), second.next_start_after, 2);
+        assert_eq!(last.entries.iter().map(|e| e.id).collect::<Vec<_>>(), vec![4]);
+        assert_eq!(last.next_start_after, None);
+
+        // limit is capped, and 0 still returns something
+        let page = state.get_audit_log(&AuditFilter::default(), None, MAX_AUDIT_PAGE_SIZE + 50);
+        assert_eq!(page.entries.len(), 5);
+        assert_eq!(state.get_audit_log(&AuditFilter::default(), None, 0).entries.len(), 1);
+    }
+}
@@ -0,0 +1,438 @@
+//! Controller-only export/import of the whole canister state, used to copy
+//! prod state into staging for debugging.
+//!
+//! Export: `begin_export` encodes the state once, then call `export_state_chunk` with
+//! increasing offsets until `total_len` bytes are collected. Every chunk is a slice of that
+//! one encoding, so later changes to the state don't mix into it. `end_export` frees it.
+//!
+//! Import: `begin_import` with the export's length and checksum, then
+//! `import_state_chunk` in order. `stage_import` then restores the upload a step at a time
+//! (decoding it, copying its entries, running each migration) into the set of map memories
+//! not in use, see `CanisterState::map_bank`; call it until it reports `Ready`. Nothing
+//! changes for the live state until `commit_import`, which switches to the staged maps in
+//! one step. The target keeps its own roles and controllers, so importing a prod export
+//! doesn't lock out the staging admins.
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem::take;
+
+use crate::audit::{self, AuditTarget};
+use crate::controller::{ControllerAuditEntry, PendingControllerRemoval};
+use crate::lifecycle::snapshot;
+use crate::migration::MigrationEngine;
+use crate::roles::Role;
+use crate::memory::{MapKey, MapValue, StableMap};
+use crate::{is_admin, rebuild_payment_id_index, CanisterState, StateSnapshot, STATE};
+
+/// Keeps a single chunk well below the message size limits
+pub const MAX_EXPORT_CHUNK_LEN: u64 = 1024 * 1024;
+
+/// Largest snapshot `begin_import` accepts. The upload is held on the heap until it is
+/// decoded, and the decoded entries until they are copied into the staged maps.
+pub const MAX_IMPORT_LEN: u64 = 256 * 1024 * 1024;
+
+/// Map entries one `stage_import` call copies into the staged maps
+pub const MAX_STAGED_ENTRIES_PER_CALL: u64 = 10_000;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct StateExport {
+    /// size of the whole snapshot in bytes
+    pub total_len: u64,
+    /// hex encoded SHA-256 of the whole snapshot
+    pub checksum: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct StateExportChunk {
+    /// size of the whole snapshot in bytes
+    pub total_len: u64,
+    /// hex encoded SHA-256 of the whole snapshot
+    pub checksum: String,
+    pub data: Vec<u8>,
+}
+
+struct PreparedExport {
+    checksum: String,
+    bytes: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum ImportStage {
+    /// decoded entries are being copied into the staged maps
+    Copying,
+    /// the staged state is being brought up to the current schema, one migration per call
+    Migrating,
+    /// `commit_import` can switch to the staged state
+    Ready,
+}
+
+/// Where a staged import is at, returned by every `stage_import` call
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ImportProgress {
+    pub stage: ImportStage,
+    pub entries_copied: u64,
+    pub entries_total: u64,
+    /// schema version of the staged state so far
+    pub schema_version: u64,
+}
+
+struct PendingImport {
+    total_len: u64,
+    checksum: String,
+    bytes: Vec<u8>,
+    // over the bytes received so far, so the final check doesn't rehash the whole upload
+    hasher: Sha256,
+}
+
+/// An import being restored next to the live state, see the module docs
+pub struct StagedImport {
+    /// maps in the memories not in use, plus every heap field of the snapshot
+    state: CanisterState,
+    /// entries still to be copied into `state`; only its maps are used
+    remaining: StateSnapshot,
+    stage: ImportStage,
+    entries_copied: u64,
+    entries_total: u64,
+}
+
+thread_local! {
+    static PREPARED_EXPORT: RefCell<Option<PreparedExport>> = const { RefCell::new(None) };
+    static PENDING_IMPORT: RefCell<Option<PendingImport>> = const { RefCell::new(None) };
+    static STAGED_IMPORT: RefCell<Option<StagedImport>> = const { RefCell::new(None) };
+}
+
+fn checksum_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+pub fn export_snapshot_bytes(state: &CanisterState) -> Result<Vec<u8>, String> {
+    snapshot::encode_to_bytes(&state.to_snapshot())
+}
+
+/// Who may manage a canister. An import keeps the target's rather than taking the source's.
+struct AccessControl {
+    controllers: Option<Vec<Principal>>,
+    roles: BTreeMap<Principal, BTreeSet<Role>>,
+    pending_controller_removals: BTreeMap<Principal, PendingControllerRemoval>,
+    controller_audit_log: Vec<ControllerAuditEntry>,
+}
+
+impl AccessControl {
+    fn of(state: &CanisterState) -> Self {
+        Self {
+            controllers: state.controllers.clone(),
+            roles: state.roles.clone(),
+            pending_controller_removals: state.pending_controller_removals.clone(),
+            controller_audit_log: state.controller_audit_log.clone(),
+        }
+    }
+
+    fn restore(self, state: &mut CanisterState) {
+        state.controllers = self.controllers;
+        state.roles = self.roles;
+        state.pending_controller_removals = self.pending_controller_removals;
+        state.controller_audit_log = self.controller_audit_log;
+    }
+}
+
+/// Moves up to `budget` entries from `from` into `to`
+fn move_entries<K, V>(from: &mut BTreeMap<K, V>, to: &mut StableMap<K, V>, budget: &mut u64)
+where
+    K: MapKey,
+    V: MapValue,
+{
+    while *budget > 0 {
+        let Some((key, value)) = from.pop_first() else {
+            return;
+        };
+        to.insert(key, value);
+        *budget -= 1;
+    }
+}
+
+impl StagedImport {
+    /// Decodes `bytes` into a state whose maps are in the memories `live` doesn't use
+    pub fn decode(live: &CanisterState, bytes: &[u8]) -> Result<Self, String> {
+        let mut snapshot: StateSnapshot = snapshot::decode_from_bytes(bytes)?;
+        let remaining = StateSnapshot {
+            users: take(&mut snapshot.users),
+            bookings: take(&mut snapshot.bookings),
+            booking_timelines: take(&mut snapshot.booking_timelines),
+            booking_revisions: take(&mut snapshot.booking_revisions),
+            payment_events: take(&mut snapshot.payment_events),
+            wishlist: take(&mut snapshot.wishlist),
+            payment_id_index: take(&mut snapshot.payment_id_index),
+            user_principal_email_index: take(&mut snapshot.user_principal_email_index),
+            ..StateSnapshot::default()
+        };
+        let entries_total = [
+            remaining.users.len(),
+            remaining.bookings.len(),
+            remaining.booking_timelines.len(),
+            remaining.booking_revisions.len(),
+            remaining.payment_events.len(),
+            remaining.wishlist.len(),
+            remaining.payment_id_index.len(),
+            remaining.user_principal_email_index.len(),
+        ]
+        .iter()
+        .sum::<usize>() as u64;
+
+        let mut state = CanisterState::empty_in(live.map_bank.other());
+        // heap fields only, the maps are copied by `step`
+        state.restore_partial_snapshot(snapshot, &[]);
+
+        Ok(Self {
+            state,
+            remaining,
+            stage: ImportStage::Copying,
+            entries_copied: 0,
+            entries_total,
+        })
+    }
+
+    pub fn progress(&self) -> ImportProgress {
+        ImportProgress {
+            stage: self.stage,
+            entries_copied: self.entries_copied,
+            entries_total: self.entries_total,
+            schema_version: self.state.schema_metadata.current_version,
+        }
+    }
+
+    /// Does the next bounded piece of work: copies up to `max_entries` entries, or runs one
+    /// migration. Does nothing once `Ready`.
+    pub fn step(&mut self, max_entries: u64) -> Result<ImportProgress, String> {
+        match self.stage {
+            ImportStage::Copying => {
+                let mut budget = max_entries;
+                let (state, remaining) = (&mut self.state, &mut self.remaining);
+                move_entries(&mut remaining.users, &mut state.users, &mut budget);
+                move_entries(&mut remaining.bookings, &mut state.bookings, &mut budget);
+                move_entries(&mut remaining.booking_timelines, &mut state.booking_timelines, &mut budget);
+                move_entries(&mut remaining.booking_revisions, &mut state.booking_revisions, &mut budget);
+                move_entries(&mut remaining.payment_events, &mut state.payment_events, &mut budget);
+                move_entries(&mut remaining.wishlist, &mut state.wishlist, &mut budget);
+                move_entries(&mut remaining.payment_id_index, &mut state.payment_id_index, &mut budget);
+                move_entries(
+                    &mut remaining.user_principal_email_index,
+                    &mut state.user_principal_email_index,
+                    &mut budget,
+                );
+                self.entries_copied += max_entries - budget;
+                if budget > 0 {
+                    self.stage = ImportStage::Migrating;
+                }
+            }
+            ImportStage::Migrating => {
+                let applied = MigrationEngine::new()
+                    .apply_next_migration_without_backup(&mut self.state)
+                    .map_err(|e| format!("Migration of imported state failed: {}", e))?;
+                if applied.is_none() {
+                    self.stage = ImportStage::Ready;
+                }
+            }
+            ImportStage::Ready => {}
+        }
+        Ok(self.progress())
+    }
+
+    /// Switches `live` over to the staged state, except for its access control (roles,
+    /// controllers and their pending removals and log), audit log and webhook secrets.
+    /// Only swaps which memories the maps are in, so it takes the same time for any state;
+    /// the maps left behind are cleared for the next import. Returns the schema version.
+    pub fn swap_into(self, live: &mut CanisterState) -> Result<u64, String> {
+        if self.stage != ImportStage::Ready {
+            return Err("Import is not staged yet, call stage_import until it is Ready".to_string());
+        }
+        let mut staged = self.state;
+        std::mem::swap(&mut staged.audit_log, &mut live.audit_log);
+        staged.webhook_secrets = take(&mut live.webhook_secrets);
+        // after the migrations, which would otherwise move the source's controllers into roles
+        AccessControl::of(live).restore(&mut staged);
+
+        let mut previous = std::mem::replace(live, staged);
+        previous.restore_snapshot(StateSnapshot::default());
+        Ok(live.schema_metadata.current_version)
+    }
+}
+
+/// Encodes the current state for `export_state_chunk`, replacing any earlier export
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn begin_export() -> Result<StateExport, String> {
+    let bytes = STATE.with(|state| export_snapshot_bytes(&state.borrow()))?;
+    let export = StateExport {
+        total_len: bytes.len() as u64,
+        checksum: checksum_hex(&bytes),
+    };
+    PREPARED_EXPORT.with(|prepared| {
+        *prepared.borrow_mut() = Some(PreparedExport {
+            checksum: export.checksum.clone(),
+            bytes,
+        });
+    });
+    Ok(export)
+}
+
+/// Frees the export prepared by `begin_export`
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn end_export() {
+    PREPARED_EXPORT.with(|prepared| *prepared.borrow_mut() = None);
+}
+
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn export_state_chunk(offset: u64, len: u64) -> Result<StateExportChunk, String> {
+    PREPARED_EXPORT.with(|prepared| {
+        let prepared = prepared.borrow();
+        let export = prepared
+            .as_ref()
+            .ok_or_else(|| "No export in progress, call begin_export first".to_string())?;
+        let total_len = export.bytes.len() as u64;
+
+        if offset > total_len {
+            return Err(format!(
+                "Offset {} is past the end of the snapshot ({} bytes)",
+                offset, total_len
+            ));
+        }
+        let end = offset
+            .saturating_add(len.min(MAX_EXPORT_CHUNK_LEN))
+            .min(total_len);
+
+        Ok(StateExportChunk {
+            total_len,
+            checksum: export.checksum.clone(),
+            data: export.bytes[offset as usize..end as usize].to_vec(),
+        })
+    })
+}
+
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn begin_import(total_len: u64, checksum: String) -> Result<(), String> {
+    if total_len == 0 {
+        return Err("Import length must be greater than 0".to_string());
+    }
+    if total_len > MAX_IMPORT_LEN {
+        return Err(format!(
+            "Import length {} exceeds the maximum of {} bytes",
+            total_len, MAX_IMPORT_LEN
+        ));
+    }
+
+    // starting over discards whatever was uploaded or staged before
+    STAGED_IMPORT.with(|staged| *staged.borrow_mut() = None);
+    PENDING_IMPORT.with(|pending| {
+        *pending.borrow_mut() = Some(PendingImport {
+            total_len,
+            checksum: checksum.to_lowercase(),
+            bytes: Vec::new(),
+            hasher: Sha256::new(),
+        });
+    });
+    Ok(())
+}
+
+/// Chunks must be sent in order; returns the number of bytes received so far
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn import_state_chunk(offset: u64, data: Vec<u8>) -> Result<u64, String> {
+    PENDING_IMPORT.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        let import = pending
+            .as_mut()
+            .ok_or_else(|| "No import in progress, call begin_import first".to_string())?;
+
+        let received = import.bytes.len() as u64;
+        if offset != received {
+            return Err(format!(
+                "Expected chunk at offset {} but got {}",
+                received, offset
+            ));
+        }
+        if received + data.len() as u64 > import.total_len {
+            return Err(format!(
+                "Chunk exceeds the declared import length of {} bytes",
+                import.total_len
+            ));
+        }
+
+        import.hasher.update(&data);
+        import.bytes.extend_from_slice(&data);
+        Ok(import.bytes.len() as u64)
+    })
+}
+
+/// Advances the import by one step, see the module docs. The first call checks and decodes
+/// the upload. A failed step discards the import; the live state is never touched.
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn stage_import() -> Result<ImportProgress, String> {
+    if let Some(staged) = STAGED_IMPORT.with(|staged| staged.borrow_mut().take()) {
+        return advance(staged);
+    }
+
+    let import = PENDING_IMPORT
+        .with(|pending| pending.borrow_mut().take())
+        .ok_or_else(|| "No import in progress, call begin_import first".to_string())?;
+
+    if import.bytes.len() as u64 != import.total_len {
+        let received = import.bytes.len();
+        let total_len = import.total_len;
+        // keep the upload so the missing chunks can still be sent
+        PENDING_IMPORT.with(|pending| *pending.borrow_mut() = Some(import));
+        return Err(format!(
+            "Import incomplete: received {} of {} bytes",
+            received, total_len
+        ));
+    }
+
+    if hex::encode(import.hasher.finalize()) != import.checksum {
+        return Err("Import checksum mismatch, upload discarded".to_string());
+    }
+
+    let staged = STATE.with(|state| StagedImport::decode(&state.borrow(), &import.bytes))?;
+    let progress = staged.progress();
+    STAGED_IMPORT.with(|slot| *slot.borrow_mut() = Some(staged));
+    Ok(progress)
+}
+
+fn advance(mut staged: StagedImport) -> Result<ImportProgress, String> {
+    let progress = staged.step(MAX_STAGED_ENTRIES_PER_CALL)?;
+    STAGED_IMPORT.with(|slot| *slot.borrow_mut() = Some(staged));
+    Ok(progress)
+}
+
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn get_import_progress() -> Option<ImportProgress> {
+    STAGED_IMPORT.with(|staged| staged.borrow().as_ref().map(StagedImport::progress))
+}
+
+/// Switches to the state staged by `stage_import`, in one step
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn commit_import() -> Result<String, String> {
+    let staged = STAGED_IMPORT
+        .with(|staged| staged.borrow_mut().take())
+        .ok_or_else(|| "No import staged, call stage_import first".to_string())?;
+    if staged.stage != ImportStage::Ready {
+        let message = format!(
+            "Import is not staged yet ({:?}), call stage_import until it is Ready",
+            staged.stage
+        );
+        STAGED_IMPORT.with(|slot| *slot.borrow_mut() = Some(staged));
+        return Err(message);
+    }
+
+    let version = audit::audited(
+        "commit_import",
+        AuditTarget::default(),
+        |state| state.schema_metadata.current_version,
+        |state| staged.swap_into(state),
+    )?;
+    rebuild_payment_id_index();
+
+    Ok(format!("Imported state at schema version {}", version))
+}
+
+#[cfg(test)]
+mod tests;
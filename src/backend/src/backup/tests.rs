@@ -0,0 +1,314 @@
+#[cfg(test)]
+mod backup_tests {
+    use crate::backup::*;
+    use crate::models::*;
+    use crate::roles::Role;
+    use crate::STATE;
+    use crate::test_utils::create_valid_booking_with_payment_id_v2 as create_test_booking;
+    use candid::Principal;
+
+    fn create_test_state() -> CanisterState {
+        let mut state = CanisterState::new();
+        let booking = create_test_booking("APP001", "user1@example.com", "pay_1");
+        state.add_booking_and_user("user1@example.com", booking).unwrap();
+        state
+            .payment_id_index
+            .insert("pay_1".to_string(), BookingId::new("APP001".to_string(), "user1@example.com".to_string()));
+        state.add_to_wishlist_by_email(
+            "user1@example.com".to_string(),
+            HotelId { hotel_code: "H100".to_string() },
+        );
+        state.controllers = Some(vec![Principal::from_text("be2us-64aaa-aaaaa-qaabq-cai").unwrap()]);
+        // already at the latest schema, importing must not re-run anything
+        state.schema_metadata.current_version = 1006;
+        state
+    }
+
+    /// Every stage of an import at once
+    fn import_snapshot_bytes(state: &mut CanisterState, bytes: &[u8]) -> Result<u64, String> {
+        let mut staged = StagedImport::decode(state, bytes)?;
+        while staged.step(MAX_STAGED_ENTRIES_PER_CALL)?.stage != ImportStage::Ready {}
+        staged.swap_into(state)
+    }
+
+    fn export_all_chunks(chunk_len: u64) -> (Vec<u8>, String) {
+        let export = begin_export().unwrap();
+        let mut bytes = vec![];
+        loop {
+            let chunk = export_state_chunk(bytes.len() as u64, chunk_len).unwrap();
+            assert_eq!(chunk.checksum, export.checksum);
+            assert_eq!(chunk.total_len, export.total_len);
+            bytes.extend_from_slice(&chunk.data);
+            if bytes.len() as u64 == chunk.total_len {
+                return (bytes, export.checksum);
+            }
+        }
+    }
+
+    #[test]
+    fn test_export_chunks_reassemble_to_snapshot() {
+        STATE.with(|s| *s.borrow_mut() = create_test_state());
+
+        let (bytes, checksum) = export_all_chunks(7);
+        let full = STATE.with(|s| export_snapshot_bytes(&s.borrow())).unwrap();
+        assert_eq!(bytes, full);
+        assert_eq!(checksum.len(), 64);
+
+        let snapshot: StateSnapshot = crate::lifecycle::snapshot::decode_from_bytes(&bytes).unwrap();
+        assert_eq!(snapshot.bookings.len(), 1);
+        assert_eq!(snapshot.users.len(), 1);
+        assert_eq!(snapshot.wishlist.len(), 1);
+        assert_eq!(snapshot.payment_id_index.len(), 1);
+    }
+
+    #[test]
+    fn test_export_rejects_offset_past_end() {
+        STATE.with(|s| *s.borrow_mut() = create_test_state());
+        begin_export().unwrap();
+
+        let result = export_state_chunk(u64::MAX, 10);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_export_serves_the_state_at_begin_export() {
+        STATE.with(|s| *s.borrow_mut() = create_test_state());
+        let export = begin_export().unwrap();
+        let first = export_state_chunk(0, 7).unwrap();
+
+        STATE.with(|s| {
+            let booking = create_test_booking("APP002", "user2@example.com", "pay_2");
+            s.borrow_mut().add_booking_and_user("user2@example.com", booking).unwrap();
+        });
+        // the rest still comes from the state at begin_export
+        let mut bytes = first.data;
+        while (bytes.len() as u64) < export.total_len {
+            let chunk = export_state_chunk(bytes.len() as u64, 7).unwrap();
+            assert_eq!(chunk.checksum, export.checksum);
+            bytes.extend(chunk.data);
+        }
+        let snapshot: StateSnapshot = crate::lifecycle::snapshot::decode_from_bytes(&bytes).unwrap();
+        assert_eq!(snapshot.bookings.len(), 1);
+
+        end_export();
+        let result = export_state_chunk(0, 7);
+        assert!(result.unwrap_err().contains("call begin_export first"));
+    }
+
+    #[test]
+    fn test_import_round_trip_replaces_state() {
+        STATE.with(|s| *s.borrow_mut() = create_test_state());
+        let (bytes, checksum) = export_all_chunks(MAX_EXPORT_CHUNK_LEN);
+
+        // diverge from the exported state
+        STATE.with(|s| {
+            let mut state = s.borrow_mut();
+            let booking = create_test_booking("APP002", "user2@example.com", "pay_2");
+            state.add_booking_and_user("user2@example.com", booking).unwrap();
+            state.clear_wishlist_by_email("user1@example.com");
+        });
+
+        begin_import(bytes.len() as u64, checksum).unwrap();
+        for (i, chunk) in bytes.chunks(5).enumerate() {
+            import_state_chunk((i * 5) as u64, chunk.to_vec()).unwrap();
+        }
+        let progress = stage_import().unwrap();
+        assert_eq!(progress.stage, ImportStage::Copying);
+        // user, booking, wishlist and an entry in each index
+        assert_eq!(progress.entries_total, 5);
+        while stage_import().unwrap().stage != ImportStage::Ready {}
+        // nothing changes until the commit
+        STATE.with(|s| assert_eq!(s.borrow().bookings.len(), 2));
+
+        let result = commit_import();
+        assert!(result.is_ok());
+
+        STATE.with(|s| {
+            let state = s.borrow();
+            assert_eq!(state.bookings.len(), 1);
+            assert!(state.get_user_bookings("user2@example.com").is_none());
+            assert_eq!(state.get_wishlist_by_email("user1@example.com").unwrap().len(), 1);
+            assert_eq!(state.schema_metadata.current_version, 1006);
+        });
+    }
+
+    #[test]
+    fn test_import_rejects_checksum_mismatch() {
+        STATE.with(|s| *s.borrow_mut() = create_test_state());
+        let (bytes, _checksum) = export_all_chunks(MAX_EXPORT_CHUNK_LEN);
+
+        STATE.with(|s| {
+            let booking = create_test_booking("APP002", "user2@example.com", "pay_2");
+            s.borrow_mut().add_booking_and_user("user2@example.com", booking).unwrap();
+        });
+
+        begin_import(bytes.len() as u64, "00".repeat(32)).unwrap();
+        import_state_chunk(0, bytes).unwrap();
+        let result = stage_import();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("checksum mismatch"));
+
+        // state untouched
+        STATE.with(|s| assert_eq!(s.borrow().bookings.len(), 2));
+    }
+
+    #[test]
+    fn test_import_rejects_out_of_order_chunk() {
+        begin_import(10, "00".repeat(32)).unwrap();
+
+        let result = import_state_chunk(5, vec![0; 5]);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Expected chunk at offset 0"));
+    }
+
+    #[test]
+    fn test_begin_import_rejects_oversized_length() {
+        let result = begin_import(MAX_IMPORT_LEN + 1, "00".repeat(32));
+        assert!(result.unwrap_err().contains("exceeds the maximum"));
+        assert!(begin_import(MAX_IMPORT_LEN, "00".repeat(32)).is_ok());
+    }
+
+    #[test]
+    fn test_import_keeps_target_access_control() {
+        let admin = Principal::from_slice(&[1; 29]);
+        let support = Principal::from_slice(&[2; 29]);
+        let mut source = create_test_state();
+        source.controllers = None;
+        source.grant_role(admin, Role::Admin).unwrap();
+        let bytes = export_snapshot_bytes(&source).unwrap();
+
+        let staging_admin = Principal::from_slice(&[3; 29]);
+        let mut target = CanisterState::new();
+        target.schema_metadata.current_version = 1006;
+        target.grant_role(staging_admin, Role::Admin).unwrap();
+        target.grant_role(support, Role::Support).unwrap();
+        let roles = target.roles.clone();
+
+        import_snapshot_bytes(&mut target, &bytes).unwrap();
+        assert_eq!(target.bookings.len(), 1);
+        assert_eq!(target.roles, roles);
+        assert_eq!(target.controllers, None);
+        assert!(!target.has_role(&admin, Role::Admin));
+    }
+
+    #[test]
+    fn test_import_keeps_target_access_control_across_migrations() {
+        // exported before roles existed: its controllers would be moved into roles
+        let mut source = create_test_state();
+        source.schema_metadata.current_version = 1002;
+        let bytes = export_snapshot_bytes(&source).unwrap();
+
+        let staging_admin = Principal::from_slice(&[3; 29]);
+        let mut target = CanisterState::new();
+        target.schema_metadata.current_version = 1006;
+        target.grant_role(staging_admin, Role::Admin).unwrap();
+
+        import_snapshot_bytes(&mut target, &bytes).unwrap();
+        assert_eq!(target.principals_with_role(Role::Admin), vec![staging_admin]);
+        assert_eq!(target.controllers, None);
+    }
+
+    #[test]
+    fn test_import_rejects_chunk_past_declared_length() {
+        begin_import(4, "00".repeat(32)).unwrap();
+
+        let result = import_state_chunk(0, vec![0; 5]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_stage_import_requires_begin() {
+        let result = stage_import();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("No import in progress"));
+    }
+
+    #[test]
+    fn test_commit_import_requires_a_ready_stage() {
+        assert!(commit_import().unwrap_err().contains("No import staged"));
+
+        STATE.with(|s| *s.borrow_mut() = create_test_state());
+        let (bytes, checksum) = export_all_chunks(MAX_EXPORT_CHUNK_LEN);
+        begin_import(bytes.len() as u64, checksum).unwrap();
+        import_state_chunk(0, bytes).unwrap();
+        stage_import().unwrap();
+
+        let result = commit_import();
+        assert!(result.unwrap_err().contains("not staged yet"));
+        // still staged, so it can be finished and committed
+        while stage_import().unwrap().stage != ImportStage::Ready {}
+        assert!(commit_import().is_ok());
+    }
+
+    #[test]
+    fn test_stage_import_incomplete_keeps_upload() {
+        begin_import(10, "00".repeat(32)).unwrap();
+        import_state_chunk(0, vec![0; 4]).unwrap();
+
+        let result = stage_import();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("received 4 of 10 bytes"));
+
+        // the remaining chunk can still be sent
+        assert_eq!(import_state_chunk(4, vec![0; 6]).unwrap(), 10);
+    }
+
+    #[test]
+    fn test_import_runs_pending_migrations() {
+        let mut state = create_test_state();
+        state.schema_metadata.current_version = 1002;
+        let bytes = export_snapshot_bytes(&state).unwrap();
+
+        let version = import_snapshot_bytes(&mut state, &bytes).unwrap();
+        assert_eq!(version, 1006);
+        assert_eq!(state.schema_metadata.applied_migrations.len(), 4);
+        assert_eq!(state.bookings.len(), 1);
+    }
+
+    #[test]
+    fn test_staged_import_copies_in_bounded_steps_and_migrates_one_at_a_time() {
+        let mut source = create_test_state();
+        source.schema_metadata.current_version = 1004;
+        let bytes = export_snapshot_bytes(&source).unwrap();
+
+        let mut target = CanisterState::new();
+        let mut staged = StagedImport::decode(&target, &bytes).unwrap();
+        assert_eq!(staged.progress().entries_total, 5);
+
+        assert_eq!(staged.step(2).unwrap().entries_copied, 2);
+        assert_eq!(staged.step(2).unwrap().entries_copied, 4);
+        let progress = staged.step(2).unwrap();
+        assert_eq!(progress.entries_copied, 5);
+        assert_eq!(progress.stage, ImportStage::Migrating);
+
+        assert_eq!(staged.step(2).unwrap().schema_version, 1005);
+        assert_eq!(staged.step(2).unwrap().schema_version, 1006);
+        assert_eq!(staged.step(2).unwrap().stage, ImportStage::Ready);
+        // the live state is untouched until the swap
+        assert_eq!(target.schema_metadata.current_version, 1000);
+        assert_eq!(target.map_bank, MapBank::Primary);
+
+        let bank = target.map_bank;
+        staged.swap_into(&mut target).unwrap();
+        assert_eq!(target.map_bank, bank.other());
+        assert_eq!(target.bookings.len(), 1);
+        assert_eq!(target.schema_metadata.current_version, 1006);
+        // the maps left behind are cleared for the next import
+        assert!(CanisterState::empty_in(bank).bookings.is_empty());
+    }
+
+    #[test]
+    fn test_reopened_maps_follow_the_bank() {
+        let mut target = CanisterState::new();
+        let bytes = export_snapshot_bytes(&create_test_state()).unwrap();
+        import_snapshot_bytes(&mut target, &bytes).unwrap();
+        assert_eq!(target.map_bank, MapBank::Secondary);
+
+        // as after an upgrade: deserializing opens the primary maps
+        let mut reloaded = CanisterState::new();
+        reloaded.map_bank = target.map_bank;
+        assert!(reloaded.bookings.is_empty());
+        reloaded.open_maps();
+        assert_eq!(reloaded.bookings.len(), 1);
+    }
+}
@@ -7,66 +7,278 @@
 //     }
 // }
 
-use candid::Principal;
+//! The flat controllers list was replaced by roles (see `roles.rs`, migration 1004).
+//! These endpoints remain for existing clients and manage the `Admin` role.
+//!
+//! Removing a controller takes two calls: `propose_controller_removal` and then
+//! `confirm_controller_removal` by another admin before the proposal expires; the legacy
+//! `remove_controller` only proposes. Only an IC-level controller can remove the last one.
+//! Every change is kept in `controller_audit_log`.
 
-use crate::STATE;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
 
-pub fn is_controller() -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    if ic_cdk::api::is_controller(&caller) {
-        return Ok(());
+use crate::audit::{self, AuditTarget};
+use crate::errors::{BackendError, BackendResult};
+use crate::roles::{is_admin, Role};
+use crate::{CanisterState, STATE};
+
+/// How long a removal proposal can be confirmed for, in nanoseconds (24 hours)
+pub const CONTROLLER_REMOVAL_TTL_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct PendingControllerRemoval {
+    pub controller: Principal,
+    pub proposed_by: Principal,
+    pub proposed_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum ControllerAction {
+    Added,
+    RemovalProposed { expires_at: u64 },
+    RemovalConfirmed,
+    RemovalCancelled,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ControllerAuditEntry {
+    pub caller: Principal,
+    pub action: ControllerAction,
+    pub controller: Principal,
+    pub timestamp: u64,
+}
+
+/// What the general audit log records for a controller change
+fn controller_state(state: &CanisterState, controller: &Principal) -> serde_json::Value {
+    serde_json::json!({
+        "roles": state.roles.get(controller),
+        "pending_removal": state.pending_controller_removals.get(controller),
+    })
+}
+
+impl CanisterState {
+    fn record_controller_change(
+        &mut self,
+        caller: Principal,
+        action: ControllerAction,
+        controller: Principal,
+        now: u64,
+    ) {
+        self.controller_audit_log.push(ControllerAuditEntry {
+            caller,
+            action,
+            controller,
+            timestamp: now,
+        });
     }
-    STATE.with(|state| {
-        if state
-            .borrow()
-            .controllers
-            .as_ref()
-            .map(|f| f.contains(&ic_cdk::caller()))
-            .unwrap_or(false)
-        {
-            Ok(())
-        } else {
-            Err("You are not authorized to perform this action.".to_string())
+
+    /// Fails unless removing `controller` leaves at least one admin, or the caller
+    /// controls the canister at the IC level and can't be locked out anyway.
+    fn ensure_not_last_controller(
+        &self,
+        controller: &Principal,
+        caller_is_ic_controller: bool,
+    ) -> BackendResult<()> {
+        let admins = self.principals_with_role(Role::Admin);
+        if !caller_is_ic_controller && admins.iter().all(|admin| admin == controller) {
+            return Err(BackendError::Unauthorized(
+                "only an IC-level controller can remove the last controller".to_string(),
+            ));
         }
-    })
+        Ok(())
+    }
+
+    pub fn add_controller(
+        &mut self,
+        caller: Principal,
+        controller: Principal,
+        now: u64,
+    ) -> BackendResult<()> {
+        if self.has_role(&controller, Role::Admin) {
+            return Err(BackendError::Conflict("Controller already exists.".to_string()));
+        }
+        self.grant_role(controller, Role::Admin)?;
+        self.record_controller_change(caller, ControllerAction::Added, controller, now);
+        Ok(())
+    }
+
+    /// Returns when the proposal expires. An expired proposal for the same controller is replaced.
+    pub fn propose_controller_removal(
+        &mut self,
+        caller: Principal,
+        controller: Principal,
+        now: u64,
+        caller_is_ic_controller: bool,
+    ) -> BackendResult<u64> {
+        if !self.has_role(&controller, Role::Admin) {
+            return Err(BackendError::not_found("Controller", controller.to_text()));
+        }
+        if let Some(pending) = self.pending_controller_removals.get(&controller) {
+            if pending.expires_at > now {
+                return Err(BackendError::Conflict(format!(
+                    "Removal of {} is already proposed and expires at {}",
+                    controller, pending.expires_at
+                )));
+            }
+        }
+        self.ensure_not_last_controller(&controller, caller_is_ic_controller)?;
+
+        let expires_at = now.saturating_add(CONTROLLER_REMOVAL_TTL_NS);
+        self.pending_controller_removals.insert(
+            controller,
+            PendingControllerRemoval {
+                controller,
+                proposed_by: caller,
+                proposed_at: now,
+                expires_at,
+            },
+        );
+        self.record_controller_change(
+            caller,
+            ControllerAction::RemovalProposed { expires_at },
+            controller,
+            now,
+        );
+        Ok(expires_at)
+    }
+
+    pub fn confirm_controller_removal(
+        &mut self,
+        caller: Principal,
+        controller: Principal,
+        now: u64,
+        caller_is_ic_controller: bool,
+    ) -> BackendResult<()> {
+        let pending = self
+            .pending_controller_removals
+            .get(&controller)
+            .cloned()
+            .ok_or_else(|| BackendError::not_found("Controller removal proposal", controller.to_text()))?;
+        if pending.expires_at <= now {
+            self.pending_controller_removals.remove(&controller);
+            return Err(BackendError::Conflict(format!(
+                "Removal proposal for {} expired at {}, propose it again",
+                controller, pending.expires_at
+            )));
+        }
+        // IC-level controllers can change the canister anyway, so they may confirm alone
+        if pending.proposed_by == caller && !caller_is_ic_controller {
+            return Err(BackendError::Unauthorized(
+                "a removal must be confirmed by another admin than the one who proposed it"
+                    .to_string(),
+            ));
+        }
+        // the last-controller check runs again, other admins may have left since the proposal
+        self.ensure_not_last_controller(&controller, caller_is_ic_controller)?;
+
+        self.revoke_role(controller, Role::Admin)
+            .map_err(|_| BackendError::not_found("Controller", controller.to_text()))?;
+        self.pending_controller_removals.remove(&controller);
+        self.record_controller_change(caller, ControllerAction::RemovalConfirmed, controller, now);
+        Ok(())
+    }
+
+    pub fn cancel_controller_removal(
+        &mut self,
+        caller: Principal,
+        controller: Principal,
+        now: u64,
+    ) -> BackendResult<()> {
+        self.pending_controller_removals
+            .remove(&controller)
+            .ok_or_else(|| BackendError::not_found("Controller removal proposal", controller.to_text()))?;
+        self.record_controller_change(caller, ControllerAction::RemovalCancelled, controller, now);
+        Ok(())
+    }
 }
 
 #[ic_cdk_macros::query]
 fn get_controllers() -> Vec<Principal> {
-    STATE.with(|state| state.borrow().controllers.clone().unwrap_or_default())
+    STATE.with(|state| state.borrow().principals_with_role(Role::Admin))
 }
 
-#[ic_cdk_macros::update(guard = "is_controller")]
+#[ic_cdk_macros::update(guard = "is_admin")]
 pub fn add_controller(new_controller: Principal) -> Result<(), String> {
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        let controllers = state.controllers.get_or_insert_with(Vec::new);
-
-        if controllers.contains(&new_controller) {
-            Err("Controller already exists.".to_string())
-        } else {
-            controllers.push(new_controller);
-            Ok(())
-        }
-    })
+    add_controller_v2(new_controller).map_err(String::from)
 }
 
-#[ic_cdk_macros::update(guard = "is_controller")]
+#[ic_cdk_macros::update(guard = "is_admin")]
+pub fn add_controller_v2(new_controller: Principal) -> BackendResult<()> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    audit::audited(
+        "add_controller",
+        AuditTarget::principal(new_controller),
+        |state| controller_state(state, &new_controller),
+        |state| state.add_controller(caller, new_controller, now),
+    )
+}
+
+/// Only proposes the removal, so `Ok` means it is pending: the controller stays until
+/// another admin calls `confirm_controller_removal`. `remove_controller_v2` also returns
+/// when the proposal expires.
+#[ic_cdk_macros::update(guard = "is_admin")]
 pub fn remove_controller(controller_to_remove: Principal) -> Result<(), String> {
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        if let Some(index) = state
-            .controllers
-            .as_ref()
-            .unwrap_or(&vec![])
-            .iter()
-            .position(|x| *x == controller_to_remove)
-        {
-            // state.controllers.remove(index);
-            state.controllers.as_mut().map(|f| f.remove(index));
-            Ok(())
-        } else {
-            Err("Controller not found.".to_string())
-        }
-    })
+    remove_controller_v2(controller_to_remove)?;
+    Ok(())
+}
+
+/// Same as `propose_controller_removal`: returns when the pending removal expires,
+/// see `confirm_controller_removal`
+#[ic_cdk_macros::update(guard = "is_admin")]
+pub fn remove_controller_v2(controller_to_remove: Principal) -> BackendResult<u64> {
+    propose_controller_removal(controller_to_remove)
+}
+
+#[ic_cdk_macros::update(guard = "is_admin")]
+pub fn propose_controller_removal(controller: Principal) -> BackendResult<u64> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    let caller_is_ic_controller = ic_cdk::api::is_controller(&caller);
+    audit::audited(
+        "propose_controller_removal",
+        AuditTarget::principal(controller),
+        |state| controller_state(state, &controller),
+        |state| state.propose_controller_removal(caller, controller, now, caller_is_ic_controller),
+    )
 }
+
+#[ic_cdk_macros::update(guard = "is_admin")]
+pub fn confirm_controller_removal(controller: Principal) -> BackendResult<()> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    let caller_is_ic_controller = ic_cdk::api::is_controller(&caller);
+    audit::audited(
+        "confirm_controller_removal",
+        AuditTarget::principal(controller),
+        |state| controller_state(state, &controller),
+        |state| state.confirm_controller_removal(caller, controller, now, caller_is_ic_controller),
+    )
+}
+
+#[ic_cdk_macros::update(guard = "is_admin")]
+pub fn cancel_controller_removal(controller: Principal) -> BackendResult<()> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    audit::audited(
+        "cancel_controller_removal",
+        AuditTarget::principal(controller),
+        |state| controller_state(state, &controller),
+        |state| state.cancel_controller_removal(caller, controller, now),
+    )
+}
+
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn get_pending_controller_removals() -> Vec<PendingControllerRemoval> {
+    STATE.with(|state| state.borrow().pending_controller_removals.values().cloned().collect())
+}
+
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn get_controller_audit_log() -> Vec<ControllerAuditEntry> {
+    STATE.with(|state| state.borrow().controller_audit_log.clone())
+}
+
+#[cfg(test)]
+mod tests;
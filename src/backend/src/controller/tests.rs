@@ -0,0 +1,164 @@
+#[cfg(test)]
+mod controller_tests {
+    use crate::controller::{ControllerAction, CONTROLLER_REMOVAL_TTL_NS};
+    use crate::errors::BackendError;
+    use crate::models::*;
+    use crate::roles::Role;
+    use candid::Principal;
+
+    fn admin_1() -> Principal {
+        Principal::from_text("znxnh-f2v3a-dwwtd-jhyr2-tzdg7-xrm43-xf6xc-q4nfk-arhfb-54k5n-zae").unwrap()
+    }
+
+    fn admin_2() -> Principal {
+        Principal::from_text("krnjz-sqido-sjhrl-jprhc-g6ezq-xidpq-futvc-3mwtc-cpee4-z3cls-wqe").unwrap()
+    }
+
+    fn create_test_state() -> CanisterState {
+        let mut state = CanisterState::new();
+        state.grant_role(admin_1(), Role::Admin).unwrap();
+        state.grant_role(admin_2(), Role::Admin).unwrap();
+        state
+    }
+
+    #[test]
+    fn test_add_controller_is_audited() {
+        let mut state = CanisterState::new();
+        state.add_controller(admin_1(), admin_2(), 10).unwrap();
+
+        assert!(state.has_role(&admin_2(), Role::Admin));
+        assert_eq!(state.controller_audit_log.len(), 1);
+        let entry = &state.controller_audit_log[0];
+        assert_eq!(entry.caller, admin_1());
+        assert_eq!(entry.controller, admin_2());
+        assert_eq!(entry.action, ControllerAction::Added);
+        assert_eq!(entry.timestamp, 10);
+
+        let result = state.add_controller(admin_1(), admin_2(), 11);
+        assert!(matches!(result, Err(BackendError::Conflict(_))));
+        assert_eq!(state.controller_audit_log.len(), 1);
+    }
+
+    #[test]
+    fn test_propose_then_confirm_removes_controller() {
+        let mut state = create_test_state();
+
+        let expires_at = state.propose_controller_removal(admin_1(), admin_2(), 100, false).unwrap();
+        assert_eq!(expires_at, 100 + CONTROLLER_REMOVAL_TTL_NS);
+        // nothing is removed until confirmed
+        assert!(state.has_role(&admin_2(), Role::Admin));
+
+        state.confirm_controller_removal(admin_2(), admin_2(), 200, false).unwrap();
+        assert!(!state.has_role(&admin_2(), Role::Admin));
+        assert!(state.pending_controller_removals.is_empty());
+
+        let actions: Vec<ControllerAction> =
+            state.controller_audit_log.iter().map(|e| e.action.clone()).collect();
+        assert_eq!(
+            actions,
+            vec![
+                ControllerAction::RemovalProposed { expires_at },
+                ControllerAction::RemovalConfirmed
+            ]
+        );
+    }
+
+    #[test]
+    fn test_proposer_cannot_confirm() {
+        let mut state = create_test_state();
+        state.propose_controller_removal(admin_1(), admin_2(), 100, false).unwrap();
+
+        let result = state.confirm_controller_removal(admin_1(), admin_2(), 200, false);
+        assert!(matches!(result, Err(BackendError::Unauthorized(_))));
+        assert!(state.has_role(&admin_2(), Role::Admin));
+        assert!(state.pending_controller_removals.contains_key(&admin_2()));
+        assert_eq!(state.controller_audit_log.len(), 1);
+
+        // unless they control the canister at the IC level
+        state.confirm_controller_removal(admin_1(), admin_2(), 200, true).unwrap();
+        assert!(!state.has_role(&admin_2(), Role::Admin));
+    }
+
+    #[test]
+    fn test_confirm_without_proposal_fails() {
+        let mut state = create_test_state();
+
+        let result = state.confirm_controller_removal(admin_1(), admin_2(), 200, false);
+        assert!(matches!(result, Err(BackendError::NotFound { .. })));
+        assert!(state.has_role(&admin_2(), Role::Admin));
+    }
+
+    #[test]
+    fn test_expired_proposal_cannot_be_confirmed() {
+        let mut state = create_test_state();
+        let expires_at = state.propose_controller_removal(admin_1(), admin_2(), 100, false).unwrap();
+
+        let result = state.confirm_controller_removal(admin_1(), admin_2(), expires_at, false);
+        assert!(matches!(result, Err(BackendError::Conflict(_))));
+        assert!(state.has_role(&admin_2(), Role::Admin));
+        assert!(state.pending_controller_removals.is_empty());
+
+        // it can be proposed again
+        assert!(state.propose_controller_removal(admin_1(), admin_2(), expires_at, false).is_ok());
+    }
+
+    #[test]
+    fn test_duplicate_proposal_is_conflict() {
+        let mut state = create_test_state();
+        state.propose_controller_removal(admin_1(), admin_2(), 100, false).unwrap();
+
+        let result = state.propose_controller_removal(admin_1(), admin_2(), 150, false);
+        assert!(matches!(result, Err(BackendError::Conflict(_))));
+    }
+
+    #[test]
+    fn test_cancel_proposal() {
+        let mut state = create_test_state();
+        state.propose_controller_removal(admin_1(), admin_2(), 100, false).unwrap();
+
+        state.cancel_controller_removal(admin_2(), admin_2(), 150).unwrap();
+        assert!(state.pending_controller_removals.is_empty());
+        assert_eq!(
+            state.controller_audit_log.last().unwrap().action,
+            ControllerAction::RemovalCancelled
+        );
+
+        let result = state.confirm_controller_removal(admin_1(), admin_2(), 200, false);
+        assert!(matches!(result, Err(BackendError::NotFound { .. })));
+    }
+
+    #[test]
+    fn test_last_controller_needs_ic_controller() {
+        let mut state = CanisterState::new();
+        state.grant_role(admin_1(), Role::Admin).unwrap();
+
+        let result = state.propose_controller_removal(admin_1(), admin_1(), 100, false);
+        assert!(matches!(result, Err(BackendError::Unauthorized(_))));
+
+        state.propose_controller_removal(admin_1(), admin_1(), 100, true).unwrap();
+        state.confirm_controller_removal(admin_1(), admin_1(), 200, true).unwrap();
+        assert!(state.principals_with_role(Role::Admin).is_empty());
+    }
+
+    #[test]
+    fn test_confirm_rechecks_last_controller() {
+        let mut state = create_test_state();
+        state.propose_controller_removal(admin_1(), admin_1(), 100, false).unwrap();
+        state.propose_controller_removal(admin_1(), admin_2(), 100, false).unwrap();
+
+        state.confirm_controller_removal(admin_2(), admin_1(), 200, false).unwrap();
+        let result = state.confirm_controller_removal(admin_2(), admin_2(), 200, false);
+        assert!(matches!(result, Err(BackendError::Unauthorized(_))));
+        assert_eq!(state.principals_with_role(Role::Admin), vec![admin_2()]);
+    }
+
+    #[test]
+    fn test_propose_removal_of_unknown_controller() {
+        let mut state = create_test_state();
+        let stranger = Principal::from_text("be2us-64aaa-aaaaa-qaabq-cai").unwrap();
+
+        let result = state.propose_controller_removal(admin_1(), stranger, 100, false);
+        assert!(matches!(result, Err(BackendError::NotFound { .. })));
+        assert!(state.controller_audit_log.is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+//! Typed errors returned by `CanisterState` and the `_v2` endpoints.
+//!
+//! The original endpoints keep their `Result<_, String>` signatures so existing
+//! clients of `can.did` keep working; they return `to_legacy_string`, the exact text
+//! they used to send for the same error.
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::BookingId;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum BackendError {
+    /// `entity` is e.g. "User" or "Booking", `id` what was looked up
+    NotFound { entity: String, id: String },
+    Unauthorized(String),
+    Validation { field: String, reason: String },
+    /// The request clashes with existing data, e.g. a duplicate booking id
+    Conflict(String),
+    InvalidTransition { from: String, to: String },
+    Internal(String),
+}
+
+pub type BackendResult<T> = Result<T, BackendError>;
+
+impl BackendError {
+    pub fn not_found(entity: &str, id: impl Into<String>) -> Self {
+        Self::NotFound {
+            entity: entity.to_string(),
+            id: id.into(),
+        }
+    }
+
+    pub fn validation(field: &str, reason: impl Into<String>) -> Self {
+        Self::Validation {
+            field: field.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound { entity, id } => write!(f, "{} '{}' not found", entity, id),
+            Self::Unauthorized(reason) => write!(f, "Unauthorized: {}", reason),
+            Self::Validation { field, reason } => write!(f, "Invalid {}: {}", field, reason),
+            Self::Conflict(reason) => write!(f, "{}", reason),
+            Self::InvalidTransition { from, to } => {
+                write!(f, "Invalid status transition from {} to {}", from, to)
+            }
+            Self::Internal(reason) => write!(f, "Internal error: {}", reason),
+        }
+    }
+}
+
+/// The endpoints that returned `Result<_, String>` before `BackendError` was introduced
+#[derive(Clone, Copy, Debug)]
+pub enum LegacyEndpoint<'a> {
+    AddBooking,
+    UpdatePaymentDetails,
+    UpdateBookRoomResponse,
+    UpdateEmailSent,
+    GetEmailSent(&'a BookingId),
+    UpdateUserPrincipalEmailIndex,
+}
+
+impl BackendError {
+    /// What `endpoint` returned for this error before `BackendError`, typos included, so
+    /// clients matching on the text keep working. Errors the endpoint didn't have back then
+    /// use the `Display` text.
+    pub fn to_legacy_string(&self, endpoint: LegacyEndpoint) -> String {
+        use LegacyEndpoint::*;
+        match (self, endpoint) {
+            (Self::NotFound { entity, id }, UpdatePaymentDetails | UpdateBookRoomResponse)
+                if entity == "User" =>
+            {
+                format!("User with email '{}' not found", id)
+            }
+            (Self::NotFound { entity, id }, UpdatePaymentDetails) if entity == "Booking" => {
+                format!("Booking with app_reference '{}' not found", id)
+            }
+            (Self::NotFound { entity, id }, UpdateBookRoomResponse) if entity == "Booking" => {
+                format!("Booking with app_refrence '{}' not found", id)
+            }
+            (Self::NotFound { entity, .. }, GetEmailSent(booking_id)) if entity == "Booking" => {
+                format!("Booking does not exist with booking_id {:?} ", booking_id)
+            }
+            (Self::Validation { field, .. }, UpdatePaymentDetails) if field == "payment_id_v2" => {
+                "Payment ID v2 cannot be empty".to_string()
+            }
+            (Self::Validation { field, .. }, UpdateUserPrincipalEmailIndex) if field == "email" => {
+                "Invalid email format".to_string()
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl From<BackendError> for String {
+    fn from(error: BackendError) -> Self {
+        error.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests;
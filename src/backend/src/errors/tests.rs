@@ -0,0 +1,121 @@
+#[cfg(test)]
+mod errors_tests {
+    use crate::errors::{BackendError, BackendResult, LegacyEndpoint};
+    use crate::models::*;
+    use crate::test_utils::create_valid_booking as create_test_booking;
+
+    #[test]
+    fn test_display_is_readable() {
+        assert_eq!(
+            BackendError::not_found("User", "a@b.com").to_string(),
+            "User 'a@b.com' not found"
+        );
+        assert_eq!(
+            BackendError::validation("email", "invalid email format").to_string(),
+            "Invalid email: invalid email format"
+        );
+        assert_eq!(
+            String::from(BackendError::Conflict("Email already sent".to_string())),
+            "Email already sent"
+        );
+    }
+
+    #[test]
+    fn test_legacy_endpoints_keep_their_old_messages() {
+        let mut state = CanisterState::new();
+        let booking = create_test_booking("APP001", "user1@example.com");
+        let booking_id = booking.booking_id.clone();
+        state.add_booking_and_user("user1@example.com", booking).unwrap();
+        let unknown_user = BookingId::new("APP001".to_string(), "nobody@example.com".to_string());
+        let unknown_booking = BookingId::new("APP404".to_string(), "user1@example.com".to_string());
+        let payment_details = |booking_id: &BookingId, payment_id_v2: &str| {
+            let mut payment_details = PaymentDetails::new(booking_id.clone());
+            payment_details.payment_api_response.payment_id_v2 = payment_id_v2.to_string();
+            payment_details
+        };
+
+        let legacy = LegacyEndpoint::UpdatePaymentDetails;
+        let error = |result: BackendResult<Booking>| result.unwrap_err().to_legacy_string(legacy);
+        assert_eq!(
+            error(state.update_payment_details(unknown_user.clone(), payment_details(&unknown_user, "pay_1"))),
+            "User with email 'nobody@example.com' not found"
+        );
+        assert_eq!(
+            error(state.update_payment_details(unknown_booking.clone(), payment_details(&unknown_booking, "pay_1"))),
+            "Booking with app_reference 'APP404' not found"
+        );
+        assert_eq!(
+            error(state.update_payment_details(booking_id.clone(), payment_details(&booking_id, ""))),
+            "Payment ID v2 cannot be empty"
+        );
+        state
+            .update_payment_details(booking_id.clone(), payment_details(&booking_id, "pay_1"))
+            .unwrap();
+        assert_eq!(
+            error(state.update_payment_details(unknown_booking.clone(), payment_details(&unknown_booking, "pay_1"))),
+            "Payment ID v2 'pay_1' is already used by other booking"
+        );
+
+        let legacy = LegacyEndpoint::UpdateBookRoomResponse;
+        let error = |result: BackendResult<String>| result.unwrap_err().to_legacy_string(legacy);
+        assert_eq!(
+            error(state.update_book_room_response(unknown_user, BEBookRoomResponse::default())),
+            "User with email 'nobody@example.com' not found"
+        );
+        assert_eq!(
+            error(state.update_book_room_response(unknown_booking.clone(), BEBookRoomResponse::default())),
+            "Booking with app_refrence 'APP404' not found"
+        );
+
+        let legacy = LegacyEndpoint::GetEmailSent(&unknown_booking);
+        assert_eq!(
+            state.get_email_sent(&unknown_booking).unwrap_err().to_legacy_string(legacy),
+            "Booking does not exist with booking_id BookingId { app_reference: \"APP404\", email: \"user1@example.com\" } "
+        );
+
+        state.update_email_sent(booking_id.clone(), true).unwrap();
+        let result = state.update_email_sent(booking_id, true);
+        assert_eq!(
+            result.unwrap_err().to_legacy_string(LegacyEndpoint::UpdateEmailSent),
+            "Email already sent"
+        );
+
+        let invalid_email = BackendError::validation("email", "invalid email format");
+        assert_eq!(
+            invalid_email.to_legacy_string(LegacyEndpoint::UpdateUserPrincipalEmailIndex),
+            "Invalid email format"
+        );
+    }
+
+    #[test]
+    fn test_update_payment_details_unknown_user_is_not_found() {
+        let mut state = CanisterState::new();
+        let booking_id = BookingId::new("APP001".to_string(), "nobody@example.com".to_string());
+        let mut payment_details = PaymentDetails::new(booking_id.clone());
+        payment_details.payment_api_response.payment_id_v2 = "pay_1".to_string();
+
+        let result = state.update_payment_details(booking_id, payment_details);
+        assert_eq!(result.unwrap_err(), BackendError::not_found("User", "nobody@example.com"));
+    }
+
+    #[test]
+    fn test_get_email_sent_unknown_booking_is_not_found() {
+        let mut state = CanisterState::new();
+        let booking_id = BookingId::new("APP404".to_string(), "user1@example.com".to_string());
+
+        let result = state.get_email_sent(&booking_id);
+        assert_eq!(result.unwrap_err(), BackendError::not_found("Booking", "APP404"));
+    }
+
+    #[test]
+    fn test_update_email_sent_twice_is_conflict() {
+        let mut state = CanisterState::new();
+        let booking = create_test_booking("APP001", "user1@example.com");
+        let booking_id = booking.booking_id.clone();
+        state.add_booking_and_user("user1@example.com", booking).unwrap();
+
+        state.update_email_sent(booking_id.clone(), true).unwrap();
+        let result = state.update_email_sent(booking_id, true);
+        assert!(matches!(result, Err(BackendError::Conflict(_))));
+    }
+}
@@ -0,0 +1,183 @@
+//! Consistency checks of the secondary indexes against the maps they are derived from.
+//!
+//! `payment_id_index` follows the `payment_id_v2` of each booking in `bookings`,
+//! `email_sent` is keyed by existing bookings and `user_principal_email_index` points at
+//! emails in `users`. `check_integrity` reports where they drifted apart, `repair_indexes`
+//! brings them back in line. Bookings sharing a `payment_id_v2` can't be repaired that way:
+//! which one the payment belongs to has to be decided by hand.
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::audit::{self, AuditTarget};
+use crate::errors::BackendResult;
+use crate::roles::is_admin;
+use crate::{BookingId, CanisterState, STATE};
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum IntegrityIssue {
+    /// `payment_id_index` maps to a booking that doesn't exist or has another `payment_id_v2`
+    DanglingPaymentIndexEntry {
+        payment_id_v2: String,
+        booking_id: BookingId,
+    },
+    /// the booking's `payment_id_v2` isn't mapped to it in `payment_id_index`
+    MissingPaymentIndexEntry {
+        payment_id_v2: String,
+        booking_id: BookingId,
+    },
+    /// the same `payment_id_v2` on several bookings
+    DuplicatePaymentId {
+        payment_id_v2: String,
+        booking_ids: Vec<BookingId>,
+    },
+    /// `email_sent` entry of a booking that doesn't exist
+    DanglingEmailSent { booking_id: BookingId },
+    /// `user_principal_email_index` entry whose email isn't in `users`
+    DanglingPrincipalEmail { principal: Principal, email: String },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct IntegrityReport {
+    pub bookings_checked: u64,
+    pub issues: Vec<IntegrityIssue>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct IndexRepair {
+    pub dry_run: bool,
+    /// fixed, or that would be without `dry_run`
+    pub repaired: Vec<IntegrityIssue>,
+    /// duplicate payment ids, to be resolved by hand
+    pub unrepaired: Vec<IntegrityIssue>,
+}
+
+impl CanisterState {
+    pub fn check_integrity(&self) -> IntegrityReport {
+        let mut issues = Vec::new();
+
+        let mut bookings_checked = 0;
+        let mut bookings_by_payment_id: BTreeMap<String, Vec<BookingId>> = BTreeMap::new();
+        for (booking_id, booking) in self.bookings.iter() {
+            bookings_checked += 1;
+            let payment_id_v2 = booking.payment_details.payment_api_response.payment_id_v2;
+            if !payment_id_v2.is_empty() {
+                bookings_by_payment_id
+                    .entry(payment_id_v2)
+                    .or_default()
+                    .push(booking_id);
+            }
+        }
+
+        // dangling before missing, see `repair_indexes`
+        for (payment_id_v2, booking_id) in self.payment_id_index.iter() {
+            let owned = bookings_by_payment_id
+                .get(&payment_id_v2)
+                .is_some_and(|booking_ids| booking_ids.contains(&booking_id));
+            if !owned {
+                issues.push(IntegrityIssue::DanglingPaymentIndexEntry {
+                    payment_id_v2,
+                    booking_id,
+                });
+            }
+        }
+        for (payment_id_v2, mut booking_ids) in bookings_by_payment_id {
+            if booking_ids.len() > 1 {
+                issues.push(IntegrityIssue::DuplicatePaymentId {
+                    payment_id_v2,
+                    booking_ids,
+                });
+            } else if let Some(booking_id) = booking_ids.pop() {
+                if self.payment_id_index.get(&payment_id_v2).as_ref() != Some(&booking_id) {
+                    issues.push(IntegrityIssue::MissingPaymentIndexEntry {
+                        payment_id_v2,
+                        booking_id,
+                    });
+                }
+            }
+        }
+
+        if let Some(email_sent) = &self.email_sent {
+            for booking_id in email_sent.email_sent.keys() {
+                if !self.bookings.contains_key(booking_id) {
+                    issues.push(IntegrityIssue::DanglingEmailSent {
+                        booking_id: booking_id.clone(),
+                    });
+                }
+            }
+        }
+
+        for (principal, email) in self.user_principal_email_index.iter() {
+            if !self.users.contains_key(&email) {
+                issues.push(IntegrityIssue::DanglingPrincipalEmail { principal, email });
+            }
+        }
+
+        IntegrityReport {
+            bookings_checked,
+            issues,
+        }
+    }
+
+    /// Fixes what `check_integrity` reports, except duplicate payment ids. Dangling index
+    /// entries come first in the report, so an entry pointing at the wrong booking is removed
+    /// before the right one is added.
+    pub fn repair_indexes(&mut self, dry_run: bool) -> IndexRepair {
+        let (unrepaired, repaired): (Vec<_>, Vec<_>) = self
+            .check_integrity()
+            .issues
+            .into_iter()
+            .partition(|issue| matches!(issue, IntegrityIssue::DuplicatePaymentId { .. }));
+
+        if !dry_run {
+            for issue in &repaired {
+                match issue {
+                    IntegrityIssue::DanglingPaymentIndexEntry { payment_id_v2, .. } => {
+                        self.payment_id_index.remove(payment_id_v2);
+                    }
+                    IntegrityIssue::MissingPaymentIndexEntry {
+                        payment_id_v2,
+                        booking_id,
+                    } => {
+                        self.payment_id_index
+                            .insert(payment_id_v2.clone(), booking_id.clone());
+                    }
+                    IntegrityIssue::DanglingEmailSent { booking_id } => {
+                        if let Some(email_sent) = self.email_sent.as_mut() {
+                            email_sent.email_sent.remove(booking_id);
+                        }
+                    }
+                    IntegrityIssue::DanglingPrincipalEmail { principal, .. } => {
+                        self.user_principal_email_index.remove(principal);
+                    }
+                    IntegrityIssue::DuplicatePaymentId { .. } => {}
+                }
+            }
+        }
+
+        IndexRepair {
+            dry_run,
+            repaired,
+            unrepaired,
+        }
+    }
+}
+
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn check_integrity() -> IntegrityReport {
+    STATE.with(|state| state.borrow().check_integrity())
+}
+
+/// see `CanisterState::repair_indexes`; a dry run changes nothing and isn't audited
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn repair_indexes(dry_run: bool) -> BackendResult<IndexRepair> {
+    if dry_run {
+        return Ok(STATE.with(|state| state.borrow_mut().repair_indexes(true)));
+    }
+    audit::audited(
+        "repair_indexes",
+        AuditTarget::default(),
+        |state| state.check_integrity().issues,
+        |state| Ok(state.repair_indexes(false)),
+    )
+}
@@ -0,0 +1,195 @@
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod integrity_tests {
+    use crate::integrity::IntegrityIssue;
+    use crate::models::*;
+    use crate::test_utils::create_valid_booking;
+    use candid::Principal;
+
+    const EMAIL: &str = "user1@example.com";
+
+    fn booking_id(app_ref: &str) -> BookingId {
+        BookingId::new(app_ref.to_string(), EMAIL.to_string())
+    }
+
+    fn user_principal() -> Principal {
+        Principal::from_slice(&[1; 29])
+    }
+
+    /// APP001 and APP002 paid with pay_1 and pay_2, email sent for APP001, principal linked
+    fn create_consistent_state() -> CanisterState {
+        let mut state = CanisterState::new();
+        for (app_ref, payment_id_v2) in [("APP001", "pay_1"), ("APP002", "pay_2")] {
+            state
+                .add_booking_and_user(EMAIL, create_valid_booking(app_ref, EMAIL))
+                .unwrap();
+            let mut payment_details = PaymentDetails::new(booking_id(app_ref));
+            payment_details.payment_api_response.payment_id_v2 = payment_id_v2.to_string();
+            state
+                .update_payment_details(booking_id(app_ref), payment_details)
+                .unwrap();
+        }
+        state.update_email_sent(booking_id("APP001"), true).unwrap();
+        state
+            .user_principal_email_index
+            .insert(user_principal(), EMAIL.to_string());
+        state
+    }
+
+    /// Sets `payment_id_v2` on the stored booking without touching the index
+    fn set_stored_payment_id_v2(state: &mut CanisterState, booking_id: &BookingId, payment_id_v2: &str) {
+        let mut booking = state.bookings.get(booking_id).unwrap();
+        booking.payment_details.payment_api_response.payment_id_v2 = payment_id_v2.to_string();
+        state.bookings.insert(booking_id.clone(), booking);
+    }
+
+    #[test]
+    fn test_consistent_state_has_no_issues() {
+        let state = create_consistent_state();
+
+        let report = state.check_integrity();
+        assert_eq!(report.bookings_checked, 2);
+        assert_eq!(report.issues, vec![]);
+    }
+
+    #[test]
+    fn test_dangling_and_missing_payment_index_entries() {
+        let mut state = create_consistent_state();
+        state.payment_id_index.remove(&"pay_1".to_string());
+        state
+            .payment_id_index
+            .insert("pay_gone".to_string(), booking_id("APP404"));
+        // the index still maps the old payment id
+        set_stored_payment_id_v2(&mut state, &booking_id("APP002"), "pay_3");
+
+        let issues = state.check_integrity().issues;
+        assert_eq!(
+            issues,
+            vec![
+                IntegrityIssue::DanglingPaymentIndexEntry {
+                    payment_id_v2: "pay_2".to_string(),
+                    booking_id: booking_id("APP002"),
+                },
+                IntegrityIssue::DanglingPaymentIndexEntry {
+                    payment_id_v2: "pay_gone".to_string(),
+                    booking_id: booking_id("APP404"),
+                },
+                IntegrityIssue::MissingPaymentIndexEntry {
+                    payment_id_v2: "pay_1".to_string(),
+                    booking_id: booking_id("APP001"),
+                },
+                IntegrityIssue::MissingPaymentIndexEntry {
+                    payment_id_v2: "pay_3".to_string(),
+                    booking_id: booking_id("APP002"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_payment_id_v2() {
+        let mut state = create_consistent_state();
+        set_stored_payment_id_v2(&mut state, &booking_id("APP002"), "pay_1");
+
+        let issues = state.check_integrity().issues;
+        assert_eq!(
+            issues,
+            vec![
+                // pay_2 no longer belongs to any booking
+                IntegrityIssue::DanglingPaymentIndexEntry {
+                    payment_id_v2: "pay_2".to_string(),
+                    booking_id: booking_id("APP002"),
+                },
+                IntegrityIssue::DuplicatePaymentId {
+                    payment_id_v2: "pay_1".to_string(),
+                    booking_ids: vec![booking_id("APP001"), booking_id("APP002")],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_email_sent_and_principal_index_entries_without_target() {
+        let mut state = create_consistent_state();
+        state.bookings.remove(&booking_id("APP001"));
+        state.payment_id_index.remove(&"pay_1".to_string());
+        let stranger = Principal::from_slice(&[7; 29]);
+        state
+            .user_principal_email_index
+            .insert(stranger, "nobody@example.com".to_string());
+
+        let issues = state.check_integrity().issues;
+        assert_eq!(
+            issues,
+            vec![
+                IntegrityIssue::DanglingEmailSent {
+                    booking_id: booking_id("APP001"),
+                },
+                IntegrityIssue::DanglingPrincipalEmail {
+                    principal: stranger,
+                    email: "nobody@example.com".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_repair_dry_run_changes_nothing() {
+        let mut state = create_consistent_state();
+        state.payment_id_index.remove(&"pay_1".to_string());
+
+        let repair = state.repair_indexes(true);
+        assert!(repair.dry_run);
+        assert_eq!(repair.repaired.len(), 1);
+        assert_eq!(state.check_integrity().issues, repair.repaired);
+    }
+
+    #[test]
+    fn test_repair_fixes_everything_but_duplicates() {
+        let mut state = create_consistent_state();
+        state
+            .add_booking_and_user(EMAIL, create_valid_booking("APP003", EMAIL))
+            .unwrap();
+        // pay_1 now maps to the wrong booking
+        state
+            .payment_id_index
+            .insert("pay_1".to_string(), booking_id("APP002"));
+        set_stored_payment_id_v2(&mut state, &booking_id("APP001"), "");
+        set_stored_payment_id_v2(&mut state, &booking_id("APP003"), "pay_2");
+        state.update_email_sent(booking_id("APP404"), true).unwrap();
+        let stranger = Principal::from_slice(&[7; 29]);
+        state
+            .user_principal_email_index
+            .insert(stranger, "nobody@example.com".to_string());
+
+        let repair = state.repair_indexes(false);
+        assert!(!repair.dry_run);
+        assert_eq!(repair.repaired.len(), 3);
+        assert_eq!(
+            repair.unrepaired,
+            vec![IntegrityIssue::DuplicatePaymentId {
+                payment_id_v2: "pay_2".to_string(),
+                booking_ids: vec![booking_id("APP002"), booking_id("APP003")],
+            }]
+        );
+        assert_eq!(state.check_integrity().issues, repair.unrepaired);
+        assert_eq!(state.payment_id_index.get(&"pay_1".to_string()), None);
+        assert_eq!(state.payment_id_index.get(&"pay_2".to_string()), Some(booking_id("APP002")));
+        assert!(state.get_email_sent(&booking_id("APP001")).unwrap());
+        assert!(state.user_principal_email_index.get(&stranger).is_none());
+        assert_eq!(state.user_principal_email_index.get(&user_principal()), Some(EMAIL.to_string()));
+    }
+
+    #[test]
+    fn test_repair_points_the_index_at_the_right_booking() {
+        let mut state = create_consistent_state();
+        set_stored_payment_id_v2(&mut state, &booking_id("APP001"), "");
+        set_stored_payment_id_v2(&mut state, &booking_id("APP002"), "pay_1");
+
+        let repair = state.repair_indexes(false);
+        assert_eq!(repair.repaired.len(), 3);
+        assert_eq!(state.check_integrity().issues, vec![]);
+        assert_eq!(state.payment_id_index.get(&"pay_1".to_string()), Some(booking_id("APP002")));
+        assert_eq!(state.payment_id_index.len(), 1);
+    }
+}
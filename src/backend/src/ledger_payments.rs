@@ -0,0 +1,567 @@
+//! Payments in ICP and other ICRC-1 tokens, without an external gateway.
+//!
+//! Every booking gets its own subaccount of this canister, derived from its `BookingId`, so a
+//! transfer to it can only be meant for that booking. `verify_ledger_payment` reads the
+//! subaccount's balance on each configured ledger and feeds the result through the
+//! `IcpLedger` adapter into `ingest_payment_event`, like any provider callback. The funds stay
+//! in the booking's subaccount until an admin moves them out with `sweep_ledger_payment`,
+//! e.g. to the treasury once the booking is paid, or back to the guest for a refund.
+use candid::{CandidType, Nat, Principal};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::audit::{self, AuditTarget, FieldChange};
+use crate::errors::{BackendError, BackendResult};
+use crate::payment_events::PaymentEvent;
+use crate::payments::{IcpLedger, PaymentProvider};
+use crate::roles::{is_admin, Role};
+use crate::{BackendPaymentStatus, Booking, BookingId, CanisterState, CurrencyCode, STATE};
+
+/// Keeps booking subaccounts apart from any other subaccount derived from the same bytes
+const SUBACCOUNT_DOMAIN: &[u8] = b"estate-booking-payment";
+
+/// ckETH and friends use 18; `amount_due` stays within u128 up to that
+const MAX_DECIMALS: u8 = 18;
+
+/// How often a booking's owner can have its balances checked. Each check is an
+/// `icrc1_balance_of` call per ledger, paid for by this canister.
+const OWNER_CHECK_INTERVAL_NS: u64 = 30 * 1_000_000_000;
+
+thread_local! {
+    /// When each booking was last checked for its owner; forgotten on upgrade
+    static LAST_OWNER_CHECKS: RefCell<BTreeMap<BookingId, u64>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// An ICRC-1 ledger bookings can be paid on
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LedgerConfig {
+    /// e.g. "ICP" or "ckUSDC"
+    pub token: String,
+    pub ledger_canister_id: Principal,
+    /// 8 for ICP, 6 for ckUSDC
+    pub decimals: u8,
+    /// what one token is worth in 10^-`PRICE_SCALE` units of `price_currency`, so
+    /// 1_000_000_000 for ckUSDC in USD
+    pub price_per_token_e9: u64,
+    /// only bookings in this currency can be paid on the ledger
+    #[serde(default)]
+    pub price_currency: CurrencyCode,
+}
+
+/// ICRC-1 `Account`
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct LedgerAmountDue {
+    pub token: String,
+    pub ledger_canister_id: Principal,
+    /// in the token's base units, e.g. e8s
+    pub amount: Nat,
+}
+
+/// ICRC-1 `TransferArg`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+/// ICRC-1 `TransferError`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// Where and how much to pay for one booking, on each configured ledger
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct LedgerPaymentAddress {
+    pub account: Account,
+    /// ICRC-1 textual encoding of `account`, what wallets ask for
+    pub account_text: String,
+    pub amounts_due: Vec<LedgerAmountDue>,
+}
+
+/// The booking's subaccount: SHA-256 over the length-prefixed domain, app reference and email
+pub fn payment_subaccount(booking_id: &BookingId) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in [
+        SUBACCOUNT_DOMAIN,
+        booking_id.get_app_reference().as_bytes(),
+        booking_id.get_user_email().as_bytes(),
+    ] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// The account of canister `owner` that payments for `booking_id` go to
+pub fn payment_account(owner: Principal, booking_id: &BookingId) -> Account {
+    Account {
+        owner,
+        subaccount: Some(payment_subaccount(booking_id).to_vec()),
+    }
+}
+
+/// `<owner>-<checksum>.<subaccount hex without leading zeros>`, or just the owner for the
+/// default subaccount (ICRC-1 textual encoding)
+pub fn account_to_text(account: &Account) -> String {
+    let owner = account.owner.to_text();
+    let subaccount = match &account.subaccount {
+        Some(subaccount) if subaccount.iter().any(|byte| *byte != 0) => subaccount,
+        _ => return owner,
+    };
+    let checksum = crc32([account.owner.as_slice(), subaccount].concat().as_slice());
+    format!(
+        "{}-{}.{}",
+        owner,
+        base32(&checksum.to_be_bytes()),
+        hex::encode(subaccount).trim_start_matches('0')
+    )
+}
+
+/// CRC-32 (IEEE), as used in principal and account text
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// RFC 4648 base32, lowercase and unpadded like principal text
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut text = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    text
+}
+
+/// Token prices are kept in billionths of their currency, so converting between base units
+/// and `Money` is integer math and exact even for 18 decimals
+pub const PRICE_SCALE: u32 = 9;
+
+/// `requested_payment` in the ledger's base units, rounded up. The ledger must be priced
+/// in the booking's currency, see `ledgers_for_booking`. Saturates for amounts far beyond
+/// what any booking costs.
+pub fn amount_due(booking: &Booking, ledger: &LedgerConfig) -> u128 {
+    let requested = booking.get_requested_payment();
+    let amount_minor = u128::try_from(requested.amount_minor).unwrap_or(0);
+    // amount_minor / 10^minor_units currency units, each worth 10^PRICE_SCALE / price tokens
+    let numerator = 10u128
+        .checked_pow(ledger.decimals as u32 + PRICE_SCALE)
+        .and_then(|scale| amount_minor.checked_mul(scale));
+    let denominator = 10u128
+        .checked_pow(requested.currency.minor_units())
+        .and_then(|scale| (ledger.price_per_token_e9.max(1) as u128).checked_mul(scale));
+    match (numerator, denominator) {
+        (Some(numerator), Some(denominator)) => numerator.div_ceil(denominator),
+        _ => u128::MAX,
+    }
+}
+
+/// Ledgers are keyed by lowercased token
+fn ledger_key(token: &str) -> String {
+    token.trim().to_lowercase()
+}
+
+/// What a sweep of `balance` moves: all of it but the fee the ledger takes for the transfer
+fn sweep_amount(balance: u128, fee: u128) -> BackendResult<u128> {
+    match balance.checked_sub(fee) {
+        Some(amount) if amount > 0 => Ok(amount),
+        _ => Err(BackendError::validation(
+            "balance",
+            format!("{} does not cover the transfer fee of {}", balance, fee),
+        )),
+    }
+}
+
+/// Records an owner's check of `booking_id` at `now_ns`, unless the last one was less than
+/// `OWNER_CHECK_INTERVAL_NS` ago. Drops checks that no longer count.
+fn claim_owner_check(
+    last_checks: &mut BTreeMap<BookingId, u64>,
+    booking_id: &BookingId,
+    now_ns: u64,
+) -> BackendResult<()> {
+    last_checks.retain(|_, checked_at| now_ns.saturating_sub(*checked_at) < OWNER_CHECK_INTERVAL_NS);
+    if last_checks.contains_key(booking_id) {
+        return Err(BackendError::Conflict(format!(
+            "the payment was checked less than {} seconds ago",
+            OWNER_CHECK_INTERVAL_NS / 1_000_000_000
+        )));
+    }
+    last_checks.insert(booking_id.clone(), now_ns);
+    Ok(())
+}
+
+impl CanisterState {
+    /// Adds the ledger for `ledger.token`, or replaces it
+    pub fn set_payment_ledger(&mut self, ledger: LedgerConfig) -> BackendResult<()> {
+        if ledger.token.trim().is_empty() {
+            return Err(BackendError::validation("token", "cannot be empty"));
+        }
+        if ledger.decimals > MAX_DECIMALS {
+            return Err(BackendError::validation(
+                "decimals",
+                format!("cannot be more than {}", MAX_DECIMALS),
+            ));
+        }
+        if ledger.price_per_token_e9 == 0 {
+            return Err(BackendError::validation("price_per_token_e9", "must be positive"));
+        }
+        ledger.price_currency.validate()?;
+        self.payment_ledgers.insert(ledger_key(&ledger.token), ledger);
+        Ok(())
+    }
+
+    pub fn remove_payment_ledger(&mut self, token: &str) -> BackendResult<()> {
+        self.payment_ledgers
+            .remove(&ledger_key(token))
+            .map(|_| ())
+            .ok_or_else(|| BackendError::not_found("Payment ledger", token))
+    }
+
+    /// The ledgers priced in the booking's currency
+    pub fn ledgers_for_booking(&self, booking_id: &BookingId) -> BackendResult<Vec<LedgerConfig>> {
+        let booking = self
+            .get_booking_by_id(booking_id)
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))?;
+        if self.payment_ledgers.is_empty() {
+            return Err(BackendError::validation("ledger", "no payment ledgers are configured"));
+        }
+        let currency = &booking.get_requested_payment().currency;
+        let ledgers: Vec<LedgerConfig> = self
+            .payment_ledgers
+            .values()
+            .filter(|ledger| &ledger.price_currency == currency)
+            .cloned()
+            .collect();
+        if ledgers.is_empty() {
+            return Err(BackendError::validation(
+                "ledger",
+                format!("no payment ledger is priced in {}", currency),
+            ));
+        }
+        Ok(ledgers)
+    }
+
+    /// `owner` is this canister's id
+    pub fn get_payment_address(
+        &self,
+        booking_id: &BookingId,
+        owner: Principal,
+    ) -> BackendResult<LedgerPaymentAddress> {
+        let booking = self
+            .get_booking_by_id(booking_id)
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))?;
+        if booking.cancellation.is_some() {
+            return Err(BackendError::Conflict("booking is cancelled".to_string()));
+        }
+        let ledgers = self.ledgers_for_booking(booking_id)?;
+
+        let account = payment_account(owner, booking_id);
+        Ok(LedgerPaymentAddress {
+            account_text: account_to_text(&account),
+            account,
+            amounts_due: ledgers
+                .iter()
+                .map(|ledger| LedgerAmountDue {
+                    token: ledger.token.clone(),
+                    ledger_canister_id: ledger.ledger_canister_id,
+                    amount: Nat::from(amount_due(&booking, ledger)),
+                })
+                .collect(),
+        })
+    }
+
+    /// The ledger `token` is paid on, for sweeping the booking's subaccount
+    pub fn ledger_for_sweep(&self, booking_id: &BookingId, token: &str) -> BackendResult<LedgerConfig> {
+        if !self.bookings.contains_key(booking_id) {
+            return Err(BackendError::not_found("Booking", booking_id.get_app_reference()));
+        }
+        self.payment_ledgers
+            .get(&ledger_key(token))
+            .cloned()
+            .ok_or_else(|| BackendError::not_found("Payment ledger", token))
+    }
+
+    /// Owners can only have a booking checked while it still waits for its payment
+    pub fn awaits_ledger_payment(&self, booking_id: &BookingId) -> BackendResult<()> {
+        let booking = self
+            .get_booking_by_id(booking_id)
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))?;
+        if booking.cancellation.is_some() {
+            return Err(BackendError::Conflict("booking is cancelled".to_string()));
+        }
+        let status = &booking.payment_details.payment_status;
+        if matches!(
+            status,
+            BackendPaymentStatus::Paid(_)
+                | BackendPaymentStatus::RefundPending(_)
+                | BackendPaymentStatus::Refunded { .. }
+        ) {
+            return Err(BackendError::Conflict(format!("payment is already {}", status.name())));
+        }
+        Ok(())
+    }
+
+    /// The payment event for the balances of the booking's subaccount, one per ledger.
+    /// The ledger closest to the amount due is reported; `None` if nothing has arrived or
+    /// nothing changed since the last check. Ledgers in another currency are ignored.
+    pub fn ledger_payment_event(
+        &self,
+        booking_id: &BookingId,
+        balances: &[(LedgerConfig, u128)],
+        now_ns: u64,
+    ) -> BackendResult<Option<PaymentEvent>> {
+        let booking = self
+            .get_booking_by_id(booking_id)
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))?;
+        let currency = &booking.get_requested_payment().currency;
+        let best = balances
+            .iter()
+            .filter(|(ledger, balance)| *balance > 0 && &ledger.price_currency == currency)
+            .map(|(ledger, balance)| (ledger, *balance, amount_due(&booking, ledger).max(1)))
+            .max_by(|(_, a, a_due), (_, b, b_due)| {
+                (*a as f64 / *a_due as f64).total_cmp(&(*b as f64 / *b_due as f64))
+            });
+        let Some((ledger, received, expected)) = best else {
+            return Ok(None);
+        };
+
+        let payment_id_v2 = hex::encode(payment_subaccount(booking_id));
+        // base units as strings: 18 decimal tokens overflow a JSON u64
+        let payload = json!({
+            "payment_id": payment_id_v2,
+            "status": if received >= expected { "received" } else { "partially_received" },
+            "token": ledger.token,
+            "decimals": ledger.decimals,
+            "expected": expected.to_string(),
+            "received": received.to_string(),
+            "order_id": booking_id.get_app_reference(),
+            "price_per_token_e9": ledger.price_per_token_e9,
+            "price_currency": currency.as_str(),
+            "created_at_ns": now_ns,
+            "updated_at_ns": now_ns,
+        });
+        let mut record = IcpLedger.normalize(&payload)?;
+
+        let current = &booking.payment_details.payment_api_response;
+        if current.provider == record.provider && current.payment_id_v2 == record.payment_id_v2 {
+            if current.payment_status == record.provider_status
+                && current.amount_paid.as_ref() == Some(&record.amount_paid)
+            {
+                return Ok(None);
+            }
+            record.created_at = current.created_at.clone();
+        }
+
+        Ok(Some(PaymentEvent {
+            booking_id: Some(booking_id.clone()),
+            payment_api_response: record.into(),
+        }))
+    }
+}
+
+/// Controllers and the services and staff that may read any booking
+fn is_staff(caller: Principal) -> bool {
+    let staff = [Role::BookingService, Role::PaymentService, Role::Support];
+    ic_cdk::api::is_controller(&caller)
+        || STATE.with(|state| state.borrow().has_any_role(&caller, &staff))
+}
+
+/// The booking's owner, or staff
+fn authorize_booking_access(caller: Principal, booking_id: &BookingId) -> BackendResult<()> {
+    if is_staff(caller) {
+        return Ok(());
+    }
+    STATE.with(|state| state.borrow().get_booking_for_caller(caller, booking_id).map(|_| ()))
+}
+
+async fn balance_of(ledger: &LedgerConfig, account: &Account) -> BackendResult<u128> {
+    let (balance,): (Nat,) =
+        ic_cdk::call(ledger.ledger_canister_id, "icrc1_balance_of", (account,))
+            .await
+            .map_err(|error| ledger_error(ledger, error))?;
+    to_u128(ledger, "balance", &balance)
+}
+
+fn ledger_error(ledger: &LedgerConfig, (code, message): (ic_cdk::api::call::RejectionCode, String)) -> BackendError {
+    BackendError::Internal(format!(
+        "{} ledger {} did not answer: {:?} {}",
+        ledger.token, ledger.ledger_canister_id, code, message
+    ))
+}
+
+fn to_u128(ledger: &LedgerConfig, what: &str, value: &Nat) -> BackendResult<u128> {
+    u128::try_from(&value.0).map_err(|_| {
+        BackendError::Internal(format!("{} {} {} is out of range", ledger.token, what, value))
+    })
+}
+
+async fn transfer_fee(ledger: &LedgerConfig) -> BackendResult<u128> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger.ledger_canister_id, "icrc1_fee", ())
+        .await
+        .map_err(|error| ledger_error(ledger, error))?;
+    to_u128(ledger, "fee", &fee)
+}
+
+/// Returns the block index of the transfer
+async fn transfer(ledger: &LedgerConfig, arg: TransferArg) -> BackendResult<Nat> {
+    let (result,): (Result<Nat, TransferError>,) =
+        ic_cdk::call(ledger.ledger_canister_id, "icrc1_transfer", (arg,))
+            .await
+            .map_err(|error| ledger_error(ledger, error))?;
+    result.map_err(|error| {
+        BackendError::Internal(format!("{} transfer failed: {:?}", ledger.token, error))
+    })
+}
+
+#[ic_cdk_macros::query]
+fn get_payment_address(booking_id: BookingId) -> BackendResult<LedgerPaymentAddress> {
+    authorize_booking_access(ic_cdk::caller(), &booking_id)?;
+    STATE.with(|state| state.borrow().get_payment_address(&booking_id, ic_cdk::id()))
+}
+
+/// Anyone who may see the booking can ask for the check: what was paid comes from the
+/// ledgers, not from the caller. Owners only while the payment is outstanding, and once per
+/// `OWNER_CHECK_INTERVAL_NS` per booking; staff can also check settled bookings, e.g. for
+/// late overpayments.
+#[ic_cdk_macros::update]
+async fn verify_ledger_payment(booking_id: BookingId) -> BackendResult<Booking> {
+    let caller = ic_cdk::caller();
+    if !is_staff(caller) {
+        authorize_booking_access(caller, &booking_id)?;
+        STATE.with(|state| state.borrow().awaits_ledger_payment(&booking_id))?;
+        LAST_OWNER_CHECKS.with(|checks| {
+            claim_owner_check(&mut checks.borrow_mut(), &booking_id, ic_cdk::api::time())
+        })?;
+    }
+    let ledgers = STATE.with(|state| state.borrow().ledgers_for_booking(&booking_id))?;
+
+    let account = payment_account(ic_cdk::id(), &booking_id);
+    let mut balances = Vec::new();
+    for ledger in ledgers {
+        let balance = balance_of(&ledger, &account).await?;
+        balances.push((ledger, balance));
+    }
+
+    let event = STATE.with(|state| {
+        state
+            .borrow()
+            .ledger_payment_event(&booking_id, &balances, ic_cdk::api::time())
+    })?;
+    match event {
+        Some(event) => audit::audited(
+            "verify_ledger_payment",
+            AuditTarget::booking(&booking_id),
+            |state| state.get_booking_by_id(&booking_id),
+            |state| state.ingest_payment_event(event),
+        )
+        .map(|result| result.booking),
+        None => STATE
+            .with(|state| state.borrow().get_booking_by_id(&booking_id))
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference())),
+    }
+}
+
+/// Moves everything the booking's subaccount holds on `token`'s ledger, less the transfer
+/// fee, to `to` and returns the ledger's block index. Nothing here checks the booking's
+/// payment status; the sweep is in the audit log with the balance it moved.
+#[ic_cdk_macros::update(guard = "is_admin")]
+async fn sweep_ledger_payment(booking_id: BookingId, token: String, to: Account) -> BackendResult<Nat> {
+    let ledger = STATE.with(|state| state.borrow().ledger_for_sweep(&booking_id, &token))?;
+    let from = payment_account(ic_cdk::id(), &booking_id);
+    let balance = balance_of(&ledger, &from).await?;
+    let fee = transfer_fee(&ledger).await?;
+    let amount = sweep_amount(balance, fee)?;
+
+    let block_index = transfer(
+        &ledger,
+        TransferArg {
+            from_subaccount: from.subaccount,
+            to,
+            amount: Nat::from(amount),
+            fee: Some(Nat::from(fee)),
+            memo: None,
+            created_at_time: Some(ic_cdk::api::time()),
+        },
+    )
+    .await?;
+
+    let (caller, now) = audit::call_context();
+    STATE.with(|state| {
+        state.borrow_mut().record_audit(
+            caller,
+            "sweep_ledger_payment",
+            now,
+            AuditTarget::booking(&booking_id),
+            vec![FieldChange {
+                field: format!("ledger_balance.{}", ledger.token),
+                before: Some(balance.to_string()),
+                after: Some("0".to_string()),
+            }],
+        )
+    });
+    Ok(block_index)
+}
+
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn set_payment_ledger(ledger: LedgerConfig) -> BackendResult<()> {
+    audit::audited(
+        "set_payment_ledger",
+        AuditTarget::default(),
+        |state| state.payment_ledgers.clone(),
+        |state| state.set_payment_ledger(ledger),
+    )
+}
+
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn remove_payment_ledger(token: String) -> BackendResult<()> {
+    audit::audited(
+        "remove_payment_ledger",
+        AuditTarget::default(),
+        |state| state.payment_ledgers.clone(),
+        |state| state.remove_payment_ledger(&token),
+    )
+}
+
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn get_payment_ledgers() -> Vec<LedgerConfig> {
+    STATE.with(|state| state.borrow().payment_ledgers.values().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,322 @@
+#[cfg(test)]
+mod ledger_payments_tests {
+    use candid::{Nat, Principal};
+    use std::collections::BTreeMap;
+
+    use crate::errors::BackendError;
+    use crate::ledger_payments::*;
+    use crate::models::*;
+    use crate::payment_events::PaymentEventOutcome;
+    use crate::payments::ICP_LEDGER;
+    use crate::test_utils::{self, create_valid_booking, usd};
+
+    const EMAIL: &str = "user1@example.com";
+
+    fn canister_id() -> Principal {
+        Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+    }
+
+    fn icp() -> LedgerConfig {
+        LedgerConfig {
+            token: "ICP".to_string(),
+            ledger_canister_id: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+            decimals: 8,
+            price_per_token_e9: 10_000_000_000,
+            price_currency: CurrencyCode::usd(),
+        }
+    }
+
+    fn ckusdc() -> LedgerConfig {
+        LedgerConfig {
+            token: "ckUSDC".to_string(),
+            ledger_canister_id: Principal::from_text("xevnm-gaaaa-aaaar-qafnq-cai").unwrap(),
+            decimals: 6,
+            price_per_token_e9: 1_000_000_000,
+            price_currency: CurrencyCode::usd(),
+        }
+    }
+
+    /// A state with one 400 USD booking and both ledgers configured
+    fn create_test_state() -> (CanisterState, BookingId) {
+        let (mut state, booking_id) = test_utils::create_test_state(EMAIL);
+        state.set_payment_ledger(icp()).unwrap();
+        state.set_payment_ledger(ckusdc()).unwrap();
+        (state, booking_id)
+    }
+
+    fn verify(
+        state: &mut CanisterState,
+        booking_id: &BookingId,
+        balances: &[(LedgerConfig, u128)],
+        now_ns: u64,
+    ) -> Option<Booking> {
+        let event = state.ledger_payment_event(booking_id, balances, now_ns).unwrap()?;
+        let result = state.ingest_payment_event(event).unwrap();
+        assert_eq!(result.outcome, PaymentEventOutcome::Applied);
+        Some(result.booking)
+    }
+
+    #[test]
+    fn test_payment_subaccount_is_per_booking() {
+        let booking_id = BookingId::new("APP001".to_string(), EMAIL.to_string());
+
+        assert_eq!(payment_subaccount(&booking_id), payment_subaccount(&booking_id.clone()));
+        assert_ne!(
+            payment_subaccount(&booking_id),
+            payment_subaccount(&BookingId::new("APP002".to_string(), EMAIL.to_string()))
+        );
+        // parts are length-prefixed, so moving a character between them changes the subaccount
+        assert_ne!(
+            payment_subaccount(&BookingId::new("ab".to_string(), "c".to_string())),
+            payment_subaccount(&BookingId::new("a".to_string(), "bc".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_account_text_matches_the_icrc1_examples() {
+        let owner =
+            Principal::from_text("k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae")
+                .unwrap();
+        let account = |subaccount: Option<Vec<u8>>| account_to_text(&Account { owner, subaccount });
+
+        assert_eq!(account(None), owner.to_text());
+        assert_eq!(account(Some(vec![0; 32])), owner.to_text());
+        assert_eq!(
+            account(Some((1..=32).collect())),
+            "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20"
+        );
+        let mut subaccount = vec![0; 32];
+        subaccount[31] = 1;
+        assert_eq!(
+            account(Some(subaccount)),
+            "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae-6cc627i.1"
+        );
+    }
+
+    #[test]
+    fn test_payment_ledger_management() {
+        let mut state = CanisterState::new();
+
+        assert!(state
+            .set_payment_ledger(LedgerConfig {
+                token: " ".to_string(),
+                ..icp()
+            })
+            .is_err());
+        assert!(state
+            .set_payment_ledger(LedgerConfig {
+                price_per_token_e9: 0,
+                ..icp()
+            })
+            .is_err());
+        assert!(state
+            .set_payment_ledger(LedgerConfig {
+                decimals: 19,
+                ..icp()
+            })
+            .is_err());
+        assert!(state.payment_ledgers.is_empty());
+
+        state.set_payment_ledger(icp()).unwrap();
+        // tokens are case-insensitive, so this replaces the ICP ledger
+        state
+            .set_payment_ledger(LedgerConfig {
+                token: "icp".to_string(),
+                price_per_token_e9: 12_500_000_000,
+                ..icp()
+            })
+            .unwrap();
+        assert_eq!(state.payment_ledgers.len(), 1);
+        assert_eq!(state.payment_ledgers["icp"].price_per_token_e9, 12_500_000_000);
+
+        state.remove_payment_ledger("ICP").unwrap();
+        assert!(matches!(
+            state.remove_payment_ledger("ICP"),
+            Err(BackendError::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_get_payment_address() {
+        let (mut state, booking_id) = create_test_state();
+
+        let address = state.get_payment_address(&booking_id, canister_id()).unwrap();
+        assert_eq!(address.account, payment_account(canister_id(), &booking_id));
+        assert_eq!(address.account_text, account_to_text(&address.account));
+        // 400 USD is 40 ICP at 10 USD and 400 ckUSDC
+        let amounts: Vec<(String, Nat)> = address
+            .amounts_due
+            .into_iter()
+            .map(|due| (due.token, due.amount))
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                ("ckUSDC".to_string(), Nat::from(400_000_000u64)),
+                ("ICP".to_string(), Nat::from(4_000_000_000u64)),
+            ]
+        );
+
+        let missing = BookingId::new("APP404".to_string(), EMAIL.to_string());
+        assert!(matches!(
+            state.get_payment_address(&missing, canister_id()),
+            Err(BackendError::NotFound { .. })
+        ));
+
+        state.remove_payment_ledger("ICP").unwrap();
+        state.remove_payment_ledger("ckUSDC").unwrap();
+        assert!(matches!(
+            state.get_payment_address(&booking_id, canister_id()),
+            Err(BackendError::Validation { .. })
+        ));
+
+        state.set_payment_ledger(icp()).unwrap();
+        state.cancel_booking(booking_id.clone(), "changed plans".to_string()).unwrap();
+        assert!(matches!(
+            state.get_payment_address(&booking_id, canister_id()),
+            Err(BackendError::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn test_amount_due_is_exact_for_18_decimals() {
+        let booking = create_valid_booking("APP001", EMAIL);
+        let cketh = |price_per_token_e9| LedgerConfig {
+            token: "ckETH".to_string(),
+            ledger_canister_id: Principal::from_text("ss2fx-dyaaa-aaaar-qacoq-cai").unwrap(),
+            decimals: 18,
+            price_per_token_e9,
+            price_currency: CurrencyCode::usd(),
+        };
+
+        // 400 USD at 3 USD, rounded up in the last wei
+        assert_eq!(amount_due(&booking, &cketh(3_000_000_000)), 133_333_333_333_333_333_334);
+        // f64 math gets 127_324_062_019_550_592 here
+        assert_eq!(amount_due(&booking, &cketh(3_141_590_000_000)), 127_324_062_019_550_610);
+    }
+
+    #[test]
+    fn test_ledger_payment_marks_the_booking_paid() {
+        let (mut state, booking_id) = create_test_state();
+
+        // nothing has arrived yet
+        assert!(verify(&mut state, &booking_id, &[(ckusdc(), 0), (icp(), 0)], 1).is_none());
+
+        let booking = verify(&mut state, &booking_id, &[(ckusdc(), 150_000_000), (icp(), 0)], 2)
+            .unwrap();
+        assert_eq!(
+            booking.payment_details.payment_status,
+            BackendPaymentStatus::PartiallyPaid(hex::encode(payment_subaccount(&booking_id)))
+        );
+        let response = &booking.payment_details.payment_api_response;
+        assert_eq!(response.provider, ICP_LEDGER);
+        assert_eq!(response.amount_paid, Some(usd(15000)));
+        let mismatch = booking.payment_mismatch.unwrap();
+        assert_eq!(mismatch.kind, PaymentMismatchKind::Underpaid);
+        assert_eq!(mismatch.paid_amount, usd(15000));
+
+        // the same balance again is not a new event
+        assert!(verify(&mut state, &booking_id, &[(ckusdc(), 150_000_000), (icp(), 0)], 3).is_none());
+
+        let booking = verify(&mut state, &booking_id, &[(ckusdc(), 400_000_000), (icp(), 0)], 4)
+            .unwrap();
+        assert!(booking.payment_details.is_paid());
+        assert_eq!(booking.payment_mismatch, None);
+        assert_eq!(
+            state.payment_id_index.get(&hex::encode(payment_subaccount(&booking_id))),
+            Some(booking_id.clone())
+        );
+        assert!(verify(&mut state, &booking_id, &[(ckusdc(), 400_000_000), (icp(), 0)], 5).is_none());
+        assert_eq!(state.get_payment_events(&booking_id).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_ledger_closest_to_the_amount_due_is_reported() {
+        let (state, booking_id) = create_test_state();
+
+        // 100 ckUSDC is a quarter, 30 ICP three quarters of the 400 USD
+        let event = state
+            .ledger_payment_event(
+                &booking_id,
+                &[(ckusdc(), 100_000_000), (icp(), 3_000_000_000)],
+                1,
+            )
+            .unwrap()
+            .unwrap();
+        let response = event.payment_api_response;
+        assert_eq!(response.payment_status, "partially_received");
+        assert_eq!(response.amount_paid, Some(usd(30000)));
+        assert_eq!(response.price, Some(usd(40000)));
+
+        // a full payment on either ledger is enough
+        let event = state
+            .ledger_payment_event(&booking_id, &[(ckusdc(), 100_000_000), (icp(), 4_000_000_000)], 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.payment_api_response.payment_status, "received");
+    }
+
+    #[test]
+    fn test_owners_can_only_check_outstanding_payments() {
+        let (mut state, booking_id) = create_test_state();
+        assert_eq!(state.awaits_ledger_payment(&booking_id), Ok(()));
+
+        verify(&mut state, &booking_id, &[(ckusdc(), 150_000_000), (icp(), 0)], 1).unwrap();
+        assert_eq!(state.awaits_ledger_payment(&booking_id), Ok(()));
+
+        verify(&mut state, &booking_id, &[(ckusdc(), 400_000_000), (icp(), 0)], 2).unwrap();
+        assert!(matches!(
+            state.awaits_ledger_payment(&booking_id),
+            Err(BackendError::Conflict(_))
+        ));
+
+        let missing = BookingId::new("APP404".to_string(), EMAIL.to_string());
+        assert!(matches!(
+            state.awaits_ledger_payment(&missing),
+            Err(BackendError::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_owner_checks_are_throttled_per_booking() {
+        let mut last_checks = BTreeMap::new();
+        let booking_id = BookingId::new("APP001".to_string(), EMAIL.to_string());
+        let other = BookingId::new("APP002".to_string(), EMAIL.to_string());
+
+        assert_eq!(claim_owner_check(&mut last_checks, &booking_id, 1_000), Ok(()));
+        assert!(matches!(
+            claim_owner_check(&mut last_checks, &booking_id, 2_000),
+            Err(BackendError::Conflict(_))
+        ));
+        assert_eq!(claim_owner_check(&mut last_checks, &other, 2_000), Ok(()));
+
+        let later = 1_000 + OWNER_CHECK_INTERVAL_NS;
+        assert_eq!(claim_owner_check(&mut last_checks, &booking_id, later), Ok(()));
+        assert!(claim_owner_check(&mut last_checks, &other, later).is_err());
+
+        // expired checks are dropped
+        let third = BookingId::new("APP003".to_string(), EMAIL.to_string());
+        claim_owner_check(&mut last_checks, &third, later * 2).unwrap();
+        assert_eq!(last_checks.len(), 1);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let (state, booking_id) = create_test_state();
+
+        assert_eq!(state.ledger_for_sweep(&booking_id, "icp"), Ok(icp()));
+        assert!(matches!(
+            state.ledger_for_sweep(&booking_id, "ckETH"),
+            Err(BackendError::NotFound { .. })
+        ));
+        let missing = BookingId::new("APP404".to_string(), EMAIL.to_string());
+        assert!(matches!(
+            state.ledger_for_sweep(&missing, "ICP"),
+            Err(BackendError::NotFound { .. })
+        ));
+
+        assert_eq!(sweep_amount(400_000_000, 10_000), Ok(399_990_000));
+        assert!(sweep_amount(10_000, 10_000).is_err());
+        assert!(sweep_amount(0, 10_000).is_err());
+    }
+}
@@ -1,19 +1,48 @@
 pub mod models;
 pub use models::*;
-mod controller;
+pub mod audit;
+mod backup;
+pub mod controller;
+pub mod roles;
+pub mod timeline;
+pub mod payment_events;
+pub mod payments;
+pub mod ledger_payments;
+pub mod reconciliation;
+pub mod integrity;
+pub mod webhooks;
+pub mod errors;
 mod migration;
 mod migrations;
 
-use candid::Principal;
-pub use controller::is_controller;
+use audit::{AuditFilter, AuditLogPage, AuditTarget};
+use backup::{ImportProgress, StateExport, StateExportChunk};
+use controller::{ControllerAuditEntry, PendingControllerRemoval};
+pub use errors::{BackendError, BackendResult, LegacyEndpoint};
+use migration::{MigrationPreview, SchemaVersion};
+use candid::{Nat, Principal};
+pub use roles::{
+    can_manage_bookings, can_read_analytics, can_read_bookings, is_admin, is_booking_service,
+    is_payment_service,
+};
+use roles::{require_role_or_owner, Role, RoleAssignment};
+use ledger_payments::{Account, LedgerConfig, LedgerPaymentAddress};
+use payment_events::{PaymentEvent, PaymentEventRecord, PaymentEventResult};
+use reconciliation::ReconciliationReport;
+use integrity::{IndexRepair, IntegrityReport};
+use timeline::BookingEvent;
 
 use std::cell::RefCell;
 
 pub mod memory;
 pub mod lifecycle;
 
+#[cfg(test)]
+mod integrity_tests;
 #[cfg(test)]
 mod payment_id_index_tests;
+#[cfg(test)]
+mod test_utils;
 
 thread_local! {
     pub static STATE: RefCell<CanisterState> = RefCell::new(CanisterState::default());
@@ -42,34 +71,23 @@ pub fn rebuild_payment_id_index() {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
 
-        // Only rebuild if index doesn't exist or is empty (new field after upgrade)
-        if let Some(ref payment_index) = state.payment_id_index {
-            if !payment_index.is_empty() {
-                return; // Index already exists, no need to rebuild
-            }
+        // Only rebuild if index is empty (new stable map after upgrade)
+        if !state.payment_id_index.is_empty() {
+            return; // Index already exists, no need to rebuild
         }
 
         // Collect payment_id_v2 -> booking_id mappings
         let mut payment_mappings = Vec::new();
-        for user_info in state.users.values() {
-            for booking in user_info.bookings.values() {
-                let payment_id_v2 = &booking.payment_details.payment_api_response.payment_id_v2;
-                if !payment_id_v2.is_empty() {
-                    payment_mappings.push((payment_id_v2.clone(), booking.booking_id.clone()));
-                }
+        for booking in state.bookings.values() {
+            let payment_id_v2 = &booking.payment_details.payment_api_response.payment_id_v2;
+            if !payment_id_v2.is_empty() {
+                payment_mappings.push((payment_id_v2.clone(), booking.booking_id.clone()));
             }
         }
 
-        // Initialize the index if it doesn't exist
-        if state.payment_id_index.is_none() {
-            state.payment_id_index = Some(std::collections::BTreeMap::new());
-        }
-
         // Build index from collected mappings
-        if let Some(ref mut payment_index) = state.payment_id_index {
-            for (payment_id_v2, booking_id) in payment_mappings {
-                payment_index.insert(payment_id_v2, booking_id);
-            }
+        for (payment_id_v2, booking_id) in payment_mappings {
+            state.payment_id_index.insert(payment_id_v2, booking_id);
         }
     });
 }
@@ -77,9 +95,39 @@ pub fn rebuild_payment_id_index() {
 ////////////////////////////
 // CREATE / UPDATE
 ////////////////////////////
-#[ic_cdk_macros::update(guard = "is_controller")]
+// Endpoints returning `Result<_, String>` are kept for existing clients. Each has a
+// `_v2` twin returning `BackendError`, which new clients should use instead.
+// Both are audited under the name without `_v2`.
+
+fn email_sent_for(state: &CanisterState, booking_id: &BookingId) -> Option<bool> {
+    state
+        .email_sent
+        .as_ref()
+        .and_then(|sent| sent.get_email_sent_status_for_booking_id(booking_id))
+}
+
+fn schema_versions(state: &CanisterState) -> serde_json::Value {
+    serde_json::json!({
+        "current_version": state.schema_metadata.current_version,
+        "target_version": state.schema_metadata.target_version,
+    })
+}
+
+#[ic_cdk_macros::update(guard = "is_booking_service")]
 fn add_booking(email: String, booking: Booking) -> Result<String, String> {
-    STATE.with(|state| state.borrow_mut().add_booking_and_user(&email, booking))
+    add_booking_v2(email, booking)
+        .map_err(|e| e.to_legacy_string(LegacyEndpoint::AddBooking))
+}
+
+#[ic_cdk_macros::update(guard = "is_booking_service")]
+fn add_booking_v2(email: String, booking: Booking) -> BackendResult<String> {
+    let booking_id = booking.booking_id.clone();
+    audit::audited(
+        "add_booking",
+        AuditTarget::booking(&booking_id),
+        |state| state.get_booking_by_id(&booking_id),
+        |state| state.add_booking_and_user(&email, booking),
+    )
 }
 
 // #[ic_cdk_macros::update(guard = "is_controller")]
@@ -98,80 +146,177 @@ fn add_booking(email: String, booking: Booking) -> Result<String, String> {
 //     })
 // }
 
-#[ic_cdk_macros::update(guard = "is_controller")]
+#[ic_cdk_macros::update(guard = "is_payment_service")]
 fn update_payment_details(
     booking_id: BookingId,
     payment_details: PaymentDetails,
 ) -> Result<Booking, String> {
-    STATE.with(|state| {
-        state
-            .borrow_mut()
-            .update_payment_details(booking_id, payment_details)
-    })
+    update_payment_details_v2(booking_id, payment_details)
+        .map_err(|e| e.to_legacy_string(LegacyEndpoint::UpdatePaymentDetails))
 }
 
-// #[ic_cdk_macros::update(guard = "is_controller")]
-// fn update_booking(email: String, booking_id: String, booking: Booking) -> Result<(), String> {
-//     STATE.with(|state| {
-//         state.borrow_mut().update_booking(&email, &booking_id, booking)
-//     })
-// }
+#[ic_cdk_macros::update(guard = "is_payment_service")]
+fn update_payment_details_v2(
+    booking_id: BookingId,
+    payment_details: PaymentDetails,
+) -> BackendResult<Booking> {
+    audit::audited(
+        "update_payment_details",
+        AuditTarget::booking(&booking_id),
+        |state| state.get_booking_by_id(&booking_id),
+        |state| state.update_payment_details(booking_id.clone(), payment_details),
+    )
+}
 
-// #[ic_cdk_macros::update(guard = "is_controller")]
-// fn cancel_booking(email: String, booking_id: String) -> Result<(), String> {
-//     STATE.with(|state| {
-//         state.borrow_mut().cancel_booking(&email, &booking_id)
-//     })
-// }
+/// Cancels the booking; a paid booking is left waiting for `record_refund`
+#[ic_cdk_macros::update(guard = "can_manage_bookings")]
+fn cancel_booking(booking_id: BookingId, reason: String) -> BackendResult<Booking> {
+    audit::audited(
+        "cancel_booking",
+        AuditTarget::booking(&booking_id),
+        |state| state.get_booking_by_id(&booking_id),
+        |state| state.cancel_booking(booking_id.clone(), reason),
+    )
+}
+
+/// Changes dates, rooms or guests and keeps the previous version, see `get_booking_revisions`.
+/// `amendment.force` (amending a confirmed, cancelled or failed booking) needs `Admin`.
+#[ic_cdk_macros::update(guard = "can_manage_bookings")]
+fn amend_booking(booking_id: BookingId, amendment: BookingAmendment) -> BackendResult<AmendmentResult> {
+    let caller_is_admin = is_admin().is_ok();
+    audit::audited(
+        "amend_booking",
+        AuditTarget::booking(&booking_id),
+        |state| state.get_booking_by_id(&booking_id),
+        |state| state.amend_booking(booking_id.clone(), amendment, caller_is_admin),
+    )
+}
+
+#[ic_cdk_macros::query(guard = "can_read_bookings")]
+fn get_booking_revisions(booking_id: BookingId) -> BackendResult<Vec<BookingRevision>> {
+    STATE.with(|state| state.borrow().get_booking_revisions(&booking_id))
+}
+
+/// Called once the payment provider has refunded a cancelled booking
+#[ic_cdk_macros::update(guard = "is_payment_service")]
+fn record_refund(booking_id: BookingId, amount: Money, reference: String) -> BackendResult<Booking> {
+    audit::audited(
+        "record_refund",
+        AuditTarget::booking(&booking_id),
+        |state| state.get_booking_by_id(&booking_id),
+        |state| state.record_refund(booking_id.clone(), amount, reference),
+    )
+}
+
+/// How far a payment may differ from the requested amount before `update_payment_details`
+/// flags the booking
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn set_payment_tolerance(tolerance: PaymentTolerance) -> BackendResult<()> {
+    audit::audited(
+        "set_payment_tolerance",
+        AuditTarget::default(),
+        |state| state.payment_tolerance.clone(),
+        |state| state.set_payment_tolerance(tolerance),
+    )
+}
+
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn get_payment_tolerance() -> PaymentTolerance {
+    STATE.with(|state| state.borrow().payment_tolerance.clone())
+}
+
+/// Bookings whose payment is under or over the requested amount
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn get_payment_mismatches() -> Vec<PaymentMismatchReport> {
+    STATE.with(|state| state.borrow().get_payment_mismatches())
+}
 
 ////////////////////////////
 // READ
 ////////////////////////////
 
+/// Traps for other callers than `get_user_bookings_v2` allows, as it can't return the error
 #[ic_cdk_macros::query]
 fn get_user_bookings(email: String) -> Option<Vec<Booking>> {
+    match get_user_bookings_v2(email) {
+        Ok(bookings) => Some(bookings),
+        Err(BackendError::Unauthorized(error)) => ic_cdk::trap(&error),
+        Err(_) => None,
+    }
+}
+
+/// For `can_read_bookings` and the user `email` itself
+#[ic_cdk_macros::query]
+fn get_user_bookings_v2(email: String) -> BackendResult<Vec<Booking>> {
+    require_role_or_owner(can_read_bookings, &email).map_err(BackendError::Unauthorized)?;
     STATE.with(|state| {
         state
             .borrow()
             .get_user_bookings(&email)
-            .map(|bookings| bookings.values().cloned().collect())
+            .map(|bookings| bookings.into_values().collect())
+            .ok_or_else(|| BackendError::not_found("User", &email))
     })
 }
 
-#[ic_cdk_macros::update(guard = "is_controller")]
+#[ic_cdk_macros::update(guard = "is_booking_service")]
 fn update_book_room_response(
     booking_id: BookingId,
     book_room_response: BEBookRoomResponse,
 ) -> Result<String, String> {
-    STATE.with(|state| {
-        state
-            .borrow_mut()
-            .update_book_room_response(booking_id, book_room_response)
-    })
+    update_book_room_response_v2(booking_id, book_room_response)
+        .map_err(|e| e.to_legacy_string(LegacyEndpoint::UpdateBookRoomResponse))
 }
 
-#[ic_cdk_macros::update(guard = "is_controller")]
+#[ic_cdk_macros::update(guard = "is_booking_service")]
+fn update_book_room_response_v2(
+    booking_id: BookingId,
+    book_room_response: BEBookRoomResponse,
+) -> BackendResult<String> {
+    audit::audited(
+        "update_book_room_response",
+        AuditTarget::booking(&booking_id),
+        |state| state.get_booking_by_id(&booking_id),
+        |state| state.update_book_room_response(booking_id.clone(), book_room_response),
+    )
+}
+
+#[ic_cdk_macros::update(guard = "is_booking_service")]
 fn add_to_wishlist_by_email(email: String, hotel_id: HotelId) -> Result<String, String> {
-    STATE.with(|state| {
-        state.borrow_mut().add_to_wishlist_by_email(email, hotel_id);
-        Ok("Added to wishlist".to_string())
-    })
+    audit::audited(
+        "add_to_wishlist_by_email",
+        AuditTarget::email(&email),
+        |state| state.wishlist.get(&email),
+        |state| {
+            state.add_to_wishlist_by_email(email.clone(), hotel_id);
+            Ok("Added to wishlist".to_string())
+        },
+    )
 }
 
-#[ic_cdk_macros::update(guard = "is_controller")]
+#[ic_cdk_macros::update(guard = "is_booking_service")]
 fn remove_from_wishlist_by_email(email: String, hotel_id: HotelId) -> Result<String, String> {
-    STATE.with(|state| {
-        state.borrow_mut().remove_from_wishlist_by_email(&email, &hotel_id.hotel_code);
-        Ok("Removed from wishlist".to_string())
-    })
+    audit::audited(
+        "remove_from_wishlist_by_email",
+        AuditTarget::email(&email),
+        |state| state.wishlist.get(&email),
+        |state| {
+            state.remove_from_wishlist_by_email(&email, &hotel_id.hotel_code);
+            Ok("Removed from wishlist".to_string())
+        },
+    )
 }
 
-#[ic_cdk_macros::update(guard = "is_controller")]
+#[ic_cdk_macros::update(guard = "is_booking_service")]
 fn clear_wishlist_by_email(email: String) -> Result<String, String> {
-    STATE.with(|state| {
-        state.borrow_mut().clear_wishlist_by_email(&email);
-        Ok("Cleared wishlist".to_string())
-    })
+    audit::audited(
+        "clear_wishlist_by_email",
+        AuditTarget::email(&email),
+        |state| state.wishlist.get(&email),
+        |state| {
+            state.clear_wishlist_by_email(&email);
+            Ok("Cleared wishlist".to_string())
+        },
+    )
 }
 
 #[ic_cdk_macros::query]
@@ -182,21 +327,27 @@ fn get_wishlist_count_for_a_hotel_id(hotel_id: String) -> Result<usize, String>
     })
 }
 
+/// Traps for other callers than `get_wishlist_by_email_v2` allows, as it can't return the error
 #[ic_cdk_macros::query]
 fn get_wishlist_by_email(email: String) -> Vec<HotelId> {
-    STATE.with(|state| {
+    get_wishlist_by_email_v2(email).unwrap_or_else(|error| ic_cdk::trap(&error.to_string()))
+}
+
+/// For `is_booking_service` and the user `email` itself
+#[ic_cdk_macros::query]
+fn get_wishlist_by_email_v2(email: String) -> BackendResult<Vec<HotelId>> {
+    require_role_or_owner(is_booking_service, &email).map_err(BackendError::Unauthorized)?;
+    Ok(STATE.with(|state| {
         state
             .borrow()
-            .wishlist
-            .get(&email)
-            .cloned()
+            .get_wishlist_by_email(&email)
             .unwrap_or_default()
-    })
+    }))
 }
 
 
 
-#[ic_cdk_macros::query(guard = "is_controller")]
+#[ic_cdk_macros::query(guard = "can_read_analytics")]
 fn get_all_bookings() -> Vec<BookingSummary> {
     STATE.with(|state| state.borrow().get_all_bookings())
 }
@@ -211,7 +362,7 @@ fn greet(GreetParams(name): GreetParams) -> GreetResponse {
     GreetResponse(resp_strng)
 }
 
-#[ic_cdk_macros::query(guard = "is_controller")]
+#[ic_cdk_macros::query(guard = "can_read_bookings")]
 fn is_booking_paid(booking_id: BookingId) -> bool {
     STATE.with(|state| {
         state
@@ -222,30 +373,64 @@ fn is_booking_paid(booking_id: BookingId) -> bool {
     })
 }
 
-#[ic_cdk_macros::update(guard = "is_controller")]
+#[ic_cdk_macros::update(guard = "is_booking_service")]
 fn update_email_sent(booking_id: BookingId, sent: bool) -> Result<(), String> {
-    STATE.with(|state| state.borrow_mut().update_email_sent(booking_id, sent))
+    update_email_sent_v2(booking_id, sent)
+        .map_err(|e| e.to_legacy_string(LegacyEndpoint::UpdateEmailSent))
+}
+
+#[ic_cdk_macros::update(guard = "is_booking_service")]
+fn update_email_sent_v2(booking_id: BookingId, sent: bool) -> BackendResult<()> {
+    audit::audited(
+        "update_email_sent",
+        AuditTarget::booking(&booking_id),
+        |state| email_sent_for(state, &booking_id),
+        |state| state.update_email_sent(booking_id.clone(), sent),
+    )
 }
 
+/// For `can_read_bookings` and the owner of the booking
 #[ic_cdk_macros::query]
 fn get_email_sent(booking_id: BookingId) -> Result<bool, String> {
+    require_role_or_owner(can_read_bookings, booking_id.get_user_email())?;
+    get_email_sent_v2(booking_id.clone())
+        .map_err(|e| e.to_legacy_string(LegacyEndpoint::GetEmailSent(&booking_id)))
+}
+
+/// For `can_read_bookings` and the owner of the booking
+#[ic_cdk_macros::query]
+fn get_email_sent_v2(booking_id: BookingId) -> BackendResult<bool> {
+    require_role_or_owner(can_read_bookings, booking_id.get_user_email())
+        .map_err(BackendError::Unauthorized)?;
     STATE.with(|state| state.borrow_mut().get_email_sent(&booking_id))
 }
 
+/// Traps for other callers than `get_booking_by_id_v2` allows, as it can't return the error
 #[ic_cdk_macros::query]
 fn get_booking_by_id(booking_id: BookingId) -> Option<Booking> {
-    print!("get_booking_by_id - {booking_id:?}");
-    STATE.with(|state| state.borrow().get_booking_by_id(&booking_id).cloned())
+    match get_booking_by_id_v2(booking_id) {
+        Ok(booking) => Some(booking),
+        Err(BackendError::Unauthorized(error)) => ic_cdk::trap(&error),
+        Err(_) => None,
+    }
 }
 
+/// For `can_read_bookings` and the owner of the booking
 #[ic_cdk_macros::query]
+fn get_booking_by_id_v2(booking_id: BookingId) -> BackendResult<Booking> {
+    require_role_or_owner(can_read_bookings, booking_id.get_user_email())
+        .map_err(BackendError::Unauthorized)?;
+    STATE.with(|state| state.borrow().get_booking_by_id(&booking_id))
+        .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))
+}
+
+#[ic_cdk_macros::query(guard = "can_read_bookings")]
 fn get_booking_id_by_payment_id_v2(payment_id_v2: String) -> Option<BookingId> {
     STATE.with(|state| {
         state
             .borrow()
             .payment_id_index
-            .as_ref()
-            .and_then(|index| index.get(&payment_id_v2).cloned())
+            .get(&payment_id_v2)
     })
 }
 
@@ -254,50 +439,136 @@ fn get_current_migration_info() -> (u64, String) {
     STATE.with(|state| state.borrow().get_current_migration_info())
 }
 
-#[ic_cdk_macros::update(guard = "is_controller")]
+#[ic_cdk_macros::update(guard = "is_admin")]
 fn run_migrations() -> Result<String, String> {
     use crate::migration::MigrationEngine;
     
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
+    audit::audited("run_migrations", AuditTarget::default(), schema_versions, |state| {
         let engine = MigrationEngine::new();
         
-        let pending_count = engine.get_pending_migrations(&state).len();
+        let pending_count = engine.get_pending_migrations(state).len();
         if pending_count == 0 {
             return Ok("No pending migrations to run".to_string());
         }
         
-        engine.apply_migrations(&mut state)
+        engine.apply_migrations(state)
             .map_err(|e| format!("Migration failed: {}", e))?;
         
         Ok(format!("Successfully applied {} migration(s)", pending_count))
     })
 }
 
-#[ic_cdk_macros::update(guard = "is_controller")]
-fn update_user_principal_email_index(principal: Principal, email: String) -> Result<String, String> {
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        
-        // Validate email format (basic validation)
-        if email.is_empty() || !email.contains('@') {
-            return Err("Invalid email format".to_string());
+/// Reverts applied migrations down to `target_version` and pins `target_version` to it,
+/// so the next upgrade doesn't re-apply them. Clear the pin with `set_target_migration_version`.
+/// A rollback that would leave no `Admin` (e.g. below 1004) needs an IC-level controller.
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn rollback_migrations(target_version: u64) -> Result<String, String> {
+    use crate::migration::MigrationEngine;
+
+    let caller_is_ic_controller = ic_cdk::api::is_controller(&ic_cdk::caller());
+    audit::audited("rollback_migrations", AuditTarget::default(), schema_versions, |state| {
+        let backup = state.to_snapshot();
+        let rolled_back = MigrationEngine::new().rollback_to_version(state, target_version)?;
+        if !caller_is_ic_controller && state.principals_with_role(Role::Admin).is_empty() {
+            state.restore_snapshot(backup);
+            return Err(
+                "Rollback would remove every controller, only an IC-level controller can do that"
+                    .to_string(),
+            );
         }
-        
-        // Update the index
-        state.user_principal_email_index.insert(principal, email.clone());
-        
-        Ok(format!("Successfully mapped principal {} to email {}", principal, email))
+        state.schema_metadata.target_version = Some(target_version);
+
+        Ok(format!(
+            "Rolled back {} migration(s) {:?} to version {}",
+            rolled_back.len(),
+            rolled_back,
+            target_version
+        ))
+    })
+}
+
+#[ic_cdk_macros::query]
+fn get_migration_history() -> Vec<SchemaVersion> {
+    STATE.with(|state| state.borrow().schema_metadata.applied_migrations.clone())
+}
+
+/// Dry run of `run_migrations`, on a heap copy of the state
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn preview_migrations() -> Vec<MigrationPreview> {
+    use crate::migration::MigrationEngine;
+
+    STATE.with(|state| MigrationEngine::new().preview_migrations(&state.borrow()))
+}
+
+/// Stops `run_migrations` (and post_upgrade) at `target_version`; `None` removes the limit
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn set_target_migration_version(target_version: Option<u64>) -> Result<String, String> {
+    audit::audited("set_target_migration_version", AuditTarget::default(), schema_versions, |state| {
+        if let Some(target) = target_version {
+            if target < state.schema_metadata.current_version {
+                return Err(format!(
+                    "Target version {} is below the current version {}",
+                    target, state.schema_metadata.current_version
+                ));
+            }
+        }
+        state.schema_metadata.target_version = target_version;
+        Ok(format!("Target migration version set to {:?}", target_version))
     })
 }
 
+#[ic_cdk_macros::update(guard = "is_booking_service")]
+fn update_user_principal_email_index(principal: Principal, email: String) -> Result<String, String> {
+    update_user_principal_email_index_v2(principal, email)
+        .map_err(|e| e.to_legacy_string(LegacyEndpoint::UpdateUserPrincipalEmailIndex))
+}
+
+#[ic_cdk_macros::update(guard = "is_booking_service")]
+fn update_user_principal_email_index_v2(principal: Principal, email: String) -> BackendResult<String> {
+    audit::audited(
+        "update_user_principal_email_index",
+        AuditTarget::principal(principal),
+        |state| state.user_principal_email_index.get(&principal),
+        |state| {
+            // Validate email format (basic validation)
+            if email.is_empty() || !email.contains('@') {
+                return Err(BackendError::validation("email", "invalid email format"));
+            }
+
+            // Update the index
+            state.user_principal_email_index.insert(principal, email.clone());
+
+            Ok(format!("Successfully mapped principal {} to email {}", principal, email))
+        },
+    )
+}
+
+/// For end users: the email is taken from the caller's principal, never from the request
+#[ic_cdk_macros::update]
+fn create_my_booking(booking: Booking) -> BackendResult<String> {
+    let caller = ic_cdk::caller();
+    let booking_id = booking.booking_id.clone();
+    audit::audited(
+        "create_my_booking",
+        AuditTarget::booking(&booking_id),
+        |state| state.get_booking_by_id(&booking_id),
+        |state| state.add_booking_for_caller(caller, booking),
+    )
+}
+
+#[ic_cdk_macros::query]
+fn get_my_booking(booking_id: BookingId) -> BackendResult<Booking> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| state.borrow().get_booking_for_caller(caller, &booking_id))
+}
+
 #[ic_cdk_macros::query]
 fn my_bookings() -> Vec<Booking> {
    let user = ic_cdk::caller();
    STATE.with(|state| {
        state.borrow()
            .get_user_bookings_by_principal(user)
-           .map(|bookings| bookings.values().cloned().collect())
+           .map(|bookings| bookings.into_values().collect())
            .unwrap_or_default()
    })
 }
@@ -1,4 +1,3 @@
 pub mod pre_upgrade;
 pub mod post_upgrade;
-
- 
\ No newline at end of file
+pub mod snapshot;
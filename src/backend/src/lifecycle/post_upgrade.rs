@@ -1,7 +1,6 @@
-use ciborium::de;
 use ic_cdk_macros::post_upgrade;
-use ic_stable_structures::reader::Reader;
 
+use crate::lifecycle::snapshot;
 use crate::memory;
 use crate::migration::MigrationEngine;
 
@@ -16,18 +15,13 @@ fn post_upgrade() {
 }
 
 fn restore_data_from_stable_memory() {
-    let heap_data = memory::get_upgrades_memory();
-    let mut upgrade_reader = Reader::new(&heap_data, 0);
+    let upgrade_memory = memory::get_upgrades_memory();
 
-    let mut heap_data_len_bytes = [0; 4];
-    upgrade_reader.read(&mut heap_data_len_bytes).unwrap();
-    let heap_data_len = u32::from_le_bytes(heap_data_len_bytes) as usize;
-
-    let mut canister_data_bytes = vec![0; heap_data_len];
-    upgrade_reader.read(&mut canister_data_bytes).unwrap();
-
-    let canister_data =
-        de::from_reader(&*canister_data_bytes).expect("Failed to deserialize heap data");
+    // traps (and so rolls back the upgrade) if the snapshot is truncated or corrupted
+    let mut canister_data = snapshot::load_state(&upgrade_memory).unwrap_or_else(|e| {
+        ic_cdk::trap(&format!("Failed to restore state from stable memory: {}", e))
+    });
+    canister_data.open_maps();
 
     CANISTER_DATA.with_borrow_mut(|cdata| {
         *cdata = canister_data;
@@ -38,7 +32,13 @@ fn run_migrations() {
     let migration_engine = MigrationEngine::new();
     
     CANISTER_DATA.with_borrow_mut(|state| {
-        match migration_engine.apply_migrations(state) {
+        // reported through get_current_migration_info, doesn't stop the upgrade
+        for mismatch in migration_engine.check_fingerprints(state) {
+            ic_cdk::println!("Migration fingerprint mismatch: {}", mismatch);
+        }
+
+        // no backup needed: the trap below rolls back the whole upgrade
+        match migration_engine.apply_migrations_without_backup(state) {
             Ok(()) => {
                 ic_cdk::println!("Migrations applied successfully");
             }
@@ -0,0 +1,264 @@
+//! Layout of the state snapshot kept in the upgrades memory.
+//!
+//! | offset | size | field                                |
+//! |--------|------|--------------------------------------|
+//! | 0      | 4    | magic bytes `ESTB`                   |
+//! | 4      | 2    | format version (u16, little-endian)  |
+//! | 6      | 1    | encoding id (1 = CBOR)               |
+//! | 7      | 1    | reserved, always 0                   |
+//! | 8      | 8    | payload length (u64, little-endian)  |
+//! | 16     | 32   | SHA-256 of the payload               |
+//! | 48     | ..   | payload                              |
+//!
+//! Builds before this format wrote `[u32 little-endian length][CBOR]`. `load_state` still
+//! reads that layout so those canisters can upgrade into this one.
+//!
+//! State exports (`encode_to_bytes` / `decode_from_bytes`) use the same header.
+use ciborium::{de, ser};
+use ic_stable_structures::reader::{BufferedReader, Reader};
+use ic_stable_structures::writer::{BufferedWriter, Writer};
+use ic_stable_structures::Memory;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+
+use crate::CanisterState;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// Payload is streamed to/from stable memory in chunks of this size
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"ESTB";
+pub const SNAPSHOT_FORMAT_VERSION: u16 = 1;
+pub const ENCODING_CBOR: u8 = 1;
+pub const HEADER_LEN: usize = 48;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotHeader {
+    pub format_version: u16,
+    pub encoding: u8,
+    pub payload_len: u64,
+    pub checksum: [u8; 32],
+}
+
+impl SnapshotHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&SNAPSHOT_MAGIC);
+        bytes[4..6].copy_from_slice(&self.format_version.to_le_bytes());
+        bytes[6] = self.encoding;
+        bytes[8..16].copy_from_slice(&self.payload_len.to_le_bytes());
+        bytes[16..48].copy_from_slice(&self.checksum);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; HEADER_LEN]) -> Result<Self, String> {
+        if bytes[0..4] != SNAPSHOT_MAGIC {
+            return Err("Snapshot header has invalid magic bytes".to_string());
+        }
+
+        let format_version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if format_version != SNAPSHOT_FORMAT_VERSION {
+            return Err(format!(
+                "Unsupported snapshot format version {} (expected {})",
+                format_version, SNAPSHOT_FORMAT_VERSION
+            ));
+        }
+
+        let encoding = bytes[6];
+        if encoding != ENCODING_CBOR {
+            return Err(format!("Unsupported snapshot encoding id {}", encoding));
+        }
+
+        let mut payload_len = [0u8; 8];
+        payload_len.copy_from_slice(&bytes[8..16]);
+        let mut checksum = [0u8; 32];
+        checksum.copy_from_slice(&bytes[16..48]);
+
+        Ok(Self {
+            format_version,
+            encoding,
+            payload_len: u64::from_le_bytes(payload_len),
+            checksum,
+        })
+    }
+}
+
+/// Passes writes through while hashing and counting them
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            written: 0,
+        }
+    }
+
+    fn finish(mut self) -> std::io::Result<(u64, [u8; 32])> {
+        self.inner.flush()?;
+        Ok((self.written, self.hasher.finalize().into()))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Serializes `state` into `memory`: the payload is streamed first, the header is
+/// written last, once the length and checksum are known.
+pub fn save_state<M: Memory>(memory: &mut M, state: &CanisterState) -> Result<(), String> {
+    let mut writer = HashingWriter::new(BufferedWriter::new(
+        CHUNK_SIZE,
+        Writer::new(memory, HEADER_LEN as u64),
+    ));
+    ser::into_writer(state, &mut writer).map_err(|e| format!("Failed to encode state: {}", e))?;
+    let (payload_len, checksum) = writer
+        .finish()
+        .map_err(|e| format!("Failed to write state to stable memory: {}", e))?;
+
+    let header = SnapshotHeader {
+        format_version: SNAPSHOT_FORMAT_VERSION,
+        encoding: ENCODING_CBOR,
+        payload_len,
+        checksum,
+    };
+    Writer::new(memory, 0)
+        .write(&header.to_bytes())
+        .map_err(|_| "Failed to write snapshot header to stable memory".to_string())
+}
+
+/// Reads back a state written by `save_state`, or by the legacy length-prefixed layout
+pub fn load_state<M: Memory>(memory: &M) -> Result<CanisterState, String> {
+    let memory_len = memory.size() * WASM_PAGE_SIZE;
+    if memory_len < 4 {
+        return Err("Upgrade memory is empty".to_string());
+    }
+
+    let mut magic = [0u8; 4];
+    memory.read(0, &mut magic);
+    if magic != SNAPSHOT_MAGIC {
+        return load_legacy_state(memory, memory_len, u32::from_le_bytes(magic));
+    }
+
+    if memory_len < HEADER_LEN as u64 {
+        return Err("Upgrade memory is too small to hold a snapshot header".to_string());
+    }
+    let mut header_bytes = [0u8; HEADER_LEN];
+    memory.read(0, &mut header_bytes);
+    let header = SnapshotHeader::from_bytes(&header_bytes)?;
+
+    let payload_end = (HEADER_LEN as u64)
+        .checked_add(header.payload_len)
+        .ok_or_else(|| "Snapshot payload length overflows".to_string())?;
+    if payload_end > memory_len {
+        return Err(format!(
+            "Snapshot is truncated: header declares {} payload bytes but only {} are available",
+            header.payload_len,
+            memory_len - HEADER_LEN as u64
+        ));
+    }
+
+    let checksum = payload_checksum(memory, HEADER_LEN as u64, header.payload_len);
+    if checksum != header.checksum {
+        return Err("Snapshot checksum mismatch, upgrade memory is corrupted".to_string());
+    }
+
+    decode_payload(memory, HEADER_LEN as u64, header.payload_len)
+}
+
+/// Encodes `value` as header + CBOR payload into a byte buffer
+pub fn encode_to_bytes<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![0u8; HEADER_LEN];
+    let mut writer = HashingWriter::new(&mut bytes);
+    ser::into_writer(value, &mut writer).map_err(|e| format!("Failed to encode state: {}", e))?;
+    let (payload_len, checksum) = writer
+        .finish()
+        .map_err(|e| format!("Failed to encode state: {}", e))?;
+
+    let header = SnapshotHeader {
+        format_version: SNAPSHOT_FORMAT_VERSION,
+        encoding: ENCODING_CBOR,
+        payload_len,
+        checksum,
+    };
+    bytes[..HEADER_LEN].copy_from_slice(&header.to_bytes());
+    Ok(bytes)
+}
+
+/// Inverse of `encode_to_bytes`, verifying the length and checksum first
+pub fn decode_from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    let header_bytes: &[u8; HEADER_LEN] = bytes
+        .get(..HEADER_LEN)
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| "Snapshot is too small to hold a header".to_string())?;
+    let header = SnapshotHeader::from_bytes(header_bytes)?;
+
+    let payload = &bytes[HEADER_LEN..];
+    if payload.len() as u64 != header.payload_len {
+        return Err(format!(
+            "Snapshot length mismatch: header declares {} payload bytes but got {}",
+            header.payload_len,
+            payload.len()
+        ));
+    }
+
+    let checksum: [u8; 32] = Sha256::digest(payload).into();
+    if checksum != header.checksum {
+        return Err("Snapshot checksum mismatch".to_string());
+    }
+
+    de::from_reader(payload).map_err(|e| format!("Failed to decode state: {}", e))
+}
+
+fn load_legacy_state<M: Memory>(
+    memory: &M,
+    memory_len: u64,
+    payload_len: u32,
+) -> Result<CanisterState, String> {
+    let payload_len = payload_len as u64;
+    if 4 + payload_len > memory_len {
+        return Err(format!(
+            "Upgrade memory has neither a snapshot header nor a valid legacy length ({} bytes)",
+            payload_len
+        ));
+    }
+
+    decode_payload(memory, 4, payload_len)
+}
+
+fn payload_checksum<M: Memory>(memory: &M, offset: u64, len: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut read = 0u64;
+    while read < len {
+        let n = (len - read).min(CHUNK_SIZE as u64) as usize;
+        memory.read(offset + read, &mut buf[..n]);
+        hasher.update(&buf[..n]);
+        read += n as u64;
+    }
+    hasher.finalize().into()
+}
+
+fn decode_payload<M: Memory>(memory: &M, offset: u64, len: u64) -> Result<CanisterState, String> {
+    let reader = BufferedReader::new(CHUNK_SIZE, Reader::new(memory, offset)).take(len);
+    de::from_reader(reader).map_err(|e| format!("Failed to decode state: {}", e))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,124 @@
+#[cfg(test)]
+mod snapshot_tests {
+    use crate::lifecycle::snapshot::{load_state, save_state, SnapshotHeader, HEADER_LEN};
+    use crate::models::*;
+    use candid::Principal;
+    use ic_stable_structures::{Memory, VectorMemory};
+    use ic_stable_structures::writer::Writer;
+
+    fn create_test_state() -> CanisterState {
+        let mut state = CanisterState::new();
+        state.controllers = Some(vec![Principal::from_text("be2us-64aaa-aaaaa-qaabq-cai").unwrap()]);
+        state.schema_metadata.current_version = 1003;
+        state
+    }
+
+    fn read_header(memory: &VectorMemory) -> SnapshotHeader {
+        let mut bytes = [0u8; HEADER_LEN];
+        memory.read(0, &mut bytes);
+        SnapshotHeader::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut memory = VectorMemory::default();
+        let state = create_test_state();
+
+        save_state(&mut memory, &state).unwrap();
+        let restored = load_state(&memory).unwrap();
+
+        assert_eq!(restored.schema_metadata.current_version, 1003);
+        assert_eq!(restored.controllers, state.controllers);
+    }
+
+    #[test]
+    fn test_snapshot_header_fields() {
+        let mut memory = VectorMemory::default();
+        save_state(&mut memory, &create_test_state()).unwrap();
+
+        let header = read_header(&memory);
+        assert_eq!(header.format_version, 1);
+        assert_eq!(header.encoding, 1);
+        assert!(header.payload_len > 0);
+    }
+
+    #[test]
+    fn test_snapshot_detects_corrupted_payload() {
+        let mut memory = VectorMemory::default();
+        save_state(&mut memory, &create_test_state()).unwrap();
+
+        // flip a byte inside the payload
+        let mut byte = [0u8; 1];
+        memory.read(HEADER_LEN as u64 + 2, &mut byte);
+        memory.write(HEADER_LEN as u64 + 2, &[byte[0] ^ 0xff]);
+
+        let result = load_state(&memory);
+        assert!(result.is_err());
+        assert!(result.err().unwrap().contains("checksum mismatch"));
+    }
+
+    #[test]
+    fn test_snapshot_detects_truncated_payload() {
+        let mut memory = VectorMemory::default();
+        save_state(&mut memory, &create_test_state()).unwrap();
+
+        // declare more payload than the memory holds
+        let mut header = read_header(&memory);
+        header.payload_len = memory.size() * 65536;
+        memory.write(0, &header.to_bytes());
+
+        let result = load_state(&memory);
+        assert!(result.is_err());
+        assert!(result.err().unwrap().contains("truncated"));
+    }
+
+    #[test]
+    fn test_snapshot_rejects_unknown_format_version() {
+        let mut memory = VectorMemory::default();
+        save_state(&mut memory, &create_test_state()).unwrap();
+
+        memory.write(4, &99u16.to_le_bytes());
+
+        let result = load_state(&memory);
+        assert!(result.is_err());
+        assert!(result.err().unwrap().contains("Unsupported snapshot format version 99"));
+    }
+
+    #[test]
+    fn test_snapshot_reads_legacy_layout() {
+        let mut memory = VectorMemory::default();
+        let state = create_test_state();
+
+        // what pre_upgrade used to write: [u32 LE length][CBOR]
+        let mut state_bytes = vec![];
+        ciborium::ser::into_writer(&state, &mut state_bytes).unwrap();
+        let mut writer = Writer::new(&mut memory, 0);
+        writer.write(&(state_bytes.len() as u32).to_le_bytes()).unwrap();
+        writer.write(&state_bytes).unwrap();
+
+        let restored = load_state(&memory).unwrap();
+        assert_eq!(restored.schema_metadata.current_version, 1003);
+        assert_eq!(restored.controllers, state.controllers);
+    }
+
+    #[test]
+    fn test_snapshot_rejects_empty_memory() {
+        let memory = VectorMemory::default();
+
+        let result = load_state(&memory);
+        assert!(result.is_err());
+        assert!(result.err().unwrap().contains("empty"));
+    }
+
+    #[test]
+    fn test_snapshot_rejects_invalid_legacy_length() {
+        let mut memory = VectorMemory::default();
+        Writer::new(&mut memory, 0)
+            .write(&u32::MAX.to_le_bytes())
+            .unwrap();
+
+        let result = load_state(&memory);
+        assert!(result.is_err());
+        assert!(result.err().unwrap().contains("valid legacy length"));
+    }
+}
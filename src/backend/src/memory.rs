@@ -1,17 +1,234 @@
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-    DefaultMemoryImpl,
+    storable::{Bound, Storable},
+    DefaultMemoryImpl, StableBTreeMap, VectorMemory,
 };
+use serde::{de::DeserializeOwned, Serialize};
+use std::borrow::Cow;
 use std::cell::RefCell;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
 
 // A memory for upgrades, where data from the heap can be serialized/deserialized.
 const UPGRADES: MemoryId = MemoryId::new(0);
 
 // A memory for the StableBTreeMap we're using. A new memory should be created for
 // every additional stable structure.
+const USERS: MemoryId = MemoryId::new(1);
+const BOOKINGS: MemoryId = MemoryId::new(2);
+const WISHLIST: MemoryId = MemoryId::new(3);
+const PAYMENT_ID_INDEX: MemoryId = MemoryId::new(4);
+const USER_PRINCIPAL_EMAIL_INDEX: MemoryId = MemoryId::new(5);
+const AUDIT_LOG: MemoryId = MemoryId::new(6);
+const BOOKING_TIMELINES: MemoryId = MemoryId::new(7);
+const BOOKING_REVISIONS: MemoryId = MemoryId::new(8);
+const PAYMENT_EVENTS: MemoryId = MemoryId::new(9);
+
+// A second memory for each map above except the audit log. An import restores the maps into
+// whichever set is not in use and then switches over, see `CanisterState::map_bank`.
+const USERS_SECONDARY: MemoryId = MemoryId::new(10);
+const BOOKINGS_SECONDARY: MemoryId = MemoryId::new(11);
+const WISHLIST_SECONDARY: MemoryId = MemoryId::new(12);
+const PAYMENT_ID_INDEX_SECONDARY: MemoryId = MemoryId::new(13);
+const USER_PRINCIPAL_EMAIL_INDEX_SECONDARY: MemoryId = MemoryId::new(14);
+const BOOKING_TIMELINES_SECONDARY: MemoryId = MemoryId::new(15);
+const BOOKING_REVISIONS_SECONDARY: MemoryId = MemoryId::new(16);
+const PAYMENT_EVENTS_SECONDARY: MemoryId = MemoryId::new(17);
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+/// Memory of a `StableMap`: one of the canister's virtual memories, or a heap buffer for
+/// scratch copies of the state that must never reach stable memory.
+/// `ReadOnly` lets a scratch copy read a virtual memory in place; writing to it traps.
+#[derive(Clone)]
+pub enum MapMemory {
+    Stable(Memory),
+    Heap(VectorMemory),
+    ReadOnly(Memory),
+}
+
+impl MapMemory {
+    pub fn scratch() -> Self {
+        MapMemory::Heap(VectorMemory::default())
+    }
+
+    /// Read-only view of this memory. Empty memories (and heap buffers) become a new scratch
+    /// buffer instead, since initialising a map on them writes its header.
+    pub fn read_only(self) -> Self {
+        match self {
+            MapMemory::Stable(memory) | MapMemory::ReadOnly(memory) if ic_stable_structures::Memory::size(&memory) > 0 => {
+                MapMemory::ReadOnly(memory)
+            }
+            _ => MapMemory::scratch(),
+        }
+    }
+}
+
+impl ic_stable_structures::Memory for MapMemory {
+    fn size(&self) -> u64 {
+        match self {
+            MapMemory::Stable(memory) => memory.size(),
+            MapMemory::Heap(memory) => memory.size(),
+            MapMemory::ReadOnly(memory) => memory.size(),
+        }
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        match self {
+            MapMemory::Stable(memory) => memory.grow(pages),
+            MapMemory::Heap(memory) => memory.grow(pages),
+            MapMemory::ReadOnly(_) => panic!("cannot grow a read-only memory"),
+        }
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        match self {
+            MapMemory::Stable(memory) => memory.read(offset, dst),
+            MapMemory::Heap(memory) => memory.read(offset, dst),
+            MapMemory::ReadOnly(memory) => memory.read(offset, dst),
+        }
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        match self {
+            MapMemory::Stable(memory) => memory.write(offset, src),
+            MapMemory::Heap(memory) => memory.write(offset, src),
+            MapMemory::ReadOnly(_) => panic!("cannot write to a read-only memory"),
+        }
+    }
+}
+
+/// A value of a `StableMap` as stored, decoded by the map rather than by `StableBTreeMap`
+/// so that it knows which entry it is decoding. Same bytes and bound as `V`, so the layout
+/// in stable memory is the same as for a `StableBTreeMap<K, V>`.
+pub struct Encoded<V> {
+    bytes: Vec<u8>,
+    value: PhantomData<V>,
+}
+
+impl<V: Storable> Storable for Encoded<V> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            bytes: bytes.into_owned(),
+            value: PhantomData,
+        }
+    }
+
+    const BOUND: Bound = V::BOUND;
+}
+
+/// Key of a `StableMap`
+pub trait MapKey: Storable + Ord + Clone + Debug {}
+
+impl<K: Storable + Ord + Clone + Debug> MapKey for K {}
+
+/// Value of a `StableMap`
+pub trait MapValue: Storable + Sized {
+    /// `Storable::from_bytes` that fails instead of trapping, so the map can name the entry
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, String>;
+}
+
+impl MapValue for String {
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+    }
+}
+
+/// `StableBTreeMap` with a name. A value that fails to decode traps naming the map and its
+/// key; a key that fails to decode only names its type, see `from_cbor_bytes`.
+pub struct StableMap<K, V>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    name: &'static str,
+    map: StableBTreeMap<K, Encoded<V>, MapMemory>,
+}
+
+impl<K, V> StableMap<K, V>
+where
+    K: MapKey,
+    V: MapValue,
+{
+    pub fn init(name: &'static str, memory: MapMemory) -> Self {
+        Self {
+            name,
+            map: StableBTreeMap::init(memory),
+        }
+    }
+
+    fn decode(&self, key: &K, encoded: Encoded<V>) -> V {
+        V::try_from_bytes(&encoded.bytes).unwrap_or_else(|error| {
+            panic!("failed to decode {} entry {:?}: {}", self.name, key, error)
+        })
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.map.get(key).map(|encoded| self.decode(key, encoded))
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let encoded = Encoded::from_bytes(value.to_bytes());
+        let previous = self.map.insert(key.clone(), encoded);
+        previous.map(|encoded| self.decode(&key, encoded))
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.map.remove(key).map(|encoded| self.decode(key, encoded))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    pub fn clear_new(&mut self) {
+        self.map.clear_new()
+    }
+
+    pub fn into_memory(self) -> MapMemory {
+        self.map.into_memory()
+    }
+
+    pub fn last_key_value(&self) -> Option<(K, V)> {
+        self.map
+            .last_key_value()
+            .map(|(key, encoded)| (key.clone(), self.decode(&key, encoded)))
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (K, V)> + '_ {
+        self.map
+            .iter()
+            .map(|(key, encoded)| (key.clone(), self.decode(&key, encoded)))
+    }
+
+    pub fn range(&self, range: impl RangeBounds<K>) -> impl DoubleEndedIterator<Item = (K, V)> + '_ {
+        self.map
+            .range(range)
+            .map(|(key, encoded)| (key.clone(), self.decode(&key, encoded)))
+    }
+
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = K> + '_ {
+        self.map.keys()
+    }
+
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = V> + '_ {
+        self.iter().map(|(_, value)| value)
+    }
+}
+
 thread_local! {
     // The memory manager is used for simulating multiple memories. Given a `MemoryId` it can
     // return a memory that can be used by stable structures.
@@ -23,8 +240,117 @@ pub fn get_upgrades_memory() -> Memory {
     MEMORY_MANAGER.with(|m| m.borrow_mut().get(UPGRADES))
 }
 
+pub fn get_users_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(USERS)))
+}
+
+pub fn get_bookings_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(BOOKINGS)))
+}
+
+pub fn get_wishlist_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(WISHLIST)))
+}
+
+pub fn get_payment_id_index_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(PAYMENT_ID_INDEX)))
+}
+
+pub fn get_user_principal_email_index_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(USER_PRINCIPAL_EMAIL_INDEX)))
+}
+
+pub fn get_booking_timelines_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(BOOKING_TIMELINES)))
+}
+
+pub fn get_booking_revisions_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(BOOKING_REVISIONS)))
+}
+
+pub fn get_payment_events_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(PAYMENT_EVENTS)))
+}
+
+pub fn get_users_secondary_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(USERS_SECONDARY)))
+}
+
+pub fn get_bookings_secondary_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(BOOKINGS_SECONDARY)))
+}
+
+pub fn get_wishlist_secondary_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(WISHLIST_SECONDARY)))
+}
+
+pub fn get_payment_id_index_secondary_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(PAYMENT_ID_INDEX_SECONDARY)))
+}
+
+pub fn get_user_principal_email_index_secondary_memory() -> MapMemory {
+    MapMemory::Stable(
+        MEMORY_MANAGER.with(|m| m.borrow_mut().get(USER_PRINCIPAL_EMAIL_INDEX_SECONDARY)),
+    )
+}
+
+pub fn get_booking_timelines_secondary_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(BOOKING_TIMELINES_SECONDARY)))
+}
+
+pub fn get_booking_revisions_secondary_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(BOOKING_REVISIONS_SECONDARY)))
+}
+
+pub fn get_payment_events_secondary_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(PAYMENT_EVENTS_SECONDARY)))
+}
+
+pub fn get_audit_log_memory() -> MapMemory {
+    MapMemory::Stable(MEMORY_MANAGER.with(|m| m.borrow_mut().get(AUDIT_LOG)))
+}
+
 pub fn init_memory_manager() {
     MEMORY_MANAGER.with(|m| {
         *m.borrow_mut() = MemoryManager::init_with_bucket_size(DefaultMemoryImpl::default(), 1);
     })
-}
\ No newline at end of file
+}
+
+/// Encodes a value stored in a stable map.
+/// CBOR (same as the upgrade snapshot) so `#[serde(default)]` keeps working for new fields.
+pub fn to_cbor_bytes<T: Serialize>(value: &T) -> Cow<'static, [u8]> {
+    let mut bytes = vec![];
+    ciborium::ser::into_writer(value, &mut bytes).expect("failed to encode stable map entry");
+    Cow::Owned(bytes)
+}
+
+pub fn from_cbor_bytes<T: DeserializeOwned>(bytes: Cow<[u8]>) -> T {
+    try_from_cbor_bytes(&bytes).unwrap_or_else(|error| {
+        panic!("failed to decode {}: {}", std::any::type_name::<T>(), error)
+    })
+}
+
+pub fn try_from_cbor_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    ciborium::de::from_reader(bytes).map_err(|e| e.to_string())
+}
+
+/// Copies every entry of `map` into a heap `BTreeMap`.
+pub fn stable_map_to_btree<K, V>(map: &StableMap<K, V>) -> std::collections::BTreeMap<K, V>
+where
+    K: MapKey,
+    V: MapValue,
+{
+    map.iter().collect()
+}
+
+/// Replaces the contents of `map` with `entries`
+pub fn replace_stable_map<K, V>(map: &mut StableMap<K, V>, entries: std::collections::BTreeMap<K, V>)
+where
+    K: MapKey,
+    V: MapValue,
+{
+    map.clear_new();
+    for (key, value) in entries {
+        map.insert(key, value);
+    }
+}
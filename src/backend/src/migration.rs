@@ -1,7 +1,16 @@
-use crate::{migrations::{AddDefaultControllersMigration, AddPaymentIdV2Migration}, CanisterState};
+use crate::{
+    migrations::{
+        AddDefaultControllersMigration, AddPaymentIdV2Migration, ControllersToRolesMigration,
+        MoneyMigration, MoveToStableStorageMigration, PaymentStatusMigration,
+    },
+    CanisterState, StateMap, UserInfoAndBookings,
+};
 use candid::CandidType;
 use ic_cdk::api::time;
+use ic_stable_structures::Storable;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 
 /// Get current timestamp, returns 0 in test environment for deterministic testing
 fn get_timestamp() -> u64 {
@@ -16,6 +25,20 @@ pub struct SchemaVersion {
     pub applied_at: u64,
     /// Human-readable description of the migration
     pub description: String,
+    /// Set when the migration was reverted by `rollback_to_version`; the entry is kept so
+    /// the history shows it. Applying the migration again adds a new entry.
+    #[serde(default)]
+    pub rolled_back_at: Option<u64>,
+    /// `migration_fingerprint` of the migration when it was applied. Empty for entries
+    /// recorded before fingerprints existed, until `check_fingerprints` fills them in.
+    #[serde(default)]
+    pub fingerprint: String,
+}
+
+/// Hex SHA-256 of version and description, used to notice a migration that was edited
+/// after it had already been applied
+pub fn migration_fingerprint(version: u64, description: &str) -> String {
+    hex::encode(Sha256::digest(format!("{}:{}", version, description)))
 }
 
 /// Schema metadata for tracking migration state and history
@@ -29,15 +52,18 @@ pub struct SchemaVersion {
 /// `current_version` determines which migrations to apply:
 /// - If canister is at version 1000, migrations 1001+ will be applied
 /// - If canister is at version 1002, only migrations 1003+ will be applied
-/// - Rollbacks decrease `current_version` and remove migration history
+/// - Rollbacks decrease `current_version` and mark the reverted entries as rolled back
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct SchemaMetadata {
     /// Current schema version of the canister
     pub current_version: u64,
-    /// History of all successfully applied migrations
+    /// History of all successfully applied migrations, including rolled back ones
     pub applied_migrations: Vec<SchemaVersion>,
-    /// Optional target version for future migrations
+    /// Optional target version: when set, migrations above it are not applied
     pub target_version: Option<u64>,
+    /// Applied migrations whose fingerprint no longer matches this build, found in post_upgrade
+    #[serde(default)]
+    pub fingerprint_mismatches: Vec<String>,
 }
 
 impl Default for SchemaMetadata {
@@ -46,6 +72,7 @@ impl Default for SchemaMetadata {
             current_version: 1000,
             applied_migrations: Vec::new(),
             target_version: None,
+            fingerprint_mismatches: Vec::new(),
         }
     }
 }
@@ -56,70 +83,236 @@ pub trait Migration: Send + Sync {
     fn migrate_up(&self, state: &mut CanisterState) -> Result<(), String>;
     fn migrate_down(&self, state: &mut CanisterState) -> Result<(), String>;
     fn validate(&self, state: &CanisterState) -> Result<(), String>;
+    /// Whether `migrate_down` actually restores the previous schema.
+    /// Migrations that lose data on the way up must return false to block rollbacks.
+    fn is_reversible(&self) -> bool {
+        true
+    }
+    /// Stable maps that `migrate_up` or `migrate_down` may change. Only these (and the heap
+    /// fields) are backed up while the migration runs, so a map missing here isn't restored
+    /// when the batch fails. Defaults to every map.
+    fn changed_maps(&self) -> &[StateMap] {
+        &StateMap::ALL
+    }
+}
+
+/// Stable maps changed by any of `migrations`
+fn changed_maps(migrations: &[&dyn Migration]) -> Vec<StateMap> {
+    let mut maps: Vec<StateMap> = migrations
+        .iter()
+        .flat_map(|migration| migration.changed_maps().iter().copied())
+        .collect();
+    maps.sort();
+    maps.dedup();
+    maps
 }
 
 /// Migration engine for managing schema evolution in the canister
 /// 
 /// ## ✅ Implemented Features
-/// - **Auto-Discovery**: Automatically registers and sorts migrations by version
+/// - **Registry Checks**: Duplicate or out-of-order versions are rejected at construction,
+///   gaps between versions (a missing `a{version}_` file) are logged as warnings
+/// - **Fingerprints**: Each history entry stores `migration_fingerprint()`; post_upgrade
+///   reports applied migrations that were edited since via `check_fingerprints()`
 /// - **Sequential Application**: Applies unapplied migrations in version order
 /// - **Rollback Support**: Can rollback to previous versions via `rollback_to_version()`
+/// - **Rollback Safeguards**: Rollbacks only start if every migration in range is reversible,
+///   and leave the state untouched if any `migrate_down` fails
 /// - **Validation**: Validates each migration after application
+/// - **Atomic Batches**: A failing migration restores the state from before the batch,
+///   from a backup of the heap fields and of the stable maps the batch's migrations change
 /// - **History Tracking**: Records all applied migrations with timestamps and descriptions
 /// - **Pending Detection**: Identifies unapplied migrations via `get_pending_migrations()`
-/// 
-/// ## ❌ Not Yet Implemented
-/// - **Target Version Control**: `target_version` field exists but not respected in `apply_migrations()`
-/// - **Dry Run Mode**: No preview capability before applying migrations
-/// - **Rollback Safeguards**: No validation of rollback safety before execution
+/// - **Target Version Control**: Migrations above `SchemaMetadata::target_version` are skipped
+/// - **Dry Run Mode**: `preview_migrations()` reports what pending migrations would do
 pub struct MigrationEngine {
     migrations: Vec<Box<dyn Migration>>,
 }
 
 impl MigrationEngine {
     pub fn new() -> Self {
-        Self {
-            migrations: vec![
-                Box::new(AddPaymentIdV2Migration),
-                Box::new(AddDefaultControllersMigration),
-            ],
+        let engine = Self::from_migrations(vec![
+            Box::new(AddPaymentIdV2Migration),
+            Box::new(AddDefaultControllersMigration),
+            Box::new(MoveToStableStorageMigration),
+            Box::new(ControllersToRolesMigration),
+            Box::new(PaymentStatusMigration),
+            Box::new(MoneyMigration),
+        ])
+        .unwrap_or_else(|e| panic!("Invalid migration registry: {}", e));
+
+        for warning in engine.registry_warnings() {
+            ic_cdk::println!("Migration registry warning: {}", warning);
         }
+        engine
     }
 
-    pub fn add_migration(&mut self, migration: Box<dyn Migration>) {
+    /// Migrations must be listed in strictly increasing version order, each above the
+    /// base version 1000
+    pub fn from_migrations(migrations: Vec<Box<dyn Migration>>) -> Result<Self, String> {
+        let mut previous = SchemaMetadata::default().current_version;
+        for migration in migrations.iter() {
+            let version = migration.version();
+            if version == previous {
+                return Err(format!("Duplicate migration version {}", version));
+            }
+            if version < previous {
+                return Err(format!(
+                    "Migration {} is registered after {}, versions must be in increasing order",
+                    version, previous
+                ));
+            }
+            previous = version;
+        }
+        Ok(Self { migrations })
+    }
+
+    /// Only accepts versions above every registered one, so the registry stays ordered
+    #[allow(dead_code)]
+    pub fn add_migration(&mut self, migration: Box<dyn Migration>) -> Result<(), String> {
+        let latest = self
+            .migrations
+            .last()
+            .map(|m| m.version())
+            .unwrap_or(SchemaMetadata::default().current_version);
+        let version = migration.version();
+        if version <= latest {
+            return Err(format!(
+                "Migration {} must be above the latest registered version {}",
+                version, latest
+            ));
+        }
         self.migrations.push(migration);
-        // Sort migrations by version to ensure correct order
-        self.migrations.sort_by_key(|a| a.version());
+        Ok(())
     }
 
-    pub fn apply_migrations(&self, state: &mut CanisterState) -> Result<(), String> {
-        let current_version = state.schema_metadata.current_version;
+    /// Missing versions between registered migrations; they are allowed but usually mean
+    /// an `a{version}_` file was forgotten
+    pub fn registry_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let mut previous = SchemaMetadata::default().current_version;
+        for migration in self.migrations.iter() {
+            let version = migration.version();
+            if version > previous + 1 {
+                warnings.push(format!(
+                    "Migration versions jump from {} to {}, no a{}_*.rs registered",
+                    previous,
+                    version,
+                    previous + 1
+                ));
+            }
+            previous = version;
+        }
+        warnings
+    }
 
-        let mut unapplied: Vec<_> = self
-            .migrations
-            .iter()
-            .filter(|m| m.version() > current_version)
-            .collect();
+    /// Compares the fingerprint of every applied migration with this build's and stores
+    /// the differences in `SchemaMetadata::fingerprint_mismatches`. Entries without a
+    /// fingerprint can't be checked and are given the current one.
+    pub fn check_fingerprints(&self, state: &mut CanisterState) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        for entry in state.schema_metadata.applied_migrations.iter_mut() {
+            if entry.rolled_back_at.is_some() {
+                continue;
+            }
+            let Some(migration) = self.migrations.iter().find(|m| m.version() == entry.version) else {
+                mismatches.push(format!(
+                    "migration {} ({}) was applied but is not registered in this build",
+                    entry.version, entry.description
+                ));
+                continue;
+            };
 
-        if unapplied.is_empty() {
+            let fingerprint = migration_fingerprint(migration.version(), migration.description());
+            if entry.fingerprint.is_empty() {
+                entry.fingerprint = fingerprint;
+            } else if entry.fingerprint != fingerprint {
+                mismatches.push(format!(
+                    "migration {} was changed after being applied (applied as \"{}\", now \"{}\")",
+                    entry.version,
+                    entry.description,
+                    migration.description()
+                ));
+            }
+        }
+        state.schema_metadata.fingerprint_mismatches = mismatches.clone();
+        mismatches
+    }
+
+    /// Applies all pending migrations as one batch. If any `migrate_up` or `validate`
+    /// fails, `state` (including the migration history) is restored to what it was
+    /// before the call and the failing migration is named in the error.
+    pub fn apply_migrations(&self, state: &mut CanisterState) -> Result<(), MigrationError> {
+        let pending = self.sorted_pending_migrations(state);
+        if pending.is_empty() {
             return Ok(());
         }
 
-        // Sort unapplied migrations by version to ensure correct order
-        unapplied.sort_by_key(|a| a.version());
+        let maps = changed_maps(&pending);
+        let backup = state.to_partial_snapshot(&maps);
+        let result = Self::apply(state, pending);
+        if result.is_err() {
+            state.restore_partial_snapshot(backup, &maps);
+        }
+        result
+    }
+
+    /// `apply_migrations` without the backup, for post_upgrade: it traps when this fails,
+    /// and the IC then discards every change made by the upgrade. `state` is left
+    /// half-migrated on failure, so any other caller must trap as well.
+    pub fn apply_migrations_without_backup(
+        &self,
+        state: &mut CanisterState,
+    ) -> Result<(), MigrationError> {
+        let pending = self.sorted_pending_migrations(state);
+        Self::apply(state, pending)
+    }
+
+    /// Applies only the next pending migration, without a backup, for a state that is
+    /// thrown away if this fails (a staged import). Returns its version, or None once
+    /// nothing is pending.
+    pub fn apply_next_migration_without_backup(
+        &self,
+        state: &mut CanisterState,
+    ) -> Result<Option<u64>, MigrationError> {
+        let Some(next) = self.sorted_pending_migrations(state).first().copied() else {
+            return Ok(None);
+        };
+        Self::apply(state, vec![next])?;
+        Ok(Some(next.version()))
+    }
+
+    fn sorted_pending_migrations(&self, state: &CanisterState) -> Vec<&dyn Migration> {
+        let mut pending = self.get_pending_migrations(state);
+        pending.sort_by_key(|a| a.version());
+        pending
+    }
 
-        for migration in unapplied {
-            // Apply migration
-            migration.migrate_up(state)?;
+    /// Runs `migrations` in order and records them, stopping at the first failure
+    fn apply(state: &mut CanisterState, migrations: Vec<&dyn Migration>) -> Result<(), MigrationError> {
+        for migration in migrations {
+            // Apply migration, then validate its result
+            let result = migration
+                .migrate_up(state)
+                .map_err(|e| (MigrationStage::Up, e))
+                .and_then(|_| migration.validate(state).map_err(|e| (MigrationStage::Validate, e)));
 
-            // Validate migration result
-            migration.validate(state)?;
+            if let Err((stage, message)) = result {
+                return Err(MigrationError {
+                    version: migration.version(),
+                    description: migration.description().to_string(),
+                    stage,
+                    message,
+                });
+            }
 
             // Record successful migration
             state.schema_metadata.applied_migrations.push(SchemaVersion {
                 version: migration.version(),
                 applied_at: get_timestamp(),
                 description: migration.description().to_string(),
+                rolled_back_at: None,
+                fingerprint: migration_fingerprint(migration.version(), migration.description()),
             });
 
             // Update current version
@@ -129,54 +322,219 @@ impl MigrationEngine {
         Ok(())
     }
 
+    /// Reverts every applied migration above `target_version`, newest first.
+    ///
+    /// Refuses to start unless each of them is registered and reversible. If any
+    /// `migrate_down` fails, `state` is restored to what it was before the call.
+    /// Returns the reverted versions.
     pub fn rollback_to_version(
         &self,
         state: &mut CanisterState,
         target_version: u64,
-    ) -> Result<(), String> {
+    ) -> Result<Vec<u64>, String> {
         let current_version = state.schema_metadata.current_version;
 
         if target_version >= current_version {
             return Err("Target version must be lower than current version".to_string());
         }
 
-        let to_rollback: Vec<_> = self
-            .migrations
-            .iter()
-            .filter(|m| m.version() > target_version && m.version() <= current_version)
-            .collect();
-
-        // Rollback in reverse order
-        for migration in to_rollback.iter().rev() {
-            migration.migrate_down(state)?;
+        let mut to_rollback = Vec::new();
+        for applied in self.get_applied_migrations(state) {
+            if applied.version <= target_version {
+                continue;
+            }
+            let migration = self
+                .migrations
+                .iter()
+                .find(|m| m.version() == applied.version)
+                .ok_or_else(|| {
+                    format!("Migration {} is not registered and cannot be rolled back", applied.version)
+                })?;
+            if !migration.is_reversible() {
+                return Err(format!(
+                    "Migration {} ({}) is not reversible",
+                    migration.version(),
+                    migration.description()
+                ));
+            }
+            to_rollback.push(migration.as_ref());
+        }
+        to_rollback.sort_by_key(|m| std::cmp::Reverse(m.version()));
 
-            // Remove from applied migrations
-            state.schema_metadata
-                .applied_migrations
-                .retain(|v| v.version != migration.version());
+        let maps = changed_maps(&to_rollback);
+        let backup = state.to_partial_snapshot(&maps);
+        for migration in to_rollback.iter() {
+            if let Err(e) = migration.migrate_down(state) {
+                state.restore_partial_snapshot(backup, &maps);
+                return Err(format!(
+                    "Rollback of migration {} failed, state left unchanged: {}",
+                    migration.version(),
+                    e
+                ));
+            }
         }
 
-        // Update current version
+        let rolled_back_at = get_timestamp();
+        for entry in state.schema_metadata.applied_migrations.iter_mut() {
+            if entry.version > target_version && entry.rolled_back_at.is_none() {
+                entry.rolled_back_at = Some(rolled_back_at);
+            }
+        }
         state.schema_metadata.current_version = target_version;
 
-        Ok(())
+        Ok(to_rollback.iter().map(|m| m.version()).collect())
     }
 
+    /// Migrations above the current version, up to `target_version` when it is set
     pub fn get_pending_migrations(&self, state: &CanisterState) -> Vec<&dyn Migration> {
         let current_version = state.schema_metadata.current_version;
+        let target_version = state.schema_metadata.target_version.unwrap_or(u64::MAX);
 
         self.migrations
             .iter()
-            .filter(|m| m.version() > current_version)
+            .filter(|m| m.version() > current_version && m.version() <= target_version)
             .map(|m| m.as_ref())
             .collect()
     }
 
+    /// Dry run of `apply_migrations`: runs every pending migration and its validation,
+    /// reporting the outcome of each. Stops at the first failure.
+    /// The migrations run on a `CanisterState::scratch_copy` holding only the maps they
+    /// change, so `state` is never changed.
+    pub fn preview_migrations(&self, state: &CanisterState) -> Vec<MigrationPreview> {
+        let pending = self.sorted_pending_migrations(state);
+        let mut scratch = state.scratch_copy(&changed_maps(&pending));
+        let mut reports = Vec::new();
+
+        for migration in pending {
+            // only maps the migration may change can differ afterwards
+            let before = record_digests(&scratch, migration.changed_maps());
+            let result = migration
+                .migrate_up(&mut scratch)
+                .and_then(|_| migration.validate(&scratch));
+            let after = record_digests(&scratch, migration.changed_maps());
+
+            reports.push(MigrationPreview {
+                version: migration.version(),
+                description: migration.description().to_string(),
+                success: result.is_ok(),
+                error: result.clone().err(),
+                users_touched: count_differences(&before.0, &after.0),
+                bookings_touched: count_differences(&before.1, &after.1),
+            });
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        reports
+    }
+
+    /// Migrations currently in effect, i.e. applied and not rolled back since
     pub fn get_applied_migrations(&self, state: &CanisterState) -> Vec<SchemaVersion> {
-        state.schema_metadata.applied_migrations.clone()
+        state
+            .schema_metadata
+            .applied_migrations
+            .iter()
+            .filter(|m| m.rolled_back_at.is_none())
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MigrationStage {
+    Up,
+    Validate,
+}
+
+/// Why `MigrationEngine::apply_migrations` stopped. The state has already been
+/// restored when this is returned.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrationError {
+    pub version: u64,
+    pub description: String,
+    pub stage: MigrationStage,
+    pub message: String,
+}
+
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stage = match self.stage {
+            MigrationStage::Up => "migrate_up",
+            MigrationStage::Validate => "validate",
+        };
+        write!(
+            f,
+            "migration {} ({}) failed in {}, no migrations were applied: {}",
+            self.version, self.description, stage, self.message
+        )
     }
 }
 
+/// Outcome of one migration in `MigrationEngine::preview_migrations`
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct MigrationPreview {
+    pub version: u64,
+    pub description: String,
+    pub success: bool,
+    pub error: Option<String>,
+    /// users added, removed, changed or moved between heap and stable storage
+    pub users_touched: u64,
+    /// bookings added, removed, changed or moved between heap and stable storage
+    pub bookings_touched: u64,
+}
+
+type RecordDigests = (BTreeMap<String, [u8; 32]>, BTreeMap<crate::BookingId, [u8; 32]>);
+
+/// Digests of the users and bookings keyed by id: the legacy heap records, plus the stable
+/// ones if `maps` has them. A record's digest covers where it is stored, so moving it
+/// between heap and stable storage also counts as touching it.
+fn record_digests(state: &CanisterState, maps: &[StateMap]) -> RecordDigests {
+    fn digest(location: u8, bytes: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update([location]);
+        hasher.update(bytes);
+        hasher.finalize().into()
+    }
+
+    let mut users = BTreeMap::new();
+    let mut bookings = BTreeMap::new();
+
+    for (email, user) in state.legacy_users.iter() {
+        let profile = UserInfoAndBookings {
+            primary_user: user.primary_user.clone(),
+            bookings: BTreeMap::new(),
+        };
+        users.insert(email.clone(), digest(0, &profile.to_bytes()));
+        for (booking_id, booking) in user.bookings.iter() {
+            bookings.insert(booking_id.clone(), digest(0, &booking.to_bytes()));
+        }
+    }
+    if maps.contains(&StateMap::Users) {
+        for (email, user) in state.users.iter() {
+            users.insert(email, digest(1, &user.to_bytes()));
+        }
+    }
+    if maps.contains(&StateMap::Bookings) {
+        for (booking_id, booking) in state.bookings.iter() {
+            bookings.insert(booking_id, digest(1, &booking.to_bytes()));
+        }
+    }
+
+    (users, bookings)
+}
+
+fn count_differences<K: Ord>(before: &BTreeMap<K, [u8; 32]>, after: &BTreeMap<K, [u8; 32]>) -> u64 {
+    let changed_or_removed = before
+        .iter()
+        .filter(|(key, value)| after.get(key) != Some(value))
+        .count();
+    let added = after.keys().filter(|key| !before.contains_key(key)).count();
+    (changed_or_removed + added) as u64
+}
+
 impl Default for MigrationEngine {
     fn default() -> Self {
         Self::new()
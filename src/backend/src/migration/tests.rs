@@ -1,6 +1,13 @@
 #[cfg(test)]
+#[allow(deprecated)]
 mod migration_engine_tests {
-    use crate::migration::{Migration, MigrationEngine, SchemaMetadata};
+    use crate::migration::{
+        migration_fingerprint, Migration, MigrationEngine, MigrationStage, SchemaMetadata,
+    };
+    use crate::migrations::{
+        AddDefaultControllersMigration, AddPaymentIdV2Migration, MoveToStableStorageMigration,
+    };
+    use crate::memory::MapMemory;
     use crate::models::*;
     use std::collections::BTreeMap;
 
@@ -19,6 +26,8 @@ mod migration_engine_tests {
             book_room_status: None,
             user_selected_hotel_room_details: HotelRoomDetails::default(),
             payment_details,
+            cancellation: None,
+            payment_mismatch: None,
         };
         
         let mut user_bookings = BTreeMap::new();
@@ -29,7 +38,8 @@ mod migration_engine_tests {
             bookings: user_bookings,
         };
         
-        state.users.insert("test@example.com".to_string(), user_info);
+        // pre-1003 data lives on the heap until MoveToStableStorageMigration runs
+        state.legacy_users.insert("test@example.com".to_string(), user_info);
         state
     }
 
@@ -44,7 +54,7 @@ mod migration_engine_tests {
     #[test]
     fn test_migration_engine_new() {
         let engine = MigrationEngine::new();
-        assert_eq!(engine.migrations.len(), 2); // Should have AddPaymentIdV2Migration
+        assert_eq!(engine.migrations.len(), 6);
     }
 
     #[test]
@@ -64,7 +74,7 @@ mod migration_engine_tests {
         let state = create_test_state(); // Default version is 1000
         
         let pending = engine.get_pending_migrations(&state);
-        assert_eq!(pending.len(), 2);
+        assert_eq!(pending.len(), 6);
         assert_eq!(pending[0].version(), 1001);
     }
 
@@ -78,7 +88,7 @@ mod migration_engine_tests {
         assert!(result.is_ok());
         
         let applied = engine.get_applied_migrations(&state);
-        assert_eq!(applied.len(), 2);
+        assert_eq!(applied.len(), 6);
         assert_eq!(applied[0].version, 1001);
         assert_eq!(applied[0].description, "Add payment_id_v2 field and migrate existing payment_id data");
     }
@@ -89,16 +99,22 @@ mod migration_engine_tests {
         let initial_count = engine.migrations.len();
         
         // Create a test migration
-        struct TestMigration;
+        struct TestMigration(u64);
         impl Migration for TestMigration {
-            fn version(&self) -> u64 { 1002 }
+            fn version(&self) -> u64 { self.0 }
             fn description(&self) -> &str { "Test migration" }
             fn migrate_up(&self, _state: &mut CanisterState) -> Result<(), String> { Ok(()) }
             fn migrate_down(&self, _state: &mut CanisterState) -> Result<(), String> { Ok(()) }
             fn validate(&self, _state: &CanisterState) -> Result<(), String> { Ok(()) }
         }
         
-        engine.add_migration(Box::new(TestMigration));
+        assert!(engine.add_migration(Box::new(TestMigration(1007))).is_ok());
+        assert_eq!(engine.migrations.len(), initial_count + 1);
+
+        // duplicates and versions below the latest are rejected
+        let result = engine.add_migration(Box::new(TestMigration(1007)));
+        assert!(result.unwrap_err().contains("1007"));
+        assert!(engine.add_migration(Box::new(TestMigration(1002))).is_err());
         assert_eq!(engine.migrations.len(), initial_count + 1);
     }
 
@@ -108,4 +124,467 @@ mod migration_engine_tests {
         assert_eq!(state.schema_metadata.current_version, 1000);
         assert!(state.schema_metadata.applied_migrations.is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_apply_migrations_stops_at_target_version() {
+        let engine = MigrationEngine::new();
+        let mut state = create_test_state();
+        state.schema_metadata.target_version = Some(1002);
+
+        assert_eq!(engine.get_pending_migrations(&state).len(), 2);
+        let result = engine.apply_migrations(&mut state);
+        assert!(result.is_ok());
+        assert_eq!(state.schema_metadata.current_version, 1002);
+        assert_eq!(state.schema_metadata.applied_migrations.len(), 2);
+        // 1003 not applied, data still on the heap
+        assert_eq!(state.legacy_users.len(), 1);
+
+        // lifting the target applies the rest
+        state.schema_metadata.target_version = None;
+        assert!(engine.apply_migrations(&mut state).is_ok());
+        assert_eq!(state.schema_metadata.current_version, 1006);
+    }
+
+    #[test]
+    fn test_preview_migrations_does_not_mutate_state() {
+        let engine = MigrationEngine::new();
+        let state = create_test_state();
+
+        let preview = engine.preview_migrations(&state);
+        assert_eq!(preview.len(), 6);
+        assert!(preview.iter().all(|p| p.success && p.error.is_none()));
+        assert_eq!(preview[0].version, 1001);
+        // payment_id_v2 backfill touches the one booking
+        assert_eq!(preview[0].bookings_touched, 1);
+        assert_eq!(preview[0].users_touched, 0);
+        // default controllers don't touch users or bookings
+        assert_eq!(preview[1].bookings_touched, 0);
+        // moving to stable storage touches everything
+        assert_eq!(preview[2].users_touched, 1);
+        assert_eq!(preview[2].bookings_touched, 1);
+        assert_eq!(preview[3].users_touched, 0);
+        assert!(state.roles.is_empty());
+
+        assert_eq!(state.schema_metadata.current_version, 1000);
+        assert!(state.schema_metadata.applied_migrations.is_empty());
+        assert!(state.controllers.is_none());
+        assert!(state.bookings.is_empty());
+        let user = state.legacy_users.get("test@example.com").unwrap();
+        let booking = user.bookings.values().next().unwrap();
+        assert_eq!(booking.payment_details.payment_api_response.payment_id_v2, "");
+    }
+
+    #[test]
+    fn test_preview_migrations_respects_target_version() {
+        let engine = MigrationEngine::new();
+        let mut state = create_test_state();
+        state.schema_metadata.target_version = Some(1001);
+
+        let preview = engine.preview_migrations(&state);
+        assert_eq!(preview.len(), 1);
+        assert_eq!(preview[0].version, 1001);
+    }
+
+    #[test]
+    fn test_preview_migrations_reports_failure() {
+        struct FailingMigration;
+        impl Migration for FailingMigration {
+            fn version(&self) -> u64 { 1007 }
+            fn description(&self) -> &str { "Always fails validation" }
+            fn migrate_up(&self, _state: &mut CanisterState) -> Result<(), String> { Ok(()) }
+            fn migrate_down(&self, _state: &mut CanisterState) -> Result<(), String> { Ok(()) }
+            fn validate(&self, _state: &CanisterState) -> Result<(), String> {
+                Err("broken".to_string())
+            }
+        }
+
+        let mut engine = MigrationEngine::new();
+        engine.add_migration(Box::new(FailingMigration)).unwrap();
+        let state = create_test_state();
+
+        let preview = engine.preview_migrations(&state);
+        assert_eq!(preview.len(), 7);
+        assert!(preview[..6].iter().all(|p| p.success));
+        assert!(!preview[6].success);
+        assert_eq!(preview[6].error.as_deref(), Some("broken"));
+        assert_eq!(state.schema_metadata.current_version, 1000);
+    }
+
+    #[test]
+    fn test_rollback_keeps_history() {
+        let engine = MigrationEngine::new();
+        let mut state = create_test_state();
+        // 1006 can't be rolled back
+        state.schema_metadata.target_version = Some(1005);
+        engine.apply_migrations(&mut state).unwrap();
+
+        let rolled_back = engine.rollback_to_version(&mut state, 1003).unwrap();
+        assert_eq!(rolled_back, vec![1005, 1004]);
+        assert_eq!(state.schema_metadata.current_version, 1003);
+
+        // entries are marked, not removed
+        let history = &state.schema_metadata.applied_migrations;
+        assert_eq!(history.len(), 5);
+        assert!(history[..3].iter().all(|m| m.rolled_back_at.is_none()));
+        assert!(history[3..].iter().all(|m| m.rolled_back_at.is_some()));
+        assert_eq!(engine.get_applied_migrations(&state).len(), 3);
+
+        // re-applying adds new entries
+        engine.apply_migrations(&mut state).unwrap();
+        assert_eq!(state.schema_metadata.applied_migrations.len(), 7);
+        assert_eq!(engine.get_applied_migrations(&state).len(), 5);
+        assert_eq!(state.get_current_migration_info().0, 1005);
+    }
+
+    #[test]
+    fn test_rollback_refuses_to_leave_stable_storage() {
+        let engine = MigrationEngine::new();
+        let mut state = create_test_state();
+        state.schema_metadata.target_version = Some(1005);
+        engine.apply_migrations(&mut state).unwrap();
+
+        let result = engine.rollback_to_version(&mut state, 1002);
+        assert!(result.unwrap_err().contains("1003"));
+
+        // the bookings are still where the endpoints read them
+        assert_eq!(state.schema_metadata.current_version, 1005);
+        assert_eq!(state.bookings.len(), 1);
+        assert!(state.legacy_users.is_empty());
+    }
+
+    #[test]
+    fn test_rollback_rejects_target_not_below_current() {
+        let engine = MigrationEngine::new();
+        let mut state = create_test_state();
+        engine.apply_migrations(&mut state).unwrap();
+
+        let result = engine.rollback_to_version(&mut state, 1006);
+        assert!(result.is_err());
+        assert_eq!(state.schema_metadata.current_version, 1006);
+    }
+
+    struct FailingDownMigration;
+    impl Migration for FailingDownMigration {
+        fn version(&self) -> u64 { 1007 }
+        fn description(&self) -> &str { "Rollback always fails" }
+        fn migrate_up(&self, _state: &mut CanisterState) -> Result<(), String> { Ok(()) }
+        fn migrate_down(&self, _state: &mut CanisterState) -> Result<(), String> {
+            Err("cannot go back".to_string())
+        }
+        fn validate(&self, _state: &CanisterState) -> Result<(), String> { Ok(()) }
+    }
+
+    #[test]
+    fn test_rollback_refuses_irreversible_migration() {
+        // MoneyMigration (1006) is not reversible
+        let engine = MigrationEngine::new();
+        let mut state = create_test_state();
+        engine.apply_migrations(&mut state).unwrap();
+
+        let result = engine.rollback_to_version(&mut state, 1004);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("1006"));
+
+        // nothing was touched, not even 1005 which is reversible
+        assert_eq!(state.schema_metadata.current_version, 1006);
+        assert_eq!(state.bookings.len(), 1);
+        assert!(state.legacy_users.is_empty());
+    }
+
+    #[test]
+    fn test_rollback_refuses_unregistered_migration() {
+        let engine = MigrationEngine::new();
+        let mut state = create_test_state();
+        engine.apply_migrations(&mut state).unwrap();
+        state.schema_metadata.applied_migrations[2].version = 1999;
+
+        let result = engine.rollback_to_version(&mut state, 1000);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("1999 is not registered"));
+    }
+
+    struct ClearRolesMigration;
+    impl Migration for ClearRolesMigration {
+        fn version(&self) -> u64 { 1008 }
+        fn description(&self) -> &str { "Clears roles on rollback" }
+        fn migrate_up(&self, _state: &mut CanisterState) -> Result<(), String> { Ok(()) }
+        fn migrate_down(&self, state: &mut CanisterState) -> Result<(), String> {
+            state.roles.clear();
+            Ok(())
+        }
+        fn validate(&self, _state: &CanisterState) -> Result<(), String> { Ok(()) }
+    }
+
+    #[test]
+    fn test_rollback_failure_leaves_state_unchanged() {
+        let mut engine = MigrationEngine::new();
+        engine.add_migration(Box::new(FailingDownMigration)).unwrap();
+        engine.add_migration(Box::new(ClearRolesMigration)).unwrap();
+        let mut state = create_test_state();
+        engine.apply_migrations(&mut state).unwrap();
+
+        // 1008 is reverted first, then 1007 fails
+        let result = engine.rollback_to_version(&mut state, 1006);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("1007"));
+        assert_eq!(state.roles.len(), 2);
+        assert_eq!(state.schema_metadata.current_version, 1008);
+        assert!(state
+            .schema_metadata
+            .applied_migrations
+            .iter()
+            .all(|m| m.rolled_back_at.is_none()));
+        assert_eq!(state.bookings.len(), 1);
+    }
+
+    struct FailingUpMigration;
+    impl Migration for FailingUpMigration {
+        fn version(&self) -> u64 { 1002 }
+        fn description(&self) -> &str { "Fails halfway" }
+        fn migrate_up(&self, state: &mut CanisterState) -> Result<(), String> {
+            // partial change before failing
+            state.controllers = Some(vec![]);
+            Err("boom".to_string())
+        }
+        fn migrate_down(&self, _state: &mut CanisterState) -> Result<(), String> { Ok(()) }
+        fn validate(&self, _state: &CanisterState) -> Result<(), String> { Ok(()) }
+    }
+
+    fn assert_untouched(state: &CanisterState) {
+        assert_eq!(state.schema_metadata.current_version, 1000);
+        assert!(state.schema_metadata.applied_migrations.is_empty());
+        assert!(state.controllers.is_none());
+        assert!(state.bookings.is_empty());
+        let user = state.legacy_users.get("test@example.com").unwrap();
+        let booking = user.bookings.values().next().unwrap();
+        assert_eq!(booking.payment_details.payment_api_response.payment_id_v2, "");
+    }
+
+    #[test]
+    fn test_failing_middle_migration_restores_state() {
+        let engine = MigrationEngine::from_migrations(vec![
+            Box::new(AddPaymentIdV2Migration),
+            Box::new(FailingUpMigration),
+            Box::new(MoveToStableStorageMigration),
+        ])
+        .unwrap();
+        let mut state = create_test_state();
+
+        let err = engine.apply_migrations(&mut state).unwrap_err();
+        assert_eq!(err.version, 1002);
+        assert_eq!(err.stage, MigrationStage::Up);
+        assert_eq!(err.message, "boom");
+        assert!(err.to_string().contains("migration 1002 (Fails halfway) failed in migrate_up"));
+
+        // 1001 ran before the failure, its changes and history entry are gone
+        assert_untouched(&state);
+    }
+
+    #[test]
+    fn test_failing_validation_after_stable_move_restores_state() {
+        struct FailingValidation;
+        impl Migration for FailingValidation {
+            fn version(&self) -> u64 { 1007 }
+            fn description(&self) -> &str { "Fails validation" }
+            fn migrate_up(&self, _state: &mut CanisterState) -> Result<(), String> { Ok(()) }
+            fn migrate_down(&self, _state: &mut CanisterState) -> Result<(), String> { Ok(()) }
+            fn validate(&self, _state: &CanisterState) -> Result<(), String> {
+                Err("invalid".to_string())
+            }
+        }
+
+        let mut engine = MigrationEngine::new();
+        engine.add_migration(Box::new(FailingValidation)).unwrap();
+        let mut state = create_test_state();
+
+        let err = engine.apply_migrations(&mut state).unwrap_err();
+        assert_eq!(err.version, 1007);
+        assert_eq!(err.stage, MigrationStage::Validate);
+
+        // 1003 had moved everything to stable maps; it is back on the heap
+        assert_untouched(&state);
+
+        // the good migrations still apply once the broken one is gone
+        let engine = MigrationEngine::new();
+        assert!(engine.apply_migrations(&mut state).is_ok());
+        assert_eq!(state.schema_metadata.current_version, 1006);
+    }
+
+    struct ClearBookingsMigration;
+    impl Migration for ClearBookingsMigration {
+        fn version(&self) -> u64 { 1007 }
+        fn description(&self) -> &str { "Clears bookings, then fails" }
+        fn migrate_up(&self, state: &mut CanisterState) -> Result<(), String> {
+            state.bookings.clear_new();
+            Err("boom".to_string())
+        }
+        fn migrate_down(&self, _state: &mut CanisterState) -> Result<(), String> { Ok(()) }
+        fn validate(&self, _state: &CanisterState) -> Result<(), String> { Ok(()) }
+        fn changed_maps(&self) -> &[StateMap] {
+            &[StateMap::Bookings]
+        }
+    }
+
+    #[test]
+    fn test_failing_migration_restores_the_maps_it_changes() {
+        let mut state = create_test_state();
+        MigrationEngine::new().apply_migrations(&mut state).unwrap();
+        let bookings_before = state.bookings.len();
+        assert_eq!(bookings_before, 1);
+
+        let mut engine = MigrationEngine::new();
+        engine.add_migration(Box::new(ClearBookingsMigration)).unwrap();
+        let err = engine.apply_migrations(&mut state).unwrap_err();
+        assert_eq!(err.version, 1007);
+
+        assert_eq!(state.bookings.len(), bookings_before);
+        assert_eq!(state.schema_metadata.current_version, 1006);
+    }
+
+    #[test]
+    fn test_preview_migrations_leaves_stable_maps_alone() {
+        let mut state = create_test_state();
+        MigrationEngine::new().apply_migrations(&mut state).unwrap();
+
+        let mut engine = MigrationEngine::new();
+        engine.add_migration(Box::new(ClearBookingsMigration)).unwrap();
+        let preview = engine.preview_migrations(&state);
+        assert_eq!(preview.len(), 1);
+        assert_eq!(preview[0].bookings_touched, 1);
+
+        assert_eq!(state.bookings.len(), 1);
+        assert_eq!(state.users.len(), 1);
+    }
+
+    #[test]
+    fn test_scratch_copy_only_copies_the_listed_maps() {
+        let mut state = create_test_state();
+        MigrationEngine::new().apply_migrations(&mut state).unwrap();
+
+        let scratch = state.scratch_copy(&[StateMap::Bookings]);
+        assert!(matches!(scratch.bookings.into_memory(), MapMemory::Heap(_)));
+        // the other maps are still readable, in place
+        assert_eq!(scratch.users.len(), 1);
+        assert!(matches!(scratch.users.into_memory(), MapMemory::ReadOnly(_)));
+
+        let mut scratch = state.scratch_copy(&[StateMap::Bookings]);
+        scratch.bookings.clear_new();
+        assert!(scratch.bookings.is_empty());
+        assert_eq!(state.bookings.len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "read-only memory")]
+    fn test_scratch_copy_traps_on_writes_to_maps_not_copied() {
+        let mut state = create_test_state();
+        MigrationEngine::new().apply_migrations(&mut state).unwrap();
+
+        let mut scratch = state.scratch_copy(&[StateMap::Bookings]);
+        scratch.users.clear_new();
+    }
+
+    #[test]
+    fn test_partial_snapshot_only_copies_the_listed_maps() {
+        let mut state = create_test_state();
+        MigrationEngine::new().apply_migrations(&mut state).unwrap();
+
+        let snapshot = state.to_partial_snapshot(&[StateMap::Bookings]);
+        assert_eq!(snapshot.bookings.len(), 1);
+        assert!(snapshot.users.is_empty());
+        assert!(snapshot.booking_timelines.is_empty());
+        // heap fields are always copied
+        assert_eq!(snapshot.schema_metadata.current_version, 1006);
+    }
+
+    #[test]
+    fn test_registry_rejects_duplicate_versions() {
+        let result = MigrationEngine::from_migrations(vec![
+            Box::new(AddPaymentIdV2Migration),
+            Box::new(AddPaymentIdV2Migration),
+        ]);
+        assert_eq!(result.err().unwrap(), "Duplicate migration version 1001");
+    }
+
+    #[test]
+    fn test_registry_rejects_out_of_order_versions() {
+        let result = MigrationEngine::from_migrations(vec![
+            Box::new(AddDefaultControllersMigration),
+            Box::new(AddPaymentIdV2Migration),
+        ]);
+        assert!(result.err().unwrap().contains("increasing order"));
+    }
+
+    #[test]
+    fn test_registry_warns_about_gaps() {
+        assert!(MigrationEngine::new().registry_warnings().is_empty());
+
+        let engine = MigrationEngine::from_migrations(vec![
+            Box::new(AddPaymentIdV2Migration),
+            Box::new(MoveToStableStorageMigration),
+        ])
+        .unwrap();
+        let warnings = engine.registry_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("no a1002_*.rs registered"));
+    }
+
+    #[test]
+    fn test_applied_migrations_record_fingerprint() {
+        let engine = MigrationEngine::new();
+        let mut state = create_test_state();
+        engine.apply_migrations(&mut state).unwrap();
+
+        let first = &state.schema_metadata.applied_migrations[0];
+        assert_eq!(first.fingerprint, migration_fingerprint(1001, &first.description));
+        assert!(engine.check_fingerprints(&mut state).is_empty());
+    }
+
+    #[test]
+    fn test_check_fingerprints_detects_edited_migration() {
+        let engine = MigrationEngine::new();
+        let mut state = create_test_state();
+        engine.apply_migrations(&mut state).unwrap();
+
+        // as if an older build had applied 1001 with a different description
+        state.schema_metadata.applied_migrations[0].fingerprint =
+            migration_fingerprint(1001, "Old description");
+
+        let mismatches = engine.check_fingerprints(&mut state);
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].contains("migration 1001 was changed"));
+
+        let (version, description) = state.get_current_migration_info();
+        assert_eq!(version, 1006);
+        assert!(description.contains("WARNING: migration 1001 was changed"));
+    }
+
+    #[test]
+    fn test_check_fingerprints_backfills_missing() {
+        let engine = MigrationEngine::new();
+        let mut state = create_test_state();
+        engine.apply_migrations(&mut state).unwrap();
+        for entry in state.schema_metadata.applied_migrations.iter_mut() {
+            entry.fingerprint = String::new();
+        }
+
+        assert!(engine.check_fingerprints(&mut state).is_empty());
+        assert!(state
+            .schema_metadata
+            .applied_migrations
+            .iter()
+            .all(|m| !m.fingerprint.is_empty()));
+    }
+
+    #[test]
+    fn test_check_fingerprints_reports_unregistered_migration() {
+        let engine = MigrationEngine::new();
+        let mut state = create_test_state();
+        engine.apply_migrations(&mut state).unwrap();
+        state.schema_metadata.applied_migrations[2].version = 1999;
+
+        let mismatches = engine.check_fingerprints(&mut state);
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].contains("1999"));
+        assert!(mismatches[0].contains("not registered"));
+    }
+}
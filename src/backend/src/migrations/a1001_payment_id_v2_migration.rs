@@ -1,5 +1,5 @@
 use crate::migration::Migration;
-use crate::CanisterState;
+use crate::{CanisterState, StateMap};
 
 pub struct AddPaymentIdV2Migration;
 
@@ -18,10 +18,11 @@ impl Migration for AddPaymentIdV2Migration {
         "Add payment_id_v2 field and migrate existing payment_id data"
     }
 
+    // runs before 1003 moves users into stable memory, so it works on the heap copy
     fn migrate_up(&self, state: &mut CanisterState) -> Result<(), String> {
         let mut migration_count = 0;
         
-        for user in state.users.values_mut() {
+        for user in state.legacy_users.values_mut() {
             for booking in user.bookings.values_mut() {
                 let payment_response = &mut booking.payment_details.payment_api_response;
                 
@@ -43,7 +44,7 @@ impl Migration for AddPaymentIdV2Migration {
         // For rollback, we can clear payment_id_v2 fields
         let mut rollback_count = 0;
         
-        for user in state.users.values_mut() {
+        for user in state.legacy_users.values_mut() {
             for booking in user.bookings.values_mut() {
                 let payment_response = &mut booking.payment_details.payment_api_response;
                 
@@ -63,7 +64,7 @@ impl Migration for AddPaymentIdV2Migration {
     fn validate(&self, state: &CanisterState) -> Result<(), String> {
         let mut validation_errors = Vec::new();
         
-        for (user_email, user) in state.users.iter() {
+        for (user_email, user) in state.legacy_users.iter() {
             for (booking_id, booking) in user.bookings.iter() {
                 let payment_response = &booking.payment_details.payment_api_response;
                 
@@ -98,4 +99,8 @@ impl Migration for AddPaymentIdV2Migration {
             Err(format!("Validation failed: {}", validation_errors.join("; ")))
         }
     }
+
+    fn changed_maps(&self) -> &[StateMap] {
+        &[]
+    }
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
 use crate::migration::Migration;
-use crate::CanisterState;
+use crate::{CanisterState, StateMap};
 use candid::Principal;
 
 const DEFAULT_CONTROLLERS: &[&str] = &[
@@ -105,4 +105,8 @@ impl Migration for AddDefaultControllersMigration {
         
         Ok(())
     }
+
+    fn changed_maps(&self) -> &[StateMap] {
+        &[]
+    }
 }
\ No newline at end of file
@@ -0,0 +1,127 @@
+use crate::memory::stable_map_to_btree;
+use crate::migration::Migration;
+use crate::{CanisterState, StateMap, UserInfoAndBookings, Wishlist};
+
+pub struct MoveToStableStorageMigration;
+
+impl Migration for MoveToStableStorageMigration {
+    fn version(&self) -> u64 {
+        1003
+    }
+
+    fn description(&self) -> &str {
+        "Move users, bookings, wishlist and indexes from heap into stable memory"
+    }
+
+    fn migrate_up(&self, state: &mut CanisterState) -> Result<(), String> {
+        let mut user_count = 0;
+        let mut booking_count = 0;
+
+        for (email, user) in std::mem::take(&mut state.legacy_users) {
+            for (booking_id, booking) in user.bookings {
+                state.bookings.insert(booking_id, booking);
+                booking_count += 1;
+            }
+            // bookings are kept in their own map, only the profile goes into `users`
+            let profile = UserInfoAndBookings {
+                primary_user: user.primary_user,
+                bookings: Default::default(),
+            };
+            state.users.insert(email, profile);
+            user_count += 1;
+        }
+
+        for (email, hotels) in std::mem::take(&mut state.legacy_wishlist) {
+            state.wishlist.insert(email, Wishlist(hotels));
+        }
+
+        for (payment_id_v2, booking_id) in state.legacy_payment_id_index.take().unwrap_or_default() {
+            state.payment_id_index.insert(payment_id_v2, booking_id);
+        }
+
+        for (principal, email) in std::mem::take(&mut state.legacy_user_principal_email_index) {
+            state.user_principal_email_index.insert(principal, email);
+        }
+
+        ic_cdk::println!(
+            "MoveToStableStorageMigration: Moved {} users and {} bookings to stable memory",
+            user_count,
+            booking_count
+        );
+
+        Ok(())
+    }
+
+    fn migrate_down(&self, state: &mut CanisterState) -> Result<(), String> {
+        let mut legacy_users = stable_map_to_btree(&state.users);
+        for (booking_id, booking) in state.bookings.iter() {
+            legacy_users
+                .entry(booking_id.get_user_email().to_string())
+                .or_default()
+                .bookings
+                .insert(booking_id, booking);
+        }
+        let user_count = legacy_users.len();
+
+        state.legacy_users = legacy_users;
+        state.legacy_wishlist = state
+            .wishlist
+            .iter()
+            .map(|(email, wishlist)| (email, wishlist.0))
+            .collect();
+        state.legacy_payment_id_index = Some(stable_map_to_btree(&state.payment_id_index));
+        state.legacy_user_principal_email_index =
+            stable_map_to_btree(&state.user_principal_email_index);
+
+        state.users.clear_new();
+        state.bookings.clear_new();
+        state.wishlist.clear_new();
+        state.payment_id_index.clear_new();
+        state.user_principal_email_index.clear_new();
+
+        ic_cdk::println!(
+            "MoveToStableStorageMigration rollback: Moved {} users back to heap",
+            user_count
+        );
+
+        Ok(())
+    }
+
+    fn validate(&self, state: &CanisterState) -> Result<(), String> {
+        if !state.legacy_users.is_empty()
+            || !state.legacy_wishlist.is_empty()
+            || state.legacy_payment_id_index.is_some()
+            || !state.legacy_user_principal_email_index.is_empty()
+        {
+            return Err("Validation failed: heap data left behind after moving to stable memory".to_string());
+        }
+
+        for booking_id in state.bookings.keys() {
+            if !state.users.contains_key(&booking_id.get_user_email().to_string()) {
+                return Err(format!(
+                    "Validation failed: booking {} has no user entry for {}",
+                    booking_id.get_app_reference(),
+                    booking_id.get_user_email()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The endpoints only read the stable maps, so every booking would vanish from the API
+    /// once `migrate_down` moves them back to the heap
+    fn is_reversible(&self) -> bool {
+        false
+    }
+
+    fn changed_maps(&self) -> &[StateMap] {
+        &[
+            StateMap::Users,
+            StateMap::Bookings,
+            StateMap::Wishlist,
+            StateMap::PaymentIdIndex,
+            StateMap::UserPrincipalEmailIndex,
+        ]
+    }
+}
@@ -0,0 +1,66 @@
+use crate::migration::Migration;
+use crate::roles::Role;
+use crate::{CanisterState, StateMap};
+
+/// Replaces the flat `controllers` list by the `Admin` role
+pub struct ControllersToRolesMigration;
+
+impl Migration for ControllersToRolesMigration {
+    fn version(&self) -> u64 {
+        1004
+    }
+
+    fn description(&self) -> &str {
+        "Grant the Admin role to every controller and retire the controllers list"
+    }
+
+    fn migrate_up(&self, state: &mut CanisterState) -> Result<(), String> {
+        let controllers = state.controllers.take().unwrap_or_default();
+
+        for controller in controllers.iter() {
+            state.roles.entry(*controller).or_default().insert(Role::Admin);
+        }
+
+        ic_cdk::println!(
+            "ControllersToRolesMigration: Granted Admin to {} controllers",
+            controllers.len()
+        );
+        Ok(())
+    }
+
+    fn migrate_down(&self, state: &mut CanisterState) -> Result<(), String> {
+        // only Admin has an equivalent before 1004, refuse to drop the other roles silently
+        if let Some((principal, roles)) = state
+            .roles
+            .iter()
+            .find(|(_, roles)| roles.iter().any(|role| *role != Role::Admin))
+        {
+            return Err(format!(
+                "Principal {} holds roles {:?} that can't be expressed as controllers, revoke them first",
+                principal, roles
+            ));
+        }
+
+        let admins: Vec<_> = std::mem::take(&mut state.roles).into_keys().collect();
+        ic_cdk::println!(
+            "ControllersToRolesMigration rollback: Restored {} controllers",
+            admins.len()
+        );
+        state.controllers = Some(admins);
+        Ok(())
+    }
+
+    fn validate(&self, state: &CanisterState) -> Result<(), String> {
+        if state.controllers.is_some() {
+            return Err("Validation failed: controllers list should be retired after migration".to_string());
+        }
+        if state.roles.values().any(|roles| roles.is_empty()) {
+            return Err("Validation failed: principal with an empty role set".to_string());
+        }
+        Ok(())
+    }
+
+    fn changed_maps(&self) -> &[StateMap] {
+        &[]
+    }
+}
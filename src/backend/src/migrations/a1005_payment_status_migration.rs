@@ -0,0 +1,132 @@
+use crate::migration::Migration;
+use crate::timeline::{BookingEventKind, BookingTimeline};
+use crate::{BackendPaymentStatus, BookingId, CanisterState, Money, StateMap};
+
+/// Rewrites `Unpaid("<payment id> - <STATUS>")` and `Paid("<payment id> - COMPLETED")`
+/// into the specific payment states, in bookings and in their timelines
+pub struct PaymentStatusMigration;
+
+/// `Some((payment id, STATUS))` for references written by the old status mapping
+fn split_trans_ref(trans_ref: &str) -> Option<(&str, &str)> {
+    trans_ref
+        .rsplit_once(" - ")
+        .filter(|(_, status)| {
+            !status.is_empty() && status.chars().all(|c| c.is_ascii_uppercase() || c == '_')
+        })
+}
+
+/// `refunded_amount` is used for "REFUNDED", which didn't keep the amount
+pub fn convert_legacy_status(status: BackendPaymentStatus, refunded_amount: Money) -> BackendPaymentStatus {
+    match status {
+        BackendPaymentStatus::Paid(trans_ref) => match split_trans_ref(&trans_ref) {
+            Some((payment_id_v2, "COMPLETED")) => BackendPaymentStatus::Paid(payment_id_v2.to_string()),
+            _ => BackendPaymentStatus::Paid(trans_ref),
+        },
+        BackendPaymentStatus::Unpaid(None) => BackendPaymentStatus::Pending(None),
+        BackendPaymentStatus::Unpaid(Some(trans_ref)) => match split_trans_ref(&trans_ref) {
+            Some((payment_id_v2, status)) => {
+                BackendPaymentStatus::from_provider_status(status, payment_id_v2.to_string(), refunded_amount)
+            }
+            // free-form reason from `mark_payment_not_complete`
+            None => BackendPaymentStatus::Failed(trans_ref),
+        },
+        other => other,
+    }
+}
+
+/// Inverse of `convert_legacy_status`. `RefundPending` and `Refunded` existed before 1005.
+pub fn to_legacy_status(status: BackendPaymentStatus) -> BackendPaymentStatus {
+    let unpaid = |payment_id_v2: String, status: &str| {
+        BackendPaymentStatus::Unpaid(Some(format!("{} - {}", payment_id_v2, status)))
+    };
+    match status {
+        BackendPaymentStatus::Pending(None) => BackendPaymentStatus::Unpaid(None),
+        BackendPaymentStatus::Pending(Some(payment_id_v2)) => unpaid(payment_id_v2, "WAITING"),
+        BackendPaymentStatus::Confirming(payment_id_v2) => unpaid(payment_id_v2, "CONFIRMING"),
+        BackendPaymentStatus::PartiallyPaid(payment_id_v2) => unpaid(payment_id_v2, "PARTIALLY_PAID"),
+        BackendPaymentStatus::Paid(payment_id_v2) => {
+            BackendPaymentStatus::Paid(format!("{} - COMPLETED", payment_id_v2))
+        }
+        BackendPaymentStatus::Failed(payment_id_v2) => unpaid(payment_id_v2, "FAILED"),
+        BackendPaymentStatus::Expired(payment_id_v2) => unpaid(payment_id_v2, "EXPIRED"),
+        BackendPaymentStatus::Cancelled(payment_id_v2) => unpaid(payment_id_v2, "CANCELLED"),
+        other => other,
+    }
+}
+
+fn convert_all(
+    state: &mut CanisterState,
+    convert: impl Fn(BackendPaymentStatus, Money) -> BackendPaymentStatus,
+) -> usize {
+    let bookings: Vec<_> = state.bookings.iter().collect();
+    let count = bookings.len();
+    for (booking_id, mut booking) in bookings {
+        let refunded_amount = booking
+            .payment_details
+            .payment_api_response
+            .legacy_price(&booking.legacy_currency());
+        let status = std::mem::take(&mut booking.payment_details.payment_status);
+        booking.payment_details.payment_status = convert(status, refunded_amount);
+        state.bookings.insert(booking_id, booking);
+    }
+
+    let timelines: Vec<(BookingId, BookingTimeline)> = state.booking_timelines.iter().collect();
+    for (booking_id, mut timeline) in timelines {
+        for event in timeline.0.iter_mut() {
+            if let BookingEventKind::PaymentStatusChanged { from, to } = &mut event.kind {
+                *from = convert(std::mem::take(from), Money::default());
+                *to = convert(std::mem::take(to), Money::default());
+            }
+        }
+        state.booking_timelines.insert(booking_id, timeline);
+    }
+    count
+}
+
+impl Migration for PaymentStatusMigration {
+    fn version(&self) -> u64 {
+        1005
+    }
+
+    fn description(&self) -> &str {
+        "Convert Paid/Unpaid payment statuses to the full payment status model"
+    }
+
+    fn migrate_up(&self, state: &mut CanisterState) -> Result<(), String> {
+        let count = convert_all(state, convert_legacy_status);
+        ic_cdk::println!("PaymentStatusMigration: Converted {} bookings", count);
+        Ok(())
+    }
+
+    fn migrate_down(&self, state: &mut CanisterState) -> Result<(), String> {
+        let count = convert_all(state, |status, _| to_legacy_status(status));
+        ic_cdk::println!("PaymentStatusMigration rollback: Converted {} bookings", count);
+        Ok(())
+    }
+
+    fn validate(&self, state: &CanisterState) -> Result<(), String> {
+        for booking in state.bookings.values() {
+            match &booking.payment_details.payment_status {
+                BackendPaymentStatus::Unpaid(_) => {
+                    return Err(format!(
+                        "Validation failed: booking {} still has a legacy Unpaid status",
+                        booking.booking_id.get_app_reference()
+                    ));
+                }
+                BackendPaymentStatus::Paid(trans_ref) if split_trans_ref(trans_ref).is_some() => {
+                    return Err(format!(
+                        "Validation failed: booking {} has an unconverted reference '{}'",
+                        booking.booking_id.get_app_reference(),
+                        trans_ref
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn changed_maps(&self) -> &[StateMap] {
+        &[StateMap::Bookings, StateMap::BookingTimelines]
+    }
+}
@@ -0,0 +1,148 @@
+use crate::migration::Migration;
+use crate::timeline::BookingEventKind;
+use crate::{BackendPaymentStatus, Booking, BookingId, CanisterState, CurrencyCode, Money, StateMap};
+
+/// Fills the `Money` amounts from the floats they replace: requested payments and room prices
+/// of bookings and their revisions, and provider responses of bookings and their payment
+/// event logs. Amendment deltas and payment mismatches, which were floats too, are derived
+/// again from the converted amounts, and refunded amounts, read as USD, are moved into the
+/// booking's currency.
+///
+/// Not reversible: builds from before 1006 can't read the `Money` deltas and mismatches.
+pub struct MoneyMigration;
+
+/// A `Refunded` amount read from its float is in USD, see `money_or_legacy_float`
+fn convert_legacy_refund(status: &mut BackendPaymentStatus, currency: &CurrencyCode) {
+    if let BackendPaymentStatus::Refunded { amount, .. } = status {
+        if &amount.currency != currency {
+            *amount = Money::from_legacy(amount.to_major(), currency);
+        }
+    }
+}
+
+impl Migration for MoneyMigration {
+    fn version(&self) -> u64 {
+        1006
+    }
+
+    fn description(&self) -> &str {
+        "Convert float amounts to Money with ISO 4217 currencies"
+    }
+
+    fn migrate_up(&self, state: &mut CanisterState) -> Result<(), String> {
+        let bookings: Vec<_> = state.bookings.iter().collect();
+        let count = bookings.len();
+        for (booking_id, mut booking) in bookings {
+            let currency = booking.legacy_currency();
+            booking.fill_money_from_legacy();
+            convert_legacy_refund(&mut booking.payment_details.payment_status, &currency);
+            booking.payment_mismatch = booking.check_payment_amount(
+                &booking.payment_details.payment_api_response,
+                &booking.payment_details.payment_status,
+                &state.payment_tolerance,
+            );
+
+            if let Some(mut log) = state.payment_events.get(&booking_id) {
+                for record in log.0.iter_mut() {
+                    record.payment_api_response.fill_money_from_legacy(&currency);
+                }
+                state.payment_events.insert(booking_id.clone(), log);
+            }
+            convert_revisions(state, &booking_id, &booking, &currency);
+            convert_timeline_refunds(state, &booking_id, &currency);
+            state.bookings.insert(booking_id, booking);
+        }
+        ic_cdk::println!("MoneyMigration: Converted {} bookings", count);
+        Ok(())
+    }
+
+    fn migrate_down(&self, _state: &mut CanisterState) -> Result<(), String> {
+        Err("MoneyMigration cannot be rolled back".to_string())
+    }
+
+    fn validate(&self, state: &CanisterState) -> Result<(), String> {
+        for booking in state.bookings.values() {
+            let details = &booking.user_selected_hotel_room_details;
+            let amounts = std::iter::once(&details.requested_payment)
+                .chain(details.room_details.iter().map(|room| &room.price))
+                .chain(std::iter::once(&booking.payment_details.payment_api_response.price))
+                .flatten();
+            for amount in amounts {
+                if amount.currency.validate().is_err() {
+                    return Err(format!(
+                        "Validation failed: booking {} has an amount in unknown currency '{}'",
+                        booking.booking_id.get_app_reference(),
+                        amount.currency
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_reversible(&self) -> bool {
+        false
+    }
+
+    fn changed_maps(&self) -> &[StateMap] {
+        &[
+            StateMap::Bookings,
+            StateMap::BookingTimelines,
+            StateMap::BookingRevisions,
+            StateMap::PaymentEvents,
+        ]
+    }
+}
+
+/// Converts the revisions of one booking and recomputes their deltas: each is the requested
+/// payment after that amendment, in the next revision or the booking itself, minus its own
+fn convert_revisions(
+    state: &mut CanisterState,
+    booking_id: &BookingId,
+    booking: &Booking,
+    currency: &CurrencyCode,
+) {
+    let Some(mut revisions) = state.booking_revisions.get(booking_id) else {
+        return;
+    };
+    for revision in revisions.0.iter_mut() {
+        revision.user_selected_hotel_room_details.fill_money_from_legacy(currency);
+    }
+    let mut amended_to = booking.get_requested_payment();
+    for revision in revisions.0.iter_mut().rev() {
+        let previous = revision
+            .user_selected_hotel_room_details
+            .requested_payment
+            .clone()
+            .unwrap_or_default();
+        revision.price_delta = amended_to
+            .checked_sub(&previous)
+            .unwrap_or_else(|_| Money::zero(currency.clone()));
+        amended_to = previous;
+    }
+
+    if let Some(mut timeline) = state.booking_timelines.get(booking_id) {
+        for event in timeline.0.iter_mut() {
+            if let BookingEventKind::Amended { revision, price_delta } = &mut event.kind {
+                if let Some(converted) = revisions.0.iter().find(|r| r.revision == *revision) {
+                    *price_delta = converted.price_delta.clone();
+                }
+            }
+        }
+        state.booking_timelines.insert(booking_id.clone(), timeline);
+    }
+    state.booking_revisions.insert(booking_id.clone(), revisions);
+}
+
+fn convert_timeline_refunds(state: &mut CanisterState, booking_id: &BookingId, currency: &CurrencyCode) {
+    let Some(mut timeline) = state.booking_timelines.get(booking_id) else {
+        return;
+    };
+    for event in timeline.0.iter_mut() {
+        if let BookingEventKind::PaymentStatusChanged { from, to } = &mut event.kind {
+            convert_legacy_refund(from, currency);
+            convert_legacy_refund(to, currency);
+        }
+    }
+    state.booking_timelines.insert(booking_id.clone(), timeline);
+}
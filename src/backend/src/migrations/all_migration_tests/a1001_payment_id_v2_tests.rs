@@ -1,4 +1,5 @@
 
+#[allow(deprecated)]
 mod payment_id_v2_tests {
     use crate::migrations::AddPaymentIdV2Migration;
     use crate::models::*;
@@ -20,6 +21,8 @@ mod payment_id_v2_tests {
             book_room_status: None,
             user_selected_hotel_room_details: HotelRoomDetails::default(),
             payment_details,
+            cancellation: None,
+            payment_mismatch: None,
         };
         
         let mut user_bookings = BTreeMap::new();
@@ -30,7 +33,7 @@ mod payment_id_v2_tests {
             bookings: user_bookings,
         };
         
-        state.users.insert("test@example.com".to_string(), user_info);
+        state.legacy_users.insert("test@example.com".to_string(), user_info);
         state
     }
 
@@ -48,7 +51,7 @@ mod payment_id_v2_tests {
         let mut state = create_test_state_with_payment();
         
         // Ensure payment_id_v2 is empty before migration
-        let booking = state.users.get("test@example.com").unwrap()
+        let booking = state.legacy_users.get("test@example.com").unwrap()
             .bookings.values().next().unwrap();
         assert_eq!(booking.payment_details.payment_api_response.payment_id_v2, "");
         
@@ -57,7 +60,7 @@ mod payment_id_v2_tests {
         assert!(result.is_ok());
         
         // Verify migration result
-        let booking = state.users.get("test@example.com").unwrap()
+        let booking = state.legacy_users.get("test@example.com").unwrap()
             .bookings.values().next().unwrap();
         assert_eq!(booking.payment_details.payment_api_response.payment_id_v2, "12345");
     }
@@ -71,7 +74,7 @@ mod payment_id_v2_tests {
     //     let _ = migration.migrate_up(&mut state);
         
     //     // Verify payment_id_v2 is set
-    //     let booking = state.users.get("test@example.com").unwrap()
+    //     let booking = state.legacy_users.get("test@example.com").unwrap()
     //         .bookings.values().next().unwrap();
     //     assert_eq!(booking.payment_details.payment_api_response.payment_id_v2, "12345");
         
@@ -80,7 +83,7 @@ mod payment_id_v2_tests {
     //     assert!(result.is_ok());
         
     //     // Verify payment_id_v2 is cleared
-    //     let booking = state.users.get("test@example.com").unwrap()
+    //     let booking = state.legacy_users.get("test@example.com").unwrap()
     //         .bookings.values().next().unwrap();
     //     assert_eq!(booking.payment_details.payment_api_response.payment_id_v2, "");
     // }
@@ -127,6 +130,8 @@ mod payment_id_v2_tests {
             book_room_status: None,
             user_selected_hotel_room_details: HotelRoomDetails::default(),
             payment_details,
+            cancellation: None,
+            payment_mismatch: None,
         };
         
         let mut user_bookings = BTreeMap::new();
@@ -137,14 +142,14 @@ mod payment_id_v2_tests {
             bookings: user_bookings,
         };
         
-        state.users.insert("test@example.com".to_string(), user_info);
+        state.legacy_users.insert("test@example.com".to_string(), user_info);
         
         // Apply migration
         let result = migration.migrate_up(&mut state);
         assert!(result.is_ok());
         
         // Verify payment_id_v2 remains empty (no migration for zero payment_id)
-        let booking = state.users.get("test@example.com").unwrap()
+        let booking = state.legacy_users.get("test@example.com").unwrap()
             .bookings.values().next().unwrap();
         assert_eq!(booking.payment_details.payment_api_response.payment_id_v2, "");
     }
@@ -166,6 +171,8 @@ mod payment_id_v2_tests {
             book_room_status: None,
             user_selected_hotel_room_details: HotelRoomDetails::default(),
             payment_details,
+            cancellation: None,
+            payment_mismatch: None,
         };
         
         let mut user_bookings = BTreeMap::new();
@@ -176,14 +183,14 @@ mod payment_id_v2_tests {
             bookings: user_bookings,
         };
         
-        state.users.insert("test@example.com".to_string(), user_info);
+        state.legacy_users.insert("test@example.com".to_string(), user_info);
         
         // Apply migration
         let result = migration.migrate_up(&mut state);
         assert!(result.is_ok());
         
         // Verify payment_id_v2 remains unchanged
-        let booking = state.users.get("test@example.com").unwrap()
+        let booking = state.legacy_users.get("test@example.com").unwrap()
             .bookings.values().next().unwrap();
         assert_eq!(booking.payment_details.payment_api_response.payment_id_v2, "existing_value");
     }
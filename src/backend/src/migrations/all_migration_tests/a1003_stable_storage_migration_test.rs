@@ -0,0 +1,192 @@
+mod stable_storage_tests {
+    use crate::migrations::MoveToStableStorageMigration;
+    use crate::models::*;
+    use crate::migration::Migration;
+    use candid::Principal;
+    use std::collections::BTreeMap;
+
+    fn create_test_booking(app_ref: &str, email: &str, payment_id_v2: &str) -> Booking {
+        let booking_id = BookingId::new(app_ref.to_string(), email.to_string());
+        let mut payment_details = PaymentDetails::new(booking_id.clone());
+        payment_details.payment_api_response.payment_id_v2 = payment_id_v2.to_string();
+
+        Booking {
+            booking_id,
+            guests: UserDetails::default(),
+            book_room_status: None,
+            user_selected_hotel_room_details: HotelRoomDetails::default(),
+            payment_details,
+            cancellation: None,
+            payment_mismatch: None,
+        }
+    }
+
+    fn create_principal() -> Principal {
+        Principal::from_text("be2us-64aaa-aaaaa-qaabq-cai").unwrap()
+    }
+
+    // state as it looks right after restoring a pre-1003 upgrade snapshot
+    fn create_legacy_state() -> CanisterState {
+        let mut state = CanisterState::new();
+
+        let mut user1_bookings = BTreeMap::new();
+        for booking in [
+            create_test_booking("APP001", "user1@example.com", "pay_1"),
+            create_test_booking("APP002", "user1@example.com", "pay_2"),
+        ] {
+            user1_bookings.insert(booking.booking_id.clone(), booking);
+        }
+        let mut user2_bookings = BTreeMap::new();
+        let booking3 = create_test_booking("APP003", "user2@example.com", "pay_3");
+        user2_bookings.insert(booking3.booking_id.clone(), booking3);
+
+        state.legacy_users.insert(
+            "user1@example.com".to_string(),
+            UserInfoAndBookings {
+                primary_user: AdultDetail::default(),
+                bookings: user1_bookings,
+            },
+        );
+        state.legacy_users.insert(
+            "user2@example.com".to_string(),
+            UserInfoAndBookings {
+                primary_user: AdultDetail::default(),
+                bookings: user2_bookings,
+            },
+        );
+
+        state.legacy_wishlist.insert(
+            "user1@example.com".to_string(),
+            vec![HotelId { hotel_code: "H100".to_string() }],
+        );
+
+        let mut payment_index = BTreeMap::new();
+        payment_index.insert(
+            "pay_1".to_string(),
+            BookingId::new("APP001".to_string(), "user1@example.com".to_string()),
+        );
+        state.legacy_payment_id_index = Some(payment_index);
+
+        state
+            .legacy_user_principal_email_index
+            .insert(create_principal(), "user1@example.com".to_string());
+
+        state
+    }
+
+    #[test]
+    fn test_stable_storage_migration_version() {
+        let migration = MoveToStableStorageMigration;
+        assert_eq!(migration.version(), 1003);
+        assert!(!migration.is_reversible());
+    }
+
+    #[test]
+    fn test_stable_storage_migration_up_moves_everything() {
+        let migration = MoveToStableStorageMigration;
+        let mut state = create_legacy_state();
+
+        let result = migration.migrate_up(&mut state);
+        assert!(result.is_ok());
+
+        // heap copies are drained
+        assert!(state.legacy_users.is_empty());
+        assert!(state.legacy_wishlist.is_empty());
+        assert!(state.legacy_payment_id_index.is_none());
+        assert!(state.legacy_user_principal_email_index.is_empty());
+
+        // and available through the stable maps
+        assert_eq!(state.users.len(), 2);
+        assert_eq!(state.bookings.len(), 3);
+        assert_eq!(state.get_user_bookings("user1@example.com").unwrap().len(), 2);
+        assert_eq!(state.get_user_bookings("user2@example.com").unwrap().len(), 1);
+        assert_eq!(state.get_wishlist_by_email("user1@example.com").unwrap().len(), 1);
+        assert_eq!(
+            state.payment_id_index.get(&"pay_1".to_string()),
+            Some(BookingId::new("APP001".to_string(), "user1@example.com".to_string()))
+        );
+        assert_eq!(
+            state.get_user_bookings_by_principal(create_principal()).unwrap().len(),
+            2
+        );
+
+        // bookings are not duplicated inside the stored profile
+        let stored_user = state.users.get(&"user1@example.com".to_string()).unwrap();
+        assert!(stored_user.bookings.is_empty());
+
+        assert!(migration.validate(&state).is_ok());
+    }
+
+    #[test]
+    fn test_stable_storage_migration_up_empty_state() {
+        let migration = MoveToStableStorageMigration;
+        let mut state = CanisterState::new();
+
+        let result = migration.migrate_up(&mut state);
+        assert!(result.is_ok());
+        assert!(state.users.is_empty());
+        assert!(state.bookings.is_empty());
+        assert!(migration.validate(&state).is_ok());
+    }
+
+    #[test]
+    fn test_stable_storage_migration_down_restores_heap() {
+        let migration = MoveToStableStorageMigration;
+        let mut state = create_legacy_state();
+
+        let _ = migration.migrate_up(&mut state);
+        let result = migration.migrate_down(&mut state);
+        assert!(result.is_ok());
+
+        assert!(state.users.is_empty());
+        assert!(state.bookings.is_empty());
+        assert!(state.wishlist.is_empty());
+        assert!(state.payment_id_index.is_empty());
+        assert!(state.user_principal_email_index.is_empty());
+
+        assert_eq!(state.legacy_users.len(), 2);
+        assert_eq!(state.legacy_users.get("user1@example.com").unwrap().bookings.len(), 2);
+        assert_eq!(state.legacy_wishlist.len(), 1);
+        assert_eq!(state.legacy_payment_id_index.as_ref().unwrap().len(), 1);
+        assert_eq!(state.legacy_user_principal_email_index.len(), 1);
+    }
+
+    #[test]
+    fn test_stable_storage_migration_validate_failure_leftover_heap() {
+        let migration = MoveToStableStorageMigration;
+        let state = create_legacy_state();
+
+        // Don't apply migration, heap data is still there
+        let result = migration.validate(&state);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("heap data left behind"));
+    }
+
+    #[test]
+    fn test_legacy_snapshot_deserializes_into_legacy_fields() {
+        // CBOR written by the pre-1003 `pre_upgrade` has the maps under their old names
+        #[derive(serde::Serialize)]
+        struct OldCanisterState {
+            users: BTreeMap<String, UserInfoAndBookings>,
+            wishlist: BTreeMap<String, Vec<HotelId>>,
+            email_sent: Option<EmailSentStruct>,
+            payment_id_index: Option<BTreeMap<String, BookingId>>,
+        }
+
+        let legacy = create_legacy_state();
+        let old_state = OldCanisterState {
+            users: legacy.legacy_users.clone(),
+            wishlist: legacy.legacy_wishlist.clone(),
+            email_sent: None,
+            payment_id_index: legacy.legacy_payment_id_index.clone(),
+        };
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&old_state, &mut bytes).unwrap();
+        let restored: CanisterState = ciborium::de::from_reader(&*bytes).unwrap();
+
+        assert_eq!(restored.legacy_users.len(), 2);
+        assert_eq!(restored.legacy_wishlist.len(), 1);
+        assert_eq!(restored.legacy_payment_id_index.unwrap().len(), 1);
+    }
+}
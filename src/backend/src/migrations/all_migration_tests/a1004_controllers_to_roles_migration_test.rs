@@ -0,0 +1,92 @@
+mod controllers_to_roles_tests {
+    use crate::migration::Migration;
+    use crate::migrations::ControllersToRolesMigration;
+    use crate::models::*;
+    use crate::roles::Role;
+    use candid::Principal;
+
+    fn create_principal_1() -> Principal {
+        Principal::from_text("znxnh-f2v3a-dwwtd-jhyr2-tzdg7-xrm43-xf6xc-q4nfk-arhfb-54k5n-zae").unwrap()
+    }
+
+    fn create_principal_2() -> Principal {
+        Principal::from_text("be2us-64aaa-aaaaa-qaabq-cai").unwrap()
+    }
+
+    fn create_test_state() -> CanisterState {
+        let mut state = CanisterState::new();
+        state.controllers = Some(vec![create_principal_1(), create_principal_2()]);
+        state
+    }
+
+    #[test]
+    fn test_controllers_to_roles_migration_version() {
+        let migration = ControllersToRolesMigration;
+        assert_eq!(migration.version(), 1004);
+    }
+
+    #[test]
+    fn test_controllers_to_roles_migration_up() {
+        let migration = ControllersToRolesMigration;
+        let mut state = create_test_state();
+
+        let result = migration.migrate_up(&mut state);
+        assert!(result.is_ok());
+
+        assert!(state.controllers.is_none());
+        assert!(state.has_role(&create_principal_1(), Role::Admin));
+        assert!(state.has_role(&create_principal_2(), Role::Admin));
+        assert_eq!(state.roles.len(), 2);
+        assert!(migration.validate(&state).is_ok());
+    }
+
+    #[test]
+    fn test_controllers_to_roles_migration_up_no_controllers() {
+        let migration = ControllersToRolesMigration;
+        let mut state = CanisterState::new();
+
+        let result = migration.migrate_up(&mut state);
+        assert!(result.is_ok());
+        assert!(state.roles.is_empty());
+        assert!(migration.validate(&state).is_ok());
+    }
+
+    #[test]
+    fn test_controllers_to_roles_migration_down() {
+        let migration = ControllersToRolesMigration;
+        let mut state = create_test_state();
+
+        let _ = migration.migrate_up(&mut state);
+        let result = migration.migrate_down(&mut state);
+        assert!(result.is_ok());
+
+        assert!(state.roles.is_empty());
+        let controllers = state.controllers.unwrap();
+        assert_eq!(controllers.len(), 2);
+        assert!(controllers.contains(&create_principal_1()));
+        assert!(controllers.contains(&create_principal_2()));
+    }
+
+    #[test]
+    fn test_controllers_to_roles_migration_down_refuses_other_roles() {
+        let migration = ControllersToRolesMigration;
+        let mut state = create_test_state();
+
+        let _ = migration.migrate_up(&mut state);
+        state.grant_role(create_principal_2(), Role::PaymentService).unwrap();
+
+        let result = migration.migrate_down(&mut state);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("revoke them first"));
+    }
+
+    #[test]
+    fn test_controllers_to_roles_migration_validate_failure() {
+        let migration = ControllersToRolesMigration;
+        let state = create_test_state();
+
+        // Don't apply migration, controllers list is still there
+        let result = migration.validate(&state);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,142 @@
+#[allow(deprecated)]
+mod payment_status_migration_tests {
+    use crate::migration::Migration;
+    use crate::migrations::{convert_legacy_status, to_legacy_status, PaymentStatusMigration};
+    use crate::models::*;
+    use crate::test_utils::{create_valid_booking, usd};
+    use crate::timeline::{BookingEvent, BookingEventKind, BookingTimeline};
+    use candid::Principal;
+
+    fn unpaid(trans_ref: &str) -> BackendPaymentStatus {
+        BackendPaymentStatus::Unpaid(Some(trans_ref.to_string()))
+    }
+
+    fn create_test_state(statuses: &[(&str, BackendPaymentStatus)]) -> CanisterState {
+        let mut state = CanisterState::new();
+        for (app_ref, status) in statuses {
+            let mut booking = create_valid_booking(app_ref, "user@example.com");
+            booking.payment_details.payment_status = status.clone();
+            booking.payment_details.payment_api_response.price_amount = 400;
+            state.bookings.insert(booking.booking_id.clone(), booking);
+        }
+        state
+    }
+
+    fn status_of(state: &CanisterState, app_ref: &str) -> BackendPaymentStatus {
+        let booking_id = BookingId::new(app_ref.to_string(), "user@example.com".to_string());
+        state.bookings.get(&booking_id).unwrap().payment_details.payment_status
+    }
+
+    #[test]
+    fn test_payment_status_migration_version() {
+        assert_eq!(PaymentStatusMigration.version(), 1005);
+    }
+
+    #[test]
+    fn test_convert_legacy_status() {
+        use BackendPaymentStatus::*;
+
+        let cases = vec![
+            (Paid("pay_1 - COMPLETED".to_string()), Paid("pay_1".to_string())),
+            (Paid("manual-ref".to_string()), Paid("manual-ref".to_string())),
+            (Unpaid(None), Pending(None)),
+            (unpaid("pay_1 - WAITING"), Pending(Some("pay_1".to_string()))),
+            (unpaid("pay_1 - CONFIRMING"), Confirming("pay_1".to_string())),
+            (unpaid("pay_1 - PARTIALLY_PAID"), PartiallyPaid("pay_1".to_string())),
+            (unpaid("pay_1 - FAILED"), Failed("pay_1".to_string())),
+            (unpaid("pay_1 - EXPIRED"), Expired("pay_1".to_string())),
+            (unpaid("pay_1 - CANCELLED"), Cancelled("pay_1".to_string())),
+            (
+                unpaid("pay_1 - REFUNDED"),
+                Refunded {
+                    amount: usd(40000),
+                    reference: "pay_1".to_string(),
+                },
+            ),
+            // ids may contain " - " themselves, the status is the last part
+            (unpaid("a - b - FAILED"), Failed("a - b".to_string())),
+            (unpaid("card declined"), Failed("card declined".to_string())),
+        ];
+
+        for (legacy, expected) in cases {
+            assert_eq!(convert_legacy_status(legacy.clone(), usd(40000)), expected, "{:?}", legacy);
+        }
+    }
+
+    #[test]
+    fn test_legacy_round_trip() {
+        use BackendPaymentStatus::*;
+
+        for status in [
+            Pending(None),
+            Pending(Some("pay_1".to_string())),
+            Confirming("pay_1".to_string()),
+            PartiallyPaid("pay_1".to_string()),
+            Paid("pay_1".to_string()),
+            Failed("pay_1".to_string()),
+            Expired("pay_1".to_string()),
+            Cancelled("pay_1".to_string()),
+            RefundPending("pay_1".to_string()),
+        ] {
+            let legacy = to_legacy_status(status.clone());
+            assert_eq!(convert_legacy_status(legacy, Money::default()), status);
+        }
+    }
+
+    #[test]
+    fn test_payment_status_migration_up_and_down() {
+        let migration = PaymentStatusMigration;
+        let mut state = create_test_state(&[
+            ("APP001", BackendPaymentStatus::Paid("pay_1 - COMPLETED".to_string())),
+            ("APP002", unpaid("pay_2 - WAITING")),
+            ("APP003", BackendPaymentStatus::Unpaid(None)),
+        ]);
+        assert!(migration.validate(&state).is_err());
+
+        migration.migrate_up(&mut state).unwrap();
+        assert!(migration.validate(&state).is_ok());
+        assert_eq!(status_of(&state, "APP001"), BackendPaymentStatus::Paid("pay_1".to_string()));
+        assert_eq!(
+            status_of(&state, "APP002"),
+            BackendPaymentStatus::Pending(Some("pay_2".to_string()))
+        );
+        assert_eq!(status_of(&state, "APP003"), BackendPaymentStatus::Pending(None));
+
+        migration.migrate_down(&mut state).unwrap();
+        assert_eq!(
+            status_of(&state, "APP001"),
+            BackendPaymentStatus::Paid("pay_1 - COMPLETED".to_string())
+        );
+        assert_eq!(status_of(&state, "APP002"), unpaid("pay_2 - WAITING"));
+        assert_eq!(status_of(&state, "APP003"), BackendPaymentStatus::Unpaid(None));
+    }
+
+    #[test]
+    fn test_payment_status_migration_converts_timelines() {
+        let migration = PaymentStatusMigration;
+        let mut state = create_test_state(&[("APP001", unpaid("pay_1 - FAILED"))]);
+        let booking_id = BookingId::new("APP001".to_string(), "user@example.com".to_string());
+        state.booking_timelines.insert(
+            booking_id.clone(),
+            BookingTimeline(vec![BookingEvent {
+                timestamp: 0,
+                caller: Principal::anonymous(),
+                kind: BookingEventKind::PaymentStatusChanged {
+                    from: BackendPaymentStatus::Unpaid(None),
+                    to: unpaid("pay_1 - FAILED"),
+                },
+            }]),
+        );
+
+        migration.migrate_up(&mut state).unwrap();
+
+        let timeline = state.booking_timelines.get(&booking_id).unwrap();
+        assert_eq!(
+            timeline.0[0].kind,
+            BookingEventKind::PaymentStatusChanged {
+                from: BackendPaymentStatus::Pending(None),
+                to: BackendPaymentStatus::Failed("pay_1".to_string()),
+            }
+        );
+    }
+}
@@ -0,0 +1,254 @@
+#[allow(deprecated)]
+mod money_migration_tests {
+    use crate::memory::to_cbor_bytes;
+    use crate::migration::Migration;
+    use crate::migrations::MoneyMigration;
+    use crate::models::*;
+    use crate::payment_events::{PaymentEventLog, PaymentEventOutcome, PaymentEventRecord};
+    use crate::test_utils::create_valid_booking;
+    use crate::timeline::{BookingEvent, BookingEventKind, BookingTimeline};
+    use candid::Principal;
+    use ic_stable_structures::Storable;
+    use serde::Serialize;
+    use serde_json::json;
+
+    const EMAIL: &str = "user@example.com";
+
+    fn money(amount_minor: i128, currency: &str) -> Money {
+        Money::new(amount_minor, CurrencyCode::new(currency).unwrap())
+    }
+
+    /// A booking as stored before 1006: only the float amounts are set
+    fn legacy_booking(app_ref: &str, requested: f64, room_price: f32, price_currency: &str) -> Booking {
+        let mut booking = create_valid_booking(app_ref, EMAIL);
+        let details = &mut booking.user_selected_hotel_room_details;
+        details.requested_payment = None;
+        details.requested_payment_amount = requested;
+        details.room_details[0].price = None;
+        details.room_details[0].room_price = room_price;
+        let response = &mut booking.payment_details.payment_api_response;
+        response.payment_id_v2 = format!("pay_{}", app_ref);
+        response.price_amount = requested.round() as u64;
+        response.price_currency = price_currency.to_string();
+        booking
+    }
+
+    fn migrated(state: &CanisterState, app_ref: &str) -> Booking {
+        let booking_id = BookingId::new(app_ref.to_string(), EMAIL.to_string());
+        state.bookings.get(&booking_id).unwrap()
+    }
+
+    #[test]
+    fn test_money_migration_version() {
+        assert_eq!(MoneyMigration.version(), 1006);
+        assert!(!MoneyMigration.is_reversible());
+        assert!(MoneyMigration.migrate_down(&mut CanisterState::new()).is_err());
+    }
+
+    #[test]
+    fn test_amounts_are_converted_in_the_payment_currency() {
+        let mut state = CanisterState::new();
+
+        let mut partially_paid = legacy_booking("APP001", 400.0, 99.99, "eur");
+        let response = &mut partially_paid.payment_details.payment_api_response;
+        response.pay_amount = 0.1;
+        response.actually_paid = 0.09;
+        partially_paid.payment_details.payment_status =
+            BackendPaymentStatus::PartiallyPaid("pay_APP001".to_string());
+        let yen = legacy_booking("APP002", 52000.0, 13000.0, "JPY");
+        // nothing to go by but the default
+        let unpriced = legacy_booking("APP003", 19.999, 5.0, "");
+        for booking in [partially_paid, yen, unpriced] {
+            state.bookings.insert(booking.booking_id.clone(), booking);
+        }
+
+        MoneyMigration.migrate_up(&mut state).unwrap();
+        MoneyMigration.validate(&state).unwrap();
+
+        let booking = migrated(&state, "APP001");
+        let details = &booking.user_selected_hotel_room_details;
+        assert_eq!(details.requested_payment, Some(money(40000, "EUR")));
+        // the f32 was 99.98999786...
+        assert_eq!(details.room_details[0].price, Some(money(9999, "EUR")));
+        let response = &booking.payment_details.payment_api_response;
+        assert_eq!(response.price, Some(money(40000, "EUR")));
+        assert_eq!(response.amount_paid, Some(money(36000, "EUR")));
+        // the mismatch is derived again from the converted amounts
+        let mismatch = booking.payment_mismatch.unwrap();
+        assert_eq!(mismatch.kind, PaymentMismatchKind::Underpaid);
+        assert_eq!(mismatch.delta, money(-4000, "EUR"));
+
+        let booking = migrated(&state, "APP002");
+        assert_eq!(booking.get_requested_payment(), money(52000, "JPY"));
+        assert_eq!(booking.payment_details.payment_api_response.price, Some(money(52000, "JPY")));
+        // no amounts reported beyond the price
+        assert_eq!(booking.payment_details.payment_api_response.amount_paid, None);
+
+        let booking = migrated(&state, "APP003");
+        assert_eq!(booking.get_requested_payment(), money(2000, "USD"));
+        assert_eq!(booking.user_selected_hotel_room_details.room_details[0].price, Some(money(500, "USD")));
+    }
+
+    #[test]
+    fn test_revisions_timeline_and_payment_events_are_converted() {
+        let mut state = CanisterState::new();
+        // amended from 400 to 600 and then to 500
+        let booking = legacy_booking("APP001", 500.0, 100.0, "usd");
+        let booking_id = booking.booking_id.clone();
+        state.bookings.insert(booking_id.clone(), booking.clone());
+
+        let revisions = [(1, 400.0), (2, 600.0)].map(|(revision, requested)| {
+            let mut details = booking.user_selected_hotel_room_details.clone();
+            details.requested_payment_amount = requested;
+            BookingRevision {
+                revision,
+                amended_at: revision as u64,
+                guests: booking.guests.clone(),
+                user_selected_hotel_room_details: details,
+                price_delta: Money::default(),
+            }
+        });
+        state.booking_revisions.insert(booking_id.clone(), BookingRevisions(revisions.to_vec()));
+        let events = (1..=2)
+            .map(|revision| BookingEvent {
+                timestamp: revision as u64,
+                caller: Principal::anonymous(),
+                kind: BookingEventKind::Amended {
+                    revision,
+                    price_delta: Money::default(),
+                },
+            })
+            .collect();
+        state.booking_timelines.insert(booking_id.clone(), BookingTimeline(events));
+        let mut response = BEPaymentApiResponse {
+            provider: "NowPayments".to_string(),
+            payment_id_v2: "pay_1".to_string(),
+            ..Default::default()
+        };
+        response.price_amount = 500;
+        response.price_currency = "usd".to_string();
+        response.actually_paid = 250.5;
+        state.payment_events.insert(
+            booking_id.clone(),
+            PaymentEventLog(vec![PaymentEventRecord {
+                received_at: 1,
+                updated_at_ns: 1,
+                outcome: PaymentEventOutcome::Applied,
+                payment_api_response: response,
+            }]),
+        );
+
+        MoneyMigration.migrate_up(&mut state).unwrap();
+
+        let revisions = state.booking_revisions.get(&booking_id).unwrap().0;
+        assert_eq!(revisions[0].user_selected_hotel_room_details.requested_payment, Some(money(40000, "USD")));
+        assert_eq!(revisions[0].price_delta, money(20000, "USD"));
+        assert_eq!(revisions[1].price_delta, money(-10000, "USD"));
+        let deltas: Vec<Money> = state
+            .booking_timelines
+            .get(&booking_id)
+            .unwrap()
+            .0
+            .into_iter()
+            .filter_map(|event| match event.kind {
+                BookingEventKind::Amended { price_delta, .. } => Some(price_delta),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deltas, vec![money(20000, "USD"), money(-10000, "USD")]);
+
+        let log = state.payment_events.get(&booking_id).unwrap().0;
+        assert_eq!(log[0].payment_api_response.price, Some(money(50000, "USD")));
+        assert_eq!(log[0].payment_api_response.amount_paid, Some(money(25050, "USD")));
+    }
+
+    #[test]
+    fn test_float_refunds_are_moved_into_the_booking_currency() {
+        let mut state = CanisterState::new();
+        let mut stored = serde_json::to_value(legacy_booking("APP001", 400.0, 100.0, "eur")).unwrap();
+        stored["payment_details"]["payment_status"] =
+            json!({ "Refunded": { "amount": 400.0, "reference": "re_1" } });
+        let booking = Booking::from_bytes(to_cbor_bytes(&stored));
+        let booking_id = booking.booking_id.clone();
+        // read as USD, the only currency there was
+        let refunded = |amount| BackendPaymentStatus::Refunded {
+            amount,
+            reference: "re_1".to_string(),
+        };
+        assert_eq!(booking.payment_details.payment_status, refunded(money(40000, "USD")));
+        let event = BookingEvent {
+            timestamp: 1,
+            caller: Principal::anonymous(),
+            kind: BookingEventKind::PaymentStatusChanged {
+                from: BackendPaymentStatus::RefundPending("pay_APP001".to_string()),
+                to: booking.payment_details.payment_status.clone(),
+            },
+        };
+        state.bookings.insert(booking_id.clone(), booking);
+        state
+            .booking_timelines
+            .insert(booking_id.clone(), BookingTimeline(vec![event]));
+
+        MoneyMigration.migrate_up(&mut state).unwrap();
+
+        let booking = migrated(&state, "APP001");
+        assert_eq!(booking.payment_details.payment_status, refunded(money(40000, "EUR")));
+        let timeline = state.booking_timelines.get(&booking_id).unwrap();
+        assert!(matches!(
+            &timeline.0[0].kind,
+            BookingEventKind::PaymentStatusChanged { to, .. } if to == &refunded(money(40000, "EUR"))
+        ));
+    }
+
+    #[test]
+    fn test_float_mismatch_and_deltas_read_as_default() {
+        let booking = create_valid_booking("APP001", EMAIL);
+        let mut stored = serde_json::to_value(&booking).unwrap();
+        stored["payment_mismatch"] = json!({
+            "kind": "Underpaid", "requested_amount": 400.0, "paid_amount": 360.0, "delta": -40.0
+        });
+
+        let read = Booking::from_bytes(to_cbor_bytes(&stored));
+        assert_eq!(read.payment_mismatch, None);
+        assert_eq!(read.get_requested_payment(), booking.get_requested_payment());
+
+        #[derive(Serialize)]
+        enum LegacyEventKind {
+            Amended { revision: u32, price_delta: f64 },
+        }
+        #[derive(Serialize)]
+        struct LegacyEvent {
+            timestamp: u64,
+            caller: Principal,
+            kind: LegacyEventKind,
+        }
+        let event = LegacyEvent {
+            timestamp: 1,
+            caller: Principal::anonymous(),
+            kind: LegacyEventKind::Amended {
+                revision: 1,
+                price_delta: 200.0,
+            },
+        };
+        let read = BookingTimeline::from_bytes(to_cbor_bytes(&vec![event]));
+        assert_eq!(
+            read.0[0].kind,
+            BookingEventKind::Amended {
+                revision: 1,
+                price_delta: Money::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_unknown_currency() {
+        let mut state = CanisterState::new();
+        let mut booking = create_valid_booking("APP001", EMAIL);
+        booking.payment_details.payment_api_response.price =
+            Some(Money::new(1, serde_json::from_str("\"ETH\"").unwrap()));
+        state.bookings.insert(booking.booking_id.clone(), booking);
+
+        let result = MoneyMigration.validate(&state);
+        assert!(result.unwrap_err().contains("unknown currency 'ETH'"));
+    }
+}
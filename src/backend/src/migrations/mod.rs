@@ -2,10 +2,22 @@ pub mod a1001_payment_id_v2_migration;
 pub use a1001_payment_id_v2_migration::*;
 pub mod a1002_default_controllers_migration;
 pub use a1002_default_controllers_migration::*;
+pub mod a1003_stable_storage_migration;
+pub use a1003_stable_storage_migration::*;
+pub mod a1004_controllers_to_roles_migration;
+pub use a1004_controllers_to_roles_migration::*;
+pub mod a1005_payment_status_migration;
+pub use a1005_payment_status_migration::*;
+pub mod a1006_money_migration;
+pub use a1006_money_migration::*;
 
 
 #[cfg(test)]
 mod all_migration_tests{
     pub mod a1001_payment_id_v2_tests;
     pub mod a1002_default_controllers_migration_test;
+    pub mod a1003_stable_storage_migration_test;
+    pub mod a1004_controllers_to_roles_migration_test;
+    pub mod a1005_payment_status_migration_test;
+    pub mod a1006_money_migration_test;
 }
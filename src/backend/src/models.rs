@@ -1,9 +1,23 @@
 use candid::{CandidType, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
 use serde::{Deserialize, Serialize};
 use serde_json_any_key::any_key_map;
-use std::collections::BTreeMap;
-
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem::take;
+
+use crate::audit::AuditEntry;
+use crate::controller::{ControllerAuditEntry, PendingControllerRemoval};
+use crate::errors::{BackendError, BackendResult};
+use crate::ledger_payments::LedgerConfig;
+use crate::memory::{
+    self, from_cbor_bytes, replace_stable_map, stable_map_to_btree, to_cbor_bytes,
+    try_from_cbor_bytes, MapKey, MapMemory, MapValue, StableMap,
+};
 use crate::migration::SchemaMetadata;
+use crate::payment_events::PaymentEventLog;
+use crate::roles::Role;
+use crate::timeline::{BookingEventKind, BookingTimeline};
 
 pub mod payment_details;
 pub use payment_details::*;
@@ -14,35 +28,164 @@ pub use user_details::*;
 pub mod booking_details;
 pub use booking_details::*;
 
+pub mod booking_amendment;
+pub use booking_amendment::*;
+
+pub mod money;
+pub use money::*;
+
+pub mod payment_mismatch;
+pub use payment_mismatch::*;
+
 pub mod greet;
 pub use greet::*;
 
 // mod booking_state;
 // // pub use booking_state::*;
 
-#[derive(CandidType, Deserialize, Default, Serialize, Clone, Debug)]
+fn init_users_map() -> StableMap<UserEmail, UserInfoAndBookings> {
+    StableMap::init("users", memory::get_users_memory())
+}
+
+fn init_bookings_map() -> StableMap<BookingId, Booking> {
+    StableMap::init("bookings", memory::get_bookings_memory())
+}
+
+fn init_wishlist_map() -> StableMap<UserEmail, Wishlist> {
+    StableMap::init("wishlist", memory::get_wishlist_memory())
+}
+
+fn init_payment_id_index_map() -> StableMap<String, BookingId> {
+    StableMap::init("payment_id_index", memory::get_payment_id_index_memory())
+}
+
+fn init_user_principal_email_index_map() -> StableMap<Principal, UserEmail> {
+    StableMap::init("user_principal_email_index", memory::get_user_principal_email_index_memory())
+}
+
+fn init_audit_log_map() -> StableMap<u64, AuditEntry> {
+    StableMap::init("audit_log", memory::get_audit_log_memory())
+}
+
+fn init_booking_timelines_map() -> StableMap<BookingId, BookingTimeline> {
+    StableMap::init("booking_timelines", memory::get_booking_timelines_memory())
+}
+
+fn init_booking_revisions_map() -> StableMap<BookingId, BookingRevisions> {
+    StableMap::init("booking_revisions", memory::get_booking_revisions_memory())
+}
+
+fn init_payment_events_map() -> StableMap<BookingId, PaymentEventLog> {
+    StableMap::init("payment_events", memory::get_payment_events_memory())
+}
+
+/// Users, bookings with their timelines, revisions and payment events, wishlists, the two
+/// indexes and the audit log live in stable memory and are skipped by serde, so `pre_upgrade`
+/// only serializes the small remainder.
+#[derive(Deserialize, Serialize)]
 pub struct CanisterState {
-    // Map from email watever to UserInfoAndBookings
-    // #[serde(skip, default = "_default_slot_details_map")]
-    // pub users:
-    pub users: BTreeMap<String, UserInfoAndBookings>,
-    #[serde(default)]
-    pub wishlist: BTreeMap<String, Vec<HotelId>>,
+    // Map from email to the user's profile.
+    // bookings are NOT nested here, see `bookings`
+    #[serde(skip, default = "init_users_map")]
+    pub users: StableMap<UserEmail, UserInfoAndBookings>,
+    #[serde(skip, default = "init_bookings_map")]
+    pub bookings: StableMap<BookingId, Booking>,
+    // Event history of each booking, see `timeline.rs`
+    #[serde(skip, default = "init_booking_timelines_map")]
+    pub booking_timelines: StableMap<BookingId, BookingTimeline>,
+    // Earlier versions of amended bookings, see `booking_amendment.rs`
+    #[serde(skip, default = "init_booking_revisions_map")]
+    pub booking_revisions: StableMap<BookingId, BookingRevisions>,
+    // Payment provider callbacks received for each booking, see `payment_events.rs`
+    #[serde(skip, default = "init_payment_events_map")]
+    pub payment_events: StableMap<BookingId, PaymentEventLog>,
+    #[serde(skip, default = "init_wishlist_map")]
+    pub wishlist: StableMap<UserEmail, Wishlist>,
     pub email_sent: Option<EmailSentStruct>,
+    // Flat admin list from before migration 1004, which moves it into `roles`
     #[serde(default)]
     pub controllers: Option<Vec<Principal>>,
-    // Index for payment_id_v2 -> booking_id mapping (String format)
     #[serde(default)]
-    pub payment_id_index: Option<BTreeMap<String, BookingId>>,
+    pub roles: BTreeMap<Principal, BTreeSet<Role>>,
+    // Controller removals waiting for confirmation, and who changed the controllers when
+    #[serde(default)]
+    pub pending_controller_removals: BTreeMap<Principal, PendingControllerRemoval>,
+    #[serde(default)]
+    pub controller_audit_log: Vec<ControllerAuditEntry>,
+    // Index for payment_id_v2 -> booking_id mapping (String format)
+    #[serde(skip, default = "init_payment_id_index_map")]
+    pub payment_id_index: StableMap<String, BookingId>,
     // Schema evolution metadata
     #[serde(default)]
     pub schema_metadata: SchemaMetadata,
+    // How far a payment may be off before the booking is flagged, see `payment_mismatch.rs`
+    #[serde(default)]
+    pub payment_tolerance: PaymentTolerance,
+    // Ledgers bookings can be paid on directly, keyed by lowercased token, see `ledger_payments.rs`
+    #[serde(default)]
+    pub payment_ledgers: BTreeMap<String, LedgerConfig>,
 
     // Index for principal -> email mapping
+    #[serde(skip, default = "init_user_principal_email_index_map")]
+    pub user_principal_email_index: StableMap<Principal, UserEmail>,
+
+    // Per-provider webhook signing secrets, keyed by lowercased provider, see `webhooks.rs`.
+    // Not part of `StateSnapshot`, so state exports never contain them.
+    #[serde(default)]
+    pub webhook_secrets: BTreeMap<String, String>,
+
+    // Append-only, see `audit.rs`. Not part of `StateSnapshot`, so restoring a snapshot
+    // (failed migration, import) never rewrites history.
+    #[serde(skip, default = "init_audit_log_map")]
+    pub audit_log: StableMap<u64, AuditEntry>,
+
+    // Which set of virtual memories the maps above (except the audit log) live in.
+    // `commit_import` switches it, see `backup.rs`.
     #[serde(default)]
-    pub user_principal_email_index: BTreeMap<Principal, String>,
+    pub map_bank: MapBank,
+
+    // Heap copies from before migration 1003 (MoveToStableStorageMigration).
+    // They are only read from old upgrade snapshots and are drained by that migration.
+    #[serde(default, rename = "users", skip_serializing_if = "BTreeMap::is_empty")]
+    pub legacy_users: BTreeMap<String, UserInfoAndBookings>,
+    #[serde(default, rename = "wishlist", skip_serializing_if = "BTreeMap::is_empty")]
+    pub legacy_wishlist: BTreeMap<String, Vec<HotelId>>,
+    #[serde(default, rename = "payment_id_index", skip_serializing_if = "Option::is_none")]
+    pub legacy_payment_id_index: Option<BTreeMap<String, BookingId>>,
+    #[serde(
+        default,
+        rename = "user_principal_email_index",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub legacy_user_principal_email_index: BTreeMap<Principal, String>,
+}
+
+impl Default for CanisterState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hotels wishlisted by one user
+#[derive(CandidType, Deserialize, Default, Serialize, Clone, Debug)]
+pub struct Wishlist(pub Vec<HotelId>);
+
+impl Storable for Wishlist {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        to_cbor_bytes(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        from_cbor_bytes(bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
 
-    
+impl MapValue for Wishlist {
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        try_from_cbor_bytes(bytes)
+    }
 }
 
 #[derive(CandidType, Deserialize, Default, Serialize, Clone, Debug)]
@@ -52,10 +195,10 @@ pub struct EmailSentStruct {
 }
 
 impl EmailSentStruct {
-    pub fn update_email_sent(&mut self, booking_id: BookingId, sent: bool) -> Result<(), String> {
+    pub fn update_email_sent(&mut self, booking_id: BookingId, sent: bool) -> BackendResult<()> {
         // check if the status is already set to true.
         if self.email_sent.contains_key(&booking_id) {
-            return Err("Email already sent".to_string());
+            return Err(BackendError::Conflict("Email already sent".to_string()));
         }
         self.email_sent.insert(booking_id, sent);
         Ok(())
@@ -71,45 +214,384 @@ impl EmailSentStruct {
     }
 }
 
+/// A stable map of `CanisterState`. Migrations name the ones they change, so that only
+/// those are copied to the heap as a backup, see `Migration::changed_maps`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StateMap {
+    Users,
+    Bookings,
+    BookingTimelines,
+    BookingRevisions,
+    PaymentEvents,
+    Wishlist,
+    PaymentIdIndex,
+    UserPrincipalEmailIndex,
+}
+
+impl StateMap {
+    pub const ALL: [StateMap; 8] = [
+        StateMap::Users,
+        StateMap::Bookings,
+        StateMap::BookingTimelines,
+        StateMap::BookingRevisions,
+        StateMap::PaymentEvents,
+        StateMap::Wishlist,
+        StateMap::PaymentIdIndex,
+        StateMap::UserPrincipalEmailIndex,
+    ];
+
+    /// Name of the `CanisterState` field holding this map
+    pub fn name(self) -> &'static str {
+        match self {
+            StateMap::Users => "users",
+            StateMap::Bookings => "bookings",
+            StateMap::BookingTimelines => "booking_timelines",
+            StateMap::BookingRevisions => "booking_revisions",
+            StateMap::PaymentEvents => "payment_events",
+            StateMap::Wishlist => "wishlist",
+            StateMap::PaymentIdIndex => "payment_id_index",
+            StateMap::UserPrincipalEmailIndex => "user_principal_email_index",
+        }
+    }
+
+    /// The canister's virtual memory holding this map in `bank`
+    pub fn memory(self, bank: MapBank) -> MapMemory {
+        match (self, bank) {
+            (StateMap::Users, MapBank::Primary) => memory::get_users_memory(),
+            (StateMap::Bookings, MapBank::Primary) => memory::get_bookings_memory(),
+            (StateMap::BookingTimelines, MapBank::Primary) => {
+                memory::get_booking_timelines_memory()
+            }
+            (StateMap::BookingRevisions, MapBank::Primary) => {
+                memory::get_booking_revisions_memory()
+            }
+            (StateMap::PaymentEvents, MapBank::Primary) => memory::get_payment_events_memory(),
+            (StateMap::Wishlist, MapBank::Primary) => memory::get_wishlist_memory(),
+            (StateMap::PaymentIdIndex, MapBank::Primary) => memory::get_payment_id_index_memory(),
+            (StateMap::UserPrincipalEmailIndex, MapBank::Primary) => {
+                memory::get_user_principal_email_index_memory()
+            }
+            (StateMap::Users, MapBank::Secondary) => memory::get_users_secondary_memory(),
+            (StateMap::Bookings, MapBank::Secondary) => memory::get_bookings_secondary_memory(),
+            (StateMap::BookingTimelines, MapBank::Secondary) => {
+                memory::get_booking_timelines_secondary_memory()
+            }
+            (StateMap::BookingRevisions, MapBank::Secondary) => {
+                memory::get_booking_revisions_secondary_memory()
+            }
+            (StateMap::PaymentEvents, MapBank::Secondary) => {
+                memory::get_payment_events_secondary_memory()
+            }
+            (StateMap::Wishlist, MapBank::Secondary) => memory::get_wishlist_secondary_memory(),
+            (StateMap::PaymentIdIndex, MapBank::Secondary) => {
+                memory::get_payment_id_index_secondary_memory()
+            }
+            (StateMap::UserPrincipalEmailIndex, MapBank::Secondary) => {
+                memory::get_user_principal_email_index_secondary_memory()
+            }
+        }
+    }
+}
+
+/// One of the two sets of virtual memories the maps of `StateMap` can live in
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MapBank {
+    #[default]
+    Primary,
+    Secondary,
+}
+
+impl MapBank {
+    pub fn other(self) -> Self {
+        match self {
+            MapBank::Primary => MapBank::Secondary,
+            MapBank::Secondary => MapBank::Primary,
+        }
+    }
+}
+
+fn open_map<K, V>(map: StateMap, bank: MapBank) -> StableMap<K, V>
+where
+    K: MapKey,
+    V: MapValue,
+{
+    StableMap::init(map.name(), map.memory(bank))
+}
+
+/// Heap copy of a whole `CanisterState`, stable maps included.
+/// Used for state export/import and as a backup while state is being rewritten.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct StateSnapshot {
+    pub users: BTreeMap<UserEmail, UserInfoAndBookings>,
+    pub bookings: BTreeMap<BookingId, Booking>,
+    #[serde(default)]
+    pub booking_timelines: BTreeMap<BookingId, BookingTimeline>,
+    #[serde(default)]
+    pub booking_revisions: BTreeMap<BookingId, BookingRevisions>,
+    #[serde(default)]
+    pub payment_events: BTreeMap<BookingId, PaymentEventLog>,
+    pub wishlist: BTreeMap<UserEmail, Wishlist>,
+    pub email_sent: Option<EmailSentStruct>,
+    pub controllers: Option<Vec<Principal>>,
+    #[serde(default)]
+    pub roles: BTreeMap<Principal, BTreeSet<Role>>,
+    #[serde(default)]
+    pub pending_controller_removals: BTreeMap<Principal, PendingControllerRemoval>,
+    #[serde(default)]
+    pub controller_audit_log: Vec<ControllerAuditEntry>,
+    pub payment_id_index: BTreeMap<String, BookingId>,
+    pub schema_metadata: SchemaMetadata,
+    #[serde(default)]
+    pub payment_tolerance: PaymentTolerance,
+    #[serde(default)]
+    pub payment_ledgers: BTreeMap<String, LedgerConfig>,
+    pub user_principal_email_index: BTreeMap<Principal, UserEmail>,
+    // only non-empty when the state is below migration 1003
+    #[serde(default)]
+    pub legacy_users: BTreeMap<String, UserInfoAndBookings>,
+    #[serde(default)]
+    pub legacy_wishlist: BTreeMap<String, Vec<HotelId>>,
+    #[serde(default)]
+    pub legacy_payment_id_index: Option<BTreeMap<String, BookingId>>,
+    #[serde(default)]
+    pub legacy_user_principal_email_index: BTreeMap<Principal, String>,
+}
+
 impl CanisterState {
     pub fn new() -> Self {
         Self {
-            wishlist: BTreeMap::new(),
-            users: BTreeMap::new(),
+            wishlist: init_wishlist_map(),
+            users: init_users_map(),
+            bookings: init_bookings_map(),
+            booking_timelines: init_booking_timelines_map(),
+            booking_revisions: init_booking_revisions_map(),
+            payment_events: init_payment_events_map(),
             email_sent: None,
             // ongoing_bookings: BTreeMap::new(),
-            controllers: None, 
-            payment_id_index: None,
+            controllers: None,
+            roles: BTreeMap::new(),
+            pending_controller_removals: BTreeMap::new(),
+            controller_audit_log: Vec::new(),
+            payment_id_index: init_payment_id_index_map(),
             schema_metadata: SchemaMetadata::default(),
-            user_principal_email_index: BTreeMap::new(),
+            payment_tolerance: PaymentTolerance::default(),
+            payment_ledgers: BTreeMap::new(),
+            user_principal_email_index: init_user_principal_email_index_map(),
+            webhook_secrets: BTreeMap::new(),
+            audit_log: init_audit_log_map(),
+            map_bank: MapBank::Primary,
+            legacy_users: BTreeMap::new(),
+            legacy_wishlist: BTreeMap::new(),
+            legacy_payment_id_index: None,
+            legacy_user_principal_email_index: BTreeMap::new(),
         }
     }
 
+    /// A copy of this state (without webhook secrets and audit log) for dry runs: nothing
+    /// done to it reaches stable memory. Only the stable maps in `maps` are copied to the
+    /// heap; the others are read in place from the canister's memory and trap if written.
+    pub fn scratch_copy(&self, maps: &[StateMap]) -> Self {
+        fn scratch_map<K, V>(map: StateMap, bank: MapBank, copied: &[StateMap]) -> StableMap<K, V>
+        where
+            K: MapKey,
+            V: MapValue,
+        {
+            if copied.contains(&map) {
+                StableMap::init(map.name(), MapMemory::scratch())
+            } else {
+                StableMap::init(map.name(), map.memory(bank).read_only())
+            }
+        }
+        let mut scratch = Self {
+            wishlist: scratch_map(StateMap::Wishlist, self.map_bank, maps),
+            users: scratch_map(StateMap::Users, self.map_bank, maps),
+            bookings: scratch_map(StateMap::Bookings, self.map_bank, maps),
+            booking_timelines: scratch_map(StateMap::BookingTimelines, self.map_bank, maps),
+            booking_revisions: scratch_map(StateMap::BookingRevisions, self.map_bank, maps),
+            payment_events: scratch_map(StateMap::PaymentEvents, self.map_bank, maps),
+            email_sent: None,
+            controllers: None,
+            roles: BTreeMap::new(),
+            pending_controller_removals: BTreeMap::new(),
+            controller_audit_log: Vec::new(),
+            payment_id_index: scratch_map(StateMap::PaymentIdIndex, self.map_bank, maps),
+            schema_metadata: SchemaMetadata::default(),
+            payment_tolerance: PaymentTolerance::default(),
+            payment_ledgers: BTreeMap::new(),
+            user_principal_email_index: scratch_map(StateMap::UserPrincipalEmailIndex, self.map_bank, maps),
+            webhook_secrets: BTreeMap::new(),
+            audit_log: StableMap::init("audit_log", MapMemory::scratch()),
+            map_bank: self.map_bank,
+            legacy_users: BTreeMap::new(),
+            legacy_wishlist: BTreeMap::new(),
+            legacy_payment_id_index: None,
+            legacy_user_principal_email_index: BTreeMap::new(),
+        };
+        scratch.restore_partial_snapshot(self.to_partial_snapshot(maps), maps);
+        scratch
+    }
+
+    /// An empty state whose maps live in `bank`, cleared first; its audit log is a scratch
+    /// buffer. Imports are staged in one of these, see `backup.rs`.
+    pub fn empty_in(bank: MapBank) -> Self {
+        let mut state = Self {
+            wishlist: open_map(StateMap::Wishlist, bank),
+            users: open_map(StateMap::Users, bank),
+            bookings: open_map(StateMap::Bookings, bank),
+            booking_timelines: open_map(StateMap::BookingTimelines, bank),
+            booking_revisions: open_map(StateMap::BookingRevisions, bank),
+            payment_events: open_map(StateMap::PaymentEvents, bank),
+            payment_id_index: open_map(StateMap::PaymentIdIndex, bank),
+            user_principal_email_index: open_map(StateMap::UserPrincipalEmailIndex, bank),
+            audit_log: StableMap::init("audit_log", MapMemory::scratch()),
+            map_bank: bank,
+            ..Self::new()
+        };
+        state.restore_snapshot(StateSnapshot::default());
+        state
+    }
+
+    /// Opens the maps in the memories of `map_bank`. Deserializing a state opens the primary
+    /// ones, so `post_upgrade` calls this in case an import switched banks.
+    pub fn open_maps(&mut self) {
+        let bank = self.map_bank;
+        self.wishlist = open_map(StateMap::Wishlist, bank);
+        self.users = open_map(StateMap::Users, bank);
+        self.bookings = open_map(StateMap::Bookings, bank);
+        self.booking_timelines = open_map(StateMap::BookingTimelines, bank);
+        self.booking_revisions = open_map(StateMap::BookingRevisions, bank);
+        self.payment_events = open_map(StateMap::PaymentEvents, bank);
+        self.payment_id_index = open_map(StateMap::PaymentIdIndex, bank);
+        self.user_principal_email_index = open_map(StateMap::UserPrincipalEmailIndex, bank);
+    }
+
+    pub fn to_snapshot(&self) -> StateSnapshot {
+        self.to_partial_snapshot(&StateMap::ALL)
+    }
+
+    /// Every heap field, but only the stable maps in `maps`; the others are left empty
+    pub fn to_partial_snapshot(&self, maps: &[StateMap]) -> StateSnapshot {
+        fn copy<K, V>(map: &StableMap<K, V>, wanted: bool) -> BTreeMap<K, V>
+        where
+            K: MapKey,
+            V: MapValue,
+        {
+            if wanted {
+                stable_map_to_btree(map)
+            } else {
+                BTreeMap::new()
+            }
+        }
+        let has = |map: StateMap| maps.contains(&map);
+
+        StateSnapshot {
+            users: copy(&self.users, has(StateMap::Users)),
+            bookings: copy(&self.bookings, has(StateMap::Bookings)),
+            booking_timelines: copy(&self.booking_timelines, has(StateMap::BookingTimelines)),
+            booking_revisions: copy(&self.booking_revisions, has(StateMap::BookingRevisions)),
+            payment_events: copy(&self.payment_events, has(StateMap::PaymentEvents)),
+            wishlist: copy(&self.wishlist, has(StateMap::Wishlist)),
+            email_sent: self.email_sent.clone(),
+            controllers: self.controllers.clone(),
+            roles: self.roles.clone(),
+            pending_controller_removals: self.pending_controller_removals.clone(),
+            controller_audit_log: self.controller_audit_log.clone(),
+            payment_id_index: copy(&self.payment_id_index, has(StateMap::PaymentIdIndex)),
+            schema_metadata: self.schema_metadata.clone(),
+            payment_tolerance: self.payment_tolerance.clone(),
+            payment_ledgers: self.payment_ledgers.clone(),
+            user_principal_email_index: copy(
+                &self.user_principal_email_index,
+                has(StateMap::UserPrincipalEmailIndex),
+            ),
+            legacy_users: self.legacy_users.clone(),
+            legacy_wishlist: self.legacy_wishlist.clone(),
+            legacy_payment_id_index: self.legacy_payment_id_index.clone(),
+            legacy_user_principal_email_index: self.legacy_user_principal_email_index.clone(),
+        }
+    }
+
+    /// Replaces everything in this state (stable maps included) with `snapshot`
+    pub fn restore_snapshot(&mut self, snapshot: StateSnapshot) {
+        self.restore_partial_snapshot(snapshot, &StateMap::ALL);
+    }
+
+    /// Counterpart of `to_partial_snapshot`: replaces every heap field, but only the stable
+    /// maps in `maps`
+    pub fn restore_partial_snapshot(&mut self, mut snapshot: StateSnapshot, maps: &[StateMap]) {
+        for map in StateMap::ALL.into_iter().filter(|map| maps.contains(map)) {
+            match map {
+                StateMap::Users => replace_stable_map(&mut self.users, take(&mut snapshot.users)),
+                StateMap::Bookings => {
+                    replace_stable_map(&mut self.bookings, take(&mut snapshot.bookings))
+                }
+                StateMap::BookingTimelines => replace_stable_map(
+                    &mut self.booking_timelines,
+                    take(&mut snapshot.booking_timelines),
+                ),
+                StateMap::BookingRevisions => replace_stable_map(
+                    &mut self.booking_revisions,
+                    take(&mut snapshot.booking_revisions),
+                ),
+                StateMap::PaymentEvents => {
+                    replace_stable_map(&mut self.payment_events, take(&mut snapshot.payment_events))
+                }
+                StateMap::Wishlist => {
+                    replace_stable_map(&mut self.wishlist, take(&mut snapshot.wishlist))
+                }
+                StateMap::PaymentIdIndex => replace_stable_map(
+                    &mut self.payment_id_index,
+                    take(&mut snapshot.payment_id_index),
+                ),
+                StateMap::UserPrincipalEmailIndex => replace_stable_map(
+                    &mut self.user_principal_email_index,
+                    take(&mut snapshot.user_principal_email_index),
+                ),
+            }
+        }
+
+        self.email_sent = snapshot.email_sent;
+        self.controllers = snapshot.controllers;
+        self.roles = snapshot.roles;
+        self.pending_controller_removals = snapshot.pending_controller_removals;
+        self.controller_audit_log = snapshot.controller_audit_log;
+        self.schema_metadata = snapshot.schema_metadata;
+        self.payment_tolerance = snapshot.payment_tolerance;
+        self.payment_ledgers = snapshot.payment_ledgers;
+        self.legacy_users = snapshot.legacy_users;
+        self.legacy_wishlist = snapshot.legacy_wishlist;
+        self.legacy_payment_id_index = snapshot.legacy_payment_id_index;
+        self.legacy_user_principal_email_index = snapshot.legacy_user_principal_email_index;
+    }
+
     pub fn add_to_wishlist_by_email(&mut self, email: String, hotel_id: HotelId) {
-        let wishlist = self.wishlist.entry(email).or_default();
-        if !wishlist.contains(&hotel_id) {
-            wishlist.push(hotel_id);
+        let mut wishlist = self.wishlist.get(&email).unwrap_or_default();
+        if !wishlist.0.contains(&hotel_id) {
+            wishlist.0.push(hotel_id);
+            self.wishlist.insert(email, wishlist);
         }
     }
 
     pub fn remove_from_wishlist_by_email(&mut self, email: &str, hotel_code: &str) {
-        if let Some(wishlist) = self.wishlist.get_mut(email) {
-            wishlist.retain(|hotel| hotel.hotel_code != hotel_code);
+        let email = email.to_string();
+        if let Some(mut wishlist) = self.wishlist.get(&email) {
+            wishlist.0.retain(|hotel| hotel.hotel_code != hotel_code);
+            self.wishlist.insert(email, wishlist);
         }
     }
 
-    pub fn get_wishlist_by_email(&self, email: &str) -> Option<&Vec<HotelId>> {
-        self.wishlist.get(email)
+    pub fn get_wishlist_by_email(&self, email: &str) -> Option<Vec<HotelId>> {
+        self.wishlist.get(&email.to_string()).map(|wishlist| wishlist.0)
     }
 
     pub fn clear_wishlist_by_email(&mut self, email: &str) {
-        self.wishlist.remove(email);
+        self.wishlist.remove(&email.to_string());
     }
 
     pub fn get_wishlist_count_for_a_hotel_id(&self, hotel_code: &str) -> usize {
         self.wishlist
             .values()
-            .filter(|wishlist| wishlist.iter().any(|hotel| hotel.hotel_code == hotel_code))
+            .filter(|wishlist| wishlist.0.iter().any(|hotel| hotel.hotel_code == hotel_code))
             .count()
     }
 
@@ -117,169 +599,341 @@ impl CanisterState {
         let current_version = self.schema_metadata.current_version;
         let description = self.schema_metadata.applied_migrations
             .iter()
-            .find(|m| m.version == current_version)
+            .find(|m| m.version == current_version && m.rolled_back_at.is_none())
             .map(|m| m.description.clone())
             .unwrap_or_else(|| "".to_string());
-        
-        (current_version, description)
+
+        let mismatches = &self.schema_metadata.fingerprint_mismatches;
+        if mismatches.is_empty() {
+            (current_version, description)
+        } else {
+            (
+                current_version,
+                format!("{} [WARNING: {}]", description, mismatches.join("; ")),
+            )
+        }
     }
 
     pub fn get_all_bookings(&self) -> Vec<BookingSummary> {
-        self.users
-            .iter()
-            .flat_map(|(user_email, user)| {
-                user.bookings
-                    .values()
-                    .map(|booking| BookingSummary::from((user_email.as_str(), booking)))
+        self.bookings
+            .values()
+            .map(|booking| {
+                BookingSummary::from((booking.booking_id.get_user_email(), &booking))
             })
             .collect()
     }
 
+    /// Validates `booking`, then stores it with a `Pending(None)` payment status and creates
+    /// the user profile if needed. Amounts only sent as legacy floats are filled in first.
+    /// Nothing is written when validation fails or the booking id is taken.
     pub fn add_booking_and_user(
         &mut self,
         email: &str,
-        booking: Booking,
-    ) -> Result<String, String> {
-        let user_profile = self
-            .users
-            .entry(email.to_string())
-            .or_default();
-
-        let user_result = user_profile.add_booking(booking);
-        println!("add_booking_and_user - {user_result:?}");
+        mut booking: Booking,
+    ) -> BackendResult<String> {
+        if booking.booking_id.get_user_email() != email {
+            return Err(BackendError::validation(
+                "booking_id.email",
+                format!(
+                    "'{}' does not match '{}'",
+                    booking.booking_id.get_user_email(),
+                    email
+                ),
+            ));
+        }
+        booking.fill_money_from_legacy();
+        booking.validate()?;
+
+        if self.bookings.contains_key(&booking.booking_id) {
+            return Err(BackendError::Conflict("Booking ID already exists".to_string()));
+        }
+        // whatever the client sent (e.g. the legacy `Unpaid`, or even `Paid`), only payment
+        // updates move a booking on from here
+        booking.payment_details.payment_status = BackendPaymentStatus::default();
+
+        if !self.users.contains_key(&email.to_string()) {
+            self.users
+                .insert(email.to_string(), UserInfoAndBookings::default());
+        }
+        let booking_id = booking.booking_id.clone();
+        self.bookings.insert(booking_id.clone(), booking);
+        self.record_booking_event(&booking_id, BookingEventKind::Created);
         Ok("Success".into())
     }
 
-    pub fn get_user_profile(&self, email: &str) -> Option<&UserInfoAndBookings> {
-        self.users.get(email)
+    /// profile of the user, with `bookings` filled in from the bookings map
+    pub fn get_user_profile(&self, email: &str) -> Option<UserInfoAndBookings> {
+        let mut profile = self.users.get(&email.to_string())?;
+        profile.bookings = self.collect_user_bookings(email);
+        Some(profile)
     }
 
-    pub fn get_booking_by_id(&self, booking_id: &BookingId) -> Option<&Booking> {
-        // First try to find the user with the email from the booking_id
-        let user_email = booking_id.get_user_email();
-        print!("models.rs.get_booking_by_id - {booking_id:?} - {user_email:?}");
-        if let Some(user) = self.users.get(user_email) {
-            print!("models.rs - self.users.get - {user:?} - {user_email:?}");
-
-            // Then try to get the booking from that user
-            if let Some(booking) = user.get_booking_by_id(booking_id) {
-                print!("models.rs - user.get_booking_by_id - {booking_id:?} - {user_email:?} - {booking:?}");
-                return Some(booking);
-            }
+    pub fn get_booking_by_id(&self, booking_id: &BookingId) -> Option<Booking> {
+        self.bookings.get(booking_id)
+    }
+
+    pub fn get_user_bookings(&self, email: &str) -> Option<BTreeMap<BookingId, Booking>> {
+        if !self.users.contains_key(&email.to_string()) {
+            return None;
         }
+        Some(self.collect_user_bookings(email))
+    }
 
-        None
+    fn collect_user_bookings(&self, email: &str) -> BTreeMap<BookingId, Booking> {
+        self.bookings
+            .range(BookingId::first_for_email(email)..)
+            .take_while(|(booking_id, _)| booking_id.get_user_email() == email)
+            .collect()
+    }
+
+    pub fn get_user_bookings_by_principal(&self, principal: Principal) -> Option<BTreeMap<BookingId, Booking>> {
+        self.user_principal_email_index.get(&principal).and_then(|email| self.get_user_bookings(&email))
     }
 
-    pub fn get_user_bookings(&self, email: &str) -> Option<&BTreeMap<BookingId, Booking>> {
-        self.users.get(email).map(|profile| &profile.bookings)
+    /// email mapped to `caller` in `user_principal_email_index`; anonymous callers are rejected
+    pub fn resolve_caller_email(&self, caller: Principal) -> BackendResult<UserEmail> {
+        if caller == Principal::anonymous() {
+            return Err(BackendError::Unauthorized(
+                "anonymous principal cannot access bookings".to_string(),
+            ));
+        }
+        self.user_principal_email_index.get(&caller).ok_or_else(|| {
+            BackendError::Unauthorized(format!("principal {} is not linked to an email", caller))
+        })
+    }
+
+    /// Whether `caller` is the user `email`, i.e. linked to it in `user_principal_email_index`
+    pub fn caller_owns_email(&self, caller: Principal, email: &str) -> bool {
+        self.resolve_caller_email(caller)
+            .is_ok_and(|caller_email| caller_email == email)
+    }
+
+    /// Adds a booking on behalf of `caller`, which must own `booking.booking_id`.
+    /// Booking and payment status are reset: only the canister may set those.
+    pub fn add_booking_for_caller(
+        &mut self,
+        caller: Principal,
+        mut booking: Booking,
+    ) -> BackendResult<String> {
+        let email = self.resolve_caller_email(caller)?;
+        if booking.booking_id.get_user_email() != email {
+            return Err(BackendError::Unauthorized(
+                "booking id must use the caller's email".to_string(),
+            ));
+        }
+
+        booking.book_room_status = None;
+        booking.payment_details = PaymentDetails::new(booking.booking_id.clone());
+        booking.cancellation = None;
+        booking.payment_mismatch = None;
+        self.add_booking_and_user(&email, booking)
     }
 
-    pub fn get_user_bookings_by_principal(&self, principal: Principal) -> Option<&BTreeMap<BookingId, Booking>> {
-        self.user_principal_email_index.get(&principal).and_then(|email| self.get_user_bookings(email))
+    /// `booking_id` if it belongs to `caller`
+    pub fn get_booking_for_caller(
+        &self,
+        caller: Principal,
+        booking_id: &BookingId,
+    ) -> BackendResult<Booking> {
+        let email = self.resolve_caller_email(caller)?;
+        if booking_id.get_user_email() != email {
+            return Err(BackendError::Unauthorized(
+                "booking belongs to another user".to_string(),
+            ));
+        }
+        self.get_booking_by_id(booking_id)
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))
     }
 
+    /// A `price` only sent as the legacy `price_amount` is filled in, with the booking's
+    /// currency if `price_currency` isn't one
     pub fn update_payment_details(
         &mut self,
         booking_id: BookingId,
-        payment_details: PaymentDetails,
-    ) -> Result<Booking, String> {
+        mut payment_details: PaymentDetails,
+    ) -> BackendResult<Booking> {
         // validation - booking_id MUST exist.
 
         // Check if payment_id_v2 is already used by another booking
-        let payment_id_v2 = &payment_details.payment_api_response.payment_id_v2;
+        let payment_id_v2 = payment_details.payment_api_response.payment_id_v2.clone();
         if payment_id_v2.is_empty() {
-            return Err("Payment ID v2 cannot be empty".to_string());
+            return Err(BackendError::validation("payment_id_v2", "cannot be empty"));
         }
-
-        // Initialize payment_id_index if it doesn't exist
-        if self.payment_id_index.is_none() {
-            self.payment_id_index = Some(BTreeMap::new());
+        let response = &payment_details.payment_api_response;
+        if let Some(price) = &response.price {
+            price.validate("price")?;
+        }
+        if let Some(amount_paid) = &response.amount_paid {
+            amount_paid.validate("amount_paid")?;
         }
 
-        if let Some(ref payment_index) = self.payment_id_index {
-            if let Some(existing_booking_id) = payment_index.get(payment_id_v2) {
-                if existing_booking_id != &booking_id {
-                    return Err(format!(
-                        "Payment ID v2 '{}' is already used by other booking",
-                        payment_id_v2,
-                    ));
-                }
+        if let Some(existing_booking_id) = self.payment_id_index.get(&payment_id_v2) {
+            if existing_booking_id != booking_id {
+                return Err(BackendError::Conflict(format!(
+                    "Payment ID v2 '{}' is already used by other booking",
+                    payment_id_v2,
+                )));
             }
         }
 
         // Find the user by email
         let user_email = booking_id.get_user_email();
-        let user = self
-            .users
-            .get_mut(user_email)
-            .ok_or_else(|| format!("User with email '{}' not found", user_email))?;
+        if !self.users.contains_key(&user_email.to_string()) {
+            return Err(BackendError::not_found("User", user_email));
+        }
 
         // Find the booking by ID
-        let booking = user.bookings.get_mut(&booking_id).ok_or_else(|| {
-            format!(
-                "Booking with app_reference '{}' not found",
-                booking_id.get_app_reference()
-            )
-        })?;
-
-        // Get the old payment_id_v2 to remove from index if it exists
-        let old_payment_id_v2 = &booking.payment_details.payment_api_response.payment_id_v2;
+        let mut booking = self
+            .bookings
+            .get(&booking_id)
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))?;
+        payment_details
+            .payment_api_response
+            .fill_money_from_legacy(&booking.get_requested_payment().currency);
+
+        // Update booking with payment details and status; fails on an invalid status transition
+        let old_payment_id_v2 = booking.payment_details.payment_api_response.payment_id_v2.clone();
+        let old_status = booking.payment_details.payment_status.clone();
+        booking.update_payment_details_with_api_response(payment_details, &self.payment_tolerance)?;
+        self.bookings.insert(booking_id.clone(), booking.clone());
+
+        // Remove the old payment_id_v2 from the index if it changed
         if !old_payment_id_v2.is_empty() && old_payment_id_v2 != payment_id_v2 {
-            if let Some(ref mut payment_index) = self.payment_id_index {
-                payment_index.remove(old_payment_id_v2);
-            }
+            self.payment_id_index.remove(&old_payment_id_v2);
         }
 
-        // Update booking with payment details and status
-        booking.update_payment_details_with_api_response(payment_details.clone());
+        let new_status = booking.payment_details.payment_status.clone();
+        if new_status != old_status {
+            self.record_booking_event(
+                &booking_id,
+                BookingEventKind::PaymentStatusChanged {
+                    from: old_status,
+                    to: new_status,
+                },
+            );
+        }
 
         // Update the payment_id_v2 index
-        if let Some(ref mut payment_index) = self.payment_id_index {
-            payment_index.insert(payment_id_v2.clone(), booking_id.clone());
-        }
+        self.payment_id_index.insert(payment_id_v2, booking_id);
 
-        Ok(booking.clone())
+        Ok(booking)
     }
 
     pub fn update_book_room_response(
         &mut self,
         booking_id: BookingId,
         book_room_response: BEBookRoomResponse,
-    ) -> Result<String, String> {
+    ) -> BackendResult<String> {
         // ) -> Result<BEBookRoomResponse, String> {
         let user_email = booking_id.get_user_email();
-        let result = self
-            .users
-            .get_mut(user_email)
-            .ok_or_else(|| format!("User with email '{}' not found", user_email))
-            .and_then(|user| {
-                let booking = user.bookings.get_mut(&booking_id).ok_or_else(|| {
-                    format!(
-                        "Booking with app_refrence '{}' not found",
-                        booking_id.get_app_reference()
-                    )
-                })?;
-
-                booking.update_book_room_status(book_room_response.clone())?;
-                Ok("Success")
-            })?;
-        Ok(result.into())
+        if !self.users.contains_key(&user_email.to_string()) {
+            return Err(BackendError::not_found("User", user_email));
+        }
+
+        let mut booking = self
+            .bookings
+            .get(&booking_id)
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))?;
+
+        let old_status = booking.get_resolved_booking_status();
+        let old_message = booking.book_room_status.as_ref().map(|s| s.message.clone());
+        let new_message = book_room_response.message.clone();
+
+        booking.update_book_room_status(book_room_response)?;
+        let new_status = booking.get_resolved_booking_status();
+        self.bookings.insert(booking_id.clone(), booking);
+
+        if new_status != old_status {
+            self.record_booking_event(
+                &booking_id,
+                BookingEventKind::BookingStatusChanged {
+                    from: old_status,
+                    to: new_status,
+                },
+            );
+        }
+        if !new_message.is_empty() && old_message.as_ref() != Some(&new_message) {
+            self.record_booking_event(&booking_id, BookingEventKind::Message(new_message));
+        }
+        Ok("Success".into())
+    }
+
+    /// See `Booking::cancel`. Timestamps the cancellation with the current call.
+    pub fn cancel_booking(&mut self, booking_id: BookingId, reason: String) -> BackendResult<Booking> {
+        let mut booking = self
+            .bookings
+            .get(&booking_id)
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))?;
+
+        let old_booking_status = booking.get_resolved_booking_status();
+        let old_payment_status = booking.payment_details.payment_status.clone();
+        let (_, now) = crate::audit::call_context();
+        booking.cancel(reason.clone(), now)?;
+        self.bookings.insert(booking_id.clone(), booking.clone());
+
+        self.record_booking_event(&booking_id, BookingEventKind::Cancelled { reason });
+        self.record_booking_event(
+            &booking_id,
+            BookingEventKind::BookingStatusChanged {
+                from: old_booking_status,
+                to: booking.get_resolved_booking_status(),
+            },
+        );
+        if booking.payment_details.payment_status != old_payment_status {
+            self.record_booking_event(
+                &booking_id,
+                BookingEventKind::PaymentStatusChanged {
+                    from: old_payment_status,
+                    to: booking.payment_details.payment_status.clone(),
+                },
+            );
+        }
+        Ok(booking)
+    }
+
+    pub fn record_refund(
+        &mut self,
+        booking_id: BookingId,
+        amount: Money,
+        reference: String,
+    ) -> BackendResult<Booking> {
+        let mut booking = self
+            .bookings
+            .get(&booking_id)
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))?;
+
+        let old_payment_status = booking.payment_details.payment_status.clone();
+        booking.record_refund(amount, reference)?;
+        self.bookings.insert(booking_id.clone(), booking.clone());
+
+        self.record_booking_event(
+            &booking_id,
+            BookingEventKind::PaymentStatusChanged {
+                from: old_payment_status,
+                to: booking.payment_details.payment_status.clone(),
+            },
+        );
+        Ok(booking)
     }
 
     pub fn update_booking_message(
         &mut self,
         booking_id: BookingId,
         message: String,
-    ) -> Result<String, String> {
+    ) -> BackendResult<String> {
         let user_email = booking_id.get_user_email();
-        self.users
-            .get_mut(user_email)
-            .ok_or_else(|| format!("User with email '{}' not found", user_email))
-            .and_then(|user| {
-                user.update_booking_message(&booking_id, message)
-                    .map(|_| "Message updated successfully".to_string())
-            })
+        if !self.users.contains_key(&user_email.to_string()) {
+            return Err(BackendError::not_found("User", user_email));
+        }
+
+        let mut booking = self
+            .bookings
+            .get(&booking_id)
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))?;
+        booking.update_booking_message(message.clone());
+        self.bookings.insert(booking_id.clone(), booking);
+        self.record_booking_event(&booking_id, BookingEventKind::Message(message));
+        Ok("Message updated successfully".to_string())
     }
 
     // email_sent methods
@@ -296,13 +950,15 @@ impl CanisterState {
         self.email_sent.as_mut().unwrap()
     }
 
-    pub fn update_email_sent(&mut self, booking_id: BookingId, sent: bool) -> Result<(), String> {
+    pub fn update_email_sent(&mut self, booking_id: BookingId, sent: bool) -> BackendResult<()> {
         // check if the status is already set to true.
         self.get_email_sent_mut_value()
-            .update_email_sent(booking_id, sent)
+            .update_email_sent(booking_id.clone(), sent)?;
+        self.record_booking_event(&booking_id, BookingEventKind::EmailSent(sent));
+        Ok(())
     }
 
-    pub fn get_email_sent(&mut self, booking_id: &BookingId) -> Result<bool, String> {
+    pub fn get_email_sent(&mut self, booking_id: &BookingId) -> BackendResult<bool> {
         //  check if email_sent status for the booking_id exists. if yes, send the status
         if let Some(sent_val) = self
             .get_email_sent_mut_value()
@@ -313,10 +969,7 @@ impl CanisterState {
 
         //  if email_sent status for the booking_id  DOES NOT exist. if yes, check if booking_id exists. if no, return error
         if self.get_booking_by_id(booking_id).is_none() {
-            return Err(format!(
-                "Booking does not exist with booking_id {:?} ",
-                booking_id
-            ));
+            return Err(BackendError::not_found("Booking", booking_id.get_app_reference()));
         }
 
         // if email_sent status for the booking_id  DOES NOT exist. if yes, check if booking_id exists.
@@ -660,4 +1313,7 @@ mod wishlist_tests {
         assert_eq!(state.get_wishlist_count_for_a_hotel_id("H200"), 1);
         assert_eq!(state.get_wishlist_count_for_a_hotel_id("H300"), 0);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,201 @@
+use crate::errors::{BackendError, BackendResult};
+use crate::memory::{from_cbor_bytes, to_cbor_bytes, try_from_cbor_bytes, MapValue};
+use crate::timeline::BookingEventKind;
+use crate::CanisterState;
+use candid::CandidType;
+use ic_stable_structures::storable::{Bound, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use super::{
+    default_if_legacy, Booking, BookingId, HotelRoomDetails, Money, RoomDetails,
+    SelectedDateRange, UserDetails,
+};
+
+/// Changes to an existing booking; `None` keeps the current value
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct BookingAmendment {
+    pub date_range: Option<SelectedDateRange>,
+    pub room_details: Option<Vec<RoomDetails>>,
+    pub guests: Option<UserDetails>,
+    /// new total; if `None` and the dates or rooms change it is recomputed from the
+    /// room prices, see `amended_price`
+    pub requested_payment: Option<Money>,
+    /// amend even if the booking is confirmed, cancelled or failed. Admins only.
+    pub force: bool,
+}
+
+impl BookingAmendment {
+    fn is_empty(&self) -> bool {
+        self.date_range.is_none()
+            && self.room_details.is_none()
+            && self.guests.is_none()
+            && self.requested_payment.is_none()
+    }
+}
+
+/// The booking as it was before amendment number `revision` (starting at 1)
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BookingRevision {
+    pub revision: u32,
+    pub amended_at: u64,
+    pub guests: UserDetails,
+    pub user_selected_hotel_room_details: HotelRoomDetails,
+    /// new `requested_payment` minus the one in this revision
+    #[serde(default, deserialize_with = "default_if_legacy")]
+    pub price_delta: Money,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct BookingRevisions(pub Vec<BookingRevision>);
+
+impl Storable for BookingRevisions {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        to_cbor_bytes(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        from_cbor_bytes(bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl MapValue for BookingRevisions {
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        try_from_cbor_bytes(bytes)
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AmendmentResult {
+    pub booking: Booking,
+    pub revision: u32,
+    /// positive when the guest owes more, negative when part of the payment is due back
+    pub price_delta: Money,
+}
+
+/// Price after an amendment: the explicit amount if given, else, when the dates or rooms
+/// changed, the sum of the room prices times the nights if the rooms have prices, else the
+/// previous amount. A guests-only amendment keeps the previous amount, which may include
+/// taxes and fees that the room prices do not.
+fn amended_price(
+    details: &HotelRoomDetails,
+    explicit: Option<Money>,
+    reprice: bool,
+    previous: &Money,
+) -> BackendResult<Money> {
+    if let Some(amount) = explicit {
+        return Ok(amount);
+    }
+    if !reprice {
+        return Ok(previous.clone());
+    }
+    let priced_rooms = details
+        .room_details
+        .iter()
+        .filter_map(|room| room.price.as_ref())
+        .filter(|price| !price.is_zero());
+    let per_night = Money::sum(&previous.currency, priced_rooms)?;
+    if per_night.is_positive() {
+        per_night.checked_mul(details.date_range.no_of_nights())
+    } else {
+        Ok(previous.clone())
+    }
+}
+
+impl Booking {
+    /// Applies `amendment` and returns the booking as it was before and the price delta.
+    /// Nothing changes if the amended booking fails `validate`.
+    pub fn amend(&mut self, amendment: BookingAmendment) -> BackendResult<(Booking, Money)> {
+        if amendment.is_empty() {
+            return Err(BackendError::validation("amendment", "nothing to change"));
+        }
+        let status = self.get_resolved_booking_status();
+        if status.is_terminal() && !amendment.force {
+            return Err(BackendError::InvalidTransition {
+                from: format!("{:?}", status),
+                to: "Amended".to_string(),
+            });
+        }
+
+        let reprice = amendment.date_range.is_some() || amendment.room_details.is_some();
+        let mut amended = self.clone();
+        let details = &mut amended.user_selected_hotel_room_details;
+        if let Some(date_range) = amendment.date_range {
+            details.date_range = date_range;
+        }
+        if let Some(room_details) = amendment.room_details {
+            details.room_details = room_details;
+            // rooms sent with only the legacy `room_price`
+            details.fill_money_from_legacy(&self.get_requested_payment().currency);
+        }
+        if let Some(guests) = amendment.guests {
+            amended.guests = guests;
+        }
+        let previous_price = self.get_requested_payment();
+        let details = &mut amended.user_selected_hotel_room_details;
+        details.requested_payment =
+            Some(amended_price(details, amendment.requested_payment, reprice, &previous_price)?);
+
+        amended.validate()?;
+        let price_delta = amended.get_requested_payment().checked_sub(&previous_price)?;
+        let previous = std::mem::replace(self, amended);
+        Ok((previous, price_delta))
+    }
+}
+
+impl CanisterState {
+    /// `caller_is_admin` must be true for `amendment.force`
+    pub fn amend_booking(
+        &mut self,
+        booking_id: BookingId,
+        amendment: BookingAmendment,
+        caller_is_admin: bool,
+    ) -> BackendResult<AmendmentResult> {
+        if amendment.force && !caller_is_admin {
+            return Err(BackendError::Unauthorized(
+                "only admins can force an amendment".to_string(),
+            ));
+        }
+        let mut booking = self
+            .bookings
+            .get(&booking_id)
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))?;
+
+        let (previous, price_delta) = booking.amend(amendment)?;
+        self.bookings.insert(booking_id.clone(), booking.clone());
+
+        let (_, now) = crate::audit::call_context();
+        let mut revisions = self.booking_revisions.get(&booking_id).unwrap_or_default();
+        let revision = revisions.0.len() as u32 + 1;
+        revisions.0.push(BookingRevision {
+            revision,
+            amended_at: now,
+            guests: previous.guests,
+            user_selected_hotel_room_details: previous.user_selected_hotel_room_details,
+            price_delta: price_delta.clone(),
+        });
+        self.booking_revisions.insert(booking_id.clone(), revisions);
+        self.record_booking_event(
+            &booking_id,
+            BookingEventKind::Amended {
+                revision,
+                price_delta: price_delta.clone(),
+            },
+        );
+
+        Ok(AmendmentResult {
+            booking,
+            revision,
+            price_delta,
+        })
+    }
+
+    pub fn get_booking_revisions(&self, booking_id: &BookingId) -> BackendResult<Vec<BookingRevision>> {
+        if !self.bookings.contains_key(booking_id) {
+            return Err(BackendError::not_found("Booking", booking_id.get_app_reference()));
+        }
+        Ok(self.booking_revisions.get(booking_id).unwrap_or_default().0)
+    }
+}
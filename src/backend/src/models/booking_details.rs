@@ -1,43 +1,66 @@
+use crate::errors::{BackendError, BackendResult};
+use crate::memory::{from_cbor_bytes, to_cbor_bytes, try_from_cbor_bytes, MapValue};
+use crate::payments;
 use crate::{PaymentDetails, UserDetails};
 use candid::CandidType;
 use chrono::NaiveDate;
+use ic_stable_structures::storable::{Bound, Storable};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Ordering;
 
-use super::{BEPaymentApiResponse, BackendPaymentStatus};
-
-fn payment_status_to_backend_status(status: &str, payment_id_v2: String) -> BackendPaymentStatus {
-    match status {
-        // Success states
-        "completed" | "finished" => {
-            let trans_ref = format!("{} - COMPLETED", payment_id_v2);
-            BackendPaymentStatus::Paid(trans_ref)
-        }
-
-        // Definitive failure states
-        "failed" | "cancelled" | "expired" | "refunded" => {
-            let trans_ref = format!("{} - {}", payment_id_v2, status.to_uppercase());
-            BackendPaymentStatus::Unpaid(Some(trans_ref))
-        }
-
-        // Pending/unknown states
-        _ => {
-            let trans_ref = format!("{} - {}", payment_id_v2, status.to_uppercase());
-            BackendPaymentStatus::Unpaid(Some(trans_ref))
-        }
-    }
-}
+use super::{
+    none_if_legacy, BEPaymentApiResponse, BackendPaymentStatus, CurrencyCode, Money,
+    PaymentMismatch, PaymentMismatchKind, PaymentTolerance,
+};
 
 pub type AppReference = String;
 pub type UserEmail = String;
 
-#[derive(
-    CandidType, Deserialize, Default, Serialize, Clone, Debug, Eq, PartialEq, PartialOrd, Ord,
-)]
+/// Key of a booking. Sorts by email first (see the `Ord` impl), unlike the derived
+/// `(app_reference, email)` order it had before the stable maps. Everything keyed by it is
+/// listed in that order: the stable maps, `email_sent`, snapshots and exports. Changing the
+/// order again would mean rebuilding every stable map keyed by it.
+#[derive(CandidType, Deserialize, Default, Serialize, Clone, Debug, Eq, PartialEq)]
 pub struct BookingId {
     app_reference: AppReference,
     email: UserEmail,
 }
 
+/// Ordered by email first so that all bookings of one user are adjacent
+/// in the `bookings` stable map and can be fetched with a range scan.
+impl Ord for BookingId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.email
+            .cmp(&other.email)
+            .then_with(|| self.app_reference.cmp(&other.app_reference))
+    }
+}
+
+impl PartialOrd for BookingId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Storable for BookingId {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        to_cbor_bytes(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        from_cbor_bytes(bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl MapValue for BookingId {
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        try_from_cbor_bytes(bytes)
+    }
+}
+
 impl BookingId {
     pub fn new(app_reference: String, email: String) -> Self {
         Self {
@@ -53,6 +76,14 @@ impl BookingId {
     pub fn get_user_email(&self) -> &str {
         &self.email
     }
+
+    /// smallest possible id for `email`; used as the start of a range scan over a user's bookings
+    pub fn first_for_email(email: &str) -> Self {
+        Self {
+            app_reference: String::new(),
+            email: email.to_string(),
+        }
+    }
 }
 
 #[derive(CandidType, Deserialize, Default, Serialize, Clone, Debug)]
@@ -67,6 +98,39 @@ pub struct Booking {
     pub user_selected_hotel_room_details: HotelRoomDetails,
 
     pub payment_details: PaymentDetails,
+
+    /// set by `cancel_booking`
+    #[serde(default)]
+    pub cancellation: Option<BookingCancellation>,
+
+    /// set by `update_payment_details` when the amount paid doesn't match
+    /// `requested_payment`, cleared once it does
+    #[serde(default, deserialize_with = "none_if_legacy")]
+    pub payment_mismatch: Option<PaymentMismatch>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct BookingCancellation {
+    pub reason: String,
+    pub cancelled_at: u64,
+}
+
+impl Storable for Booking {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        to_cbor_bytes(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        from_cbor_bytes(bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl MapValue for Booking {
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        try_from_cbor_bytes(bytes)
+    }
 }
 
 impl Booking {
@@ -78,23 +142,80 @@ impl Booking {
         user_selected_hotel_room_details: HotelRoomDetails,
 
         payment_details: PaymentDetails,
-    ) -> Result<Self, String> {
-        let booking = Self {
+    ) -> BackendResult<Self> {
+        let mut booking = Self {
             booking_id,
             guests,
             book_room_status,
             user_selected_hotel_room_details,
             payment_details,
+            cancellation: None,
+            payment_mismatch: None,
         };
 
+        booking.fill_money_from_legacy();
         booking.validate()?;
         Ok(booking)
     }
 
-    pub fn validate(&self) -> Result<(), String> {
+    /// Checks run before a booking is stored
+    pub fn validate(&self) -> BackendResult<()> {
+        if self.booking_id.get_app_reference().trim().is_empty() {
+            return Err(BackendError::validation(
+                "booking_id.app_reference",
+                "cannot be empty",
+            ));
+        }
+
         // Validate guests exist
         if self.guests.adults.is_empty() {
-            return Err("At least one adult guest required".into());
+            return Err(BackendError::validation(
+                "guests.adults",
+                "at least one adult guest required",
+            ));
+        }
+
+        let hotel_room_details = &self.user_selected_hotel_room_details;
+        if hotel_room_details.hotel_details.hotel_code.trim().is_empty() {
+            return Err(BackendError::validation(
+                "hotel_details.hotel_code",
+                "cannot be empty",
+            ));
+        }
+
+        // no_of_nights is 0 for missing or invalid dates and for end <= start
+        if hotel_room_details.date_range.no_of_nights() == 0 {
+            return Err(BackendError::validation(
+                "date_range",
+                format!(
+                    "{} must be valid dates with end after start",
+                    hotel_room_details.date_range
+                ),
+            ));
+        }
+
+        let requested_payment = self.get_requested_payment();
+        requested_payment.validate("requested_payment")?;
+        if !requested_payment.is_positive() {
+            return Err(BackendError::validation(
+                "requested_payment",
+                format!("must be positive, got {}", requested_payment),
+            ));
+        }
+        for room in &hotel_room_details.room_details {
+            let Some(price) = &room.price else {
+                continue;
+            };
+            price.validate("room_details.price")?;
+            if !price.is_zero() && price.currency != requested_payment.currency {
+                return Err(BackendError::validation(
+                    "room_details.price",
+                    format!(
+                        "{} for room {} is not in {}",
+                        price, room.room_unique_id, requested_payment.currency
+                    ),
+                ));
+            }
         }
 
         // // Validate room allocation matches guest count
@@ -120,9 +241,28 @@ impl Booking {
             .unwrap_or(BookingStatus::BookFailed)
     }
 
-    pub fn get_requested_payment_amount(&self) -> f64 {
+    /// Zero USD only for a booking that never went through `fill_money_from_legacy`
+    pub fn get_requested_payment(&self) -> Money {
         self.user_selected_hotel_room_details
-            .requested_payment_amount
+            .requested_payment
+            .clone()
+            .unwrap_or_default()
+    }
+
+    /// The currency the booking's payment was priced in, else USD, which every amount was
+    /// assumed to be in before migration 1006
+    #[allow(deprecated)]
+    pub fn legacy_currency(&self) -> CurrencyCode {
+        CurrencyCode::new(&self.payment_details.payment_api_response.price_currency)
+            .unwrap_or_default()
+    }
+
+    /// Fills the `Money` amounts callers or stored data left out from the deprecated floats,
+    /// in `legacy_currency`
+    pub fn fill_money_from_legacy(&mut self) {
+        let currency = self.legacy_currency();
+        self.user_selected_hotel_room_details.fill_money_from_legacy(&currency);
+        self.payment_details.payment_api_response.fill_money_from_legacy(&currency);
     }
 
     pub fn get_booking_summary(&self) -> String {
@@ -146,42 +286,170 @@ impl Booking {
         self.payment_details.payment_status = new_status;
     }
 
-    pub fn update_backend_payment_status_from_api(&mut self, api_response: &BEPaymentApiResponse) {
-        let payment_status = payment_status_to_backend_status(
+    /// Derives the payment status with the adapter named by `api_response.provider`, refusing
+    /// transitions that `BackendPaymentStatus::is_valid_transition` doesn't allow.
+    /// A "finished" payment short of the requested amount is only `PartiallyPaid`.
+    pub fn update_backend_payment_status_from_api(
+        &mut self,
+        api_response: &BEPaymentApiResponse,
+        tolerance: &PaymentTolerance,
+    ) -> BackendResult<()> {
+        let mut payment_status = payments::provider_or_default(&api_response.provider).map_status(
             &api_response.payment_status,
-            api_response.payment_id_v2.clone()
+            api_response.payment_id_v2.clone(),
+            api_response.price.clone().unwrap_or_default(),
         );
+        let mismatch = self.check_payment_amount(api_response, &payment_status, tolerance);
+        if let (BackendPaymentStatus::Paid(reference), Some(mismatch)) = (&payment_status, &mismatch) {
+            if mismatch.kind == PaymentMismatchKind::Underpaid {
+                payment_status = BackendPaymentStatus::PartiallyPaid(reference.clone());
+            }
+        }
+        let current_status = &self.payment_details.payment_status;
+        if !current_status.is_valid_transition(&payment_status) {
+            return Err(BackendError::InvalidTransition {
+                from: current_status.name().to_string(),
+                to: payment_status.name().to_string(),
+            });
+        }
         self.update_payment_status(payment_status);
+        self.payment_mismatch = mismatch;
+        Ok(())
     }
 
     pub fn get_book_room_status(&self) -> Option<&BEBookRoomResponse> {
         self.book_room_status.as_ref()
     }
 
+    /// `Unknown` until a book room response arrives
+    pub fn get_resolved_booking_status(&self) -> ResolvedBookingStatus {
+        self.book_room_status
+            .as_ref()
+            .map(|status| status.commit_booking.resolved_booking_status)
+            .unwrap_or(ResolvedBookingStatus::Unknown)
+    }
+
     pub fn update_book_room_status(
         &mut self,
         new_status: BEBookRoomResponse,
-    ) -> Result<(), String> {
-        let current_status = self
-            .book_room_status
-            .as_ref()
-            .map(|status| status.commit_booking.resolved_booking_status)
-            .unwrap_or(ResolvedBookingStatus::Unknown);
+    ) -> BackendResult<()> {
+        let current_status = self.get_resolved_booking_status();
 
         if !current_status.is_valid_transition(&new_status.commit_booking.resolved_booking_status) {
-            return Err(format!(
-                "Invalid status transition from {:?} to {:?}",
-                current_status, new_status.commit_booking.resolved_booking_status
-            ));
+            return Err(BackendError::InvalidTransition {
+                from: format!("{:?}", current_status),
+                to: format!("{:?}", new_status.commit_booking.resolved_booking_status),
+            });
         }
         self.book_room_status = Some(new_status);
         Ok(())
     }
 
-    pub fn update_payment_details_with_api_response(&mut self, payment_details: PaymentDetails) {
-        let api_response = payment_details.payment_api_response.clone();
-        self.payment_details = payment_details;
-        self.update_backend_payment_status_from_api(&api_response);
+    pub fn update_booking_message(&mut self, message: String) {
+        let frontend_message = format!("[frontend] {}", message);
+
+        // If book_room_status is None, create a new one with the message
+        if let Some(status) = &mut self.book_room_status {
+            // Update the existing book_room_status message
+            status.message = frontend_message;
+        } else {
+            self.book_room_status = Some(BEBookRoomResponse {
+                status: "Updated".to_string(),
+                message: frontend_message,
+                commit_booking: BookingDetails::default(),
+            });
+        }
+    }
+
+    /// Moves the booking to `BookingCancelled`. A (partially) paid payment moves to
+    /// `RefundPending`, a started one to `Cancelled`.
+    pub fn cancel(&mut self, reason: String, cancelled_at: u64) -> BackendResult<()> {
+        if reason.trim().is_empty() {
+            return Err(BackendError::validation("reason", "cannot be empty"));
+        }
+
+        let current_status = self.get_resolved_booking_status();
+        let cancelled = ResolvedBookingStatus::BookingCancelled;
+        if !current_status.is_valid_transition(&cancelled) {
+            return Err(BackendError::InvalidTransition {
+                from: format!("{:?}", current_status),
+                to: format!("{:?}", cancelled),
+            });
+        }
+
+        let booking_id = self.booking_id.clone();
+        let status = self.book_room_status.get_or_insert_with(|| BEBookRoomResponse {
+            status: "Cancelled".to_string(),
+            message: String::new(),
+            commit_booking: BookingDetails {
+                booking_id,
+                ..Default::default()
+            },
+        });
+        status.commit_booking.resolved_booking_status = cancelled;
+        status.commit_booking.booking_status = "BookingCancelled".to_string();
+
+        match &self.payment_details.payment_status {
+            BackendPaymentStatus::Paid(payment_ref) | BackendPaymentStatus::PartiallyPaid(payment_ref) => {
+                let payment_ref = payment_ref.clone();
+                self.update_payment_status(BackendPaymentStatus::RefundPending(payment_ref));
+            }
+            BackendPaymentStatus::Pending(Some(payment_ref)) => {
+                let payment_ref = payment_ref.clone();
+                self.update_payment_status(BackendPaymentStatus::Cancelled(payment_ref));
+            }
+            _ => {}
+        }
+        self.cancellation = Some(BookingCancellation {
+            reason,
+            cancelled_at,
+        });
+        Ok(())
+    }
+
+    /// Only a booking waiting for a refund (see `cancel`) can be refunded, in its currency
+    pub fn record_refund(&mut self, amount: Money, reference: String) -> BackendResult<()> {
+        amount.validate("amount")?;
+        if !amount.is_positive() {
+            return Err(BackendError::validation(
+                "amount",
+                format!("must be positive, got {}", amount),
+            ));
+        }
+        let currency = self.get_requested_payment().currency;
+        if amount.currency != currency {
+            return Err(BackendError::validation(
+                "amount",
+                format!("{} is not in {}", amount, currency),
+            ));
+        }
+        if reference.trim().is_empty() {
+            return Err(BackendError::validation("reference", "cannot be empty"));
+        }
+        if !matches!(self.payment_details.payment_status, BackendPaymentStatus::RefundPending(_)) {
+            return Err(BackendError::InvalidTransition {
+                from: format!("{:?}", self.payment_details.payment_status),
+                to: "Refunded".to_string(),
+            });
+        }
+
+        self.update_payment_status(BackendPaymentStatus::Refunded { amount, reference });
+        Ok(())
+    }
+
+    /// Nothing changes if the new payment status is an invalid transition
+    pub fn update_payment_details_with_api_response(
+        &mut self,
+        payment_details: PaymentDetails,
+        tolerance: &PaymentTolerance,
+    ) -> BackendResult<()> {
+        self.update_backend_payment_status_from_api(&payment_details.payment_api_response, tolerance)?;
+        let payment_status = std::mem::take(&mut self.payment_details.payment_status);
+        self.payment_details = PaymentDetails {
+            payment_status,
+            ..payment_details
+        };
+        Ok(())
     }
 
     // pub fn is_confirmed(&self) -> bool {
@@ -208,15 +476,42 @@ pub struct HotelRoomDetails {
     pub date_range: SelectedDateRange,
     pub destination: Option<Destination>,
     pub room_details: Vec<RoomDetails>,
-    /// amount shown on block_room
+    /// Legacy float amount, converted into `requested_payment` by migration 1006
+    #[serde(default)]
+    #[deprecated(note = "Use requested_payment instead")]
     pub requested_payment_amount: f64,
+    /// amount shown on block_room; filled in from `requested_payment_amount` when a caller
+    /// only sends that
+    #[serde(default)]
+    pub requested_payment: Option<Money>,
+}
+
+impl HotelRoomDetails {
+    /// Sets `requested_payment` and the room prices that are `None` from their floats
+    #[allow(deprecated)]
+    pub fn fill_money_from_legacy(&mut self, currency: &CurrencyCode) {
+        if self.requested_payment.is_none() {
+            self.requested_payment = Some(Money::from_legacy(self.requested_payment_amount, currency));
+        }
+        for room in self.room_details.iter_mut() {
+            if room.price.is_none() {
+                room.price = Some(Money::from_legacy(room.room_price as f64, currency));
+            }
+        }
+    }
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
 pub struct RoomDetails {
     pub room_type_name: String,
     pub room_unique_id: String,
+    /// Legacy float price, converted into `price` by migration 1006
+    #[serde(default)]
+    #[deprecated(note = "Use price instead")]
     pub room_price: f32,
+    /// per night; filled in from `room_price` when a caller only sends that
+    #[serde(default)]
+    pub price: Option<Money>,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
@@ -243,17 +538,19 @@ pub struct SelectedDateRange {
     pub end: (u32, u32, u32),
 }
 
-/// copied from frontend repo as it is.
-impl SelectedDateRange {
-    pub fn to_string(&self) -> String {
+impl std::fmt::Display for SelectedDateRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let start_str = format!(
             "{:04}-{:02}-{:02}",
             self.start.0, self.start.1, self.start.2
         );
         let end_str = format!("{:04}-{:02}-{:02}", self.end.0, self.end.1, self.end.2);
-        format!("{} - {}", start_str, end_str)
+        write!(f, "{} - {}", start_str, end_str)
     }
+}
 
+/// copied from frontend repo as it is.
+impl SelectedDateRange {
     pub fn no_of_nights(&self) -> u32 {
         let (start_year, start_month, start_day) = self.start;
         let (end_year, end_month, end_day) = self.end;
@@ -340,9 +637,11 @@ impl ResolvedBookingStatus {
         match self {
             ResolvedBookingStatus::Unknown => true,
 
-            ResolvedBookingStatus::BookingConfirmed => {
-                matches!(next, ResolvedBookingStatus::BookingConfirmed)
-            }
+            // confirmed bookings can still be cancelled
+            ResolvedBookingStatus::BookingConfirmed => matches!(
+                next,
+                ResolvedBookingStatus::BookingConfirmed | ResolvedBookingStatus::BookingCancelled
+            ),
 
             ResolvedBookingStatus::BookingOnHold => matches!(
                 next,
@@ -402,7 +701,10 @@ impl From<(&str, &Booking)> for BookingSummary {
                 .date_range
                 .no_of_nights(),
             payment_status: booking.payment_details.get_status_display(),
-            booking_status: booking.get_booking_status(),
+            booking_status: match &booking.cancellation {
+                Some(cancellation) => format!("Cancelled: {}", cancellation.reason),
+                None => booking.get_booking_status(),
+            },
             booking_dates: booking
                 .user_selected_hotel_room_details
                 .date_range
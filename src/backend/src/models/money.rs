@@ -0,0 +1,298 @@
+use crate::errors::{BackendError, BackendResult};
+use candid::CandidType;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{self, DeserializeOwned, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+
+/// ISO 4217 codes in circulation with the number of digits after the decimal point.
+/// Sorted, for `binary_search_by_key`.
+const ISO_4217: &[(&str, u32)] = &[
+    ("AED", 2), ("AFN", 2), ("ALL", 2), ("AMD", 2), ("ANG", 2), ("AOA", 2), ("ARS", 2),
+    ("AUD", 2), ("AWG", 2), ("AZN", 2), ("BAM", 2), ("BBD", 2), ("BDT", 2), ("BGN", 2),
+    ("BHD", 3), ("BIF", 0), ("BMD", 2), ("BND", 2), ("BOB", 2), ("BOV", 2), ("BRL", 2),
+    ("BSD", 2), ("BTN", 2), ("BWP", 2), ("BYN", 2), ("BZD", 2), ("CAD", 2), ("CDF", 2),
+    ("CHE", 2), ("CHF", 2), ("CHW", 2), ("CLF", 4), ("CLP", 0), ("CNY", 2), ("COP", 2),
+    ("COU", 2), ("CRC", 2), ("CUP", 2), ("CVE", 2), ("CZK", 2), ("DJF", 0), ("DKK", 2),
+    ("DOP", 2), ("DZD", 2), ("EGP", 2), ("ERN", 2), ("ETB", 2), ("EUR", 2), ("FJD", 2),
+    ("FKP", 2), ("GBP", 2), ("GEL", 2), ("GHS", 2), ("GIP", 2), ("GMD", 2), ("GNF", 0),
+    ("GTQ", 2), ("GYD", 2), ("HKD", 2), ("HNL", 2), ("HTG", 2), ("HUF", 2), ("IDR", 2),
+    ("ILS", 2), ("INR", 2), ("IQD", 3), ("IRR", 2), ("ISK", 0), ("JMD", 2), ("JOD", 3),
+    ("JPY", 0), ("KES", 2), ("KGS", 2), ("KHR", 2), ("KMF", 0), ("KPW", 2), ("KRW", 0),
+    ("KWD", 3), ("KYD", 2), ("KZT", 2), ("LAK", 2), ("LBP", 2), ("LKR", 2), ("LRD", 2),
+    ("LSL", 2), ("LYD", 3), ("MAD", 2), ("MDL", 2), ("MGA", 2), ("MKD", 2), ("MMK", 2),
+    ("MNT", 2), ("MOP", 2), ("MRU", 2), ("MUR", 2), ("MVR", 2), ("MWK", 2), ("MXN", 2),
+    ("MXV", 2), ("MYR", 2), ("MZN", 2), ("NAD", 2), ("NGN", 2), ("NIO", 2), ("NOK", 2),
+    ("NPR", 2), ("NZD", 2), ("OMR", 3), ("PAB", 2), ("PEN", 2), ("PGK", 2), ("PHP", 2),
+    ("PKR", 2), ("PLN", 2), ("PYG", 0), ("QAR", 2), ("RON", 2), ("RSD", 2), ("RUB", 2),
+    ("RWF", 0), ("SAR", 2), ("SBD", 2), ("SCR", 2), ("SDG", 2), ("SEK", 2), ("SGD", 2),
+    ("SHP", 2), ("SLE", 2), ("SOS", 2), ("SRD", 2), ("SSP", 2), ("STN", 2), ("SVC", 2),
+    ("SYP", 2), ("SZL", 2), ("THB", 2), ("TJS", 2), ("TMT", 2), ("TND", 3), ("TOP", 2),
+    ("TRY", 2), ("TTD", 2), ("TWD", 2), ("TZS", 2), ("UAH", 2), ("UGX", 0), ("USD", 2),
+    ("USN", 2), ("UYI", 0), ("UYU", 2), ("UYW", 4), ("UZS", 2), ("VED", 2), ("VES", 2),
+    ("VND", 0), ("VUV", 0), ("WST", 2), ("XAF", 0), ("XCD", 2), ("XCG", 2), ("XOF", 0),
+    ("XPF", 0), ("YER", 2), ("ZAR", 2), ("ZMW", 2), ("ZWG", 2),];
+
+/// An ISO 4217 currency code such as "USD", stored in upper case
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CurrencyCode(String);
+
+impl Default for CurrencyCode {
+    fn default() -> Self {
+        Self::usd()
+    }
+}
+
+impl fmt::Display for CurrencyCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl CurrencyCode {
+    /// Accepts codes in any case, e.g. "usd" as sent by payment providers
+    pub fn new(code: &str) -> BackendResult<Self> {
+        let code = Self(code.trim().to_ascii_uppercase());
+        code.validate()?;
+        Ok(code)
+    }
+
+    pub fn usd() -> Self {
+        Self("USD".to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    fn lookup(&self) -> Option<u32> {
+        ISO_4217
+            .binary_search_by_key(&self.0.as_str(), |(code, _)| code)
+            .ok()
+            .map(|index| ISO_4217[index].1)
+    }
+
+    /// Codes arriving over candid aren't checked on the way in
+    pub fn validate(&self) -> BackendResult<()> {
+        self.validate_as("currency")
+    }
+
+    fn validate_as(&self, field: &str) -> BackendResult<()> {
+        match self.lookup() {
+            Some(_) => Ok(()),
+            None => Err(BackendError::validation(
+                field,
+                format!("'{}' is not an ISO 4217 currency code", self.0),
+            )),
+        }
+    }
+
+    /// Digits after the decimal point: 2 for USD, 0 for JPY, 3 for KWD
+    pub fn minor_units(&self) -> u32 {
+        self.lookup().unwrap_or(2)
+    }
+}
+
+/// An amount in the smallest unit of its currency, so cents for USD and yen for JPY.
+/// Amounts in different currencies are never combined.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Money {
+    pub amount_minor: i128,
+    pub currency: CurrencyCode,
+}
+
+impl fmt::Display for Money {
+    /// e.g. "400.00 USD" or "-1.250 KWD"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.currency.minor_units();
+        let scale = 10u128.pow(digits);
+        let sign = if self.amount_minor < 0 { "-" } else { "" };
+        let amount = self.amount_minor.unsigned_abs();
+        if digits == 0 {
+            write!(f, "{}{} {}", sign, amount, self.currency)
+        } else {
+            write!(
+                f,
+                "{}{}.{:0width$} {}",
+                sign,
+                amount / scale,
+                amount % scale,
+                self.currency,
+                width = digits as usize
+            )
+        }
+    }
+}
+
+impl Money {
+    pub fn new(amount_minor: i128, currency: CurrencyCode) -> Self {
+        Self {
+            amount_minor,
+            currency,
+        }
+    }
+
+    pub fn zero(currency: CurrencyCode) -> Self {
+        Self::new(0, currency)
+    }
+
+    /// Rounds `amount` to the currency's minor unit, e.g. 19.999 USD to 2000 cents.
+    /// Only for figures that arrive as floats, such as provider payloads and stored legacy amounts.
+    pub fn from_major(amount: f64, currency: CurrencyCode) -> BackendResult<Self> {
+        let amount_minor = (amount * 10f64.powi(currency.minor_units() as i32)).round();
+        if !amount_minor.is_finite() || amount_minor.abs() >= i128::MAX as f64 {
+            return Err(BackendError::validation(
+                "amount",
+                format!("{} is not a representable {} amount", amount, currency),
+            ));
+        }
+        Ok(Self::new(amount_minor as i128, currency))
+    }
+
+    /// `from_major` for floats kept from before migration 1006; those that don't fit the
+    /// currency (NaN, infinite) become zero
+    pub fn from_legacy(amount: f64, currency: &CurrencyCode) -> Self {
+        Self::from_major(amount, currency.clone()).unwrap_or_else(|_| Self::zero(currency.clone()))
+    }
+
+    /// Approximate, for display and for pricing that is a float anyway, like token rates
+    pub fn to_major(&self) -> f64 {
+        self.amount_minor as f64 / 10f64.powi(self.currency.minor_units() as i32)
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.amount_minor > 0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.amount_minor == 0
+    }
+
+    pub fn abs(&self) -> Self {
+        Self::new(self.amount_minor.abs(), self.currency.clone())
+    }
+
+    fn same_currency(&self, other: &Money) -> BackendResult<()> {
+        if self.currency != other.currency {
+            return Err(BackendError::validation(
+                "currency",
+                format!("cannot combine {} with {}", self.currency, other.currency),
+            ));
+        }
+        Ok(())
+    }
+
+    fn overflow(&self) -> BackendError {
+        BackendError::validation("amount", format!("{} amount out of range", self.currency))
+    }
+
+    pub fn checked_add(&self, other: &Money) -> BackendResult<Money> {
+        self.same_currency(other)?;
+        let amount_minor = self
+            .amount_minor
+            .checked_add(other.amount_minor)
+            .ok_or_else(|| self.overflow())?;
+        Ok(Self::new(amount_minor, self.currency.clone()))
+    }
+
+    pub fn checked_sub(&self, other: &Money) -> BackendResult<Money> {
+        self.same_currency(other)?;
+        let amount_minor = self
+            .amount_minor
+            .checked_sub(other.amount_minor)
+            .ok_or_else(|| self.overflow())?;
+        Ok(Self::new(amount_minor, self.currency.clone()))
+    }
+
+    /// e.g. a nightly price times the number of nights
+    pub fn checked_mul(&self, factor: u32) -> BackendResult<Money> {
+        let amount_minor = self
+            .amount_minor
+            .checked_mul(factor as i128)
+            .ok_or_else(|| self.overflow())?;
+        Ok(Self::new(amount_minor, self.currency.clone()))
+    }
+
+    /// `basis_points` hundredths of a percent of the amount, rounded half away from zero
+    /// to the minor unit
+    pub fn basis_points(&self, basis_points: u32) -> BackendResult<Money> {
+        let scaled = self
+            .amount_minor
+            .checked_mul(basis_points as i128)
+            .ok_or_else(|| self.overflow())?;
+        let (quotient, remainder) = (scaled / 10_000, scaled % 10_000);
+        let amount_minor = if remainder.abs() >= 5_000 {
+            quotient + scaled.signum()
+        } else {
+            quotient
+        };
+        Ok(Self::new(amount_minor, self.currency.clone()))
+    }
+
+    /// Zero when `amounts` is empty
+    pub fn sum<'a>(
+        currency: &CurrencyCode,
+        amounts: impl IntoIterator<Item = &'a Money>,
+    ) -> BackendResult<Money> {
+        amounts
+            .into_iter()
+            .try_fold(Self::zero(currency.clone()), |total, amount| total.checked_add(amount))
+    }
+
+    pub fn validate(&self, field: &str) -> BackendResult<()> {
+        self.currency.validate_as(field)
+    }
+}
+
+/// For fields whose type changed to `Money`: a value stored in the old shape reads as the
+/// default, and migration 1006 fills it in again from the data it was derived from.
+/// Buffered as a CBOR value rather than with `#[serde(untagged)]`, which can't hold an `i128`.
+pub(crate) fn default_if_legacy<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + Default,
+{
+    let stored = ciborium::Value::deserialize(deserializer)?;
+    Ok(stored.deserialized().unwrap_or_default())
+}
+
+/// `default_if_legacy` for optional fields. Candid only decodes `opt` through `Option`, and
+/// without field names, so a value sent over candid also reads as `None`; these fields are
+/// set by the canister, not by callers.
+pub(crate) fn none_if_legacy<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let stored = Option::<ciborium::Value>::deserialize(deserializer)?;
+    Ok(stored.and_then(|value| value.deserialized().ok()))
+}
+
+/// For amounts that were an `f64` before they became `Money`: a stored float reads as that
+/// many USD, the currency every amount was assumed to be in before migration 1006, which
+/// moves it into the booking's currency. Unlike `default_if_legacy` this reads `Money` sent
+/// over candid too.
+pub(crate) fn money_or_legacy_float<'de, D>(deserializer: D) -> Result<Money, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct MoneyOrFloat;
+
+    impl<'de> Visitor<'de> for MoneyOrFloat {
+        type Value = Money;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("Money or a float amount")
+        }
+
+        fn visit_f64<E: de::Error>(self, amount: f64) -> Result<Money, E> {
+            Money::from_major(amount, CurrencyCode::usd()).map_err(E::custom)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Money, A::Error> {
+            Money::deserialize(MapAccessDeserializer::new(map))
+        }
+    }
+
+    deserializer.deserialize_any(MoneyOrFloat)
+}
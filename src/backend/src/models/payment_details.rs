@@ -1,7 +1,7 @@
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
 
-use super::BookingId;
+use super::{money_or_legacy_float, BookingId, CurrencyCode, Money};
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
 pub struct PaymentDetails {
@@ -31,8 +31,22 @@ pub struct BEPaymentApiResponse {
     pub payment_status: String,
     // pub pay_address: String,
     // pub payin_extra_id: Option<String>,
+    /// Legacy whole-unit price, converted into `price` by migration 1006
+    #[serde(default)]
+    #[deprecated(note = "Use price instead")]
     pub price_amount: u64,
+    #[serde(default)]
+    #[deprecated(note = "Use price instead")]
     pub price_currency: String,
+    /// what the provider invoiced; filled in from `price_amount` and `price_currency` when a
+    /// caller only sends those
+    #[serde(default)]
+    pub price: Option<Money>,
+    /// what arrived, in `price`'s currency; `None` when the provider didn't say
+    #[serde(default)]
+    pub amount_paid: Option<Money>,
+    /// `pay_amount` and `actually_paid` are the provider's raw figures in `pay_currency`,
+    /// which for crypto isn't an ISO 4217 currency
     pub pay_amount: f64,
     pub actually_paid: f64,
     pub pay_currency: String,
@@ -50,11 +64,46 @@ pub struct BEPaymentApiResponse {
     // pub type_field: String,
 }
 
+impl BEPaymentApiResponse {
+    /// `price_amount` was whole units of `price_currency`, or of `fallback` if that isn't
+    /// a known currency
+    #[allow(deprecated)]
+    pub fn legacy_price(&self, fallback: &CurrencyCode) -> Money {
+        let currency = CurrencyCode::new(&self.price_currency).unwrap_or_else(|_| fallback.clone());
+        let scale = 10i128.pow(currency.minor_units());
+        Money::new(self.price_amount as i128 * scale, currency)
+    }
+
+    /// Sets `price` from `legacy_price` when it is `None`. Unless the caller sent
+    /// `amount_paid` too, that is derived the way `paid_amount` did before migration 1006,
+    /// at the rate implied by `pay_amount` / `price_amount`.
+    #[allow(deprecated)]
+    pub fn fill_money_from_legacy(&mut self, fallback: &CurrencyCode) {
+        if self.price.is_some() {
+            return;
+        }
+        let price = self.legacy_price(fallback);
+        if self.amount_paid.is_none() {
+            self.amount_paid = if self.pay_amount > 0.0 && self.price_amount > 0 {
+                Some(Money::from_legacy(
+                    self.price_amount as f64 * self.actually_paid / self.pay_amount,
+                    &price.currency,
+                ))
+            } else if self.actually_paid > 0.0 {
+                Some(Money::from_legacy(self.actually_paid, &price.currency))
+            } else {
+                None
+            };
+        }
+        self.price = Some(price);
+    }
+}
+
 impl PaymentDetails {
     pub fn new(booking_id: BookingId) -> Self {
         Self {
             booking_id,
-            payment_status: BackendPaymentStatus::Unpaid(None),
+            payment_status: BackendPaymentStatus::Pending(None),
             payment_api_response: BEPaymentApiResponse::default(),
         }
     }
@@ -65,33 +114,143 @@ impl PaymentDetails {
     }
 
     pub fn mark_payment_not_complete(&mut self, reason: String) {
-        self.payment_status = BackendPaymentStatus::Unpaid(Some(reason));
+        self.payment_status = BackendPaymentStatus::Failed(reason);
     }
 
     pub fn get_status_display(&self) -> String {
         match &self.payment_status {
+            BackendPaymentStatus::Pending(None) => "Awaiting payment".to_string(),
+            BackendPaymentStatus::Pending(Some(ref_no)) => {
+                format!("Awaiting payment (Ref: {})", ref_no)
+            }
+            BackendPaymentStatus::Confirming(ref_no) => {
+                format!("Payment confirming (Ref: {})", ref_no)
+            }
+            BackendPaymentStatus::PartiallyPaid(ref_no) => {
+                format!("Partially paid (Ref: {})", ref_no)
+            }
             BackendPaymentStatus::Paid(ref_no) => format!("Payment confirmed (Ref: {})", ref_no),
+            BackendPaymentStatus::Failed(ref_no) => format!("Payment failed (Ref: {})", ref_no),
+            BackendPaymentStatus::Expired(ref_no) => format!("Payment expired (Ref: {})", ref_no),
+            BackendPaymentStatus::Cancelled(ref_no) => {
+                format!("Payment cancelled (Ref: {})", ref_no)
+            }
+            BackendPaymentStatus::RefundPending(ref_no) => {
+                format!("Refund pending (Ref: {})", ref_no)
+            }
+            BackendPaymentStatus::Refunded { amount, reference } => {
+                format!("Refunded {} (Ref: {})", amount, reference)
+            }
             BackendPaymentStatus::Unpaid(None) => "Awaiting payment".to_string(),
             BackendPaymentStatus::Unpaid(Some(error)) => format!("Payment failed: {}", error),
         }
     }
 
+    /// Only fully paid; `Confirming` and `PartiallyPaid` don't count yet
     pub fn is_paid(&self) -> bool {
         matches!(self.payment_status, BackendPaymentStatus::Paid(_))
     }
 }
 
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+/// Payment state of a booking. The `String`s are the provider's payment id (`payment_id_v2`),
+/// or whatever reference `mark_payment_complete` / `mark_payment_not_complete` was given.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub enum BackendPaymentStatus {
-    /// transaction reference number from payments provider
+    /// `None` until a payment is started
+    Pending(Option<String>),
+    /// received, waiting for confirmations
+    Confirming(String),
+    PartiallyPaid(String),
     Paid(String),
-    /// if the transaction failed, that would be here.
-    /// if transaction is processing, then that is here too.
+    Failed(String),
+    Expired(String),
+    Cancelled(String),
+    /// booking was cancelled after it was paid
+    RefundPending(String),
+    Refunded {
+        #[serde(deserialize_with = "money_or_legacy_float")]
+        amount: Money,
+        reference: String,
+    },
+    /// Before migration 1005 every status that wasn't `Paid` was stored here, with the
+    /// provider status in a "<payment id> - <STATUS>" string. Only read from old data.
     Unpaid(Option<String>),
 }
 
 impl Default for BackendPaymentStatus {
     fn default() -> Self {
-        Self::Unpaid(None)
+        Self::Pending(None)
+    }
+}
+
+impl BackendPaymentStatus {
+    /// Maps a NowPayments status to ours; the names predate the other adapters in `payments`,
+    /// so stored statuses use them too. `refunded_amount` is only used for "refunded".
+    pub fn from_provider_status(status: &str, payment_id_v2: String, refunded_amount: Money) -> Self {
+        match status.to_lowercase().as_str() {
+            "completed" | "finished" => Self::Paid(payment_id_v2),
+            "confirming" | "confirmed" | "sending" => Self::Confirming(payment_id_v2),
+            "partially_paid" => Self::PartiallyPaid(payment_id_v2),
+            "failed" => Self::Failed(payment_id_v2),
+            "expired" => Self::Expired(payment_id_v2),
+            "cancelled" => Self::Cancelled(payment_id_v2),
+            "refunded" => Self::Refunded {
+                amount: refunded_amount,
+                reference: payment_id_v2,
+            },
+            // "waiting" and anything we don't know yet
+            _ => Self::Pending(Some(payment_id_v2)),
+        }
+    }
+
+    /// Name of the variant, for errors and logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Pending(_) => "Pending",
+            Self::Confirming(_) => "Confirming",
+            Self::PartiallyPaid(_) => "PartiallyPaid",
+            Self::Paid(_) => "Paid",
+            Self::Failed(_) => "Failed",
+            Self::Expired(_) => "Expired",
+            Self::Cancelled(_) => "Cancelled",
+            Self::RefundPending(_) => "RefundPending",
+            Self::Refunded { .. } => "Refunded",
+            Self::Unpaid(_) => "Unpaid",
+        }
+    }
+
+    /// Paid money can only leave through a refund; a failed, expired or cancelled
+    /// payment can be retried.
+    pub fn is_valid_transition(&self, next: &BackendPaymentStatus) -> bool {
+        use BackendPaymentStatus::*;
+
+        match self {
+            Pending(_) | Unpaid(_) | Failed(_) | Expired(_) | Cancelled(_) => !matches!(
+                next,
+                RefundPending(_) | Refunded { .. } | Unpaid(_)
+            ),
+
+            Confirming(_) => matches!(
+                next,
+                Confirming(_) | PartiallyPaid(_) | Paid(_) | Failed(_)
+            ),
+
+            PartiallyPaid(_) => matches!(
+                next,
+                PartiallyPaid(_)
+                    | Confirming(_)
+                    | Paid(_)
+                    | Failed(_)
+                    | Expired(_)
+                    | RefundPending(_)
+                    | Refunded { .. }
+            ),
+
+            Paid(_) => matches!(next, Paid(_) | RefundPending(_) | Refunded { .. }),
+
+            RefundPending(_) => matches!(next, RefundPending(_) | Refunded { .. }),
+
+            Refunded { .. } => matches!(next, Refunded { .. }),
+        }
     }
 }
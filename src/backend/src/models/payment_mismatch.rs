@@ -0,0 +1,185 @@
+use crate::errors::{BackendError, BackendResult};
+use crate::CanisterState;
+use candid::CandidType;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+
+use super::{
+    money_or_legacy_float, BEPaymentApiResponse, BackendPaymentStatus, Booking, BookingId,
+    CurrencyCode, Money,
+};
+
+/// How far the paid amount may be from `requested_payment` before the booking is
+/// flagged. The larger of the two allowances applies.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct PaymentTolerance {
+    /// only applies to bookings in its currency, the others just get `basis_points`
+    #[serde(deserialize_with = "money_or_legacy_float")]
+    pub absolute: Money,
+    /// of `requested_payment` in hundredths of a percent, e.g. 100 for 1%
+    #[serde(alias = "percent", deserialize_with = "basis_points_or_legacy_percent")]
+    pub basis_points: u32,
+}
+
+impl Default for PaymentTolerance {
+    fn default() -> Self {
+        Self {
+            absolute: Money::new(1, CurrencyCode::usd()),
+            basis_points: 100,
+        }
+    }
+}
+
+impl PaymentTolerance {
+    pub fn validate(&self) -> BackendResult<()> {
+        self.absolute.validate("absolute")?;
+        if self.absolute.amount_minor < 0 {
+            return Err(BackendError::validation("absolute", "cannot be negative"));
+        }
+        if self.basis_points > 10_000 {
+            return Err(BackendError::validation(
+                "basis_points",
+                "must be between 0 and 10000",
+            ));
+        }
+        Ok(())
+    }
+
+    /// In `requested_amount`'s minor unit
+    fn allowed_difference(&self, requested_amount: &Money) -> BackendResult<i128> {
+        let relative = requested_amount
+            .abs()
+            .basis_points(self.basis_points)?
+            .amount_minor;
+        if self.absolute.currency == requested_amount.currency {
+            Ok(relative.max(self.absolute.amount_minor))
+        } else {
+            Ok(relative)
+        }
+    }
+}
+
+/// The tolerance was stored as an `f64` percent, e.g. 1.0 for 1%, before migration 1006
+fn basis_points_or_legacy_percent<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct BasisPointsOrPercent;
+
+    impl Visitor<'_> for BasisPointsOrPercent {
+        type Value = u32;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("basis points or a float percent")
+        }
+
+        fn visit_u64<E: de::Error>(self, basis_points: u64) -> Result<u32, E> {
+            u32::try_from(basis_points).map_err(E::custom)
+        }
+
+        fn visit_f64<E: de::Error>(self, percent: f64) -> Result<u32, E> {
+            let basis_points = (percent * 100.0).round();
+            if !(0.0..=u32::MAX as f64).contains(&basis_points) {
+                return Err(E::custom(format!("{} is not a percent", percent)));
+            }
+            Ok(basis_points as u32)
+        }
+    }
+
+    deserializer.deserialize_any(BasisPointsOrPercent)
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum PaymentMismatchKind {
+    Underpaid,
+    Overpaid,
+}
+
+/// Set on a booking by `update_payment_details` when the amount paid is outside the tolerance
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct PaymentMismatch {
+    pub kind: PaymentMismatchKind,
+    pub requested_amount: Money,
+    pub paid_amount: Money,
+    /// `paid_amount - requested_amount`
+    pub delta: Money,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PaymentMismatchReport {
+    pub booking_id: BookingId,
+    pub payment_id_v2: String,
+    pub payment_status: BackendPaymentStatus,
+    pub mismatch: PaymentMismatch,
+}
+
+impl BEPaymentApiResponse {
+    /// What was actually paid, in `price`'s currency. Providers that only report the price
+    /// are taken to have been paid it; `None` when there are no amounts at all.
+    pub fn paid_amount(&self) -> Option<Money> {
+        match &self.amount_paid {
+            Some(amount_paid) => Some(amount_paid.clone()),
+            None => self.price.clone().filter(Money::is_positive),
+        }
+    }
+}
+
+impl Booking {
+    /// Compares the amount in `api_response` with `requested_payment`.
+    /// Only payments the provider considers settled, `Paid` or `PartiallyPaid`, are checked.
+    /// A payment in another currency than the booking can't be compared and isn't flagged.
+    pub fn check_payment_amount(
+        &self,
+        api_response: &BEPaymentApiResponse,
+        status: &BackendPaymentStatus,
+        tolerance: &PaymentTolerance,
+    ) -> Option<PaymentMismatch> {
+        if !matches!(
+            status,
+            BackendPaymentStatus::Paid(_) | BackendPaymentStatus::PartiallyPaid(_)
+        ) {
+            return None;
+        }
+        let paid_amount = api_response.paid_amount()?;
+        let requested_amount = self.get_requested_payment();
+        let delta = paid_amount.checked_sub(&requested_amount).ok()?;
+        if delta.amount_minor.abs() <= tolerance.allowed_difference(&requested_amount).ok()? {
+            return None;
+        }
+        Some(PaymentMismatch {
+            kind: if delta.amount_minor < 0 {
+                PaymentMismatchKind::Underpaid
+            } else {
+                PaymentMismatchKind::Overpaid
+            },
+            requested_amount,
+            paid_amount,
+            delta,
+        })
+    }
+}
+
+impl CanisterState {
+    pub fn set_payment_tolerance(&mut self, tolerance: PaymentTolerance) -> BackendResult<()> {
+        tolerance.validate()?;
+        self.payment_tolerance = tolerance;
+        Ok(())
+    }
+
+    /// Bookings flagged by `update_payment_details`, for manual review
+    pub fn get_payment_mismatches(&self) -> Vec<PaymentMismatchReport> {
+        self.bookings
+            .values()
+            .filter_map(|booking| {
+                let mismatch = booking.payment_mismatch?;
+                Some(PaymentMismatchReport {
+                    booking_id: booking.booking_id,
+                    payment_id_v2: booking.payment_details.payment_api_response.payment_id_v2,
+                    payment_status: booking.payment_details.payment_status,
+                    mismatch,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,1095 @@
+#[cfg(test)]
+mod add_booking_tests {
+    use crate::errors::BackendError;
+    use crate::models::*;
+    use crate::test_utils::{create_valid_booking, usd};
+
+    const EMAIL: &str = "user1@example.com";
+
+    fn assert_rejected(booking: Booking, field: &str) {
+        let mut state = CanisterState::new();
+        let result = state.add_booking_and_user(EMAIL, booking);
+
+        match result {
+            Err(BackendError::Validation { field: f, .. }) => assert_eq!(f, field),
+            other => panic!("expected validation error on {}, got {:?}", field, other),
+        }
+        // nothing is written for a rejected booking
+        assert!(state.bookings.is_empty());
+        assert!(state.users.is_empty());
+    }
+
+    #[test]
+    fn test_add_valid_booking() {
+        let mut state = CanisterState::new();
+        let booking = create_valid_booking("APP001", EMAIL);
+
+        assert_eq!(state.add_booking_and_user(EMAIL, booking).unwrap(), "Success");
+        assert_eq!(state.bookings.len(), 1);
+        assert!(state.get_user_profile(EMAIL).is_some());
+    }
+
+    #[test]
+    fn test_add_booking_rejects_duplicate() {
+        let mut state = CanisterState::new();
+        state
+            .add_booking_and_user(EMAIL, create_valid_booking("APP001", EMAIL))
+            .unwrap();
+
+        let mut duplicate = create_valid_booking("APP001", EMAIL);
+        duplicate.user_selected_hotel_room_details.requested_payment = Some(usd(100));
+        let result = state.add_booking_and_user(EMAIL, duplicate);
+        assert_eq!(
+            result.unwrap_err(),
+            BackendError::Conflict("Booking ID already exists".to_string())
+        );
+
+        // the stored booking is not overwritten
+        let stored = state.get_booking_by_id(&BookingId::new("APP001".to_string(), EMAIL.to_string()));
+        assert_eq!(stored.unwrap().get_requested_payment(), usd(40000));
+    }
+
+    #[test]
+    fn test_add_booking_rejects_email_mismatch() {
+        let booking = create_valid_booking("APP001", "someone.else@example.com");
+        assert_rejected(booking, "booking_id.email");
+    }
+
+    #[test]
+    fn test_add_booking_rejects_empty_app_reference() {
+        let booking = create_valid_booking("  ", EMAIL);
+        assert_rejected(booking, "booking_id.app_reference");
+    }
+
+    #[test]
+    fn test_add_booking_rejects_no_adults() {
+        let mut booking = create_valid_booking("APP001", EMAIL);
+        booking.guests.adults.clear();
+        assert_rejected(booking, "guests.adults");
+    }
+
+    #[test]
+    fn test_add_booking_rejects_empty_hotel_code() {
+        let mut booking = create_valid_booking("APP001", EMAIL);
+        booking.user_selected_hotel_room_details.hotel_details.hotel_code = String::new();
+        assert_rejected(booking, "hotel_details.hotel_code");
+    }
+
+    #[test]
+    fn test_add_booking_rejects_end_before_start() {
+        let mut booking = create_valid_booking("APP001", EMAIL);
+        booking.user_selected_hotel_room_details.date_range = SelectedDateRange {
+            start: (2025, 1, 5),
+            end: (2025, 1, 1),
+        };
+        assert_rejected(booking, "date_range");
+    }
+
+    #[test]
+    fn test_add_booking_rejects_invalid_date() {
+        let mut booking = create_valid_booking("APP001", EMAIL);
+        booking.user_selected_hotel_room_details.date_range = SelectedDateRange {
+            start: (2025, 2, 30),
+            end: (2025, 3, 2),
+        };
+        assert_rejected(booking, "date_range");
+    }
+
+    #[test]
+    fn test_add_booking_rejects_missing_dates() {
+        let mut booking = create_valid_booking("APP001", EMAIL);
+        booking.user_selected_hotel_room_details.date_range = SelectedDateRange::default();
+        assert_rejected(booking, "date_range");
+    }
+
+    #[test]
+    fn test_add_booking_rejects_zero_amount() {
+        let mut booking = create_valid_booking("APP001", EMAIL);
+        booking.user_selected_hotel_room_details.requested_payment = Some(usd(0));
+        assert_rejected(booking, "requested_payment");
+    }
+
+    #[test]
+    fn test_add_booking_rejects_negative_amount_or_unknown_currency() {
+        let unknown: CurrencyCode = serde_json::from_str("\"XYZ\"").unwrap();
+        for amount in [usd(-1000), Money::new(40000, unknown)] {
+            let mut booking = create_valid_booking("APP001", EMAIL);
+            booking.user_selected_hotel_room_details.requested_payment = Some(amount);
+            assert_rejected(booking, "requested_payment");
+        }
+    }
+
+    #[test]
+    fn test_add_booking_rejects_room_price_in_other_currency() {
+        let mut booking = create_valid_booking("APP001", EMAIL);
+        booking.user_selected_hotel_room_details.room_details[0].price =
+            Some(Money::new(9000, CurrencyCode::new("EUR").unwrap()));
+        assert_rejected(booking, "room_details.price");
+    }
+}
+
+#[cfg(test)]
+mod my_booking_tests {
+    use crate::errors::BackendError;
+    use crate::models::*;
+    use crate::test_utils::create_valid_booking;
+    use candid::Principal;
+
+    const EMAIL: &str = "user1@example.com";
+
+    fn user_principal() -> Principal {
+        Principal::from_text("be2us-64aaa-aaaaa-qaabq-cai").unwrap()
+    }
+
+    fn create_linked_state() -> CanisterState {
+        let mut state = CanisterState::new();
+        state
+            .user_principal_email_index
+            .insert(user_principal(), EMAIL.to_string());
+        state
+    }
+
+    #[test]
+    fn test_create_my_booking_uses_linked_email() {
+        let mut state = create_linked_state();
+        let booking = create_valid_booking("APP001", EMAIL);
+
+        assert!(state.add_booking_for_caller(user_principal(), booking).is_ok());
+        assert_eq!(
+            state.get_user_bookings_by_principal(user_principal()).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_create_my_booking_rejects_anonymous() {
+        let mut state = create_linked_state();
+        let booking = create_valid_booking("APP001", EMAIL);
+
+        let result = state.add_booking_for_caller(Principal::anonymous(), booking);
+        assert!(matches!(result, Err(BackendError::Unauthorized(_))));
+        assert!(state.bookings.is_empty());
+    }
+
+    #[test]
+    fn test_create_my_booking_rejects_unlinked_principal() {
+        let mut state = CanisterState::new();
+        let booking = create_valid_booking("APP001", EMAIL);
+
+        let result = state.add_booking_for_caller(user_principal(), booking);
+        assert!(matches!(result, Err(BackendError::Unauthorized(_))));
+    }
+
+    #[test]
+    fn test_create_my_booking_rejects_other_users_email() {
+        let mut state = create_linked_state();
+        let booking = create_valid_booking("APP001", "victim@example.com");
+
+        let result = state.add_booking_for_caller(user_principal(), booking);
+        assert!(matches!(result, Err(BackendError::Unauthorized(_))));
+        assert!(state.bookings.is_empty());
+    }
+
+    #[test]
+    fn test_create_my_booking_resets_server_owned_fields() {
+        let mut state = create_linked_state();
+        let mut booking = create_valid_booking("APP001", EMAIL);
+        booking.payment_details.payment_status = BackendPaymentStatus::Paid("forged".to_string());
+        booking.book_room_status = Some(BEBookRoomResponse::default());
+        let booking_id = booking.booking_id.clone();
+
+        state.add_booking_for_caller(user_principal(), booking).unwrap();
+
+        let stored = state.get_booking_by_id(&booking_id).unwrap();
+        assert!(!stored.payment_details.is_paid());
+        assert!(stored.book_room_status.is_none());
+    }
+
+    #[test]
+    fn test_add_booking_starts_as_pending_whatever_the_client_sent() {
+        let mut state = CanisterState::new();
+        for (app_ref, status) in [
+            ("APP001", BackendPaymentStatus::Unpaid(None)),
+            ("APP002", BackendPaymentStatus::Paid("forged".to_string())),
+        ] {
+            let mut booking = create_valid_booking(app_ref, EMAIL);
+            booking.payment_details.payment_status = status;
+            let booking_id = booking.booking_id.clone();
+
+            state.add_booking_and_user(EMAIL, booking).unwrap();
+
+            let stored = state.get_booking_by_id(&booking_id).unwrap();
+            assert_eq!(stored.payment_details.payment_status, BackendPaymentStatus::Pending(None));
+        }
+    }
+
+    #[test]
+    fn test_get_my_booking_only_returns_own_bookings() {
+        let mut state = create_linked_state();
+        state
+            .add_booking_and_user(EMAIL, create_valid_booking("APP001", EMAIL))
+            .unwrap();
+        state
+            .add_booking_and_user("other@example.com", create_valid_booking("APP002", "other@example.com"))
+            .unwrap();
+
+        let own = BookingId::new("APP001".to_string(), EMAIL.to_string());
+        assert!(state.get_booking_for_caller(user_principal(), &own).is_ok());
+
+        let other = BookingId::new("APP002".to_string(), "other@example.com".to_string());
+        let result = state.get_booking_for_caller(user_principal(), &other);
+        assert!(matches!(result, Err(BackendError::Unauthorized(_))));
+
+        let missing = BookingId::new("APP404".to_string(), EMAIL.to_string());
+        let result = state.get_booking_for_caller(user_principal(), &missing);
+        assert!(matches!(result, Err(BackendError::NotFound { .. })));
+    }
+
+    #[test]
+    fn test_caller_owns_only_its_linked_email() {
+        let state = create_linked_state();
+
+        assert!(state.caller_owns_email(user_principal(), EMAIL));
+        assert!(!state.caller_owns_email(user_principal(), "other@example.com"));
+        assert!(!state.caller_owns_email(Principal::from_slice(&[9; 29]), EMAIL));
+        assert!(!state.caller_owns_email(Principal::anonymous(), EMAIL));
+    }
+}
+
+#[cfg(test)]
+mod cancellation_tests {
+    use crate::errors::BackendError;
+    use crate::models::*;
+    use crate::test_utils::{self, usd};
+    use crate::timeline::BookingEventKind;
+
+    const EMAIL: &str = "user1@example.com";
+
+    fn create_test_state(paid: bool) -> (CanisterState, BookingId) {
+        let (mut state, booking_id) = test_utils::create_test_state(EMAIL);
+        if paid {
+            let mut payment_details = PaymentDetails::new(booking_id.clone());
+            payment_details.payment_api_response.payment_id_v2 = "pay_1".to_string();
+            payment_details.payment_api_response.payment_status = "finished".to_string();
+            state
+                .update_payment_details(booking_id.clone(), payment_details)
+                .unwrap();
+        }
+        (state, booking_id)
+    }
+
+    fn set_resolved_status(state: &mut CanisterState, booking_id: &BookingId, status: ResolvedBookingStatus) {
+        let response = BEBookRoomResponse {
+            status: "200".to_string(),
+            message: String::new(),
+            commit_booking: BookingDetails {
+                booking_id: booking_id.clone(),
+                resolved_booking_status: status,
+                booking_status: format!("{:?}", status),
+                ..Default::default()
+            },
+        };
+        state
+            .update_book_room_response(booking_id.clone(), response)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_cancel_unpaid_booking() {
+        let (mut state, booking_id) = create_test_state(false);
+
+        let booking = state
+            .cancel_booking(booking_id.clone(), "guest changed plans".to_string())
+            .unwrap();
+
+        assert_eq!(
+            booking.get_resolved_booking_status(),
+            ResolvedBookingStatus::BookingCancelled
+        );
+        assert_eq!(booking.cancellation.unwrap().reason, "guest changed plans");
+        // nothing was paid, so nothing to refund
+        assert_eq!(booking.payment_details.payment_status, BackendPaymentStatus::Pending(None));
+    }
+
+    #[test]
+    fn test_cancel_paid_confirmed_booking_waits_for_refund() {
+        let (mut state, booking_id) = create_test_state(true);
+        set_resolved_status(&mut state, &booking_id, ResolvedBookingStatus::BookingConfirmed);
+
+        let booking = state
+            .cancel_booking(booking_id.clone(), "hotel overbooked".to_string())
+            .unwrap();
+
+        assert_eq!(
+            booking.payment_details.payment_status,
+            BackendPaymentStatus::RefundPending("pay_1".to_string())
+        );
+        assert!(!booking.payment_details.is_paid());
+
+        let summary = &state.get_all_bookings()[0];
+        assert_eq!(summary.booking_status, "Cancelled: hotel overbooked");
+        assert_eq!(summary.payment_status, "Refund pending (Ref: pay_1)");
+    }
+
+    #[test]
+    fn test_cancel_rejects_invalid_transition_and_empty_reason() {
+        let (mut state, booking_id) = create_test_state(false);
+
+        let result = state.cancel_booking(booking_id.clone(), " ".to_string());
+        assert!(matches!(result, Err(BackendError::Validation { .. })));
+
+        set_resolved_status(&mut state, &booking_id, ResolvedBookingStatus::BookingFailed);
+        let result = state.cancel_booking(booking_id.clone(), "too late".to_string());
+        assert!(matches!(result, Err(BackendError::InvalidTransition { .. })));
+        assert!(state.get_booking_by_id(&booking_id).unwrap().cancellation.is_none());
+    }
+
+    #[test]
+    fn test_cancel_twice_is_invalid() {
+        let (mut state, booking_id) = create_test_state(false);
+        state.cancel_booking(booking_id.clone(), "first".to_string()).unwrap();
+
+        let result = state.cancel_booking(booking_id.clone(), "second".to_string());
+        assert!(matches!(result, Err(BackendError::InvalidTransition { .. })));
+    }
+
+    #[test]
+    fn test_cancel_missing_booking() {
+        let mut state = CanisterState::new();
+        let booking_id = BookingId::new("APP404".to_string(), EMAIL.to_string());
+
+        let result = state.cancel_booking(booking_id, "reason".to_string());
+        assert!(matches!(result, Err(BackendError::NotFound { .. })));
+    }
+
+    #[test]
+    fn test_record_refund() {
+        let (mut state, booking_id) = create_test_state(true);
+        state
+            .cancel_booking(booking_id.clone(), "hotel overbooked".to_string())
+            .unwrap();
+
+        let booking = state
+            .record_refund(booking_id.clone(), usd(40000), "re_123".to_string())
+            .unwrap();
+        assert_eq!(
+            booking.payment_details.payment_status,
+            BackendPaymentStatus::Refunded {
+                amount: usd(40000),
+                reference: "re_123".to_string()
+            }
+        );
+        assert_eq!(state.get_all_bookings()[0].payment_status, "Refunded 400.00 USD (Ref: re_123)");
+
+        let kinds: Vec<BookingEventKind> = state
+            .get_booking_timeline(&booking_id)
+            .unwrap()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        assert!(kinds.contains(&BookingEventKind::Cancelled {
+            reason: "hotel overbooked".to_string()
+        }));
+        assert!(matches!(
+            kinds.last(),
+            Some(BookingEventKind::PaymentStatusChanged {
+                to: BackendPaymentStatus::Refunded { .. },
+                ..
+            })
+        ));
+
+        // refunds are recorded once
+        let result = state.record_refund(booking_id, usd(40000), "re_124".to_string());
+        assert!(matches!(result, Err(BackendError::InvalidTransition { .. })));
+    }
+
+    #[test]
+    fn test_record_refund_requires_refund_pending() {
+        let (mut state, booking_id) = create_test_state(true);
+
+        let result = state.record_refund(booking_id.clone(), usd(40000), "re_123".to_string());
+        assert!(matches!(result, Err(BackendError::InvalidTransition { .. })));
+
+        state.cancel_booking(booking_id.clone(), "reason".to_string()).unwrap();
+        let result = state.record_refund(booking_id.clone(), usd(-100), "re_123".to_string());
+        assert!(matches!(result, Err(BackendError::Validation { .. })));
+        // the booking is in USD
+        let eur = Money::new(40000, CurrencyCode::new("EUR").unwrap());
+        let result = state.record_refund(booking_id.clone(), eur, "re_123".to_string());
+        assert!(matches!(result, Err(BackendError::Validation { .. })));
+        let result = state.record_refund(booking_id, usd(40000), String::new());
+        assert!(matches!(result, Err(BackendError::Validation { .. })));
+    }
+}
+
+#[cfg(test)]
+mod amendment_tests {
+    use crate::errors::BackendError;
+    use crate::models::*;
+    use crate::test_utils::{create_test_state, usd};
+    use crate::timeline::BookingEventKind;
+
+    const EMAIL: &str = "user1@example.com";
+
+    fn extend_stay() -> BookingAmendment {
+        BookingAmendment {
+            date_range: Some(SelectedDateRange {
+                start: (2025, 1, 1),
+                end: (2025, 1, 7),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_amend_dates_recomputes_price_and_keeps_revision() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        let result = state
+            .amend_booking(booking_id.clone(), extend_stay(), false)
+            .unwrap();
+
+        // one room at 100 per night, 4 -> 6 nights
+        assert_eq!(result.revision, 1);
+        assert_eq!(result.price_delta, usd(20000));
+        assert_eq!(result.booking.get_requested_payment(), usd(60000));
+
+        let stored = state.get_booking_by_id(&booking_id).unwrap();
+        assert_eq!(stored.user_selected_hotel_room_details.date_range.no_of_nights(), 6);
+
+        let revisions = state.get_booking_revisions(&booking_id).unwrap();
+        assert_eq!(revisions.len(), 1);
+        assert_eq!(revisions[0].revision, 1);
+        assert_eq!(revisions[0].user_selected_hotel_room_details.date_range.no_of_nights(), 4);
+        assert_eq!(revisions[0].user_selected_hotel_room_details.requested_payment, Some(usd(40000)));
+
+        let timeline = state.get_booking_timeline(&booking_id).unwrap();
+        assert_eq!(
+            timeline.last().unwrap().kind,
+            BookingEventKind::Amended {
+                revision: 1,
+                price_delta: usd(20000)
+            }
+        );
+    }
+
+    #[test]
+    fn test_explicit_price_wins() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+        let amendment = BookingAmendment {
+            requested_payment: Some(usd(35000)),
+            ..extend_stay()
+        };
+
+        let result = state.amend_booking(booking_id.clone(), amendment, false).unwrap();
+        assert_eq!(result.price_delta, usd(-5000));
+
+        // the currency can't change with an amendment
+        let in_euros = BookingAmendment {
+            requested_payment: Some(Money::new(35000, CurrencyCode::new("EUR").unwrap())),
+            ..Default::default()
+        };
+        let result = state.amend_booking(booking_id, in_euros, false);
+        assert!(matches!(result, Err(BackendError::Validation { .. })));
+    }
+
+    #[test]
+    fn test_guests_only_amendment_keeps_price() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+        // taxes and fees on top of 4 nights at 100
+        let mut booking = state.get_booking_by_id(&booking_id).unwrap();
+        booking.user_selected_hotel_room_details.requested_payment = Some(usd(46500));
+        state.bookings.insert(booking_id.clone(), booking);
+
+        let mut guests = state.get_booking_by_id(&booking_id).unwrap().guests;
+        guests.adults[0].first_name = "Jane".to_string();
+        let rename = BookingAmendment {
+            guests: Some(guests),
+            ..Default::default()
+        };
+        let result = state.amend_booking(booking_id.clone(), rename, false).unwrap();
+
+        assert_eq!(result.price_delta, usd(0));
+        assert_eq!(result.booking.get_requested_payment(), usd(46500));
+        assert_eq!(result.booking.guests.adults[0].first_name, "Jane");
+    }
+
+    #[test]
+    fn test_amendment_is_revalidated() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        let no_guests = BookingAmendment {
+            guests: Some(UserDetails::default()),
+            ..Default::default()
+        };
+        let result = state.amend_booking(booking_id.clone(), no_guests, false);
+        assert!(matches!(result, Err(BackendError::Validation { field, .. }) if field == "guests.adults"));
+
+        let zero_nights = BookingAmendment {
+            date_range: Some(SelectedDateRange {
+                start: (2025, 1, 5),
+                end: (2025, 1, 5),
+            }),
+            ..Default::default()
+        };
+        let result = state.amend_booking(booking_id.clone(), zero_nights, false);
+        assert!(matches!(result, Err(BackendError::Validation { field, .. }) if field == "date_range"));
+
+        let result = state.amend_booking(booking_id.clone(), BookingAmendment::default(), false);
+        assert!(matches!(result, Err(BackendError::Validation { .. })));
+
+        // nothing was stored
+        assert!(state.get_booking_revisions(&booking_id).unwrap().is_empty());
+        assert_eq!(state.get_booking_by_id(&booking_id).unwrap().get_requested_payment(), usd(40000));
+    }
+
+    #[test]
+    fn test_terminal_booking_needs_forced_admin_amendment() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+        state.cancel_booking(booking_id.clone(), "guest cancelled".to_string()).unwrap();
+
+        let result = state.amend_booking(booking_id.clone(), extend_stay(), false);
+        assert!(matches!(result, Err(BackendError::InvalidTransition { .. })));
+
+        let forced = BookingAmendment {
+            force: true,
+            ..extend_stay()
+        };
+        let result = state.amend_booking(booking_id.clone(), forced.clone(), false);
+        assert!(matches!(result, Err(BackendError::Unauthorized(_))));
+
+        let result = state.amend_booking(booking_id, forced, true).unwrap();
+        assert_eq!(result.revision, 1);
+    }
+
+    #[test]
+    fn test_revisions_are_numbered() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+        state.amend_booking(booking_id.clone(), extend_stay(), false).unwrap();
+        let result = state
+            .amend_booking(
+                booking_id.clone(),
+                BookingAmendment {
+                    requested_payment: Some(usd(50000)),
+                    ..Default::default()
+                },
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(result.revision, 2);
+        assert_eq!(result.price_delta, usd(-10000));
+        let revisions = state.get_booking_revisions(&booking_id).unwrap();
+        assert_eq!(revisions.iter().map(|r| r.revision).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(revisions[1].user_selected_hotel_room_details.requested_payment, Some(usd(60000)));
+    }
+
+    #[test]
+    fn test_amend_missing_booking() {
+        let mut state = CanisterState::new();
+        let booking_id = BookingId::new("APP404".to_string(), EMAIL.to_string());
+
+        let result = state.amend_booking(booking_id.clone(), extend_stay(), false);
+        assert!(matches!(result, Err(BackendError::NotFound { .. })));
+        assert!(matches!(
+            state.get_booking_revisions(&booking_id),
+            Err(BackendError::NotFound { .. })
+        ));
+    }
+}
+
+#[cfg(test)]
+mod payment_status_tests {
+    use crate::errors::BackendError;
+    use crate::models::*;
+    use crate::test_utils::{create_test_state, usd};
+
+    const EMAIL: &str = "user1@example.com";
+
+    fn provider_update(
+        state: &mut CanisterState,
+        booking_id: &BookingId,
+        provider_status: &str,
+    ) -> Result<Booking, BackendError> {
+        let mut payment_details = PaymentDetails::new(booking_id.clone());
+        payment_details.payment_api_response.payment_id_v2 = "pay_1".to_string();
+        payment_details.payment_api_response.payment_status = provider_status.to_string();
+        payment_details.payment_api_response.price = Some(usd(40000));
+        state.update_payment_details(booking_id.clone(), payment_details)
+    }
+
+    #[test]
+    fn test_from_provider_status() {
+        use BackendPaymentStatus::*;
+        let pay = || "pay_1".to_string();
+
+        let cases = vec![
+            ("waiting", Pending(Some(pay()))),
+            ("confirming", Confirming(pay())),
+            ("sending", Confirming(pay())),
+            ("partially_paid", PartiallyPaid(pay())),
+            ("finished", Paid(pay())),
+            ("COMPLETED", Paid(pay())),
+            ("failed", Failed(pay())),
+            ("expired", Expired(pay())),
+            ("cancelled", Cancelled(pay())),
+            (
+                "refunded",
+                Refunded {
+                    amount: usd(40000),
+                    reference: pay(),
+                },
+            ),
+        ];
+        for (provider_status, expected) in cases {
+            assert_eq!(
+                BackendPaymentStatus::from_provider_status(provider_status, pay(), usd(40000)),
+                expected,
+                "{}",
+                provider_status
+            );
+        }
+    }
+
+    #[test]
+    fn test_payment_status_transitions() {
+        use BackendPaymentStatus::*;
+        let pay = || "pay_1".to_string();
+
+        assert!(Pending(None).is_valid_transition(&Confirming(pay())));
+        assert!(Confirming(pay()).is_valid_transition(&Paid(pay())));
+        assert!(PartiallyPaid(pay()).is_valid_transition(&Paid(pay())));
+        assert!(Expired(pay()).is_valid_transition(&Paid(pay())));
+        assert!(Paid(pay()).is_valid_transition(&RefundPending(pay())));
+
+        assert!(!Pending(None).is_valid_transition(&RefundPending(pay())));
+        assert!(!Confirming(pay()).is_valid_transition(&Pending(Some(pay()))));
+        assert!(!Paid(pay()).is_valid_transition(&Pending(Some(pay()))));
+        assert!(!Paid(pay()).is_valid_transition(&Failed(pay())));
+        assert!(!RefundPending(pay()).is_valid_transition(&Paid(pay())));
+    }
+
+    #[test]
+    fn test_update_payment_details_follows_provider_statuses() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        let booking = provider_update(&mut state, &booking_id, "waiting").unwrap();
+        assert_eq!(
+            booking.payment_details.payment_status,
+            BackendPaymentStatus::Pending(Some("pay_1".to_string()))
+        );
+        let booking = provider_update(&mut state, &booking_id, "confirming").unwrap();
+        assert_eq!(
+            booking.payment_details.payment_status,
+            BackendPaymentStatus::Confirming("pay_1".to_string())
+        );
+        let booking = provider_update(&mut state, &booking_id, "finished").unwrap();
+        assert!(booking.payment_details.is_paid());
+        assert_eq!(state.get_all_bookings()[0].payment_status, "Payment confirmed (Ref: pay_1)");
+    }
+
+    #[test]
+    fn test_update_payment_details_refuses_regression() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+        provider_update(&mut state, &booking_id, "finished").unwrap();
+
+        let result = provider_update(&mut state, &booking_id, "waiting");
+        assert!(matches!(
+            result,
+            Err(BackendError::InvalidTransition { ref from, ref to }) if from == "Paid" && to == "Pending"
+        ));
+
+        // booking and index are unchanged
+        let booking = state.bookings.get(&booking_id).unwrap();
+        assert_eq!(
+            booking.payment_details.payment_status,
+            BackendPaymentStatus::Paid("pay_1".to_string())
+        );
+        assert_eq!(state.payment_id_index.get(&"pay_1".to_string()), Some(booking_id));
+    }
+}
+
+#[cfg(test)]
+mod payment_mismatch_tests {
+    use crate::errors::BackendError;
+    use crate::models::*;
+    use crate::payments::{provider_for, NOWPAYMENTS};
+    use crate::test_utils::{create_test_state, usd};
+    use serde_json::json;
+
+    const EMAIL: &str = "user1@example.com";
+
+    /// NowPayments callback: invoice of `price_amount` USD, 0.1 ETH expected
+    fn pay(
+        state: &mut CanisterState,
+        booking_id: &BookingId,
+        provider_status: &str,
+        price_amount: u64,
+        actually_paid: f64,
+    ) -> Result<Booking, BackendError> {
+        let payload = json!({
+            "payment_id": "pay_1", "payment_status": provider_status,
+            "price_amount": price_amount, "price_currency": "usd",
+            "pay_amount": 0.1, "actually_paid": actually_paid, "pay_currency": "eth"
+        });
+        let record = provider_for(NOWPAYMENTS).unwrap().normalize(&payload)?;
+        let payment_details = PaymentDetails {
+            payment_api_response: record.into(),
+            ..PaymentDetails::new(booking_id.clone())
+        };
+        state.update_payment_details(booking_id.clone(), payment_details)
+    }
+
+    #[test]
+    fn test_paid_amount() {
+        let mut response = BEPaymentApiResponse::default();
+        assert_eq!(response.paid_amount(), None);
+
+        // no paid amount reported, the price was paid
+        response.price = Some(usd(40000));
+        assert_eq!(response.paid_amount(), Some(usd(40000)));
+
+        response.amount_paid = Some(usd(0));
+        assert_eq!(response.paid_amount(), Some(usd(0)));
+    }
+
+    #[test]
+    fn test_exact_payment_is_not_flagged() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        let booking = pay(&mut state, &booking_id, "finished", 400, 0.1).unwrap();
+
+        assert!(booking.payment_details.is_paid());
+        assert_eq!(booking.payment_mismatch, None);
+        assert!(state.get_payment_mismatches().is_empty());
+    }
+
+    #[test]
+    fn test_finished_underpayment_is_partially_paid() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        let booking = pay(&mut state, &booking_id, "finished", 400, 0.09).unwrap();
+
+        assert_eq!(
+            booking.payment_details.payment_status,
+            BackendPaymentStatus::PartiallyPaid("pay_1".to_string())
+        );
+        let mismatch = booking.payment_mismatch.unwrap();
+        assert_eq!(mismatch.kind, PaymentMismatchKind::Underpaid);
+        assert_eq!(mismatch.requested_amount, usd(40000));
+        assert_eq!(mismatch.paid_amount, usd(36000));
+        assert_eq!(mismatch.delta, usd(-4000));
+
+        let reports = state.get_payment_mismatches();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].booking_id, booking_id);
+        assert_eq!(reports[0].payment_id_v2, "pay_1");
+
+        // the rest arrives, the flag is cleared
+        let booking = pay(&mut state, &booking_id, "finished", 400, 0.1).unwrap();
+        assert!(booking.payment_details.is_paid());
+        assert_eq!(booking.payment_mismatch, None);
+        assert!(state.get_payment_mismatches().is_empty());
+    }
+
+    #[test]
+    fn test_overpayment_stays_paid_and_is_flagged() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        let booking = pay(&mut state, &booking_id, "finished", 400, 0.15).unwrap();
+
+        assert!(booking.payment_details.is_paid());
+        assert_eq!(booking.payment_mismatch.unwrap().kind, PaymentMismatchKind::Overpaid);
+    }
+
+    #[test]
+    fn test_invoice_for_wrong_amount_is_flagged() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        // paid in full, but the invoice was created for less than the booking costs
+        let booking = pay(&mut state, &booking_id, "finished", 350, 0.1).unwrap();
+
+        assert_eq!(booking.payment_mismatch.unwrap().kind, PaymentMismatchKind::Underpaid);
+    }
+
+    #[test]
+    fn test_pending_payment_is_not_checked() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        let booking = pay(&mut state, &booking_id, "waiting", 400, 0.0).unwrap();
+
+        assert_eq!(booking.payment_mismatch, None);
+    }
+
+    #[test]
+    fn test_tolerance() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        // 1% of 400 by default
+        let booking = pay(&mut state, &booking_id, "finished", 400, 0.0991).unwrap();
+        assert!(booking.payment_details.is_paid());
+        assert_eq!(booking.payment_mismatch, None);
+
+        state
+            .set_payment_tolerance(PaymentTolerance {
+                absolute: usd(0),
+                basis_points: 0,
+            })
+            .unwrap();
+        let booking = pay(&mut state, &booking_id, "finished", 400, 0.1001).unwrap();
+        assert_eq!(booking.payment_mismatch.unwrap().kind, PaymentMismatchKind::Overpaid);
+
+        state
+            .set_payment_tolerance(PaymentTolerance {
+                absolute: usd(5000),
+                basis_points: 0,
+            })
+            .unwrap();
+        let booking = pay(&mut state, &booking_id, "finished", 400, 0.11).unwrap();
+        assert_eq!(booking.payment_mismatch, None);
+
+        // an allowance in another currency doesn't apply to the USD booking
+        state
+            .set_payment_tolerance(PaymentTolerance {
+                absolute: Money::new(5000, CurrencyCode::new("EUR").unwrap()),
+                basis_points: 0,
+            })
+            .unwrap();
+        let booking = pay(&mut state, &booking_id, "finished", 400, 0.1001).unwrap();
+        assert_eq!(booking.payment_mismatch.unwrap().kind, PaymentMismatchKind::Overpaid);
+    }
+
+    #[test]
+    fn test_set_payment_tolerance_rejects_invalid_values() {
+        let mut state = CanisterState::new();
+
+        for tolerance in [
+            PaymentTolerance {
+                absolute: usd(-100),
+                basis_points: 100,
+            },
+            PaymentTolerance {
+                absolute: usd(0),
+                basis_points: 10_001,
+            },
+        ] {
+            assert!(matches!(
+                state.set_payment_tolerance(tolerance),
+                Err(BackendError::Validation { .. })
+            ));
+        }
+        assert_eq!(state.payment_tolerance, PaymentTolerance::default());
+    }
+}
+
+#[cfg(test)]
+mod money_tests {
+    use crate::errors::BackendError;
+    use crate::memory::{from_cbor_bytes, to_cbor_bytes};
+    use crate::models::*;
+    use crate::test_utils::{create_valid_booking, usd};
+    use candid::CandidType;
+    use serde_json::json;
+
+    fn currency(code: &str) -> CurrencyCode {
+        CurrencyCode::new(code).unwrap()
+    }
+
+    #[test]
+    fn test_currency_codes() {
+        assert_eq!(currency(" usd ").as_str(), "USD");
+        assert_eq!(currency("JPY").minor_units(), 0);
+        assert_eq!(currency("KWD").minor_units(), 3);
+        assert_eq!(currency("CLF").minor_units(), 4);
+        assert_eq!(CurrencyCode::default(), CurrencyCode::usd());
+        for code in ["", "US", "BTC", "ckUSDC", "XXX"] {
+            assert!(
+                matches!(CurrencyCode::new(code), Err(BackendError::Validation { .. })),
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_major_rounds_to_the_minor_unit() {
+        assert_eq!(Money::from_major(19.999, CurrencyCode::usd()).unwrap(), usd(2000));
+        // 0.1 + 0.2 is 0.30000000000000004
+        assert_eq!(Money::from_major(0.1 + 0.2, CurrencyCode::usd()).unwrap(), usd(30));
+        assert_eq!(Money::from_major(99.99f32 as f64, CurrencyCode::usd()).unwrap(), usd(9999));
+        assert_eq!(Money::from_major(5000.4, currency("JPY")).unwrap().amount_minor, 5000);
+        assert_eq!(Money::from_major(1.2345, currency("KWD")).unwrap().amount_minor, 1235);
+        assert!(Money::from_major(f64::NAN, CurrencyCode::usd()).is_err());
+        assert!(Money::from_major(f64::INFINITY, CurrencyCode::usd()).is_err());
+        assert_eq!(usd(40050).to_major(), 400.5);
+    }
+
+    #[test]
+    fn test_arithmetic_is_exact_and_per_currency() {
+        // ten cents ten times is exactly a dollar
+        let dime = usd(10);
+        let total = Money::sum(&CurrencyCode::usd(), std::iter::repeat_n(&dime, 10)).unwrap();
+        assert_eq!(total, usd(100));
+        assert_eq!(usd(40000).checked_sub(&usd(45000)).unwrap(), usd(-5000));
+        assert_eq!(usd(10000).checked_mul(4).unwrap(), usd(40000));
+        assert_eq!(usd(40000).basis_points(100).unwrap(), usd(400));
+        // 2.5 bp of 1.00 USD is a quarter of a cent, of 2.00 half a cent
+        assert_eq!(usd(100).basis_points(25).unwrap(), usd(0));
+        assert_eq!(usd(200).basis_points(25).unwrap(), usd(1));
+        assert_eq!(usd(-200).basis_points(25).unwrap(), usd(-1));
+        // beyond what an f64 holds exactly
+        let large = Money::new(i128::MAX / 10_000, CurrencyCode::usd());
+        assert_eq!(large.basis_points(10_000).unwrap(), large);
+        assert!(Money::new(i128::MAX, CurrencyCode::usd()).basis_points(2).is_err());
+
+        let euros = Money::new(100, currency("EUR"));
+        assert!(matches!(usd(100).checked_add(&euros), Err(BackendError::Validation { .. })));
+        assert!(Money::sum(&CurrencyCode::usd(), [&usd(1), &euros]).is_err());
+        assert!(Money::new(i128::MAX, CurrencyCode::usd()).checked_add(&usd(1)).is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(usd(40000).to_string(), "400.00 USD");
+        assert_eq!(usd(-5).to_string(), "-0.05 USD");
+        assert_eq!(Money::new(5000, currency("JPY")).to_string(), "5000 JPY");
+        assert_eq!(Money::new(1_250, currency("KWD")).to_string(), "1.250 KWD");
+    }
+
+    #[test]
+    fn test_booking_with_money_decodes_from_candid() {
+        let mut booking = create_valid_booking("APP001", "user@example.com");
+        let bytes = candid::encode_one(&booking).unwrap();
+        let decoded: Booking = candid::decode_one(&bytes).unwrap();
+        assert_eq!(decoded.get_requested_payment(), booking.get_requested_payment());
+
+        // computed by the canister, so not taken from callers
+        booking.payment_mismatch = Some(PaymentMismatch {
+            kind: PaymentMismatchKind::Underpaid,
+            requested_amount: usd(40000),
+            paid_amount: usd(36000),
+            delta: usd(-4000),
+        });
+        let bytes = candid::encode_one(&booking).unwrap();
+        let decoded: Booking = candid::decode_one(&bytes).unwrap();
+        assert_eq!(decoded.payment_mismatch, None);
+    }
+    #[test]
+    #[allow(deprecated)]
+    fn test_callers_sending_only_legacy_amounts_get_money_filled_in() {
+        // what add_booking clients from before `Money` send
+        #[derive(CandidType)]
+        struct LegacyRoomDetails {
+            room_type_name: String,
+            room_unique_id: String,
+            room_price: f32,
+        }
+        #[derive(CandidType)]
+        struct LegacyHotelRoomDetails {
+            hotel_details: HotelDetails,
+            date_range: SelectedDateRange,
+            destination: Option<Destination>,
+            room_details: Vec<LegacyRoomDetails>,
+            requested_payment_amount: f64,
+        }
+        let mut booking = create_valid_booking("APP001", "user@example.com");
+        let details = &booking.user_selected_hotel_room_details;
+        let legacy = LegacyHotelRoomDetails {
+            hotel_details: details.hotel_details.clone(),
+            date_range: details.date_range.clone(),
+            destination: None,
+            room_details: vec![LegacyRoomDetails {
+                room_type_name: "Deluxe".to_string(),
+                room_unique_id: "D001".to_string(),
+                room_price: 100.0,
+            }],
+            requested_payment_amount: 400.0,
+        };
+        let bytes = candid::encode_one(&legacy).unwrap();
+        booking.user_selected_hotel_room_details = candid::decode_one(&bytes).unwrap();
+        assert_eq!(booking.user_selected_hotel_room_details.requested_payment, None);
+
+        let mut state = CanisterState::new();
+        let booking_id = booking.booking_id.clone();
+        state.add_booking_and_user("user@example.com", booking).unwrap();
+        let stored = state.get_booking_by_id(&booking_id).unwrap();
+        assert_eq!(stored.get_requested_payment(), usd(40000));
+        assert_eq!(stored.user_selected_hotel_room_details.room_details[0].price, Some(usd(10000)));
+
+        let mut payment_details = PaymentDetails::new(booking_id.clone());
+        let response = &mut payment_details.payment_api_response;
+        response.payment_id_v2 = "pay_1".to_string();
+        response.payment_status = "finished".to_string();
+        response.price_amount = 400;
+        response.price_currency = "usd".to_string();
+        let booking = state.update_payment_details(booking_id, payment_details).unwrap();
+        assert_eq!(booking.payment_details.payment_api_response.price, Some(usd(40000)));
+        assert_eq!(booking.payment_mismatch, None);
+    }
+
+    #[test]
+    fn test_float_refunds_and_tolerances_still_read() {
+        let refunded = json!({ "Refunded": { "amount": 400.0, "reference": "re_1" } });
+        let status: BackendPaymentStatus = from_cbor_bytes(to_cbor_bytes(&refunded));
+        let expected = BackendPaymentStatus::Refunded {
+            amount: usd(40000),
+            reference: "re_1".to_string(),
+        };
+        assert_eq!(status, expected);
+        let tolerance: PaymentTolerance =
+            from_cbor_bytes(to_cbor_bytes(&json!({ "absolute": 0.01, "percent": 1.0 })));
+        assert_eq!(tolerance, PaymentTolerance::default());
+        let tolerance: PaymentTolerance =
+            from_cbor_bytes(to_cbor_bytes(&json!({ "absolute": 0.01, "percent": 0.25 })));
+        assert_eq!(tolerance.basis_points, 25);
+
+        // and `Money` still reads from both storage and candid
+        let tolerance = PaymentTolerance {
+            absolute: Money::new(500, currency("EUR")),
+            basis_points: 250,
+        };
+        let read: PaymentTolerance = from_cbor_bytes(to_cbor_bytes(&tolerance));
+        assert_eq!(read, tolerance);
+        let read: PaymentTolerance = candid::decode_one(&candid::encode_one(&tolerance).unwrap()).unwrap();
+        assert_eq!(read, tolerance);
+        let read: BackendPaymentStatus = candid::decode_one(&candid::encode_one(&expected).unwrap()).unwrap();
+        assert_eq!(read, expected);
+    }
+}
+
+#[cfg(test)]
+mod stable_map_tests {
+    use crate::memory::{MapMemory, StableMap};
+    use crate::models::*;
+
+    fn corrupt_wishlist_map() -> StableMap<UserEmail, Wishlist> {
+        let memory = MapMemory::scratch();
+        // same bytes as a wishlist, but not valid CBOR
+        let mut raw: StableMap<UserEmail, String> = StableMap::init("wishlist", memory.clone());
+        raw.insert("user@example.com".to_string(), "not cbor".to_string());
+        StableMap::init("wishlist", memory)
+    }
+
+    #[test]
+    fn test_values_round_trip() {
+        let mut map: StableMap<UserEmail, Wishlist> = StableMap::init("wishlist", MapMemory::scratch());
+        let wishlist = Wishlist(vec![HotelId { hotel_code: "H1".to_string() }]);
+        assert!(map.insert("user@example.com".to_string(), wishlist).is_none());
+
+        let read = map.get(&"user@example.com".to_string()).unwrap();
+        assert_eq!(read.0[0].hotel_code, "H1");
+        assert_eq!(map.values().count(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "failed to decode wishlist entry \"user@example.com\"")]
+    fn test_undecodable_value_names_map_and_key() {
+        corrupt_wishlist_map().get(&"user@example.com".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "failed to decode wishlist entry \"user@example.com\"")]
+    fn test_undecodable_value_names_map_and_key_when_iterating() {
+        corrupt_wishlist_map().iter().for_each(drop);
+    }
+}
@@ -1,16 +1,40 @@
+use crate::errors::{BackendError, BackendResult};
+use crate::memory::{from_cbor_bytes, to_cbor_bytes, try_from_cbor_bytes, MapValue};
 use crate::Booking;
 use candid::CandidType;
+use ic_stable_structures::storable::{Bound, Storable};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::BTreeMap;
 
-use super::{BEBookRoomResponse, BookingDetails, BookingId};
+use super::BookingId;
 
 #[derive(CandidType, Deserialize, Default, Serialize, Clone, Debug)]
 pub struct UserInfoAndBookings {
     pub primary_user: AdultDetail,
+    /// empty for entries in the `users` stable map - those bookings live in
+    /// `CanisterState::bookings` and are filled in by `CanisterState::get_user_profile`
     pub bookings: BTreeMap<BookingId, Booking>,
 }
 
+impl Storable for UserInfoAndBookings {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        to_cbor_bytes(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        from_cbor_bytes(bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl MapValue for UserInfoAndBookings {
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        try_from_cbor_bytes(bytes)
+    }
+}
+
 impl UserInfoAndBookings {
     // pub fn new(primary_user: AdultDetail) -> Result<Self, String> {
     //     // Validate that primary user has required contact info
@@ -45,10 +69,10 @@ impl UserInfoAndBookings {
     //     Ok(())
     // }
 
-    pub fn add_booking(&mut self, booking: Booking) -> Result<(), String> {
+    pub fn add_booking(&mut self, booking: Booking) -> BackendResult<()> {
         // Check for duplicate booking_id
         if self.bookings.contains_key(&booking.booking_id) {
-            return Err("Booking ID already exists".into());
+            return Err(BackendError::Conflict("Booking ID already exists".into()));
         }
 
         self.bookings.insert(booking.booking_id.clone(), booking); // Insert into BTreeMap
@@ -59,26 +83,12 @@ impl UserInfoAndBookings {
         &mut self,
         booking_id: &BookingId,
         message: String,
-    ) -> Result<(), String> {
+    ) -> BackendResult<()> {
         if let Some(booking) = self.bookings.get_mut(booking_id) {
-            let frontend_message = format!("[frontend] {}", message);
-
-            // If book_room_status is None, create a new one with the message
-            if booking.book_room_status.is_none() {
-                booking.book_room_status = Some(BEBookRoomResponse {
-                    status: "Updated".to_string(),
-                    message: frontend_message,
-                    commit_booking: BookingDetails::default(),
-                });
-            } else {
-                // Update the existing book_room_status message
-                if let Some(status) = &mut booking.book_room_status {
-                    status.message = frontend_message;
-                }
-            }
+            booking.update_booking_message(message);
             Ok(())
         } else {
-            Err("Booking ID does not exist".to_string())
+            Err(BackendError::not_found("Booking", booking_id.get_app_reference()))
         }
     }
 
@@ -0,0 +1,206 @@
+//! Idempotent ingestion of payment provider callbacks.
+//!
+//! Providers may deliver the same callback several times and out of order. An event is
+//! identified by (provider, payment_id_v2, updated_at, payment_status): a repeat is ignored,
+//! one older than the last applied event of the same payment is only logged, and the others
+//! go through `update_payment_details`, which refuses status regressions. That includes
+//! events with the timestamp of an earlier one but another status, since timestamps may
+//! only have second resolution. Every distinct event is kept per booking, ordered by
+//! `updated_at`.
+use candid::CandidType;
+use chrono::DateTime;
+use ic_stable_structures::storable::{Bound, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use crate::audit::{self, call_context, AuditTarget};
+use crate::errors::{BackendError, BackendResult};
+use crate::memory::{from_cbor_bytes, to_cbor_bytes, try_from_cbor_bytes, MapValue};
+use crate::roles::{can_read_bookings, is_payment_service};
+use crate::{BEPaymentApiResponse, Booking, BookingId, CanisterState, PaymentDetails, STATE};
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PaymentEvent {
+    /// `None` to find the booking through `payment_id_v2`, which must already be known
+    pub booking_id: Option<BookingId>,
+    pub payment_api_response: BEPaymentApiResponse,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum PaymentEventOutcome {
+    Applied,
+    /// same (provider, payment_id_v2, updated_at, payment_status) as an earlier event,
+    /// not stored again
+    Duplicate,
+    /// older than the last applied event of the same payment
+    OutOfOrder,
+    /// the status change isn't allowed, e.g. "finished" back to "waiting"
+    Rejected(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PaymentEventRecord {
+    pub received_at: u64,
+    /// `updated_at` of the event, in nanoseconds since the epoch
+    pub updated_at_ns: u64,
+    pub outcome: PaymentEventOutcome,
+    pub payment_api_response: BEPaymentApiResponse,
+}
+
+impl PaymentEventRecord {
+    fn is_same_payment(&self, response: &BEPaymentApiResponse) -> bool {
+        self.payment_api_response.provider == response.provider
+            && self.payment_api_response.payment_id_v2 == response.payment_id_v2
+    }
+
+    fn is_same_event(&self, response: &BEPaymentApiResponse, updated_at_ns: u64) -> bool {
+        self.is_same_payment(response)
+            && self.updated_at_ns == updated_at_ns
+            && self.payment_api_response.payment_status == response.payment_status
+    }
+}
+
+/// Events of one booking, ordered by `updated_at`
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct PaymentEventLog(pub Vec<PaymentEventRecord>);
+
+impl Storable for PaymentEventLog {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        to_cbor_bytes(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        from_cbor_bytes(bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl MapValue for PaymentEventLog {
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        try_from_cbor_bytes(bytes)
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PaymentEventResult {
+    pub outcome: PaymentEventOutcome,
+    /// the booking after the event
+    pub booking: Booking,
+}
+
+/// RFC 3339, as sent by NowPayments ("2021-04-12T14:22:54.942Z")
+pub fn parse_updated_at(updated_at: &str) -> BackendResult<u64> {
+    DateTime::parse_from_rfc3339(updated_at)
+        .ok()
+        .and_then(|time| time.timestamp_nanos_opt())
+        .and_then(|nanos| u64::try_from(nanos).ok())
+        .ok_or_else(|| {
+            BackendError::validation("updated_at", format!("'{}' is not an RFC 3339 timestamp", updated_at))
+        })
+}
+
+impl CanisterState {
+    /// The booking `event` is for
+    pub fn resolve_payment_event_booking(&self, event: &PaymentEvent) -> BackendResult<BookingId> {
+        let payment_id_v2 = &event.payment_api_response.payment_id_v2;
+        if payment_id_v2.is_empty() {
+            return Err(BackendError::validation("payment_id_v2", "cannot be empty"));
+        }
+        let booking_id = match &event.booking_id {
+            Some(booking_id) => booking_id.clone(),
+            None => self
+                .payment_id_index
+                .get(payment_id_v2)
+                .ok_or_else(|| BackendError::not_found("Payment", payment_id_v2))?,
+        };
+        if !self.bookings.contains_key(&booking_id) {
+            return Err(BackendError::not_found("Booking", booking_id.get_app_reference()));
+        }
+        Ok(booking_id)
+    }
+
+    /// Applies `event` unless it was seen before or is older than the last applied one.
+    /// A refused status change is logged and reported as `Rejected`, not as an error,
+    /// so the provider doesn't keep retrying it.
+    pub fn ingest_payment_event(&mut self, event: PaymentEvent) -> BackendResult<PaymentEventResult> {
+        let booking_id = self.resolve_payment_event_booking(&event)?;
+        let mut response = event.payment_api_response;
+        // logged the way `update_payment_details` stores it
+        if let Some(booking) = self.bookings.get(&booking_id) {
+            response.fill_money_from_legacy(&booking.get_requested_payment().currency);
+        }
+        let updated_at_ns = parse_updated_at(&response.updated_at)?;
+
+        let mut log = self.payment_events.get(&booking_id).unwrap_or_default();
+        let same_payment = || log.0.iter().filter(|record| record.is_same_payment(&response));
+        let outcome = if log.0.iter().any(|record| record.is_same_event(&response, updated_at_ns)) {
+            PaymentEventOutcome::Duplicate
+        } else if same_payment()
+            .filter(|record| record.outcome == PaymentEventOutcome::Applied)
+            .any(|record| record.updated_at_ns > updated_at_ns)
+        {
+            PaymentEventOutcome::OutOfOrder
+        } else {
+            let payment_details = PaymentDetails {
+                payment_api_response: response.clone(),
+                ..PaymentDetails::new(booking_id.clone())
+            };
+            match self.update_payment_details(booking_id.clone(), payment_details) {
+                Ok(_) => PaymentEventOutcome::Applied,
+                Err(error @ BackendError::InvalidTransition { .. }) => {
+                    PaymentEventOutcome::Rejected(error.to_string())
+                }
+                Err(error) => return Err(error),
+            }
+        };
+
+        if outcome != PaymentEventOutcome::Duplicate {
+            let (_, now) = call_context();
+            let position = log.0.partition_point(|record| record.updated_at_ns <= updated_at_ns);
+            log.0.insert(
+                position,
+                PaymentEventRecord {
+                    received_at: now,
+                    updated_at_ns,
+                    outcome: outcome.clone(),
+                    payment_api_response: response,
+                },
+            );
+            self.payment_events.insert(booking_id.clone(), log);
+        }
+
+        let booking = self
+            .bookings
+            .get(&booking_id)
+            .ok_or_else(|| BackendError::not_found("Booking", booking_id.get_app_reference()))?;
+        Ok(PaymentEventResult { outcome, booking })
+    }
+
+    pub fn get_payment_events(&self, booking_id: &BookingId) -> BackendResult<Vec<PaymentEventRecord>> {
+        if !self.bookings.contains_key(booking_id) {
+            return Err(BackendError::not_found("Booking", booking_id.get_app_reference()));
+        }
+        Ok(self.payment_events.get(booking_id).unwrap_or_default().0)
+    }
+}
+
+/// Safe to call again with the same event, see the module docs
+#[ic_cdk_macros::update(guard = "is_payment_service")]
+pub fn ingest_payment_event(event: PaymentEvent) -> BackendResult<PaymentEventResult> {
+    let booking_id = STATE.with(|state| state.borrow().resolve_payment_event_booking(&event))?;
+    audit::audited(
+        "ingest_payment_event",
+        AuditTarget::booking(&booking_id),
+        |state| state.get_booking_by_id(&booking_id),
+        |state| state.ingest_payment_event(event),
+    )
+}
+
+#[ic_cdk_macros::query(guard = "can_read_bookings")]
+fn get_payment_events(booking_id: BookingId) -> BackendResult<Vec<PaymentEventRecord>> {
+    STATE.with(|state| state.borrow().get_payment_events(&booking_id))
+}
+
+#[cfg(test)]
+mod tests;
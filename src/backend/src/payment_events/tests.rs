@@ -0,0 +1,184 @@
+#[cfg(test)]
+mod payment_events_tests {
+    use crate::errors::BackendError;
+    use crate::models::*;
+    use crate::payment_events::{parse_updated_at, PaymentEvent, PaymentEventOutcome};
+    use crate::test_utils::{create_test_state, usd};
+    use crate::timeline::BookingEventKind;
+
+    const EMAIL: &str = "user1@example.com";
+
+    fn event(booking_id: Option<&BookingId>, status: &str, updated_at: &str) -> PaymentEvent {
+        PaymentEvent {
+            booking_id: booking_id.cloned(),
+            payment_api_response: BEPaymentApiResponse {
+                provider: "NowPayments".to_string(),
+                payment_id_v2: "pay_1".to_string(),
+                payment_status: status.to_string(),
+                price: Some(usd(40000)),
+                updated_at: updated_at.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn outcomes(state: &CanisterState, booking_id: &BookingId) -> Vec<(String, PaymentEventOutcome)> {
+        state
+            .get_payment_events(booking_id)
+            .unwrap()
+            .into_iter()
+            .map(|record| (record.payment_api_response.payment_status, record.outcome))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_updated_at() {
+        assert_eq!(parse_updated_at("1970-01-01T00:00:01Z").unwrap(), 1_000_000_000);
+        assert_eq!(
+            parse_updated_at("2021-04-12T14:22:54.942Z").unwrap(),
+            parse_updated_at("2021-04-12T16:22:54.942+02:00").unwrap()
+        );
+        assert!(matches!(
+            parse_updated_at("yesterday"),
+            Err(BackendError::Validation { .. })
+        ));
+    }
+
+    #[test]
+    fn test_events_are_applied_in_order() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        let result = state
+            .ingest_payment_event(event(Some(&booking_id), "waiting", "2025-01-01T10:00:00Z"))
+            .unwrap();
+        assert_eq!(result.outcome, PaymentEventOutcome::Applied);
+
+        // later events can find the booking through payment_id_v2
+        let result = state
+            .ingest_payment_event(event(None, "finished", "2025-01-01T10:05:00Z"))
+            .unwrap();
+        assert_eq!(result.outcome, PaymentEventOutcome::Applied);
+        assert!(result.booking.payment_details.is_paid());
+    }
+
+    #[test]
+    fn test_duplicate_event_is_ignored() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+        let finished = event(Some(&booking_id), "finished", "2025-01-01T10:05:00Z");
+
+        state.ingest_payment_event(finished.clone()).unwrap();
+        let result = state.ingest_payment_event(finished).unwrap();
+
+        assert_eq!(result.outcome, PaymentEventOutcome::Duplicate);
+        assert!(result.booking.payment_details.is_paid());
+        assert_eq!(state.get_payment_events(&booking_id).unwrap().len(), 1);
+        // only one status change on the timeline
+        let status_changes = state
+            .get_booking_timeline(&booking_id)
+            .unwrap()
+            .into_iter()
+            .filter(|e| matches!(e.kind, BookingEventKind::PaymentStatusChanged { .. }))
+            .count();
+        assert_eq!(status_changes, 1);
+    }
+
+    #[test]
+    fn test_late_event_is_logged_but_not_applied() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        state
+            .ingest_payment_event(event(Some(&booking_id), "finished", "2025-01-01T10:05:00Z"))
+            .unwrap();
+        let result = state
+            .ingest_payment_event(event(Some(&booking_id), "waiting", "2025-01-01T10:00:00Z"))
+            .unwrap();
+
+        assert_eq!(result.outcome, PaymentEventOutcome::OutOfOrder);
+        assert_eq!(
+            result.booking.payment_details.payment_status,
+            BackendPaymentStatus::Paid("pay_1".to_string())
+        );
+        // the log is ordered by updated_at, not by arrival
+        assert_eq!(
+            outcomes(&state, &booking_id),
+            vec![
+                ("waiting".to_string(), PaymentEventOutcome::OutOfOrder),
+                ("finished".to_string(), PaymentEventOutcome::Applied),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_newer_regression_is_rejected() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        state
+            .ingest_payment_event(event(Some(&booking_id), "finished", "2025-01-01T10:05:00Z"))
+            .unwrap();
+        let result = state
+            .ingest_payment_event(event(Some(&booking_id), "waiting", "2025-01-01T10:10:00Z"))
+            .unwrap();
+
+        assert!(matches!(result.outcome, PaymentEventOutcome::Rejected(_)));
+        assert!(result.booking.payment_details.is_paid());
+        assert_eq!(state.get_payment_events(&booking_id).unwrap().len(), 2);
+
+        // a rejected event still counts as seen
+        let result = state
+            .ingest_payment_event(event(Some(&booking_id), "waiting", "2025-01-01T10:10:00Z"))
+            .unwrap();
+        assert_eq!(result.outcome, PaymentEventOutcome::Duplicate);
+    }
+
+    #[test]
+    fn test_same_timestamp_with_another_status_is_not_a_duplicate() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        state
+            .ingest_payment_event(event(Some(&booking_id), "waiting", "2025-01-01T10:00:00Z"))
+            .unwrap();
+        let result = state
+            .ingest_payment_event(event(Some(&booking_id), "finished", "2025-01-01T10:00:00Z"))
+            .unwrap();
+        assert_eq!(result.outcome, PaymentEventOutcome::Applied);
+        assert!(result.booking.payment_details.is_paid());
+
+        // going back is still refused, and logged
+        let result = state
+            .ingest_payment_event(event(Some(&booking_id), "confirming", "2025-01-01T10:00:00Z"))
+            .unwrap();
+        assert!(matches!(result.outcome, PaymentEventOutcome::Rejected(_)));
+        assert_eq!(
+            outcomes(&state, &booking_id),
+            vec![
+                ("waiting".to_string(), PaymentEventOutcome::Applied),
+                ("finished".to_string(), PaymentEventOutcome::Applied),
+                ("confirming".to_string(), PaymentEventOutcome::Rejected(
+                    "Invalid status transition from Paid to Confirming".to_string()
+                )),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejects_unusable_events() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        // unknown payment and no booking id
+        assert!(matches!(
+            state.ingest_payment_event(event(None, "waiting", "2025-01-01T10:00:00Z")),
+            Err(BackendError::NotFound { .. })
+        ));
+        assert!(matches!(
+            state.ingest_payment_event(event(Some(&booking_id), "waiting", "")),
+            Err(BackendError::Validation { .. })
+        ));
+        let mut no_payment_id = event(Some(&booking_id), "waiting", "2025-01-01T10:00:00Z");
+        no_payment_id.payment_api_response.payment_id_v2 = String::new();
+        assert!(matches!(
+            state.ingest_payment_event(no_payment_id),
+            Err(BackendError::Validation { .. })
+        ));
+        assert!(state.get_payment_events(&booking_id).unwrap().is_empty());
+    }
+}
@@ -1,28 +1,20 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod payment_id_index_tests {
+    use crate::errors::BackendError;
     use crate::models::*;
     use crate::rebuild_payment_id_index;
-    use std::collections::BTreeMap;
+    use crate::test_utils::create_valid_booking_with_payment_id_v2;
 
     fn create_test_booking_with_payment_id_v2(
         app_ref: &str, 
         email: &str, 
         payment_id_v2: &str
     ) -> (BookingId, Booking) {
-        let booking_id = BookingId::new(app_ref.to_string(), email.to_string());
-        let mut payment_details = PaymentDetails::new(booking_id.clone());
-        payment_details.payment_api_response.payment_id_v2 = payment_id_v2.to_string();
-        payment_details.payment_api_response.provider = "test_provider".to_string();
-        
-        let booking = Booking {
-            booking_id: booking_id.clone(),
-            guests: UserDetails::default(),
-            book_room_status: None,
-            user_selected_hotel_room_details: HotelRoomDetails::default(),
-            payment_details,
-        };
-        
-        (booking_id, booking)
+        let mut booking = create_valid_booking_with_payment_id_v2(app_ref, email, payment_id_v2);
+        booking.payment_details.payment_api_response.provider = "test_provider".to_string();
+        
+        (booking.booking_id.clone(), booking)
     }
 
     fn create_test_state_with_multiple_bookings() -> CanisterState {
@@ -40,27 +32,16 @@ mod payment_id_index_tests {
         );
         
         // Add bookings for user1
-        let mut user1_bookings = BTreeMap::new();
-        user1_bookings.insert(booking_id1, booking1);
-        user1_bookings.insert(booking_id2, booking2);
-        
-        let user1_info = UserInfoAndBookings {
-            primary_user: AdultDetail::default(),
-            bookings: user1_bookings,
-        };
-        
+        state.add_booking_and_user("user1@example.com", booking1).unwrap();
+        state.add_booking_and_user("user1@example.com", booking2).unwrap();
+
         // Add booking for user2
-        let mut user2_bookings = BTreeMap::new();
-        user2_bookings.insert(booking_id3, booking3);
-        
-        let user2_info = UserInfoAndBookings {
-            primary_user: AdultDetail::default(),
-            bookings: user2_bookings,
-        };
-        
-        state.users.insert("user1@example.com".to_string(), user1_info);
-        state.users.insert("user2@example.com".to_string(), user2_info);
-        
+        state.add_booking_and_user("user2@example.com", booking3).unwrap();
+
+        assert!(state.bookings.contains_key(&booking_id1));
+        assert!(state.bookings.contains_key(&booking_id2));
+        assert!(state.bookings.contains_key(&booking_id3));
+
         state
     }
 
@@ -77,8 +58,7 @@ mod payment_id_index_tests {
         
         crate::STATE.with(|s| {
             let state = s.borrow();
-            assert!(state.payment_id_index.is_some());
-            assert!(state.payment_id_index.as_ref().unwrap().is_empty());
+            assert!(state.payment_id_index.is_empty());
         });
     }
 
@@ -95,7 +75,7 @@ mod payment_id_index_tests {
         
         crate::STATE.with(|s| {
             let state = s.borrow();
-            let index = state.payment_id_index.as_ref().unwrap();
+            let index = &state.payment_id_index;
             
             // Verify all 3 payment_id_v2 values are indexed
             assert_eq!(index.len(), 3);
@@ -105,9 +85,9 @@ mod payment_id_index_tests {
             let booking_id2 = BookingId::new("APP002".to_string(), "user1@example.com".to_string());
             let booking_id3 = BookingId::new("APP003".to_string(), "user2@example.com".to_string());
             
-            assert_eq!(index.get("payment_abc123"), Some(&booking_id1));
-            assert_eq!(index.get("payment_xyz789"), Some(&booking_id2));
-            assert_eq!(index.get("payment_def456"), Some(&booking_id3));
+            assert_eq!(index.get(&"payment_abc123".to_string()), Some(booking_id1));
+            assert_eq!(index.get(&"payment_xyz789".to_string()), Some(booking_id2));
+            assert_eq!(index.get(&"payment_def456".to_string()), Some(booking_id3));
         });
     }
 
@@ -116,22 +96,14 @@ mod payment_id_index_tests {
         let mut state = CanisterState::new();
         
         // Create booking with empty payment_id_v2
-        let (booking_id, mut booking) = create_test_booking_with_payment_id_v2(
+        let (_booking_id, mut booking) = create_test_booking_with_payment_id_v2(
             "APP001", "user@example.com", ""
         );
         
         // Ensure payment_id_v2 is empty
         booking.payment_details.payment_api_response.payment_id_v2 = "".to_string();
         
-        let mut user_bookings = BTreeMap::new();
-        user_bookings.insert(booking_id, booking);
-        
-        let user_info = UserInfoAndBookings {
-            primary_user: AdultDetail::default(),
-            bookings: user_bookings,
-        };
-        
-        state.users.insert("user@example.com".to_string(), user_info);
+        state.add_booking_and_user("user@example.com", booking).unwrap();
         
         crate::STATE.with(|s| {
             *s.borrow_mut() = state;
@@ -141,10 +113,9 @@ mod payment_id_index_tests {
         
         crate::STATE.with(|s| {
             let state = s.borrow();
-            let index = state.payment_id_index.as_ref().unwrap();
             
             // Index should be empty since payment_id_v2 was empty
-            assert!(index.is_empty());
+            assert!(state.payment_id_index.is_empty());
         });
     }
 
@@ -157,22 +128,20 @@ mod payment_id_index_tests {
             *state_ref = state;
             
             // Manually create a non-empty index
-            let mut existing_index = BTreeMap::new();
-            existing_index.insert("existing_key".to_string(), 
+            state_ref.payment_id_index.insert("existing_key".to_string(), 
                 BookingId::new("EXISTING".to_string(), "existing@example.com".to_string()));
-            state_ref.payment_id_index = Some(existing_index);
         });
         
         rebuild_payment_id_index();
         
         crate::STATE.with(|s| {
             let state = s.borrow();
-            let index = state.payment_id_index.as_ref().unwrap();
+            let index = &state.payment_id_index;
             
             // Should still have the existing key and not rebuild
             assert_eq!(index.len(), 1);
-            assert!(index.contains_key("existing_key"));
-            assert!(!index.contains_key("payment_abc123"));
+            assert!(index.contains_key(&"existing_key".to_string()));
+            assert!(!index.contains_key(&"payment_abc123".to_string()));
         });
     }
 
@@ -180,9 +149,6 @@ mod payment_id_index_tests {
     fn test_update_payment_details_updates_index() {
         let mut state = create_test_state_with_multiple_bookings();
         
-        // Initialize the index
-        state.payment_id_index = Some(BTreeMap::new());
-        
         let booking_id = BookingId::new("APP001".to_string(), "user1@example.com".to_string());
         let mut payment_details = PaymentDetails::new(booking_id.clone());
         payment_details.payment_api_response.payment_id_v2 = "new_payment_id_123".to_string();
@@ -191,16 +157,15 @@ mod payment_id_index_tests {
         assert!(result.is_ok());
         
         // Verify index was updated
-        let index = state.payment_id_index.as_ref().unwrap();
-        assert_eq!(index.get("new_payment_id_123"), Some(&booking_id));
+        let index = &state.payment_id_index;
+        assert_eq!(index.get(&"new_payment_id_123".to_string()), Some(booking_id));
     }
 
     #[test]
     fn test_update_payment_details_prevents_duplicate_payment_id_v2() {
         let mut state = create_test_state_with_multiple_bookings();
         
-        // Initialize the index and rebuild it
-        state.payment_id_index = Some(BTreeMap::new());
+        // Rebuild the index
         rebuild_payment_id_index_for_state(&mut state);
         
         // Try to update a different booking with an existing payment_id_v2
@@ -210,15 +175,17 @@ mod payment_id_index_tests {
         
         let result = state.update_payment_details(booking_id, payment_details);
         assert!(result.is_err());
-        assert!(result.unwrap_err().contains("already used by other booking"));
+        match result.unwrap_err() {
+            BackendError::Conflict(reason) => assert!(reason.contains("already used by other booking")),
+            other => panic!("expected Conflict, got {:?}", other),
+        }
     }
 
     #[test]
     fn test_update_payment_details_allows_same_booking_same_payment_id_v2() {
         let mut state = create_test_state_with_multiple_bookings();
         
-        // Initialize the index and rebuild it
-        state.payment_id_index = Some(BTreeMap::new());
+        // Rebuild the index
         rebuild_payment_id_index_for_state(&mut state);
         
         // Update the same booking with the same payment_id_v2 should be allowed
@@ -234,14 +201,13 @@ mod payment_id_index_tests {
     fn test_update_payment_details_removes_old_index_entry() {
         let mut state = create_test_state_with_multiple_bookings();
         
-        // Initialize the index and rebuild it
-        state.payment_id_index = Some(BTreeMap::new());
+        // Rebuild the index
         rebuild_payment_id_index_for_state(&mut state);
         
         let booking_id = BookingId::new("APP001".to_string(), "user1@example.com".to_string());
         
         // Verify old payment_id_v2 is in index
-        assert!(state.payment_id_index.as_ref().unwrap().contains_key("payment_abc123"));
+        assert!(state.payment_id_index.contains_key(&"payment_abc123".to_string()));
         
         // Update with new payment_id_v2
         let mut payment_details = PaymentDetails::new(booking_id.clone());
@@ -250,19 +216,18 @@ mod payment_id_index_tests {
         let result = state.update_payment_details(booking_id.clone(), payment_details);
         assert!(result.is_ok());
         
-        let index = state.payment_id_index.as_ref().unwrap();
+        let index = &state.payment_id_index;
         
         // Old payment_id_v2 should be removed
-        assert!(!index.contains_key("payment_abc123"));
+        assert!(!index.contains_key(&"payment_abc123".to_string()));
         
         // New payment_id_v2 should be added
-        assert_eq!(index.get("new_payment_xyz"), Some(&booking_id));
+        assert_eq!(index.get(&"new_payment_xyz".to_string()), Some(booking_id));
     }
 
     #[test]
     fn test_update_payment_details_rejects_empty_payment_id_v2() {
         let mut state = create_test_state_with_multiple_bookings();
-        state.payment_id_index = Some(BTreeMap::new());
         
         let booking_id = BookingId::new("APP001".to_string(), "user1@example.com".to_string());
         let mut payment_details = PaymentDetails::new(booking_id);
@@ -273,7 +238,10 @@ mod payment_id_index_tests {
             payment_details
         );
         assert!(result.is_err());
-        assert!(result.unwrap_err().contains("cannot be empty"));
+        assert_eq!(
+            result.unwrap_err(),
+            BackendError::validation("payment_id_v2", "cannot be empty")
+        );
     }
 
     #[test]
@@ -284,8 +252,7 @@ mod payment_id_index_tests {
             let mut state_ref = s.borrow_mut();
             *state_ref = state;
             
-            // Initialize and rebuild index
-            state_ref.payment_id_index = Some(BTreeMap::new());
+            // Rebuild index
             rebuild_payment_id_index_for_state(&mut state_ref);
         });
         
@@ -301,31 +268,20 @@ mod payment_id_index_tests {
 
     // Helper function to rebuild index for a specific state (for testing)
     fn rebuild_payment_id_index_for_state(state: &mut CanisterState) {
-        if let Some(ref payment_index) = state.payment_id_index {
-            if !payment_index.is_empty() {
-                return;
-            }
+        if !state.payment_id_index.is_empty() {
+            return;
         }
 
         let mut payment_mappings = Vec::new();
-        for user_info in state.users.values() {
-            for booking in user_info.bookings.values() {
-                let payment_id_v2 = &booking.payment_details.payment_api_response.payment_id_v2;
-                if !payment_id_v2.is_empty() {
-                    payment_mappings.push((payment_id_v2.clone(), booking.booking_id.clone()));
-                }
+        for booking in state.bookings.values() {
+            let payment_id_v2 = &booking.payment_details.payment_api_response.payment_id_v2;
+            if !payment_id_v2.is_empty() {
+                payment_mappings.push((payment_id_v2.clone(), booking.booking_id.clone()));
             }
         }
 
-        if state.payment_id_index.is_none() {
-            state.payment_id_index = Some(BTreeMap::new());
-        }
-
-        if let Some(ref mut payment_index) = state.payment_id_index {
-            payment_index.clear(); // Clear for rebuild
-            for (payment_id, booking_id) in payment_mappings {
-                payment_index.insert(payment_id, booking_id);
-            }
+        for (payment_id, booking_id) in payment_mappings {
+            state.payment_id_index.insert(payment_id, booking_id);
         }
     }
-}
\ No newline at end of file
+}
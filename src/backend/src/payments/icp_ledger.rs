@@ -0,0 +1,127 @@
+use chrono::{DateTime, SecondsFormat};
+use serde_json::Value;
+
+use super::{required_string_field, string_field, PaymentProvider, PaymentRecord};
+use crate::errors::{BackendError, BackendResult};
+use crate::ledger_payments::PRICE_SCALE;
+use crate::{BackendPaymentStatus, CurrencyCode, Money};
+
+pub const ICP_LEDGER: &str = "IcpLedger";
+
+/// ICP and other ICRC-1 tokens transferred straight to the canister. Ledger transfers are
+/// final, so there is no signature to check and no confirming state; amounts are in the
+/// token's base units (e8s for ICP) with `decimals` saying how many. Tokens aren't ISO 4217
+/// currencies, so `price_per_token_e9` (in 10^-9 units of `price_currency`) and
+/// `price_currency` convert them to the booking's currency.
+///
+/// ```json
+/// { "payment_id": "<subaccount hex>", "status": "received", "token": "ICP", "decimals": 8,
+///   "expected": "1000000000", "received": "1000000000", "order_id": "APP001",
+///   "price_per_token_e9": 40000000000, "price_currency": "usd",
+///   "created_at_ns": 1735725600000000000, "updated_at_ns": 1735725900000000000 }
+/// ```
+pub struct IcpLedger;
+
+/// ICP's 8 decimals when the payload doesn't say
+const DEFAULT_DECIMALS: u32 = 8;
+
+/// Read exactly: IC timestamps in nanoseconds don't fit an f64
+fn nanos_field(payload: &Value, field: &str) -> u64 {
+    match &payload[field] {
+        Value::Number(number) => number.as_u64().unwrap_or(0),
+        Value::String(text) => text.parse().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Read exactly, and from a string too: 18 decimal amounts don't fit a JSON u64
+fn base_units_field(payload: &Value, field: &str) -> u128 {
+    match &payload[field] {
+        Value::Number(number) => number.as_u64().unwrap_or(0) as u128,
+        Value::String(text) => text.parse().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// `units` base units of a token with `decimals` at `price_per_token_e9`, rounded to the
+/// nearest minor unit of `currency`
+fn token_value(
+    units: u128,
+    decimals: u32,
+    price_per_token_e9: u128,
+    currency: &CurrencyCode,
+) -> BackendResult<Money> {
+    let numerator = 10u128
+        .checked_pow(currency.minor_units())
+        .and_then(|scale| units.checked_mul(price_per_token_e9)?.checked_mul(scale));
+    let denominator = 10u128.checked_pow(decimals + PRICE_SCALE);
+    let amount_minor = match (numerator, denominator) {
+        (Some(numerator), Some(denominator)) => (numerator / denominator)
+            + u128::from(numerator % denominator >= denominator.div_ceil(2)),
+        _ => u128::MAX,
+    };
+    let amount_minor = i128::try_from(amount_minor).map_err(|_| {
+        BackendError::validation(
+            "amount",
+            format!("{} base units are out of range in {}", units, currency),
+        )
+    })?;
+    Ok(Money::new(amount_minor, currency.clone()))
+}
+
+fn nanos_to_rfc3339(nanos: u64) -> String {
+    let seconds = (nanos / 1_000_000_000) as i64;
+    DateTime::from_timestamp(seconds, (nanos % 1_000_000_000) as u32)
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Nanos, true))
+        .unwrap_or_default()
+}
+
+impl PaymentProvider for IcpLedger {
+    fn name(&self) -> &'static str {
+        ICP_LEDGER
+    }
+
+    fn normalize(&self, payload: &Value) -> BackendResult<PaymentRecord> {
+        let decimals = match &payload["decimals"] {
+            Value::Number(decimals) => decimals
+                .as_u64()
+                .and_then(|decimals| u32::try_from(decimals).ok())
+                .unwrap_or(DEFAULT_DECIMALS),
+            _ => DEFAULT_DECIMALS,
+        };
+        required_string_field(payload, "token")?;
+        let currency = CurrencyCode::new(&required_string_field(payload, "price_currency")?)?;
+        let price_per_token_e9 = base_units_field(payload, "price_per_token_e9");
+        if price_per_token_e9 == 0 {
+            return Err(BackendError::validation("price_per_token_e9", "must be positive"));
+        }
+        let money = |field: &str| {
+            token_value(base_units_field(payload, field), decimals, price_per_token_e9, &currency)
+        };
+
+        Ok(PaymentRecord {
+            provider: ICP_LEDGER.to_string(),
+            payment_id_v2: required_string_field(payload, "payment_id")?,
+            provider_status: required_string_field(payload, "status")?,
+            amount: money("expected")?,
+            amount_paid: money("received")?,
+            order_id: string_field(payload, "order_id"),
+            created_at: nanos_to_rfc3339(nanos_field(payload, "created_at_ns")),
+            updated_at: nanos_to_rfc3339(nanos_field(payload, "updated_at_ns")),
+        })
+    }
+
+    fn map_status(&self, status: &str, payment_id_v2: String, amount: Money) -> BackendPaymentStatus {
+        match status.to_lowercase().as_str() {
+            "received" => BackendPaymentStatus::Paid(payment_id_v2),
+            "partially_received" => BackendPaymentStatus::PartiallyPaid(payment_id_v2),
+            "expired" => BackendPaymentStatus::Expired(payment_id_v2),
+            "refunded" => BackendPaymentStatus::Refunded {
+                amount,
+                reference: payment_id_v2,
+            },
+            // "pending": nothing has arrived on the ledger yet
+            _ => BackendPaymentStatus::Pending(Some(payment_id_v2)),
+        }
+    }
+}
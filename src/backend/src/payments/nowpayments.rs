@@ -0,0 +1,66 @@
+use hmac::Hmac;
+use serde_json::Value;
+use sha2::Sha512;
+
+use super::{
+    hex_signature_matches, number_field, required_string_field, string_field, PaymentProvider,
+    PaymentRecord,
+};
+use crate::errors::BackendResult;
+use crate::{BackendPaymentStatus, CurrencyCode, Money};
+
+pub const NOWPAYMENTS: &str = "NowPayments";
+
+/// Crypto payments. Prices are set in fiat (`price_amount`, `price_currency`) and paid in a
+/// coin (`pay_amount` expected, `actually_paid` received, both in `pay_currency`).
+pub struct NowPayments;
+
+impl PaymentProvider for NowPayments {
+    fn name(&self) -> &'static str {
+        NOWPAYMENTS
+    }
+
+    fn normalize(&self, payload: &Value) -> BackendResult<PaymentRecord> {
+        let amount = number_field(payload, "price_amount");
+        let pay_amount = number_field(payload, "pay_amount");
+        let actually_paid = number_field(payload, "actually_paid");
+        // converted to the price currency at the rate the invoice was created with
+        let amount_paid = if pay_amount > 0.0 {
+            amount * actually_paid / pay_amount
+        } else {
+            actually_paid
+        };
+
+        let currency = CurrencyCode::new(&string_field(payload, "price_currency"))?;
+
+        Ok(PaymentRecord {
+            provider: NOWPAYMENTS.to_string(),
+            payment_id_v2: required_string_field(payload, "payment_id")?,
+            provider_status: required_string_field(payload, "payment_status")?,
+            amount: Money::from_major(amount, currency.clone())?,
+            amount_paid: Money::from_major(amount_paid, currency)?,
+            order_id: string_field(payload, "order_id"),
+            created_at: string_field(payload, "created_at"),
+            updated_at: string_field(payload, "updated_at"),
+        })
+    }
+
+    fn map_status(&self, status: &str, payment_id_v2: String, amount: Money) -> BackendPaymentStatus {
+        BackendPaymentStatus::from_provider_status(status, payment_id_v2, amount)
+    }
+
+    /// `x-nowpayments-sig`: hex HMAC-SHA512 of the body re-serialized with its keys sorted
+    /// at every level, keyed with the IPN secret
+    fn verify_signature(&self, secret: &str, body: &str, signature: &str) -> bool {
+        let Ok(body) = serde_json::from_str::<Value>(body) else {
+            return false;
+        };
+        // serde_json's map is a BTreeMap, so serializing sorts the keys
+        let signed_payload = body.to_string();
+        hex_signature_matches::<Hmac<Sha512>>(
+            secret.as_bytes(),
+            signed_payload.as_bytes(),
+            signature,
+        )
+    }
+}
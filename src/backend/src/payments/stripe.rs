@@ -0,0 +1,141 @@
+use chrono::{DateTime, SecondsFormat};
+use hmac::Hmac;
+use serde_json::Value;
+use sha2::Sha256;
+
+use super::{
+    hex_signature_matches, number_field, required_string_field, string_field, PaymentProvider,
+    PaymentRecord,
+};
+use crate::errors::{BackendError, BackendResult};
+use crate::{BackendPaymentStatus, CurrencyCode, Money};
+
+pub const STRIPE: &str = "Stripe";
+
+/// Currencies Stripe counts in whole units instead of cents
+const ZERO_DECIMAL_CURRENCIES: [&str; 16] = [
+    "bif", "clp", "djf", "gnf", "jpy", "kmf", "krw", "mga", "pyg", "rwf", "ugx", "vnd", "vuv",
+    "xaf", "xof", "xpf",
+];
+
+/// Card payments through Stripe-style payment intents. Takes a webhook event
+/// (`payment_intent.*` or `charge.refunded`) or a bare payment intent object.
+pub struct Stripe;
+
+fn major_units(minor_units: f64, currency: &str) -> f64 {
+    if ZERO_DECIMAL_CURRENCIES.contains(&currency) {
+        minor_units
+    } else {
+        minor_units / 100.0
+    }
+}
+
+fn unix_seconds_to_rfc3339(seconds: f64) -> String {
+    DateTime::from_timestamp(seconds as i64, 0)
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_default()
+}
+
+impl PaymentProvider for Stripe {
+    fn name(&self) -> &'static str {
+        STRIPE
+    }
+
+    fn normalize(&self, payload: &Value) -> BackendResult<PaymentRecord> {
+        let object = match &payload["data"]["object"] {
+            Value::Null => payload,
+            object => object,
+        };
+        let is_charge = object["object"] == "charge";
+        let currency = required_string_field(object, "currency")?.to_lowercase();
+        let currency_code = CurrencyCode::new(&currency)?;
+        let money = |field: &str| {
+            Money::from_major(major_units(number_field(object, field), &currency), currency_code.clone())
+        };
+        let amount = money("amount")?;
+
+        let (payment_id_v2, provider_status, amount_paid) = if is_charge {
+            if object["refunded"] != true {
+                return Err(BackendError::validation(
+                    "data.object",
+                    "only refunded charges are read, use payment intent events for payments",
+                ));
+            }
+            let refunded = money("amount_refunded")?;
+            (
+                required_string_field(object, "payment_intent")?,
+                "refunded".to_string(),
+                refunded,
+            )
+        } else {
+            let status = if payload["type"] == "payment_intent.payment_failed" {
+                "payment_failed".to_string()
+            } else {
+                required_string_field(object, "status")?
+            };
+            let received = money("amount_received")?;
+            (required_string_field(object, "id")?, status, received)
+        };
+
+        // events carry their own time; a bare object only its creation time. Whole seconds,
+        // so `ingest_payment_event` tells events of the same second apart by their status.
+        let updated_at = match number_field(payload, "created") {
+            created if created > 0.0 => created,
+            _ => number_field(object, "created"),
+        };
+        let metadata = &object["metadata"];
+        let order_id = match string_field(metadata, "app_reference") {
+            app_reference if !app_reference.is_empty() => app_reference,
+            _ => string_field(metadata, "order_id"),
+        };
+
+        Ok(PaymentRecord {
+            provider: STRIPE.to_string(),
+            payment_id_v2,
+            provider_status,
+            amount,
+            amount_paid,
+            order_id,
+            created_at: unix_seconds_to_rfc3339(number_field(object, "created")),
+            updated_at: unix_seconds_to_rfc3339(updated_at),
+        })
+    }
+
+    fn map_status(&self, status: &str, payment_id_v2: String, amount: Money) -> BackendPaymentStatus {
+        match status.to_lowercase().as_str() {
+            "succeeded" => BackendPaymentStatus::Paid(payment_id_v2),
+            // authorized but not captured yet, or still being processed
+            "processing" | "requires_capture" => BackendPaymentStatus::Confirming(payment_id_v2),
+            "payment_failed" => BackendPaymentStatus::Failed(payment_id_v2),
+            "canceled" => BackendPaymentStatus::Cancelled(payment_id_v2),
+            "refunded" => BackendPaymentStatus::Refunded {
+                amount,
+                reference: payment_id_v2,
+            },
+            // "requires_payment_method", "requires_confirmation", "requires_action"
+            _ => BackendPaymentStatus::Pending(Some(payment_id_v2)),
+        }
+    }
+
+    /// `Stripe-Signature: t=<unix time>,v1=<hex HMAC-SHA256 of "<t>.<body>">`, possibly with
+    /// several `v1` entries while a secret is rolled. The timestamp isn't checked against the
+    /// clock: a replayed event is a duplicate for `ingest_payment_event`.
+    fn verify_signature(&self, secret: &str, body: &str, signature: &str) -> bool {
+        let mut timestamp = None;
+        let mut candidates = Vec::new();
+        for part in signature.split(',') {
+            match part.trim().split_once('=') {
+                Some(("t", value)) => timestamp = Some(value),
+                Some(("v1", value)) => candidates.push(value),
+                _ => {}
+            }
+        }
+        let Some(timestamp) = timestamp else {
+            return false;
+        };
+        let signed_payload = format!("{}.{}", timestamp, body);
+        candidates.into_iter().any(|candidate| {
+            hex_signature_matches::<Hmac<Sha256>>(secret.as_bytes(), signed_payload.as_bytes(), candidate)
+        })
+    }
+}
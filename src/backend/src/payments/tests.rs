@@ -0,0 +1,273 @@
+#[cfg(test)]
+mod payments_tests {
+    use crate::models::*;
+    use crate::payments::{
+        hmac_sha256, hmac_sha512, provider_for, provider_or_default, PaymentRecord, ICP_LEDGER,
+        NOWPAYMENTS, STRIPE,
+    };
+    use crate::test_utils::{create_valid_booking, usd};
+    use serde_json::{json, Value};
+
+    const IPN_SECRET: &str = "test-ipn-secret";
+    const FINISHED_BODY: &str = include_str!("../webhooks/fixtures/nowpayments_ipn_finished.json");
+    const FINISHED_SIG: &str = include_str!("../webhooks/fixtures/nowpayments_ipn_finished.sig");
+    const PARTIALLY_PAID_BODY: &str =
+        include_str!("../webhooks/fixtures/nowpayments_ipn_partially_paid.json");
+    const PARTIALLY_PAID_SIG: &str =
+        include_str!("../webhooks/fixtures/nowpayments_ipn_partially_paid.sig");
+    const STRIPE_SECRET: &str = "whsec_test_secret";
+    const STRIPE_BODY: &str =
+        include_str!("../webhooks/fixtures/stripe_payment_intent_succeeded.json");
+    const STRIPE_SIG: &str =
+        include_str!("../webhooks/fixtures/stripe_payment_intent_succeeded.sig");
+
+    fn json(body: &str) -> Value {
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn test_hmac_rfc4231_vectors() {
+        // test case 2
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex::encode(hmac_sha512(b"Jefe", b"what do ya want for nothing?")),
+            "164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea2505549758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737"
+        );
+        // test case 6, key longer than the block size
+        let message = b"Test Using Larger Than Block-Size Key - Hash Key First";
+        assert_eq!(
+            hex::encode(hmac_sha256(&[0xaa; 131], message)),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+        assert_eq!(
+            hex::encode(hmac_sha512(&[0xaa; 131], message)),
+            "80b24263c7c1a3ebb71493c1dd7be8b49b46d1f41b4aeec1121b013783f8f3526b56d037e05f2598bd0fd2215d6a1e5295e64f73f63f0aec8b915a985d786598"
+        );
+    }
+
+    #[test]
+    fn test_provider_selection() {
+        assert_eq!(provider_for("NowPayments").unwrap().name(), NOWPAYMENTS);
+        assert_eq!(provider_for(" stripe ").unwrap().name(), STRIPE);
+        assert_eq!(provider_for("icpledger").unwrap().name(), ICP_LEDGER);
+        assert!(provider_for("PayPal").is_none());
+        // stored responses with other names were NowPayments
+        assert_eq!(provider_or_default("test_provider").name(), NOWPAYMENTS);
+    }
+
+    #[test]
+    fn test_nowpayments_signatures() {
+        let nowpayments = provider_for(NOWPAYMENTS).unwrap();
+        for (body, signature) in [
+            (FINISHED_BODY, FINISHED_SIG),
+            (PARTIALLY_PAID_BODY, PARTIALLY_PAID_SIG),
+        ] {
+            assert!(nowpayments.verify_signature(IPN_SECRET, body, signature));
+            assert!(!nowpayments.verify_signature("other-secret", body, signature));
+        }
+
+        // keys are sorted before signing, so order and whitespace don't matter
+        let reordered = json(FINISHED_BODY).as_object().unwrap().iter().rev().fold(
+            String::from("{"),
+            |body, (key, value)| {
+                format!(
+                    "{}{}\n  \"{}\": {}",
+                    body,
+                    if body.len() > 1 { "," } else { "" },
+                    key,
+                    value
+                )
+            },
+        ) + "\n}";
+        assert!(nowpayments.verify_signature(IPN_SECRET, &reordered, FINISHED_SIG));
+        assert!(!nowpayments.verify_signature(IPN_SECRET, "not json", FINISHED_SIG));
+    }
+
+    #[test]
+    fn test_nowpayments_normalize() {
+        let record = provider_for(NOWPAYMENTS)
+            .unwrap()
+            .normalize(&json(PARTIALLY_PAID_BODY))
+            .unwrap();
+
+        assert_eq!(
+            record,
+            PaymentRecord {
+                provider: NOWPAYMENTS.to_string(),
+                payment_id_v2: "5077125051".to_string(),
+                provider_status: "partially_paid".to_string(),
+                amount: usd(40000),
+                // 0.06 of the 0.1 ETH invoiced
+                amount_paid: usd(24000),
+                order_id: "APP001".to_string(),
+                created_at: "2025-01-01T10:00:00.000Z".to_string(),
+                updated_at: "2025-01-01T10:03:00.000Z".to_string(),
+            }
+        );
+        assert!(provider_for(NOWPAYMENTS)
+            .unwrap()
+            .normalize(&json!({"payment_status": "finished"}))
+            .is_err());
+        // prices must be in an ISO 4217 currency
+        let mut in_coins = json(PARTIALLY_PAID_BODY);
+        in_coins["price_currency"] = json!("btc");
+        assert!(provider_for(NOWPAYMENTS).unwrap().normalize(&in_coins).is_err());
+    }
+
+    #[test]
+    fn test_stripe_signatures() {
+        let stripe = provider_for(STRIPE).unwrap();
+
+        assert!(stripe.verify_signature(STRIPE_SECRET, STRIPE_BODY, STRIPE_SIG));
+        assert!(!stripe.verify_signature("whsec_other", STRIPE_BODY, STRIPE_SIG));
+        // the body is signed byte for byte
+        assert!(!stripe.verify_signature(STRIPE_SECRET, STRIPE_BODY.trim(), STRIPE_SIG));
+        // a different timestamp changes the signed payload
+        let resigned_at = STRIPE_SIG.replace("t=1735726201", "t=1735726202");
+        assert!(!stripe.verify_signature(STRIPE_SECRET, STRIPE_BODY, &resigned_at));
+        let no_timestamp = STRIPE_SIG.replace("t=1735726201,", "");
+        assert!(!stripe.verify_signature(STRIPE_SECRET, STRIPE_BODY, &no_timestamp));
+    }
+
+    #[test]
+    fn test_stripe_normalize() {
+        let stripe = provider_for(STRIPE).unwrap();
+
+        let record = stripe.normalize(&json(STRIPE_BODY)).unwrap();
+        assert_eq!(record.payment_id_v2, "pi_3QbT2eLkdIwHu7ix0sYbq5Zp");
+        assert_eq!(record.provider_status, "succeeded");
+        assert_eq!(record.amount, usd(40000));
+        assert_eq!(record.amount_paid, usd(40000));
+        assert_eq!(record.order_id, "APP001");
+        assert_eq!(record.created_at, "2025-01-01T10:00:00Z");
+        assert_eq!(record.updated_at, "2025-01-01T10:10:00Z");
+
+        let failed = json!({
+            "type": "payment_intent.payment_failed",
+            "created": 1735726200,
+            "data": {"object": {"id": "pi_1", "object": "payment_intent", "amount": 5000,
+                "amount_received": 0, "currency": "JPY", "status": "requires_payment_method"}}
+        });
+        let record = stripe.normalize(&failed).unwrap();
+        assert_eq!(record.provider_status, "payment_failed");
+        // yen have no minor unit
+        assert_eq!(record.amount, Money::new(5000, CurrencyCode::new("jpy").unwrap()));
+
+        let refund = json!({
+            "type": "charge.refunded",
+            "created": 1735800000,
+            "data": {"object": {"id": "ch_1", "object": "charge", "payment_intent": "pi_1",
+                "amount": 40000, "amount_refunded": 40000, "refunded": true, "currency": "usd"}}
+        });
+        let record = stripe.normalize(&refund).unwrap();
+        assert_eq!(record.payment_id_v2, "pi_1");
+        assert_eq!(record.provider_status, "refunded");
+        assert_eq!(record.amount_paid, usd(40000));
+    }
+
+    #[test]
+    fn test_icp_ledger_normalize() {
+        let ledger = provider_for(ICP_LEDGER).unwrap();
+        let payload = json!({
+            "payment_id": "0a1b2c", "status": "partially_received", "token": "ckUSDC",
+            "decimals": 6, "expected": 400000000u64, "received": 150000000u64,
+            "order_id": "APP001", "price_per_token_e9": 1_000_000_000u64, "price_currency": "usd",
+            "created_at_ns": 1735725600000000000u64, "updated_at_ns": 1735725900123456789u64
+        });
+
+        let record = ledger.normalize(&payload).unwrap();
+        assert_eq!(record.amount, usd(40000));
+        assert_eq!(record.amount_paid, usd(15000));
+        assert_eq!(record.updated_at, "2025-01-01T10:05:00.123456789Z");
+
+        let mut priced = payload.clone();
+        priced["price_per_token_e9"] = json!(2_000_000_000u64);
+        let record = ledger.normalize(&priced).unwrap();
+        assert_eq!((record.amount, record.amount_paid), (usd(80000), usd(30000)));
+        // half a cent rounds up
+        priced["received"] = json!(2500);
+        assert_eq!(ledger.normalize(&priced).unwrap().amount_paid, usd(1));
+        // 18 decimals, sent as strings
+        let mut wei = payload.clone();
+        wei["decimals"] = json!(18);
+        wei["expected"] = json!("133333333333333333334");
+        wei["received"] = json!("1666666666666666667");
+        wei["price_per_token_e9"] = json!(3_000_000_000u64);
+        let record = ledger.normalize(&wei).unwrap();
+        assert_eq!((record.amount, record.amount_paid), (usd(40000), usd(500)));
+        priced["price_per_token_e9"] = json!(0);
+        assert!(ledger.normalize(&priced).is_err());
+        // tokens are not currencies
+        let mut in_tokens = payload.clone();
+        in_tokens["price_currency"] = json!("ckUSDC");
+        assert!(ledger.normalize(&in_tokens).is_err());
+
+        // transfers are final and not signed by anyone
+        assert!(!ledger.verify_signature("secret", &payload.to_string(), ""));
+    }
+
+    #[test]
+    fn test_status_mapping_per_provider() {
+        use BackendPaymentStatus::*;
+        let id = || "p".to_string();
+        let cases = [
+            (NOWPAYMENTS, "finished", Paid(id())),
+            (NOWPAYMENTS, "waiting", Pending(Some(id()))),
+            (STRIPE, "succeeded", Paid(id())),
+            (STRIPE, "processing", Confirming(id())),
+            (STRIPE, "requires_action", Pending(Some(id()))),
+            (STRIPE, "payment_failed", Failed(id())),
+            (STRIPE, "canceled", Cancelled(id())),
+            (ICP_LEDGER, "received", Paid(id())),
+            (ICP_LEDGER, "partially_received", PartiallyPaid(id())),
+            (ICP_LEDGER, "expired", Expired(id())),
+        ];
+        for (provider, status, expected) in cases {
+            assert_eq!(
+                provider_for(provider)
+                    .unwrap()
+                    .map_status(status, id(), Money::default()),
+                expected,
+                "{} {}",
+                provider,
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn test_update_payment_details_uses_the_provider_adapter() {
+        let mut state = CanisterState::new();
+        let booking = create_valid_booking("APP001", "user1@example.com");
+        let booking_id = booking.booking_id.clone();
+        state
+            .add_booking_and_user("user1@example.com", booking)
+            .unwrap();
+
+        // "succeeded" means nothing to NowPayments, but the Stripe adapter reads it as paid
+        let record = provider_for(STRIPE)
+            .unwrap()
+            .normalize(&json(STRIPE_BODY))
+            .unwrap();
+        let payment_details = PaymentDetails {
+            payment_api_response: record.into(),
+            ..PaymentDetails::new(booking_id.clone())
+        };
+        let booking = state
+            .update_payment_details(booking_id, payment_details)
+            .unwrap();
+
+        assert!(booking.payment_details.is_paid());
+        assert_eq!(
+            booking.payment_details.payment_api_response.provider,
+            STRIPE
+        );
+        assert_eq!(
+            booking.payment_details.payment_api_response.amount_paid,
+            Some(usd(40000))
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! Report for finance on bookings whose payment and booking status don't agree.
+//!
+//! Every booking created in the requested window is sorted into the buckets it falls in:
+//! paid and booked, paid but the booking failed (to be refunded), booked without payment,
+//! stuck on hold, or missing from `payment_id_index`. A booking can be in several buckets,
+//! or in none (e.g. unpaid and not booked yet). Bookings from before timelines were recorded
+//! have no creation time; they are bucketed separately and in every report.
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::errors::{BackendError, BackendResult};
+use crate::roles::is_admin;
+use crate::timeline::BookingEventKind;
+use crate::{
+    Booking, BookingId, BookingSummary, CanisterState, CurrencyCode, Money, ResolvedBookingStatus,
+    STATE,
+};
+
+/// How long a booking may stay `BookingOnHold` before it is reported, in nanoseconds (1 hour)
+pub const ON_HOLD_LIMIT_NS: u64 = 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReconciliationBucket {
+    PaidConfirmed,
+    /// the payment has to be refunded
+    PaidFailed,
+    UnpaidConfirmed,
+    /// `BookingOnHold` for longer than `ON_HOLD_LIMIT_NS`
+    OnHoldTooLong,
+    /// has a `payment_id_v2` that `payment_id_index` doesn't map to it
+    MissingFromPaymentIndex,
+}
+
+impl ReconciliationBucket {
+    pub const ALL: [ReconciliationBucket; 5] = [
+        ReconciliationBucket::PaidConfirmed,
+        ReconciliationBucket::PaidFailed,
+        ReconciliationBucket::UnpaidConfirmed,
+        ReconciliationBucket::OnHoldTooLong,
+        ReconciliationBucket::MissingFromPaymentIndex,
+    ];
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ReconciliationEntry {
+    pub booking: BookingSummary,
+    /// what was paid for paid bookings, otherwise the requested payment
+    pub amount: Money,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ReconciliationBucketReport {
+    pub bucket: ReconciliationBucket,
+    pub bookings: Vec<ReconciliationEntry>,
+    /// of `bookings[].amount`, one per currency ordered by code
+    pub totals: Vec<Money>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ReconciliationReport {
+    pub from: u64,
+    pub to: u64,
+    /// bookings created in the window, including those in no bucket
+    pub bookings_checked: u64,
+    /// every bucket, in `ReconciliationBucket::ALL` order, even when empty
+    pub buckets: Vec<ReconciliationBucketReport>,
+    /// bookings without a timeline, whose creation time isn't known, whatever the window
+    pub undated_bookings_checked: u64,
+    /// `buckets` for the undated bookings
+    pub undated_buckets: Vec<ReconciliationBucketReport>,
+}
+
+fn totals(entries: &[ReconciliationEntry]) -> BackendResult<Vec<Money>> {
+    let mut by_currency: BTreeMap<&CurrencyCode, Vec<&Money>> = BTreeMap::new();
+    for entry in entries {
+        by_currency
+            .entry(&entry.amount.currency)
+            .or_default()
+            .push(&entry.amount);
+    }
+    by_currency
+        .into_iter()
+        .map(|(currency, amounts)| Money::sum(currency, amounts))
+        .collect()
+}
+
+impl CanisterState {
+    /// The `Created` event, or the first event of bookings created before it was recorded;
+    /// `None` for bookings without a timeline
+    fn booking_created_at(&self, booking_id: &BookingId) -> Option<u64> {
+        let events = self.booking_timelines.get(booking_id).unwrap_or_default().0;
+        events
+            .iter()
+            .find(|event| event.kind == BookingEventKind::Created)
+            .or(events.first())
+            .map(|event| event.timestamp)
+    }
+
+    /// The last change to `BookingOnHold`, or the creation time when it isn't in the timeline
+    fn on_hold_since(&self, booking_id: &BookingId) -> u64 {
+        let events = self.booking_timelines.get(booking_id).unwrap_or_default().0;
+        events
+            .iter()
+            .rev()
+            .find(|event| {
+                matches!(
+                    event.kind,
+                    BookingEventKind::BookingStatusChanged {
+                        to: ResolvedBookingStatus::BookingOnHold,
+                        ..
+                    }
+                )
+            })
+            .map(|event| event.timestamp)
+            .or_else(|| self.booking_created_at(booking_id))
+            .unwrap_or(0)
+    }
+
+    fn reconciliation_buckets(&self, booking: &Booking, now: u64) -> Vec<ReconciliationBucket> {
+        let mut buckets = Vec::new();
+        let paid = booking.payment_details.is_paid();
+        match (paid, booking.get_resolved_booking_status()) {
+            (true, ResolvedBookingStatus::BookingConfirmed) => {
+                buckets.push(ReconciliationBucket::PaidConfirmed)
+            }
+            (true, ResolvedBookingStatus::BookingFailed) => {
+                buckets.push(ReconciliationBucket::PaidFailed)
+            }
+            (false, ResolvedBookingStatus::BookingConfirmed) => {
+                buckets.push(ReconciliationBucket::UnpaidConfirmed)
+            }
+            (_, ResolvedBookingStatus::BookingOnHold)
+                if now.saturating_sub(self.on_hold_since(&booking.booking_id)) > ON_HOLD_LIMIT_NS =>
+            {
+                buckets.push(ReconciliationBucket::OnHoldTooLong)
+            }
+            _ => {}
+        }
+
+        let payment_id_v2 = &booking.payment_details.payment_api_response.payment_id_v2;
+        if !payment_id_v2.is_empty()
+            && self.payment_id_index.get(payment_id_v2).as_ref() != Some(&booking.booking_id)
+        {
+            buckets.push(ReconciliationBucket::MissingFromPaymentIndex);
+        }
+        buckets
+    }
+
+    /// Every bucket in `ReconciliationBucket::ALL` order, for `bookings` as of `now`
+    fn bucket_reports(
+        &self,
+        bookings: &[Booking],
+        now: u64,
+    ) -> BackendResult<Vec<ReconciliationBucketReport>> {
+        let mut entries: BTreeMap<ReconciliationBucket, Vec<ReconciliationEntry>> = BTreeMap::new();
+        for booking in bookings {
+            let buckets = self.reconciliation_buckets(booking, now);
+            if buckets.is_empty() {
+                continue;
+            }
+            let amount = booking
+                .payment_details
+                .is_paid()
+                .then(|| booking.payment_details.payment_api_response.paid_amount())
+                .flatten()
+                .unwrap_or_else(|| booking.get_requested_payment());
+            let summary = BookingSummary::from((booking.booking_id.get_user_email(), booking));
+            for bucket in buckets {
+                entries.entry(bucket).or_default().push(ReconciliationEntry {
+                    booking: summary.clone(),
+                    amount: amount.clone(),
+                });
+            }
+        }
+
+        ReconciliationBucket::ALL
+            .into_iter()
+            .map(|bucket| {
+                let bookings = entries.remove(&bucket).unwrap_or_default();
+                Ok(ReconciliationBucketReport {
+                    bucket,
+                    totals: totals(&bookings)?,
+                    bookings,
+                })
+            })
+            .collect()
+    }
+
+    /// Bookings created in `from..to` (nanoseconds), as of `now`, and all bookings without
+    /// a timeline
+    pub fn reconciliation_report(
+        &self,
+        from: u64,
+        to: u64,
+        now: u64,
+    ) -> BackendResult<ReconciliationReport> {
+        if from > to {
+            return Err(BackendError::validation("from", "must not be after 'to'"));
+        }
+
+        let (mut dated, mut undated) = (Vec::new(), Vec::new());
+        for booking in self.bookings.values() {
+            match self.booking_created_at(&booking.booking_id) {
+                Some(created_at) if (from..to).contains(&created_at) => dated.push(booking),
+                Some(_) => {}
+                None => undated.push(booking),
+            }
+        }
+
+        Ok(ReconciliationReport {
+            from,
+            to,
+            bookings_checked: dated.len() as u64,
+            buckets: self.bucket_reports(&dated, now)?,
+            undated_bookings_checked: undated.len() as u64,
+            undated_buckets: self.bucket_reports(&undated, now)?,
+        })
+    }
+}
+
+/// see `CanisterState::reconciliation_report`
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn reconciliation_report(from: u64, to: u64) -> BackendResult<ReconciliationReport> {
+    let now = ic_cdk::api::time();
+    STATE.with(|state| state.borrow().reconciliation_report(from, to, now))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,222 @@
+#[cfg(test)]
+mod reconciliation_tests {
+    use crate::errors::BackendError;
+    use crate::models::*;
+    use crate::reconciliation::{ReconciliationBucket, ReconciliationReport, ON_HOLD_LIMIT_NS};
+    use crate::test_utils::{create_valid_booking, usd};
+    use crate::timeline::{BookingEvent, BookingEventKind, BookingTimeline};
+    use candid::Principal;
+
+    const EMAIL: &str = "user1@example.com";
+
+    fn add_booking(state: &mut CanisterState, app_ref: &str, requested_payment: Money) -> BookingId {
+        let mut booking = create_valid_booking(app_ref, EMAIL);
+        let details = &mut booking.user_selected_hotel_room_details;
+        details.room_details[0].price = Some(Money::zero(requested_payment.currency.clone()));
+        details.requested_payment = Some(requested_payment);
+        let booking_id = booking.booking_id.clone();
+        state.add_booking_and_user(EMAIL, booking).unwrap();
+        booking_id
+    }
+
+    fn pay(state: &mut CanisterState, booking_id: &BookingId, price: Money) {
+        let mut payment_details = PaymentDetails::new(booking_id.clone());
+        let response = &mut payment_details.payment_api_response;
+        response.payment_id_v2 = format!("pay_{}", booking_id.get_app_reference());
+        response.payment_status = "finished".to_string();
+        response.price = Some(price);
+        state
+            .update_payment_details(booking_id.clone(), payment_details)
+            .unwrap();
+    }
+
+    fn set_resolved_status(state: &mut CanisterState, booking_id: &BookingId, status: ResolvedBookingStatus) {
+        let response = BEBookRoomResponse {
+            status: "200".to_string(),
+            message: String::new(),
+            commit_booking: BookingDetails {
+                booking_id: booking_id.clone(),
+                resolved_booking_status: status,
+                booking_status: format!("{:?}", status),
+                ..Default::default()
+            },
+        };
+        state
+            .update_book_room_response(booking_id.clone(), response)
+            .unwrap();
+    }
+
+    /// Replaces the timeline with one that only records the creation at `timestamp`
+    fn set_created_at(state: &mut CanisterState, booking_id: &BookingId, timestamp: u64) {
+        let event = BookingEvent {
+            timestamp,
+            caller: Principal::anonymous(),
+            kind: BookingEventKind::Created,
+        };
+        state
+            .booking_timelines
+            .insert(booking_id.clone(), BookingTimeline(vec![event]));
+    }
+
+    fn app_refs(report: &ReconciliationReport, bucket: ReconciliationBucket) -> Vec<String> {
+        report
+            .buckets
+            .iter()
+            .find(|report| report.bucket == bucket)
+            .unwrap()
+            .bookings
+            .iter()
+            .map(|entry| entry.booking.booking_id.get_app_reference().to_string())
+            .collect()
+    }
+
+    fn totals(report: &ReconciliationReport, bucket: ReconciliationBucket) -> Vec<Money> {
+        report
+            .buckets
+            .iter()
+            .find(|report| report.bucket == bucket)
+            .unwrap()
+            .totals
+            .clone()
+    }
+
+    #[test]
+    fn test_bookings_are_sorted_into_buckets() {
+        let mut state = CanisterState::new();
+        let eur = |amount_minor| Money::new(amount_minor, CurrencyCode::new("EUR").unwrap());
+
+        let paid_confirmed = add_booking(&mut state, "APP001", usd(40000));
+        pay(&mut state, &paid_confirmed, usd(40000));
+        set_resolved_status(&mut state, &paid_confirmed, ResolvedBookingStatus::BookingConfirmed);
+
+        let paid_failed = add_booking(&mut state, "APP002", usd(25000));
+        pay(&mut state, &paid_failed, usd(25000));
+        set_resolved_status(&mut state, &paid_failed, ResolvedBookingStatus::BookingFailed);
+        let paid_failed_eur = add_booking(&mut state, "APP003", eur(10000));
+        pay(&mut state, &paid_failed_eur, eur(10000));
+        set_resolved_status(&mut state, &paid_failed_eur, ResolvedBookingStatus::BookingFailed);
+
+        let unpaid_confirmed = add_booking(&mut state, "APP004", usd(30000));
+        set_resolved_status(&mut state, &unpaid_confirmed, ResolvedBookingStatus::BookingConfirmed);
+
+        let on_hold = add_booking(&mut state, "APP005", usd(30000));
+        set_resolved_status(&mut state, &on_hold, ResolvedBookingStatus::BookingOnHold);
+
+        // paid and booked, but lost from the index
+        let unindexed = add_booking(&mut state, "APP006", usd(20000));
+        pay(&mut state, &unindexed, usd(20000));
+        set_resolved_status(&mut state, &unindexed, ResolvedBookingStatus::BookingConfirmed);
+        state.payment_id_index.remove(&"pay_APP006".to_string());
+
+        // unpaid and not booked yet: nothing to reconcile
+        add_booking(&mut state, "APP007", usd(30000));
+
+        let report = state.reconciliation_report(0, u64::MAX, ON_HOLD_LIMIT_NS + 1).unwrap();
+
+        assert_eq!(report.bookings_checked, 7);
+        let buckets: Vec<_> = report.buckets.iter().map(|report| report.bucket).collect();
+        assert_eq!(buckets, ReconciliationBucket::ALL.to_vec());
+        assert_eq!(app_refs(&report, ReconciliationBucket::PaidConfirmed), ["APP001", "APP006"]);
+        assert_eq!(app_refs(&report, ReconciliationBucket::PaidFailed), ["APP002", "APP003"]);
+        assert_eq!(app_refs(&report, ReconciliationBucket::UnpaidConfirmed), ["APP004"]);
+        assert_eq!(app_refs(&report, ReconciliationBucket::OnHoldTooLong), ["APP005"]);
+        assert_eq!(app_refs(&report, ReconciliationBucket::MissingFromPaymentIndex), ["APP006"]);
+
+        // the refunds due, per currency
+        assert_eq!(totals(&report, ReconciliationBucket::PaidFailed), [eur(10000), usd(25000)]);
+        assert_eq!(totals(&report, ReconciliationBucket::PaidConfirmed), [usd(60000)]);
+        // unpaid bookings count with the requested payment
+        assert_eq!(totals(&report, ReconciliationBucket::UnpaidConfirmed), [usd(30000)]);
+    }
+
+    #[test]
+    fn test_paid_amount_is_reported() {
+        let mut state = CanisterState::new();
+        let booking_id = add_booking(&mut state, "APP001", usd(40000));
+        let mut payment_details = PaymentDetails::new(booking_id.clone());
+        let response = &mut payment_details.payment_api_response;
+        response.payment_id_v2 = "pay_1".to_string();
+        response.payment_status = "finished".to_string();
+        response.price = Some(usd(40000));
+        response.amount_paid = Some(usd(39999));
+        state
+            .update_payment_details(booking_id.clone(), payment_details)
+            .unwrap();
+        set_resolved_status(&mut state, &booking_id, ResolvedBookingStatus::BookingFailed);
+
+        let report = state.reconciliation_report(0, u64::MAX, 0).unwrap();
+        assert_eq!(totals(&report, ReconciliationBucket::PaidFailed), [usd(39999)]);
+    }
+
+    #[test]
+    fn test_on_hold_is_measured_from_the_last_change_to_on_hold() {
+        let mut state = CanisterState::new();
+        let booking_id = add_booking(&mut state, "APP001", usd(40000));
+        set_resolved_status(&mut state, &booking_id, ResolvedBookingStatus::BookingOnHold);
+
+        let report = state.reconciliation_report(0, u64::MAX, ON_HOLD_LIMIT_NS).unwrap();
+        assert!(app_refs(&report, ReconciliationBucket::OnHoldTooLong).is_empty());
+
+        // created long ago but only just put on hold
+        let mut timeline = state.booking_timelines.get(&booking_id).unwrap();
+        for event in timeline.0.iter_mut() {
+            if matches!(event.kind, BookingEventKind::BookingStatusChanged { .. }) {
+                event.timestamp = ON_HOLD_LIMIT_NS;
+            }
+        }
+        state.booking_timelines.insert(booking_id.clone(), timeline);
+        let report = state.reconciliation_report(0, u64::MAX, ON_HOLD_LIMIT_NS + 1).unwrap();
+        assert!(app_refs(&report, ReconciliationBucket::OnHoldTooLong).is_empty());
+        let report = state.reconciliation_report(0, u64::MAX, 2 * ON_HOLD_LIMIT_NS + 1).unwrap();
+        assert_eq!(app_refs(&report, ReconciliationBucket::OnHoldTooLong), ["APP001"]);
+    }
+
+    #[test]
+    fn test_only_bookings_created_in_the_window_are_checked() {
+        let mut state = CanisterState::new();
+        for (app_ref, created_at) in [("APP001", 100), ("APP002", 200), ("APP003", 300)] {
+            let booking_id = add_booking(&mut state, app_ref, usd(40000));
+            set_resolved_status(&mut state, &booking_id, ResolvedBookingStatus::BookingConfirmed);
+            set_created_at(&mut state, &booking_id, created_at);
+        }
+
+        let report = state.reconciliation_report(100, 300, 0).unwrap();
+        assert_eq!((report.from, report.to), (100, 300));
+        assert_eq!(report.bookings_checked, 2);
+        assert_eq!(app_refs(&report, ReconciliationBucket::UnpaidConfirmed), ["APP001", "APP002"]);
+
+        assert_eq!(state.reconciliation_report(5, 5, 0).unwrap().bookings_checked, 0);
+        assert!(matches!(
+            state.reconciliation_report(300, 100, 0),
+            Err(BackendError::Validation { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bookings_without_a_timeline_are_in_every_report() {
+        let mut state = CanisterState::new();
+        let dated = add_booking(&mut state, "APP001", usd(40000));
+        set_resolved_status(&mut state, &dated, ResolvedBookingStatus::BookingConfirmed);
+        set_created_at(&mut state, &dated, 100);
+        // created before timelines were recorded
+        let legacy = add_booking(&mut state, "APP002", usd(25000));
+        set_resolved_status(&mut state, &legacy, ResolvedBookingStatus::BookingConfirmed);
+        state.booking_timelines.remove(&legacy);
+
+        for (from, to) in [(0, 100), (100, 200), (5_000, u64::MAX)] {
+            let report = state.reconciliation_report(from, to, 0).unwrap();
+            assert_eq!(report.undated_bookings_checked, 1);
+            let undated = report
+                .undated_buckets
+                .iter()
+                .find(|report| report.bucket == ReconciliationBucket::UnpaidConfirmed)
+                .unwrap();
+            assert_eq!(undated.bookings[0].booking.booking_id, legacy);
+            assert_eq!(undated.totals, [usd(25000)]);
+            // and not among the dated ones
+            assert!(!app_refs(&report, ReconciliationBucket::UnpaidConfirmed).contains(&"APP002".to_string()));
+        }
+        let report = state.reconciliation_report(100, 200, 0).unwrap();
+        assert_eq!(app_refs(&report, ReconciliationBucket::UnpaidConfirmed), ["APP001"]);
+    }
+}
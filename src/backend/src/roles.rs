@@ -0,0 +1,187 @@
+//! Role based access control.
+//!
+//! Every principal can hold several roles. `Admin` passes every guard; the other roles
+//! only unlock the endpoints their service needs. Principals that control the canister
+//! at the IC level are always treated as `Admin`, so the canister can't be locked out.
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{self, AuditTarget};
+use crate::errors::{BackendError, BackendResult};
+use crate::{CanisterState, STATE};
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// everything, including roles, controllers, migrations and state import/export
+    Admin,
+    /// payment webhooks: payment details and payment status reads
+    PaymentService,
+    /// the frontend proxy: bookings, book room responses, wishlists, emails, principal mapping
+    BookingService,
+    /// customer support: reading bookings
+    Support,
+    /// dashboards: booking summaries only
+    ReadOnlyAnalytics,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct RoleAssignment {
+    pub principal: Principal,
+    pub roles: Vec<Role>,
+}
+
+impl CanisterState {
+    pub fn has_role(&self, principal: &Principal, role: Role) -> bool {
+        self.roles
+            .get(principal)
+            .map(|roles| roles.contains(&role))
+            .unwrap_or(false)
+    }
+
+    /// true if `principal` holds `Admin` or any of `roles`
+    pub fn has_any_role(&self, principal: &Principal, roles: &[Role]) -> bool {
+        self.roles
+            .get(principal)
+            .map(|held| held.contains(&Role::Admin) || roles.iter().any(|r| held.contains(r)))
+            .unwrap_or(false)
+    }
+
+    pub fn grant_role(&mut self, principal: Principal, role: Role) -> BackendResult<()> {
+        if principal == Principal::anonymous() {
+            return Err(BackendError::validation(
+                "principal",
+                "roles cannot be granted to the anonymous principal",
+            ));
+        }
+        if !self.roles.entry(principal).or_default().insert(role) {
+            return Err(BackendError::Conflict(format!(
+                "{} already has role {:?}",
+                principal, role
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn revoke_role(&mut self, principal: Principal, role: Role) -> BackendResult<()> {
+        let roles = self
+            .roles
+            .get_mut(&principal)
+            .filter(|roles| roles.contains(&role))
+            .ok_or_else(|| BackendError::not_found("Role", format!("{:?} of {}", role, principal)))?;
+        roles.remove(&role);
+        if roles.is_empty() {
+            self.roles.remove(&principal);
+        }
+        Ok(())
+    }
+
+    pub fn principals_with_role(&self, role: Role) -> Vec<Principal> {
+        self.roles
+            .iter()
+            .filter(|(_, roles)| roles.contains(&role))
+            .map(|(principal, _)| *principal)
+            .collect()
+    }
+
+    pub fn list_roles(&self) -> Vec<RoleAssignment> {
+        self.roles
+            .iter()
+            .map(|(principal, roles)| RoleAssignment {
+                principal: *principal,
+                roles: roles.iter().copied().collect(),
+            })
+            .collect()
+    }
+}
+
+fn require_any_role(roles: &[Role]) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) {
+        return Ok(());
+    }
+    if STATE.with(|state| state.borrow().has_any_role(&caller, roles)) {
+        Ok(())
+    } else {
+        Err("You are not authorized to perform this action.".to_string())
+    }
+}
+
+// Guards. Each also lets `Admin` through.
+
+pub fn is_admin() -> Result<(), String> {
+    require_any_role(&[])
+}
+
+pub fn is_payment_service() -> Result<(), String> {
+    require_any_role(&[Role::PaymentService])
+}
+
+pub fn is_booking_service() -> Result<(), String> {
+    require_any_role(&[Role::BookingService])
+}
+
+/// services and staff that need to look up individual bookings
+pub fn can_read_bookings() -> Result<(), String> {
+    require_any_role(&[Role::BookingService, Role::PaymentService, Role::Support])
+}
+
+/// cancelling bookings on behalf of a user
+pub fn can_manage_bookings() -> Result<(), String> {
+    require_any_role(&[Role::BookingService, Role::Support])
+}
+
+pub fn can_read_analytics() -> Result<(), String> {
+    require_any_role(&[Role::Support, Role::ReadOnlyAnalytics])
+}
+
+/// For the per-user reads that anyone could call before roles existed: passes `guard`, or
+/// the caller is the user `email` itself. Not a guard since it needs the endpoint's argument.
+pub fn require_role_or_owner(guard: fn() -> Result<(), String>, email: &str) -> Result<(), String> {
+    guard().or_else(|error| {
+        let caller = ic_cdk::caller();
+        if STATE.with(|state| state.borrow().caller_owns_email(caller, email)) {
+            Ok(())
+        } else {
+            Err(error)
+        }
+    })
+}
+
+/// Granting `Admin` is the same as `add_controller` and shows up in the controller audit log
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn grant_role(principal: Principal, role: Role) -> BackendResult<()> {
+    if role == Role::Admin {
+        return crate::controller::add_controller_v2(principal);
+    }
+    audit::audited(
+        "grant_role",
+        AuditTarget::principal(principal),
+        |state| state.roles.get(&principal).cloned(),
+        |state| state.grant_role(principal, role),
+    )
+}
+
+/// `Admin` can't be revoked here, it goes through `propose_controller_removal`
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn revoke_role(principal: Principal, role: Role) -> BackendResult<()> {
+    if role == Role::Admin {
+        return Err(BackendError::validation(
+            "role",
+            "Admin is removed with propose_controller_removal and confirm_controller_removal",
+        ));
+    }
+    audit::audited(
+        "revoke_role",
+        AuditTarget::principal(principal),
+        |state| state.roles.get(&principal).cloned(),
+        |state| state.revoke_role(principal, role),
+    )
+}
+
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn list_roles() -> Vec<RoleAssignment> {
+    STATE.with(|state| state.borrow().list_roles())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,99 @@
+#[cfg(test)]
+mod roles_tests {
+    use crate::errors::BackendError;
+    use crate::models::*;
+    use crate::roles::{Role, RoleAssignment};
+    use candid::Principal;
+
+    fn payment_service() -> Principal {
+        Principal::from_text("be2us-64aaa-aaaaa-qaabq-cai").unwrap()
+    }
+
+    fn admin() -> Principal {
+        Principal::from_text("znxnh-f2v3a-dwwtd-jhyr2-tzdg7-xrm43-xf6xc-q4nfk-arhfb-54k5n-zae").unwrap()
+    }
+
+    #[test]
+    fn test_grant_and_revoke_role() {
+        let mut state = CanisterState::new();
+
+        state.grant_role(payment_service(), Role::PaymentService).unwrap();
+        assert!(state.has_role(&payment_service(), Role::PaymentService));
+        assert!(!state.has_role(&payment_service(), Role::Admin));
+
+        state.revoke_role(payment_service(), Role::PaymentService).unwrap();
+        assert!(!state.has_role(&payment_service(), Role::PaymentService));
+        // principals without roles are dropped
+        assert!(state.roles.is_empty());
+    }
+
+    #[test]
+    fn test_grant_role_twice_is_conflict() {
+        let mut state = CanisterState::new();
+        state.grant_role(payment_service(), Role::Support).unwrap();
+
+        let result = state.grant_role(payment_service(), Role::Support);
+        assert!(matches!(result, Err(BackendError::Conflict(_))));
+    }
+
+    #[test]
+    fn test_grant_role_rejects_anonymous() {
+        let mut state = CanisterState::new();
+
+        let result = state.grant_role(Principal::anonymous(), Role::Admin);
+        assert!(matches!(result, Err(BackendError::Validation { .. })));
+        assert!(state.roles.is_empty());
+    }
+
+    #[test]
+    fn test_revoke_missing_role_is_not_found() {
+        let mut state = CanisterState::new();
+        state.grant_role(payment_service(), Role::PaymentService).unwrap();
+
+        let result = state.revoke_role(payment_service(), Role::Admin);
+        assert!(matches!(result, Err(BackendError::NotFound { .. })));
+        assert!(state.has_role(&payment_service(), Role::PaymentService));
+    }
+
+    #[test]
+    fn test_has_any_role_admin_passes_everything() {
+        let mut state = CanisterState::new();
+        state.grant_role(admin(), Role::Admin).unwrap();
+        state.grant_role(payment_service(), Role::PaymentService).unwrap();
+
+        assert!(state.has_any_role(&admin(), &[]));
+        assert!(state.has_any_role(&admin(), &[Role::BookingService]));
+
+        // payment service can't reach admin or booking endpoints
+        assert!(!state.has_any_role(&payment_service(), &[]));
+        assert!(!state.has_any_role(&payment_service(), &[Role::BookingService]));
+        assert!(state.has_any_role(&payment_service(), &[Role::BookingService, Role::PaymentService]));
+    }
+
+    #[test]
+    fn test_list_roles() {
+        let mut state = CanisterState::new();
+        state.grant_role(payment_service(), Role::Support).unwrap();
+        state.grant_role(payment_service(), Role::PaymentService).unwrap();
+        state.grant_role(admin(), Role::Admin).unwrap();
+
+        let roles = state.list_roles();
+        assert_eq!(roles.len(), 2);
+        assert!(roles.contains(&RoleAssignment {
+            principal: payment_service(),
+            roles: vec![Role::PaymentService, Role::Support],
+        }));
+        assert_eq!(state.principals_with_role(Role::Admin), vec![admin()]);
+    }
+
+    #[test]
+    fn test_roles_survive_snapshot_round_trip() {
+        let mut state = CanisterState::new();
+        state.grant_role(payment_service(), Role::PaymentService).unwrap();
+
+        let snapshot = state.to_snapshot();
+        let mut restored = CanisterState::new();
+        restored.restore_snapshot(snapshot);
+        assert!(restored.has_role(&payment_service(), Role::PaymentService));
+    }
+}
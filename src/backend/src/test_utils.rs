@@ -0,0 +1,79 @@
+//! Fixtures shared by unit tests across modules.
+use crate::models::*;
+
+/// `amount_minor` cents
+pub fn usd(amount_minor: i128) -> Money {
+    Money::new(amount_minor, CurrencyCode::usd())
+}
+
+/// A booking that passes `Booking::validate`
+pub fn create_valid_booking(app_ref: &str, email: &str) -> Booking {
+    let booking_id = BookingId::new(app_ref.to_string(), email.to_string());
+
+    let adult = AdultDetail {
+        first_name: "John".to_string(),
+        last_name: Some("Doe".to_string()),
+        email: Some(email.to_string()),
+        phone: Some("1234567890".to_string()),
+    };
+
+    let hotel_details = HotelDetails {
+        hotel_name: "Test Hotel".to_string(),
+        hotel_code: "TH001".to_string(),
+        hotel_image: "image.jpg".to_string(),
+        hotel_location: "Test Location".to_string(),
+        block_room_id: "BR001".to_string(),
+        hotel_token: "token123".to_string(),
+    };
+
+    let hotel_room_details = HotelRoomDetails {
+        hotel_details,
+        date_range: SelectedDateRange {
+            start: (2025, 1, 1),
+            end: (2025, 1, 5),
+        },
+        destination: None,
+        room_details: vec![RoomDetails {
+            room_type_name: "Deluxe".to_string(),
+            room_unique_id: "D001".to_string(),
+            price: Some(usd(10000)),
+            ..Default::default()
+        }],
+        requested_payment: Some(usd(40000)),
+        ..Default::default()
+    };
+
+    Booking {
+        booking_id: booking_id.clone(),
+        guests: UserDetails {
+            adults: vec![adult],
+            children: vec![],
+        },
+        book_room_status: None,
+        user_selected_hotel_room_details: hotel_room_details,
+        payment_details: PaymentDetails::new(booking_id),
+        cancellation: None,
+        payment_mismatch: None,
+    }
+}
+
+/// A state holding `create_valid_booking("APP001", email)`, a 400.00 USD booking, added the
+/// way callers add bookings
+pub fn create_test_state(email: &str) -> (CanisterState, BookingId) {
+    let mut state = CanisterState::new();
+    let booking = create_valid_booking("APP001", email);
+    let booking_id = booking.booking_id.clone();
+    state.add_booking_and_user(email, booking).unwrap();
+    (state, booking_id)
+}
+
+/// `create_valid_booking` with `payment_id_v2` set on the payment response
+pub fn create_valid_booking_with_payment_id_v2(
+    app_ref: &str,
+    email: &str,
+    payment_id_v2: &str,
+) -> Booking {
+    let mut booking = create_valid_booking(app_ref, email);
+    booking.payment_details.payment_api_response.payment_id_v2 = payment_id_v2.to_string();
+    booking
+}
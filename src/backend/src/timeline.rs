@@ -0,0 +1,100 @@
+//! Ordered history of what happened to each booking.
+//!
+//! `Booking` only holds the latest booking status, payment status and message, so every
+//! change to those is also appended here, keyed by `BookingId`. Events are recorded by
+//! the `CanisterState` methods that make the change.
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use crate::audit::call_context;
+use crate::errors::{BackendError, BackendResult};
+use crate::memory::{from_cbor_bytes, to_cbor_bytes, try_from_cbor_bytes, MapValue};
+use crate::models::{default_if_legacy, Money};
+use crate::roles::can_read_bookings;
+use crate::{BackendPaymentStatus, BookingId, CanisterState, ResolvedBookingStatus, STATE};
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum BookingEventKind {
+    Created,
+    BookingStatusChanged {
+        from: ResolvedBookingStatus,
+        to: ResolvedBookingStatus,
+    },
+    PaymentStatusChanged {
+        from: BackendPaymentStatus,
+        to: BackendPaymentStatus,
+    },
+    EmailSent(bool),
+    Message(String),
+    Cancelled { reason: String },
+    /// see `get_booking_revisions` for the booking before the amendment
+    Amended {
+        revision: u32,
+        #[serde(default, deserialize_with = "default_if_legacy")]
+        price_delta: Money,
+    },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct BookingEvent {
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub kind: BookingEventKind,
+}
+
+/// Events of one booking, oldest first
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct BookingTimeline(pub Vec<BookingEvent>);
+
+impl Storable for BookingTimeline {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        to_cbor_bytes(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        from_cbor_bytes(bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl MapValue for BookingTimeline {
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        try_from_cbor_bytes(bytes)
+    }
+}
+
+impl CanisterState {
+    /// Appends `kind` to the timeline of `booking_id`, stamped with the current caller and time.
+    /// Ignored for unknown bookings (`update_email_sent` accepts any id).
+    pub fn record_booking_event(&mut self, booking_id: &BookingId, kind: BookingEventKind) {
+        if !self.bookings.contains_key(booking_id) {
+            return;
+        }
+        let (caller, timestamp) = call_context();
+        let mut timeline = self.booking_timelines.get(booking_id).unwrap_or_default();
+        timeline.0.push(BookingEvent {
+            timestamp,
+            caller,
+            kind,
+        });
+        self.booking_timelines.insert(booking_id.clone(), timeline);
+    }
+
+    pub fn get_booking_timeline(&self, booking_id: &BookingId) -> BackendResult<Vec<BookingEvent>> {
+        if !self.bookings.contains_key(booking_id) {
+            return Err(BackendError::not_found("Booking", booking_id.get_app_reference()));
+        }
+        Ok(self.booking_timelines.get(booking_id).unwrap_or_default().0)
+    }
+}
+
+#[ic_cdk_macros::query(guard = "can_read_bookings")]
+fn get_booking_timeline(booking_id: BookingId) -> BackendResult<Vec<BookingEvent>> {
+    STATE.with(|state| state.borrow().get_booking_timeline(&booking_id))
+}
+
+#[cfg(test)]
+mod tests;
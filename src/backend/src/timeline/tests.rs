@@ -0,0 +1,158 @@
+#[cfg(test)]
+mod timeline_tests {
+    use crate::errors::BackendError;
+    use crate::models::*;
+    use crate::test_utils::create_test_state;
+    use crate::timeline::BookingEventKind;
+
+    const EMAIL: &str = "user1@example.com";
+
+    fn book_room_response(
+        booking_id: &BookingId,
+        status: ResolvedBookingStatus,
+        message: &str,
+    ) -> BEBookRoomResponse {
+        BEBookRoomResponse {
+            status: "200".to_string(),
+            message: message.to_string(),
+            commit_booking: BookingDetails {
+                booking_id: booking_id.clone(),
+                resolved_booking_status: status,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn kinds(state: &CanisterState, booking_id: &BookingId) -> Vec<BookingEventKind> {
+        state
+            .get_booking_timeline(booking_id)
+            .unwrap()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_new_booking_has_created_event() {
+        let (state, booking_id) = create_test_state(EMAIL);
+        assert_eq!(kinds(&state, &booking_id), vec![BookingEventKind::Created]);
+    }
+
+    #[test]
+    fn test_status_transitions_are_kept() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        state
+            .update_book_room_response(
+                booking_id.clone(),
+                book_room_response(&booking_id, ResolvedBookingStatus::BookingOnHold, "on hold"),
+            )
+            .unwrap();
+        state
+            .update_book_room_response(
+                booking_id.clone(),
+                book_room_response(&booking_id, ResolvedBookingStatus::BookingConfirmed, "confirmed"),
+            )
+            .unwrap();
+
+        assert_eq!(
+            kinds(&state, &booking_id),
+            vec![
+                BookingEventKind::Created,
+                BookingEventKind::BookingStatusChanged {
+                    from: ResolvedBookingStatus::Unknown,
+                    to: ResolvedBookingStatus::BookingOnHold,
+                },
+                BookingEventKind::Message("on hold".to_string()),
+                BookingEventKind::BookingStatusChanged {
+                    from: ResolvedBookingStatus::BookingOnHold,
+                    to: ResolvedBookingStatus::BookingConfirmed,
+                },
+                BookingEventKind::Message("confirmed".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejected_transition_is_not_recorded() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+        state
+            .update_book_room_response(
+                booking_id.clone(),
+                book_room_response(&booking_id, ResolvedBookingStatus::BookingFailed, ""),
+            )
+            .unwrap();
+
+        let result = state.update_book_room_response(
+            booking_id.clone(),
+            book_room_response(&booking_id, ResolvedBookingStatus::BookingConfirmed, ""),
+        );
+        assert!(matches!(result, Err(BackendError::InvalidTransition { .. })));
+        assert_eq!(kinds(&state, &booking_id).len(), 2);
+    }
+
+    #[test]
+    fn test_payment_status_change_is_recorded() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+        let mut payment_details = PaymentDetails::new(booking_id.clone());
+        payment_details.payment_api_response.payment_id_v2 = "pay_1".to_string();
+        payment_details.payment_api_response.payment_status = "finished".to_string();
+
+        state
+            .update_payment_details(booking_id.clone(), payment_details.clone())
+            .unwrap();
+        // the same status again adds nothing
+        state.update_payment_details(booking_id.clone(), payment_details).unwrap();
+
+        assert_eq!(
+            kinds(&state, &booking_id),
+            vec![
+                BookingEventKind::Created,
+                BookingEventKind::PaymentStatusChanged {
+                    from: BackendPaymentStatus::Pending(None),
+                    to: BackendPaymentStatus::Paid("pay_1".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_email_sent_and_message_are_recorded() {
+        let (mut state, booking_id) = create_test_state(EMAIL);
+
+        state.update_email_sent(booking_id.clone(), true).unwrap();
+        state
+            .update_booking_message(booking_id.clone(), "guest called".to_string())
+            .unwrap();
+
+        assert_eq!(
+            kinds(&state, &booking_id)[1..],
+            [
+                BookingEventKind::EmailSent(true),
+                BookingEventKind::Message("guest called".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timeline_of_unknown_booking() {
+        let mut state = CanisterState::new();
+        let booking_id = BookingId::new("missing".to_string(), EMAIL.to_string());
+
+        // email_sent accepts unknown ids but leaves no timeline behind
+        state.update_email_sent(booking_id.clone(), true).unwrap();
+        assert!(state.booking_timelines.is_empty());
+
+        let result = state.get_booking_timeline(&booking_id);
+        assert!(matches!(result, Err(BackendError::NotFound { .. })));
+    }
+
+    #[test]
+    fn test_timeline_survives_snapshot_round_trip() {
+        let (state, booking_id) = create_test_state(EMAIL);
+
+        let mut restored = CanisterState::new();
+        restored.restore_snapshot(state.to_snapshot());
+        assert_eq!(kinds(&restored, &booking_id), vec![BookingEventKind::Created]);
+    }
+}
@@ -0,0 +1,122 @@
+//! Signed payment provider callbacks.
+//!
+//! `ingest_payment_webhook` takes the raw callback body and the provider's signature header,
+//! checks the signature against the secret an admin configured for that provider and only
+//! then hands the event to `ingest_payment_event`. A tampered body never reaches
+//! `update_payment_details`. Signature schemes and payload formats belong to the provider
+//! adapters in `payments`.
+use serde_json::Value;
+
+use crate::audit::{self, AuditTarget};
+use crate::errors::{BackendError, BackendResult};
+use crate::payment_events::{PaymentEvent, PaymentEventResult};
+use crate::payments;
+use crate::roles::{is_admin, is_payment_service};
+use crate::{BookingId, CanisterState, STATE};
+
+fn parse_body(body: &str) -> BackendResult<Value> {
+    serde_json::from_str(body)
+        .map_err(|e| BackendError::validation("body", format!("not valid JSON: {}", e)))
+}
+
+/// Secrets are looked up by lowercased provider name
+fn secret_key(provider: &str) -> String {
+    provider.trim().to_lowercase()
+}
+
+impl CanisterState {
+    pub fn set_webhook_secret(&mut self, provider: &str, secret: String) -> BackendResult<()> {
+        if provider.trim().is_empty() {
+            return Err(BackendError::validation("provider", "cannot be empty"));
+        }
+        if secret.is_empty() {
+            return Err(BackendError::validation("secret", "cannot be empty"));
+        }
+        self.webhook_secrets.insert(secret_key(provider), secret);
+        Ok(())
+    }
+
+    pub fn remove_webhook_secret(&mut self, provider: &str) -> BackendResult<()> {
+        self.webhook_secrets
+            .remove(&secret_key(provider))
+            .map(|_| ())
+            .ok_or_else(|| BackendError::not_found("Webhook secret", provider))
+    }
+
+    /// The event in `body` if `signature` is valid for `provider`'s secret
+    pub fn verify_payment_webhook(
+        &self,
+        provider: &str,
+        body: &str,
+        signature: &str,
+        booking_id: Option<BookingId>,
+    ) -> BackendResult<PaymentEvent> {
+        let adapter = payments::provider_for(provider).ok_or_else(|| {
+            BackendError::validation("provider", format!("unknown payment provider {}", provider))
+        })?;
+        let secret = self
+            .webhook_secrets
+            .get(&secret_key(provider))
+            .ok_or_else(|| {
+                BackendError::Unauthorized(format!("no webhook secret configured for {}", provider))
+            })?;
+        if !adapter.verify_signature(secret, body, signature) {
+            return Err(BackendError::Unauthorized(
+                "invalid webhook signature".to_string(),
+            ));
+        }
+        let payment_api_response = adapter.normalize(&parse_body(body)?)?.into();
+
+        Ok(PaymentEvent {
+            booking_id,
+            payment_api_response,
+        })
+    }
+}
+
+/// `signature` is the provider's signature header, e.g. `x-nowpayments-sig` or `Stripe-Signature`.
+/// `booking_id` may be `None` once the payment is known, see `PaymentEvent`.
+#[ic_cdk_macros::update(guard = "is_payment_service")]
+fn ingest_payment_webhook(
+    provider: String,
+    body: String,
+    signature: String,
+    booking_id: Option<BookingId>,
+) -> BackendResult<PaymentEventResult> {
+    let event = STATE.with(|state| {
+        state
+            .borrow()
+            .verify_payment_webhook(&provider, &body, &signature, booking_id)
+    })?;
+    crate::payment_events::ingest_payment_event(event)
+}
+
+/// The audit log only records whether a secret is set, never the secret
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn set_webhook_secret(provider: String, secret: String) -> BackendResult<()> {
+    audit::audited(
+        "set_webhook_secret",
+        AuditTarget::default(),
+        |state| state.webhook_secrets.contains_key(&secret_key(&provider)),
+        |state| state.set_webhook_secret(&provider, secret),
+    )
+}
+
+#[ic_cdk_macros::update(guard = "is_admin")]
+fn remove_webhook_secret(provider: String) -> BackendResult<()> {
+    audit::audited(
+        "remove_webhook_secret",
+        AuditTarget::default(),
+        |state| state.webhook_secrets.contains_key(&secret_key(&provider)),
+        |state| state.remove_webhook_secret(&provider),
+    )
+}
+
+/// Providers with a secret configured
+#[ic_cdk_macros::query(guard = "is_admin")]
+fn get_webhook_providers() -> Vec<String> {
+    STATE.with(|state| state.borrow().webhook_secrets.keys().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,204 @@
+#[cfg(test)]
+mod webhooks_tests {
+    use crate::errors::BackendError;
+    use crate::models::*;
+    use crate::payment_events::{PaymentEventOutcome, PaymentEventResult};
+    use crate::payments::{hmac_sha256, NOWPAYMENTS, STRIPE};
+    use crate::test_utils;
+
+    const EMAIL: &str = "user1@example.com";
+    // secret the fixture signatures were recorded with
+    const IPN_SECRET: &str = "test-ipn-secret";
+
+    const FINISHED_BODY: &str = include_str!("fixtures/nowpayments_ipn_finished.json");
+    const FINISHED_SIG: &str = include_str!("fixtures/nowpayments_ipn_finished.sig");
+    const PARTIALLY_PAID_BODY: &str = include_str!("fixtures/nowpayments_ipn_partially_paid.json");
+    const PARTIALLY_PAID_SIG: &str = include_str!("fixtures/nowpayments_ipn_partially_paid.sig");
+    const STRIPE_SECRET: &str = "whsec_test_secret";
+    const STRIPE_BODY: &str = include_str!("fixtures/stripe_payment_intent_succeeded.json");
+    const STRIPE_SIG: &str = include_str!("fixtures/stripe_payment_intent_succeeded.sig");
+
+    fn create_test_state() -> (CanisterState, BookingId) {
+        let (mut state, booking_id) = test_utils::create_test_state(EMAIL);
+        state.set_webhook_secret(NOWPAYMENTS, IPN_SECRET.to_string()).unwrap();
+        (state, booking_id)
+    }
+
+    fn ingest(
+        state: &mut CanisterState,
+        booking_id: &BookingId,
+        body: &str,
+        signature: &str,
+    ) -> Result<PaymentEventResult, BackendError> {
+        let event = state.verify_payment_webhook(NOWPAYMENTS, body, signature, Some(booking_id.clone()))?;
+        state.ingest_payment_event(event)
+    }
+
+    #[test]
+    fn test_signed_callbacks_are_ingested() {
+        let (mut state, booking_id) = create_test_state();
+
+        let result = ingest(&mut state, &booking_id, PARTIALLY_PAID_BODY, PARTIALLY_PAID_SIG).unwrap();
+        assert_eq!(result.outcome, PaymentEventOutcome::Applied);
+        assert_eq!(
+            result.booking.payment_details.payment_status,
+            BackendPaymentStatus::PartiallyPaid("5077125051".to_string())
+        );
+
+        let result = ingest(&mut state, &booking_id, FINISHED_BODY, FINISHED_SIG).unwrap();
+        assert_eq!(result.outcome, PaymentEventOutcome::Applied);
+        assert!(result.booking.payment_details.is_paid());
+
+        // redelivery of a signed callback is harmless
+        let result = ingest(&mut state, &booking_id, FINISHED_BODY, FINISHED_SIG).unwrap();
+        assert_eq!(result.outcome, PaymentEventOutcome::Duplicate);
+    }
+
+    #[test]
+    fn test_signed_stripe_event_is_ingested() {
+        let (mut state, booking_id) = create_test_state();
+        state
+            .set_webhook_secret(STRIPE, STRIPE_SECRET.to_string())
+            .unwrap();
+
+        let event = state
+            .verify_payment_webhook(STRIPE, STRIPE_BODY, STRIPE_SIG, Some(booking_id.clone()))
+            .unwrap();
+        let result = state.ingest_payment_event(event).unwrap();
+
+        assert_eq!(result.outcome, PaymentEventOutcome::Applied);
+        assert_eq!(
+            result.booking.payment_details.payment_status,
+            BackendPaymentStatus::Paid("pi_3QbT2eLkdIwHu7ix0sYbq5Zp".to_string())
+        );
+        assert_eq!(result.booking.payment_mismatch, None);
+
+        // the NowPayments secret doesn't sign Stripe events
+        assert!(matches!(
+            state.verify_payment_webhook(STRIPE, FINISHED_BODY, FINISHED_SIG, Some(booking_id)),
+            Err(BackendError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn test_stripe_events_in_the_same_second_are_both_applied() {
+        let (mut state, booking_id) = create_test_state();
+        state
+            .set_webhook_secret(STRIPE, STRIPE_SECRET.to_string())
+            .unwrap();
+        // Stripe's `created` has second resolution, the fixture's succeeded event shares it
+        let processing_body = STRIPE_BODY
+            .replace("evt_3QbT2eLkdIwHu7ix0zWJ9n4d", "evt_3QbT2eLkdIwHu7ix0pR0cEs5")
+            .replace("payment_intent.succeeded", "payment_intent.processing")
+            .replace("\"amount_received\": 40000", "\"amount_received\": 0")
+            .replace("\"status\": \"succeeded\"", "\"status\": \"processing\"");
+        let processing_sig = format!(
+            "t=1,v1={}",
+            hex::encode(hmac_sha256(
+                STRIPE_SECRET.as_bytes(),
+                format!("1.{}", processing_body).as_bytes()
+            ))
+        );
+
+        let payment_id_v2 = "pi_3QbT2eLkdIwHu7ix0sYbq5Zp".to_string();
+        for (body, signature, status) in [
+            (processing_body.as_str(), processing_sig.as_str(), BackendPaymentStatus::Confirming(payment_id_v2.clone())),
+            (STRIPE_BODY, STRIPE_SIG, BackendPaymentStatus::Paid(payment_id_v2.clone())),
+        ] {
+            let event = state
+                .verify_payment_webhook(STRIPE, body, signature, Some(booking_id.clone()))
+                .unwrap();
+            let result = state.ingest_payment_event(event).unwrap();
+            assert_eq!(result.outcome, PaymentEventOutcome::Applied);
+            assert_eq!(result.booking.payment_details.payment_status, status);
+        }
+
+        let events = state.get_payment_events(&booking_id).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].updated_at_ns, events[1].updated_at_ns);
+    }
+
+    #[test]
+    fn test_tampered_body_is_rejected_before_any_change() {
+        let (mut state, booking_id) = create_test_state();
+        let tampered = PARTIALLY_PAID_BODY.replace("\"actually_paid\":0.06", "\"actually_paid\":0.1");
+        let tampered = tampered.replace("partially_paid", "finished");
+
+        let result = ingest(&mut state, &booking_id, &tampered, PARTIALLY_PAID_SIG);
+
+        assert!(matches!(result, Err(BackendError::Unauthorized(_))));
+        assert_eq!(
+            state.bookings.get(&booking_id).unwrap().payment_details.payment_status,
+            BackendPaymentStatus::Pending(None)
+        );
+        assert!(state.get_payment_events(&booking_id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_bad_signatures_and_unknown_providers() {
+        let (mut state, booking_id) = create_test_state();
+        let booking_id = Some(booking_id);
+
+        for signature in ["", "not hex", &FINISHED_SIG[..64]] {
+            assert!(matches!(
+                state.verify_payment_webhook(NOWPAYMENTS, FINISHED_BODY, signature, booking_id.clone()),
+                Err(BackendError::Unauthorized(_))
+            ));
+        }
+        // provider names are case-insensitive
+        assert!(state
+            .verify_payment_webhook("nowpayments", FINISHED_BODY, FINISHED_SIG, booking_id.clone())
+            .is_ok());
+        // no secret configured
+        assert!(matches!(
+            state.verify_payment_webhook(STRIPE, STRIPE_BODY, STRIPE_SIG, booking_id.clone()),
+            Err(BackendError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            state.verify_payment_webhook("PayPal", FINISHED_BODY, FINISHED_SIG, booking_id.clone()),
+            Err(BackendError::Validation { .. })
+        ));
+        // NowPayments signs the parsed body, so a body that doesn't parse can't be verified
+        assert!(matches!(
+            state.verify_payment_webhook(NOWPAYMENTS, "not json", FINISHED_SIG, booking_id.clone()),
+            Err(BackendError::Unauthorized(_))
+        ));
+        // a correctly signed payload the adapter can't read
+        state
+            .set_webhook_secret(STRIPE, STRIPE_SECRET.to_string())
+            .unwrap();
+        let body = r#"{"type":"payment_intent.succeeded","data":{"object":{"id":"pi_1"}}}"#;
+        let signature = format!(
+            "t=1,v1={}",
+            hex::encode(hmac_sha256(
+                STRIPE_SECRET.as_bytes(),
+                format!("1.{}", body).as_bytes()
+            ))
+        );
+        assert!(matches!(
+            state.verify_payment_webhook(STRIPE, body, &signature, booking_id),
+            Err(BackendError::Validation { .. })
+        ));
+    }
+
+    #[test]
+    fn test_webhook_secret_management() {
+        let mut state = CanisterState::new();
+
+        assert!(state.set_webhook_secret(NOWPAYMENTS, String::new()).is_err());
+        assert!(state.set_webhook_secret(" ", "secret".to_string()).is_err());
+        state.set_webhook_secret(NOWPAYMENTS, "secret".to_string()).unwrap();
+        assert_eq!(state.webhook_secrets.keys().collect::<Vec<_>>(), vec!["nowpayments"]);
+
+        // secrets stay out of exported snapshots and survive a restore
+        let snapshot = state.to_snapshot();
+        state.restore_snapshot(snapshot);
+        assert_eq!(state.webhook_secrets.len(), 1);
+
+        state.remove_webhook_secret("NOWPAYMENTS").unwrap();
+        assert!(matches!(
+            state.remove_webhook_secret(NOWPAYMENTS),
+            Err(BackendError::NotFound { .. })
+        ));
+    }
+}
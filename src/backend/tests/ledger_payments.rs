@@ -0,0 +1,297 @@
+//! `get_payment_address`, `verify_ledger_payment` and `sweep_ledger_payment` against a real
+//! ICRC-1 ledger in PocketIC.
+//!
+//! Needs the PocketIC server (`POCKET_IC_BIN`), this canister built for wasm32
+//! (`ESTATE_BACKEND_WASM`) and an ICRC-1 ledger wasm from an IC release
+//! (`ICRC1_LEDGER_WASM`, e.g. `ic-icrc1-ledger.wasm.gz`), so it only runs when asked:
+//!
+//! ```sh
+//! cargo build -p estate_backend --target wasm32-unknown-unknown --release
+//! ESTATE_BACKEND_WASM=target/wasm32-unknown-unknown/release/estate_backend.wasm \
+//! ICRC1_LEDGER_WASM=ic-icrc1-ledger.wasm.gz \
+//! cargo test -p estate_backend --test ledger_payments -- --ignored
+//! ```
+//!
+//! CI does the same in the `ledger-e2e` job of `.github/workflows/cargo-test.yml`.
+use candid::{encode_args, encode_one, CandidType, Nat, Principal, Reserved};
+use pocket_ic::{query_candid_as, update_candid_as, PocketIc};
+use serde::Deserialize;
+use std::time::Duration;
+
+use estate_backend::ledger_payments::{Account, LedgerConfig, LedgerPaymentAddress};
+use estate_backend::*;
+
+const EMAIL: &str = "guest@example.com";
+const TRANSFER_FEE: u64 = 10_000;
+
+// The parts of the ICRC-1 ledger interface used here. Optional fields left out are `null`.
+
+#[derive(CandidType)]
+enum LedgerArg {
+    Init(LedgerInitArgs),
+}
+
+#[derive(CandidType)]
+struct LedgerInitArgs {
+    minting_account: Account,
+    transfer_fee: Nat,
+    decimals: Option<u8>,
+    token_symbol: String,
+    token_name: String,
+    metadata: Vec<(String, MetadataValue)>,
+    initial_balances: Vec<(Account, Nat)>,
+    archive_options: ArchiveOptions,
+}
+
+#[derive(CandidType)]
+#[allow(dead_code)]
+enum MetadataValue {
+    Nat(Nat),
+    Text(String),
+}
+
+#[derive(CandidType)]
+struct ArchiveOptions {
+    num_blocks_to_archive: u64,
+    trigger_threshold: u64,
+    controller_id: Principal,
+}
+
+#[derive(CandidType)]
+struct TransferArg {
+    to: Account,
+    amount: Nat,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferResult {
+    Ok(Nat),
+    Err(Reserved),
+}
+
+fn wasm(variable: &str) -> Vec<u8> {
+    let path = std::env::var(variable).unwrap_or_else(|_| panic!("{} is not set", variable));
+    std::fs::read(&path).unwrap_or_else(|e| panic!("cannot read {}: {}", path, e))
+}
+
+fn principal(byte: u8) -> Principal {
+    Principal::from_slice(&[byte; 29])
+}
+
+fn usd(amount_minor: i128) -> Money {
+    Money::new(amount_minor, CurrencyCode::usd())
+}
+
+fn booking(email: &str) -> Booking {
+    let booking_id = BookingId::new("APP001".to_string(), email.to_string());
+    Booking {
+        booking_id: booking_id.clone(),
+        guests: UserDetails {
+            adults: vec![AdultDetail {
+                first_name: "John".to_string(),
+                last_name: Some("Doe".to_string()),
+                email: Some(email.to_string()),
+                phone: Some("1234567890".to_string()),
+            }],
+            children: vec![],
+        },
+        book_room_status: None,
+        user_selected_hotel_room_details: HotelRoomDetails {
+            hotel_details: HotelDetails {
+                hotel_name: "Test Hotel".to_string(),
+                hotel_code: "TH001".to_string(),
+                hotel_image: "image.jpg".to_string(),
+                hotel_location: "Test Location".to_string(),
+                block_room_id: "BR001".to_string(),
+                hotel_token: "token123".to_string(),
+            },
+            date_range: SelectedDateRange {
+                start: (2025, 1, 1),
+                end: (2025, 1, 5),
+            },
+            destination: None,
+            room_details: vec![RoomDetails {
+                room_type_name: "Deluxe".to_string(),
+                room_unique_id: "D001".to_string(),
+                price: Some(usd(10000)),
+                ..Default::default()
+            }],
+            requested_payment: Some(usd(40000)),
+            ..Default::default()
+        },
+        payment_details: PaymentDetails::new(booking_id),
+        cancellation: None,
+        payment_mismatch: None,
+    }
+}
+
+struct TestEnv {
+    pic: PocketIc,
+    backend: Principal,
+    ledger: Principal,
+    admin: Principal,
+    guest: Principal,
+}
+
+impl TestEnv {
+    /// A ckUSDC-like ledger where the guest holds 1000 tokens, and the backend with one
+    /// 400 USD booking of the guest's
+    fn new() -> Self {
+        let pic = PocketIc::new();
+        let (admin, guest, minter) = (principal(1), principal(2), principal(3));
+
+        let ledger = pic.create_canister_with_settings(Some(admin), None);
+        pic.add_cycles(ledger, 2_000_000_000_000);
+        let init = LedgerArg::Init(LedgerInitArgs {
+            minting_account: Account {
+                owner: minter,
+                subaccount: None,
+            },
+            transfer_fee: Nat::from(TRANSFER_FEE),
+            decimals: Some(6),
+            token_symbol: "ckUSDC".to_string(),
+            token_name: "ckUSDC".to_string(),
+            metadata: vec![],
+            initial_balances: vec![(
+                Account {
+                    owner: guest,
+                    subaccount: None,
+                },
+                Nat::from(1_000_000_000u64),
+            )],
+            archive_options: ArchiveOptions {
+                num_blocks_to_archive: 1000,
+                trigger_threshold: 2000,
+                controller_id: admin,
+            },
+        });
+        pic.install_canister(ledger, wasm("ICRC1_LEDGER_WASM"), encode_one(init).unwrap(), Some(admin));
+
+        let backend = pic.create_canister_with_settings(Some(admin), None);
+        pic.add_cycles(backend, 2_000_000_000_000);
+        pic.install_canister(backend, wasm("ESTATE_BACKEND_WASM"), encode_args(()).unwrap(), Some(admin));
+
+        let env = Self {
+            pic,
+            backend,
+            ledger,
+            admin,
+            guest,
+        };
+        let config = LedgerConfig {
+            token: "ckUSDC".to_string(),
+            ledger_canister_id: ledger,
+            decimals: 6,
+            price_per_token_e9: 1_000_000_000,
+            price_currency: CurrencyCode::usd(),
+        };
+        let (result,): (BackendResult<()>,) = env.update(admin, "set_payment_ledger", (config,));
+        result.unwrap();
+        let (result,): (BackendResult<String>,) = env.update(
+            admin,
+            "update_user_principal_email_index_v2",
+            (guest, EMAIL.to_string()),
+        );
+        result.unwrap();
+        let (result,): (BackendResult<String>,) =
+            env.update(guest, "create_my_booking", (booking(EMAIL),));
+        result.unwrap();
+        env
+    }
+
+    fn update<I, O>(&self, sender: Principal, method: &str, input: I) -> O
+    where
+        I: candid::utils::ArgumentEncoder,
+        O: for<'a> candid::utils::ArgumentDecoder<'a>,
+    {
+        update_candid_as(&self.pic, self.backend, sender, method, input).unwrap()
+    }
+
+    fn pay(&self, to: &Account, amount: u64) {
+        let transfer = TransferArg {
+            to: to.clone(),
+            amount: Nat::from(amount),
+        };
+        let (result,): (TransferResult,) =
+            update_candid_as(&self.pic, self.ledger, self.guest, "icrc1_transfer", (transfer,))
+                .unwrap();
+        assert!(matches!(result, TransferResult::Ok(_)), "{:?}", result);
+    }
+
+    fn balance(&self, owner: Principal) -> Nat {
+        let account = Account {
+            owner,
+            subaccount: None,
+        };
+        let (balance,): (Nat,) =
+            query_candid_as(&self.pic, self.ledger, owner, "icrc1_balance_of", (account,)).unwrap();
+        balance
+    }
+
+    fn verify(&self, sender: Principal, booking_id: &BookingId) -> BackendResult<Booking> {
+        let (result,) = self.update(sender, "verify_ledger_payment", (booking_id.clone(),));
+        result
+    }
+}
+
+#[test]
+#[ignore = "needs POCKET_IC_BIN, ESTATE_BACKEND_WASM and ICRC1_LEDGER_WASM"]
+fn test_ledger_payment_is_verified_on_the_ledger() {
+    let env = TestEnv::new();
+    let booking_id = BookingId::new("APP001".to_string(), EMAIL.to_string());
+
+    let (address,): (BackendResult<LedgerPaymentAddress>,) = query_candid_as(
+        &env.pic,
+        env.backend,
+        env.guest,
+        "get_payment_address",
+        (booking_id.clone(),),
+    )
+    .unwrap();
+    let address = address.unwrap();
+    assert_eq!(address.account.owner, env.backend);
+    assert_eq!(address.amounts_due[0].amount, Nat::from(400_000_000u64));
+
+    // strangers can't trigger checks on other people's bookings
+    assert!(matches!(
+        env.verify(principal(9), &booking_id),
+        Err(BackendError::Unauthorized(_))
+    ));
+
+    let booking = env.verify(env.guest, &booking_id).unwrap();
+    assert_eq!(booking.payment_details.payment_status, BackendPaymentStatus::Pending(None));
+
+    env.pay(&address.account, 150_000_000);
+    // the guest has to wait before checking the same booking again
+    assert!(matches!(env.verify(env.guest, &booking_id), Err(BackendError::Conflict(_))));
+    env.pic.advance_time(Duration::from_secs(30));
+    let booking = env.verify(env.guest, &booking_id).unwrap();
+    assert!(matches!(
+        booking.payment_details.payment_status,
+        BackendPaymentStatus::PartiallyPaid(_)
+    ));
+
+    env.pay(&address.account, 250_000_000);
+    let booking = env.verify(env.admin, &booking_id).unwrap();
+    assert!(booking.payment_details.is_paid());
+    assert_eq!(booking.payment_mismatch, None);
+
+    let (paid,): (bool,) = env.update(env.admin, "is_booking_paid", (booking_id.clone(),));
+    assert!(paid);
+    // nothing left to check for the guest
+    env.pic.advance_time(Duration::from_secs(30));
+    assert!(matches!(env.verify(env.guest, &booking_id), Err(BackendError::Conflict(_))));
+
+    // the admin moves the payment out of the booking's subaccount
+    let treasury = Account {
+        owner: principal(4),
+        subaccount: None,
+    };
+    let sweep = (booking_id, "ckUSDC".to_string(), treasury.clone());
+    let rejected: Result<(BackendResult<Nat>,), _> =
+        update_candid_as(&env.pic, env.backend, env.guest, "sweep_ledger_payment", sweep.clone());
+    assert!(rejected.is_err());
+    let (block_index,): (BackendResult<Nat>,) = env.update(env.admin, "sweep_ledger_payment", sweep);
+    block_index.unwrap();
+    assert_eq!(env.balance(treasury.owner), Nat::from(400_000_000u64 - TRANSFER_FEE));
+}